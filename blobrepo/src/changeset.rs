@@ -39,10 +39,26 @@ pub struct BlobChangeset {
     revlogcs: RevlogChangeset,
 }
 
-fn cskey(changesetid: &HgChangesetId) -> String {
+pub fn cskey(changesetid: &HgChangesetId) -> String {
     format!("changeset-{}.bincode", changesetid)
 }
 
+/// Load the parents and content of a changeset as stored, without parsing the content.
+pub fn load_raw_changeset(
+    blobstore: &Arc<Blobstore>,
+    changesetid: &HgChangesetId,
+) -> impl Future<Item = Option<BlobNode>, Error = Error> + Send + 'static {
+    blobstore.get(cskey(changesetid)).and_then(|got| match got {
+        None => Ok(None),
+        Some(bytes) => {
+            let RawCSBlob { parents, blob } = bincode::deserialize(bytes.as_ref())?;
+            let (p1, p2) = parents.get_nodes();
+            let blob = Blob::from(Bytes::from(blob.into_owned()));
+            Ok(Some(BlobNode::new(blob, p1, p2)))
+        }
+    })
+}
+
 impl BlobChangeset {
     pub fn new(revlogcs: RevlogChangeset) -> Result<Self> {
         let node = revlogcs.get_node()?;
@@ -0,0 +1,337 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Content blobs that are too large to be handled whole are stored in chunks, so that they can be
+//! read in parts and streamed.
+//!
+//! A content of at most `CHUNK_SIZE` bytes is stored as is under its key. A larger one is split
+//! in chunks of `CHUNK_SIZE` bytes (except for the last one), each stored under the SHA-1 of its
+//! data, and the list of its chunks is stored under its key prefixed with `chunked-`. Readers
+//! only look for the list when the key itself is missing, so small contents cost nothing more.
+
+use std::sync::Arc;
+
+use bincode;
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, join_all, Future};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use blobstore::{range_bounds, Blobstore};
+use mercurial_types::hash::Sha1;
+
+use errors::*;
+
+/// Size of the chunks of the large contents, and so the largest content stored whole.
+pub const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Number of chunks of a content that are fetched at the same time.
+const CHUNK_FETCHES: usize = 4;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkedContent {
+    size: u64,
+    /// Keys of the chunks, in order
+    chunks: Vec<String>,
+}
+
+fn get_chunked_key(key: &str) -> String {
+    format!("chunked-{}", key)
+}
+
+fn get_chunk_key(chunk: &[u8]) -> String {
+    format!("chunk-sha1-{}", Sha1::from(chunk))
+}
+
+fn get_chunked(blobstore: &Arc<Blobstore>, key: &str) -> BoxFuture<Option<ChunkedContent>, Error> {
+    blobstore
+        .get(get_chunked_key(key))
+        .and_then(|got| match got {
+            None => Ok(None),
+            Some(blob) => Ok(Some(bincode::deserialize(blob.as_ref())?)),
+        })
+        .boxify()
+}
+
+/// Store `content` under `key`, in chunks if it is larger than `CHUNK_SIZE`.
+pub fn put_content(
+    blobstore: &Arc<Blobstore>,
+    key: String,
+    content: Bytes,
+) -> BoxFuture<(), Error> {
+    if content.len() as u64 <= CHUNK_SIZE {
+        return blobstore.put(key, content);
+    }
+
+    let mut chunks = vec![];
+    let mut chunk_puts = vec![];
+    let mut offset = 0;
+    while offset < content.len() {
+        let end = (offset + CHUNK_SIZE as usize).min(content.len());
+        let chunk = content.slice(offset, end);
+        let chunk_key = get_chunk_key(chunk.as_ref());
+        chunk_puts.push(blobstore.put(chunk_key.clone(), chunk));
+        chunks.push(chunk_key);
+        offset = end;
+    }
+    let chunked = ChunkedContent {
+        size: content.len() as u64,
+        chunks,
+    };
+    let chunked = try_boxfuture!(bincode::serialize(&chunked).map_err(Error::from));
+
+    // The list goes last so that the content can't be found before all of its chunks are stored
+    let blobstore = blobstore.clone();
+    join_all(chunk_puts)
+        .and_then(move |_| blobstore.put(get_chunked_key(&key), Bytes::from(chunked)))
+        .boxify()
+}
+
+/// Store the content that `content` yields under `key`, like `put_content` but without holding
+/// more than a chunk of it in memory. `check` gets the size of the content once all of it is
+/// read: nothing is stored under `key` unless it succeeds, and the chunks that were already
+/// stored are left for the garbage collector.
+pub fn put_content_stream<S, F>(
+    blobstore: &Arc<Blobstore>,
+    key: String,
+    content: S,
+    check: F,
+) -> BoxFuture<(), Error>
+where
+    S: Stream<Item = Bytes, Error = Error> + Send + 'static,
+    F: FnOnce(u64) -> Result<()> + Send + 'static,
+{
+    struct Chunking {
+        // What hasn't been stored yet, at most a chunk once a part of the content is handled
+        pending: BytesMut,
+        size: u64,
+        chunks: Vec<String>,
+    }
+
+    let init = Chunking {
+        pending: BytesMut::new(),
+        size: 0,
+        chunks: vec![],
+    };
+    let chunk_blobstore = blobstore.clone();
+    let blobstore = blobstore.clone();
+
+    content
+        .fold(init, move |mut chunking, part| {
+            chunking.size += part.len() as u64;
+            chunking.pending.extend_from_slice(part.as_ref());
+            // A content of exactly CHUNK_SIZE bytes is stored whole, so a full chunk is only
+            // stored once more of the content follows it
+            let mut chunk_puts = vec![];
+            while chunking.pending.len() as u64 > CHUNK_SIZE {
+                let chunk = chunking.pending.split_to(CHUNK_SIZE as usize).freeze();
+                let chunk_key = get_chunk_key(chunk.as_ref());
+                chunk_puts.push(chunk_blobstore.put(chunk_key.clone(), chunk));
+                chunking.chunks.push(chunk_key);
+            }
+            join_all(chunk_puts).map(move |_| chunking)
+        })
+        .and_then(move |chunking| {
+            let Chunking {
+                pending,
+                size,
+                mut chunks,
+            } = chunking;
+            try_boxfuture!(check(size));
+            if chunks.is_empty() {
+                return blobstore.put(key, pending.freeze());
+            }
+
+            let last = pending.freeze();
+            let last_key = get_chunk_key(last.as_ref());
+            chunks.push(last_key.clone());
+            let chunked = ChunkedContent { size, chunks };
+            let chunked = try_boxfuture!(bincode::serialize(&chunked).map_err(Error::from));
+            blobstore
+                .put(last_key, last)
+                .and_then(move |()| blobstore.put(get_chunked_key(&key), Bytes::from(chunked)))
+                .boxify()
+        })
+        .boxify()
+}
+
+/// Whether there is a content stored under `key`.
+pub fn content_exists(blobstore: &Arc<Blobstore>, key: String) -> BoxFuture<bool, Error> {
+    let blobstore = blobstore.clone();
+    blobstore
+        .is_present(key.clone())
+        .and_then(move |present| {
+            if present {
+                future::ok(true).boxify()
+            } else {
+                blobstore.is_present(get_chunked_key(&key))
+            }
+        })
+        .boxify()
+}
+
+/// Fetch the whole content stored under `key`.
+pub fn get_content(blobstore: &Arc<Blobstore>, key: String) -> BoxFuture<Option<Bytes>, Error> {
+    let blobstore = blobstore.clone();
+    blobstore
+        .get(key.clone())
+        .and_then(move |got| match got {
+            Some(blob) => future::ok(Some(blob)).boxify(),
+            None => get_chunked(&blobstore, &key)
+                .and_then(move |chunked| match chunked {
+                    None => future::ok(None).boxify(),
+                    Some(chunked) => {
+                        let size = chunked.size;
+                        chunk_range_stream(blobstore, key, chunked, 0, size)
+                            .fold(BytesMut::with_capacity(size as usize), |mut content, chunk| {
+                                content.extend_from_slice(chunk.as_ref());
+                                Ok::<_, Error>(content)
+                            })
+                            .map(|content| Some(content.freeze()))
+                            .boxify()
+                    }
+                })
+                .boxify(),
+        })
+        .boxify()
+}
+
+/// The size of the content stored under `key`.
+pub fn get_content_size(blobstore: &Arc<Blobstore>, key: String) -> BoxFuture<Option<u64>, Error> {
+    let blobstore = blobstore.clone();
+    blobstore
+        .get_size(key.clone())
+        .and_then(move |size| match size {
+            Some(size) => future::ok(Some(size)).boxify(),
+            None => get_chunked(&blobstore, &key)
+                .map(|chunked| chunked.map(|chunked| chunked.size))
+                .boxify(),
+        })
+        .boxify()
+}
+
+/// A stream of the part of the content stored under `key` that starts at `offset` and is at
+/// most `len` bytes long. Chunked contents are read one chunk at a time.
+pub fn get_content_range(
+    blobstore: &Arc<Blobstore>,
+    key: String,
+    offset: u64,
+    len: u64,
+) -> BoxFuture<Option<BoxStream<Bytes, Error>>, Error> {
+    let blobstore = blobstore.clone();
+    blobstore
+        .get_range(key.clone(), offset, len)
+        .and_then(move |got| match got {
+            Some(blob) => future::ok(Some(stream::once(Ok(blob)).boxify())).boxify(),
+            None => get_chunked(&blobstore, &key)
+                .map(move |chunked| {
+                    chunked.map(|chunked| {
+                        chunk_range_stream(blobstore, key, chunked, offset, len)
+                    })
+                })
+                .boxify(),
+        })
+        .boxify()
+}
+
+/// The size of the content stored under `key`, and a stream of all of it. Contents stored whole
+/// are read `CHUNK_SIZE` bytes at a time too, so that at most a few chunks are held in memory.
+pub fn get_content_stream(
+    blobstore: &Arc<Blobstore>,
+    key: String,
+) -> BoxFuture<Option<(u64, BoxStream<Bytes, Error>)>, Error> {
+    let blobstore = blobstore.clone();
+    blobstore
+        .get_size(key.clone())
+        .and_then(move |size| match size {
+            Some(size) => {
+                let offsets = (0..(size + CHUNK_SIZE - 1) / CHUNK_SIZE).map(|idx| idx * CHUNK_SIZE);
+                let content = stream::iter_ok(offsets)
+                    .map(move |offset| {
+                        let key = key.clone();
+                        blobstore
+                            .get_range(key.clone(), offset, CHUNK_SIZE)
+                            .and_then(move |got| {
+                                got.ok_or(format_err!("{} vanished while it was read", key))
+                            })
+                    })
+                    .buffered(CHUNK_FETCHES)
+                    .boxify();
+                future::ok(Some((size, content))).boxify()
+            }
+            None => get_chunked(&blobstore, &key)
+                .map(move |chunked| {
+                    chunked.map(|chunked| {
+                        let size = chunked.size;
+                        (size, chunk_range_stream(blobstore, key, chunked, 0, size))
+                    })
+                })
+                .boxify(),
+        })
+        .boxify()
+}
+
+/// The keys of the blobs that make up the content stored under `key`. The key itself is returned
+/// if the content is missing.
+pub fn get_content_keys(blobstore: &Arc<Blobstore>, key: String) -> BoxFuture<Vec<String>, Error> {
+    let blobstore = blobstore.clone();
+    blobstore
+        .is_present(key.clone())
+        .and_then(move |present| {
+            if present {
+                future::ok(vec![key]).boxify()
+            } else {
+                get_chunked(&blobstore, &key)
+                    .map(move |chunked| match chunked {
+                        None => vec![key],
+                        Some(chunked) => {
+                            let mut keys = vec![get_chunked_key(&key)];
+                            keys.extend(chunked.chunks);
+                            keys
+                        }
+                    })
+                    .boxify()
+            }
+        })
+        .boxify()
+}
+
+fn chunk_range_stream(
+    blobstore: Arc<Blobstore>,
+    key: String,
+    chunked: ChunkedContent,
+    offset: u64,
+    len: u64,
+) -> BoxStream<Bytes, Error> {
+    let (start, end) = range_bounds(chunked.size, offset, len);
+    let ranges: Vec<_> = chunked
+        .chunks
+        .into_iter()
+        .enumerate()
+        .filter_map(|(idx, chunk_key)| {
+            let chunk_start = idx as u64 * CHUNK_SIZE;
+            let chunk_end = chunk_start + CHUNK_SIZE;
+            if chunk_end <= start || chunk_start >= end {
+                None
+            } else {
+                let range_start = start.max(chunk_start);
+                let range_end = end.min(chunk_end);
+                Some((chunk_key, range_start - chunk_start, range_end - range_start))
+            }
+        })
+        .collect();
+
+    stream::iter_ok(ranges)
+        .map(move |(chunk_key, offset, len)| {
+            let key = key.clone();
+            blobstore
+                .get_range(chunk_key.clone(), offset, len)
+                .and_then(move |got| got.ok_or(ErrorKind::ChunkMissing(key, chunk_key).into()))
+        })
+        .buffered(CHUNK_FETCHES)
+        .boxify()
+}
+
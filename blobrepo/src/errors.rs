@@ -11,6 +11,7 @@ use bincode;
 pub use failure::Error;
 
 use mercurial_types::{Blob, HgBlobHash, HgChangesetId, NodeHash, Parents, RepoPath, Type};
+use mercurial_types::hash::Sha256;
 
 #[derive(Debug)]
 pub enum StateOpenError {
@@ -45,6 +46,7 @@ pub enum ErrorKind {
     #[fail(display = "Node id {} is missing", _0)] NodeMissing(NodeHash),
     #[fail(display = "Content missing nodeid {} (blob hash {:?})", _0, _1)]
     ContentMissing(NodeHash, HgBlobHash),
+    #[fail(display = "Chunk {} of content {} is missing", _1, _0)] ChunkMissing(String, String),
     #[fail(display = "Uploaded blob is incomplete {:?}", _0)] BadUploadBlob(Blob),
     #[fail(display = "Parents are not in blob store {:?}", _0)] ParentsUnknown(Parents),
     #[fail(display = "Serialization of node failed {} ({})", _0, _1)]
@@ -60,4 +62,7 @@ pub enum ErrorKind {
     #[fail(display = "Parents failed to complete")] ParentsFailed,
     #[fail(display = "Expected {} to be a manifest, found a {} instead", _0, _1)]
     NotAManifest(NodeHash, Type),
+    #[fail(display = "LFS content {} has SHA-256 {}", _0, _1)] LfsContentMismatch(Sha256, Sha256),
+    #[fail(display = "LFS content {} has {} bytes, not {}", _0, _1, _2)]
+    LfsSizeMismatch(Sha256, u64, u64),
 }
@@ -9,16 +9,18 @@ use std::sync::Arc;
 
 use bytes::Bytes;
 
-use futures::future::Future;
-use futures_ext::{BoxFuture, FutureExt};
+use futures::future::{self, Future};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use mercurial::file;
 use mercurial_types::{Blob, BlobNode, HgManifestId, MPath, MPathElement, NodeHash, Parents};
 use mercurial_types::manifest::{Content, Entry, Manifest, Type};
 use mercurial_types::nodehash::EntryId;
 
-use blobstore::Blobstore;
+use blobstore::{range_bounds, Blobstore};
 
+use chunked::{get_content, get_content_range, get_content_size, CHUNK_SIZE};
 use errors::*;
 
 use manifest::BlobManifest;
@@ -44,7 +46,7 @@ pub fn fetch_file_content_and_renames_from_blobstore(
                 let key = format!("sha1-{}", node.blob.sha1());
                 let parents = node.parents;
 
-                blobstore.get(key).and_then(move |blob| {
+                get_content(&blobstore, key).and_then(move |blob| {
                     blob.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
                         .and_then(|blob| {
                             let (p1, p2) = parents.get_nodes();
@@ -63,6 +65,86 @@ pub fn fetch_file_content_and_renames_from_blobstore(
         .boxify()
 }
 
+/// The size of the blob of a file revision: its content, along with any copy metadata. Unlike the
+/// size of the content, this doesn't need any of it to be fetched.
+pub fn fetch_file_blob_size_from_blobstore(
+    blobstore: &Arc<Blobstore>,
+    nodeid: NodeHash,
+) -> BoxFuture<u64, Error> {
+    get_node(blobstore, nodeid)
+        .and_then({
+            let blobstore = blobstore.clone();
+            move |node| {
+                let key = format!("sha1-{}", node.blob.sha1());
+                get_content_size(&blobstore, key).and_then(move |size| {
+                    size.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
+                })
+            }
+        })
+        .boxify()
+}
+
+/// Returns the size of the content of a file, and a stream of a part of it. `range` gets the size
+/// of the content and returns the offset of the part and its largest length. Only the chunks of
+/// large contents that the part is in are fetched.
+pub fn fetch_file_content_range_from_blobstore<F>(
+    blobstore: &Arc<Blobstore>,
+    nodeid: NodeHash,
+    range: F,
+) -> BoxFuture<(u64, BoxStream<Bytes, Error>), Error>
+where
+    F: FnOnce(u64) -> (u64, u64) + Send + 'static,
+{
+    get_node(blobstore, nodeid)
+        .and_then({
+            let blobstore = blobstore.clone();
+            move |node| {
+                let key = format!("sha1-{}", node.blob.sha1());
+                let blobhash = node.blob;
+
+                // The copy metadata at the start of the blob is much smaller than a chunk
+                let head = get_content_range(&blobstore, key.clone(), 0, CHUNK_SIZE)
+                    .and_then(move |head| {
+                        head.ok_or(ErrorKind::ContentMissing(nodeid, blobhash).into())
+                    })
+                    .and_then(|head| {
+                        head.fold(vec![], |mut head, bytes| {
+                            head.extend_from_slice(bytes.as_ref());
+                            Ok::<_, Error>(head)
+                        })
+                    })
+                    .map(Bytes::from);
+                let size = get_content_size(&blobstore, key.clone())
+                    .and_then(move |size| {
+                        size.ok_or(ErrorKind::ContentMissing(nodeid, blobhash).into())
+                    });
+
+                head.join(size).and_then(move |(head, size)| {
+                    let (_, meta_len) = file::File::extract_meta(head.as_ref());
+                    let meta_len = meta_len as u64;
+                    let content_size = size - meta_len;
+                    let (offset, len) = range(content_size);
+                    let (start, end) = range_bounds(content_size, offset, len);
+                    let (start, end) = (meta_len + start, meta_len + end);
+
+                    if end <= head.len() as u64 {
+                        let range = head.slice(start as usize, end as usize);
+                        let content = stream::once(Ok(range)).boxify();
+                        future::ok((content_size, content)).boxify()
+                    } else {
+                        get_content_range(&blobstore, key, start, end - start)
+                            .and_then(move |content| {
+                                content.ok_or(ErrorKind::ContentMissing(nodeid, blobhash).into())
+                            })
+                            .map(move |content| (content_size, content))
+                            .boxify()
+                    }
+                })
+            }
+        })
+        .boxify()
+}
+
 impl BlobEntry {
     pub fn new(
         blobstore: Arc<Blobstore>,
@@ -101,7 +183,7 @@ impl BlobEntry {
                 move |node| {
                     let key = format!("sha1-{}", node.blob.sha1());
 
-                    blobstore.get(key).and_then(move |blob| {
+                    get_content(&blobstore, key).and_then(move |blob| {
                         blob.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
                     })
                 }
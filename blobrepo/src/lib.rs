@@ -12,6 +12,7 @@ extern crate failure_ext as failure;
 #[macro_use]
 extern crate futures;
 
+extern crate ascii;
 extern crate bincode;
 extern crate bytes;
 extern crate serde;
@@ -30,7 +31,10 @@ extern crate futures_stats;
 
 extern crate blobstore;
 extern crate bookmarks;
+extern crate bookmarks_old;
 extern crate changesets;
+extern crate compressingblob;
+extern crate dbbookmarks;
 extern crate fileblob;
 extern crate filebookmarks;
 extern crate fileheads;
@@ -41,7 +45,6 @@ extern crate heads;
 extern crate linknodes;
 extern crate manifoldblob;
 extern crate memblob;
-extern crate membookmarks;
 extern crate memheads;
 extern crate memlinknodes;
 extern crate mercurial;
@@ -52,6 +55,7 @@ extern crate storage_types;
 
 mod repo;
 mod changeset;
+mod chunked;
 mod manifest;
 mod file;
 mod errors;
@@ -70,4 +74,4 @@ pub use repo_commit::compute_changed_files;
 //
 // TODO: (jsgf) T21597565 This is exposed here for blobimport -- don't use it for anything else.
 
-pub use utils::RawNodeBlob;
+pub use utils::{get_lfs_key, RawNodeBlob};
@@ -18,6 +18,7 @@ use mercurial_types::nodehash::{HgManifestId, NULL_HASH};
 
 use blobstore::Blobstore;
 
+use chunked::get_content;
 use errors::*;
 use file::BlobEntry;
 use utils::get_node;
@@ -45,7 +46,7 @@ impl BlobManifest {
                     let blobstore = blobstore.clone();
                     move |nodeblob| {
                         let blobkey = format!("sha1-{}", nodeblob.blob.sha1());
-                        blobstore.get(blobkey)
+                        get_content(&blobstore, blobkey)
                     }
                 })
                 .and_then({
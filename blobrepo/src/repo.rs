@@ -4,16 +4,18 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
 use std::mem;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+use ascii::AsciiString;
 use bincode;
 use bytes::Bytes;
 use failure::{Fail, ResultExt};
 use futures::{Async, Poll};
-use futures::future::Future;
+use futures::future::{Future, IntoFuture};
 use futures::stream::{self, Stream};
 use futures::sync::oneshot;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
@@ -22,8 +24,11 @@ use slog::{Discard, Drain, Logger};
 use uuid::Uuid;
 
 use blobstore::Blobstore;
-use bookmarks::Bookmarks;
-use changesets::{ChangesetInsert, Changesets, SqliteChangesets};
+use bookmarks::{BookmarkUpdateLogEntry, BookmarkUpdateReason, Bookmarks, Transaction};
+use bookmarks_old::Bookmarks as OldBookmarks;
+use changesets::{ChangesetInsert, Changesets, HgChangesetIdPrefix, SqliteChangesets};
+use compressingblob::CompressingBlobstore;
+use dbbookmarks::SqliteDbBookmarks;
 use fileblob::Fileblob;
 use filebookmarks::FileBookmarks;
 use fileheads::FileHeads;
@@ -32,24 +37,68 @@ use heads::Heads;
 use linknodes::Linknodes;
 use manifoldblob::ManifoldBlob;
 use memblob::{EagerMemblob, LazyMemblob};
-use membookmarks::MemBookmarks;
 use memheads::MemHeads;
 use memlinknodes::MemLinknodes;
 use mercurial_types::{Blob, BlobNode, Changeset, Entry, HgChangesetId, MPath, Manifest, NodeHash,
-                      Parents, RepoPath, RepositoryId, Time};
+                      Parents, RepoPath, RepositoryId, Time, NULL_HASH};
+use mercurial_types::hash::{Sha256, Sha256Context};
 use mercurial_types::manifest;
 use mercurial_types::nodehash::HgManifestId;
 use rocksblob::Rocksblob;
 use rocksdb;
-use storage_types::Version;
 use tokio_core::reactor::Remote;
 
 use BlobChangeset;
+use changeset::{cskey, load_raw_changeset};
+use chunked::{content_exists, get_content, get_content_keys, get_content_stream, put_content,
+              put_content_stream};
 use BlobManifest;
 use errors::*;
-use file::{fetch_file_content_and_renames_from_blobstore, BlobEntry};
+use file::{fetch_file_blob_size_from_blobstore, fetch_file_content_and_renames_from_blobstore,
+           fetch_file_content_range_from_blobstore, BlobEntry};
 use repo_commit::*;
-use utils::{get_node, get_node_key, RawNodeBlob};
+use utils::{get_lfs_key, get_node, get_node_key, RawNodeBlob};
+
+// Every persistent repo reads its blobs through a CompressingBlobstore, so that all the tools
+// working on it can read the compressed blobs, whether or not they compress the ones they write.
+fn compressing_blobstore(blobstore: Arc<Blobstore>, level: Option<i32>) -> Arc<Blobstore> {
+    match level {
+        Some(level) => Arc::new(CompressingBlobstore::new_zstd(blobstore, level)),
+        None => Arc::new(CompressingBlobstore::new(blobstore, None)),
+    }
+}
+
+/// Opens the bookmarks of a local repo. Repos created before bookmarks were stored in SQLite
+/// have them in `books/`: they are copied over the first time the repo is opened.
+fn open_local_bookmarks(path: &Path, repoid: RepositoryId) -> Result<SqliteDbBookmarks> {
+    let db_path = path.join("bookmarks");
+    let legacy_path = path.join("books");
+    if db_path.exists() || !legacy_path.is_dir() {
+        return SqliteDbBookmarks::open_or_create(db_path);
+    }
+
+    // The bookmarks are imported into a temporary database, so that a failed import is simply
+    // retried the next time the repo is opened
+    let tmp_path = path.join("bookmarks.tmp");
+    if tmp_path.exists() {
+        fs::remove_file(&tmp_path)?;
+    }
+    {
+        let legacy = FileBookmarks::open(legacy_path)?;
+        let bookmarks = SqliteDbBookmarks::create(tmp_path.to_string_lossy())?;
+        let mut transaction = bookmarks.create_transaction(&repoid);
+        for name in legacy.keys().collect().wait()? {
+            if let Some((csid, _version)) = legacy.get(&name).wait()? {
+                let name = AsciiString::from_ascii(name)
+                    .map_err(|err| format_err!("invalid bookmark name {:?}", err.into_source()))?;
+                transaction.create(&name, &csid)?;
+            }
+        }
+        transaction.commit(BookmarkUpdateReason::Blobimport, None).wait()?;
+    }
+    fs::rename(&tmp_path, &db_path)?;
+    SqliteDbBookmarks::open(db_path.to_string_lossy())
+}
 
 pub struct BlobRepo {
     logger: Logger,
@@ -82,38 +131,45 @@ impl BlobRepo {
         }
     }
 
-    pub fn new_files(logger: Logger, path: &Path, repoid: RepositoryId) -> Result<Self> {
-        let heads = FileHeads::open(path.join("heads"))
-            .context(ErrorKind::StateOpen(StateOpenError::Heads))?;
-        let bookmarks = FileBookmarks::open(path.join("books"))
-            .context(ErrorKind::StateOpen(StateOpenError::Bookmarks))?;
+    pub fn new_files(
+        logger: Logger,
+        path: &Path,
+        repoid: RepositoryId,
+        compression_level: Option<i32>,
+    ) -> Result<Self> {
         let blobstore = Fileblob::open(path.join("blobs"))
             .context(ErrorKind::StateOpen(StateOpenError::Blobstore))?;
-        let linknodes = FileLinknodes::open(path.join("linknodes"))
-            .context(ErrorKind::StateOpen(StateOpenError::Linknodes))?;
-        let changesets = SqliteChangesets::open(path.join("changesets").to_string_lossy())
-            .context(ErrorKind::StateOpen(StateOpenError::Linknodes))?;
 
-        Ok(Self::new(
-            logger,
-            Arc::new(heads),
-            Arc::new(bookmarks),
-            Arc::new(blobstore),
-            Arc::new(linknodes),
-            Arc::new(changesets),
-            repoid,
-        ))
+        Self::new_local(logger, path, Arc::new(blobstore), repoid, compression_level)
     }
 
-    pub fn new_rocksdb(logger: Logger, path: &Path, repoid: RepositoryId) -> Result<Self> {
-        let heads = FileHeads::open(path.join("heads"))
-            .context(ErrorKind::StateOpen(StateOpenError::Heads))?;
-        let bookmarks = FileBookmarks::open(path.join("books"))
-            .context(ErrorKind::StateOpen(StateOpenError::Bookmarks))?;
-
+    pub fn new_rocksdb(
+        logger: Logger,
+        path: &Path,
+        repoid: RepositoryId,
+        compression_level: Option<i32>,
+    ) -> Result<Self> {
         let options = rocksdb::Options::new().create_if_missing(true);
         let blobstore = Rocksblob::open_with_options(path.join("blobs"), options)
             .context(ErrorKind::StateOpen(StateOpenError::Blobstore))?;
+
+        Self::new_local(logger, path, Arc::new(blobstore), repoid, compression_level)
+    }
+
+    /// Open a repo whose heads, bookmarks, linknodes and changesets live on local disk under
+    /// `path`, with blobs stored in the given blobstore. Blobs are compressed with zstd at
+    /// `compression_level` if it is set, and the compressed ones can be read either way.
+    pub fn new_local(
+        logger: Logger,
+        path: &Path,
+        blobstore: Arc<Blobstore>,
+        repoid: RepositoryId,
+        compression_level: Option<i32>,
+    ) -> Result<Self> {
+        let heads = FileHeads::open(path.join("heads"))
+            .context(ErrorKind::StateOpen(StateOpenError::Heads))?;
+        let bookmarks = open_local_bookmarks(path, repoid)
+            .context(ErrorKind::StateOpen(StateOpenError::Bookmarks))?;
         let linknodes = FileLinknodes::open(path.join("linknodes"))
             .context(ErrorKind::StateOpen(StateOpenError::Linknodes))?;
         let changesets = SqliteChangesets::open(path.join("changesets").to_string_lossy())
@@ -123,7 +179,7 @@ impl BlobRepo {
             logger,
             Arc::new(heads),
             Arc::new(bookmarks),
-            Arc::new(blobstore),
+            compressing_blobstore(blobstore, compression_level),
             Arc::new(linknodes),
             Arc::new(changesets),
             repoid,
@@ -135,7 +191,7 @@ impl BlobRepo {
     pub fn new_memblob(
         logger: Option<Logger>,
         heads: MemHeads,
-        bookmarks: MemBookmarks,
+        bookmarks: SqliteDbBookmarks,
         blobstore: EagerMemblob,
         linknodes: MemLinknodes,
         changesets: SqliteChangesets,
@@ -155,7 +211,7 @@ impl BlobRepo {
     pub fn new_lazymemblob(
         logger: Option<Logger>,
         heads: MemHeads,
-        bookmarks: MemBookmarks,
+        bookmarks: SqliteDbBookmarks,
         blobstore: LazyMemblob,
         linknodes: MemLinknodes,
         changesets: SqliteChangesets,
@@ -176,7 +232,8 @@ impl BlobRepo {
         Ok(Self::new(
             logger.unwrap_or(Logger::root(Discard {}.ignore_res(), o!())),
             Arc::new(MemHeads::new()),
-            Arc::new(MemBookmarks::new()),
+            Arc::new(SqliteDbBookmarks::in_memory()
+                .context(ErrorKind::StateOpen(StateOpenError::Bookmarks))?),
             Arc::new(EagerMemblob::new()),
             Arc::new(MemLinknodes::new()),
             Arc::new(SqliteChangesets::in_memory()
@@ -191,9 +248,11 @@ impl BlobRepo {
         prefix: &str,
         remote: &Remote,
         repoid: RepositoryId,
+        compression_level: Option<i32>,
     ) -> Result<Self> {
         let heads = MemHeads::new();
-        let bookmarks = MemBookmarks::new();
+        let bookmarks = SqliteDbBookmarks::in_memory()
+            .context(ErrorKind::StateOpen(StateOpenError::Bookmarks))?;
         let blobstore = ManifoldBlob::new_with_prefix(bucket.to_string(), prefix, remote);
         let linknodes = MemLinknodes::new();
         let changesets = SqliteChangesets::in_memory()
@@ -203,19 +262,59 @@ impl BlobRepo {
             logger,
             Arc::new(heads),
             Arc::new(bookmarks),
-            Arc::new(blobstore),
+            compressing_blobstore(Arc::new(blobstore), compression_level),
             Arc::new(linknodes),
             Arc::new(changesets),
             repoid,
         ))
     }
 
+    /// Replace the blobstore of this repo with `f(blobstore)`. This is used to layer wrappers
+    /// such as caches on top of whichever blobstore the repo was opened with.
+    pub fn wrap_blobstore<F>(self, f: F) -> Self
+    where
+        F: FnOnce(Arc<Blobstore>) -> Arc<Blobstore>,
+    {
+        BlobRepo {
+            blobstore: f(self.blobstore),
+            ..self
+        }
+    }
+
     pub fn get_file_content(&self, key: &NodeHash) -> BoxFuture<Bytes, Error> {
         fetch_file_content_and_renames_from_blobstore(&self.blobstore, *key)
             .map(|contentrename| contentrename.0)
             .boxify()
     }
 
+    /// Returns the size of the content of a file, and a stream of the part of it that starts at
+    /// `offset` and is at most `len` bytes long.
+    pub fn get_file_content_range(
+        &self,
+        key: &NodeHash,
+        offset: u64,
+        len: u64,
+    ) -> BoxFuture<(u64, BoxStream<Bytes, Error>), Error> {
+        fetch_file_content_range_from_blobstore(&self.blobstore, *key, move |_| (offset, len))
+    }
+
+    /// Returns the size of the content of a file, and a stream of its last `len` bytes.
+    pub fn get_file_content_suffix(
+        &self,
+        key: &NodeHash,
+        len: u64,
+    ) -> BoxFuture<(u64, BoxStream<Bytes, Error>), Error> {
+        fetch_file_content_range_from_blobstore(&self.blobstore, *key, move |size| {
+            (size.saturating_sub(len), len)
+        })
+    }
+
+    /// The size of the content of a file along with its copy metadata, an upper bound of the size
+    /// of the content that is known without fetching any of it.
+    pub fn get_file_blob_size(&self, key: &NodeHash) -> BoxFuture<u64, Error> {
+        fetch_file_blob_size_from_blobstore(&self.blobstore, *key)
+    }
+
     pub fn get_parents(&self, key: &NodeHash) -> BoxFuture<Parents, Error> {
         get_node(&self.blobstore, *key)
             .map(|rawnode| rawnode.parents)
@@ -228,15 +327,161 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// The content of a file and where it was copied from, with a single fetch.
+    pub fn get_file_content_and_copy(
+        &self,
+        key: &NodeHash,
+    ) -> BoxFuture<(Bytes, Option<(MPath, NodeHash)>), Error> {
+        fetch_file_content_and_renames_from_blobstore(&self.blobstore, *key)
+    }
+
     pub fn get_changesets(&self) -> BoxStream<NodeHash, Error> {
+        self.get_ancestor_changesets(self.heads.heads().boxify())
+    }
+
+    /// Walk the given changesets and all of their ancestors, yielding each one exactly once.
+    pub fn get_ancestor_changesets(
+        &self,
+        roots: BoxStream<NodeHash, Error>,
+    ) -> BoxStream<NodeHash, Error> {
         BlobChangesetStream {
             repo: self.clone(),
-            heads: self.heads.heads().boxify(),
+            heads: roots,
             state: BCState::Idle,
             seen: HashSet::new(),
         }.boxify()
     }
 
+    /// Returns the blobstore keys of the node envelope and the content blobs (several for large
+    /// contents stored in chunks) for a file or manifest node. Maintenance tools use this to find
+    /// out which blobs are still referenced.
+    pub fn get_node_blobstore_keys(&self, key: &NodeHash) -> BoxFuture<Vec<String>, Error> {
+        let nodeid = *key;
+        let blobstore = self.blobstore.clone();
+        get_node(&self.blobstore, nodeid)
+            .and_then(move |rawnode| {
+                get_content_keys(&blobstore, format!("sha1-{}", rawnode.blob.sha1()))
+            })
+            .map(move |content_keys| {
+                let mut keys = vec![get_node_key(nodeid)];
+                keys.extend(content_keys);
+                keys
+            })
+            .boxify()
+    }
+
+    /// Fetch the parents and content of a manifest or file node as stored, so that they can be
+    /// checked against the node's hash. Returns `None` if either the node or its content blob is
+    /// missing.
+    pub fn get_raw_node(&self, key: &NodeHash) -> BoxFuture<Option<BlobNode>, Error> {
+        let nodeid = *key;
+        let blobstore = self.blobstore.clone();
+        self.blobstore
+            .get(get_node_key(nodeid))
+            .and_then(move |got| match got {
+                None => Ok(None).into_future().boxify(),
+                Some(bytes) => bincode::deserialize::<RawNodeBlob>(bytes.as_ref())
+                    .map_err(|err| Error::from(ErrorKind::SerializationFailed(nodeid, err)))
+                    .into_future()
+                    .and_then(move |rawnode| {
+                        get_content(&blobstore, format!("sha1-{}", rawnode.blob.sha1()))
+                            .map(move |blob| {
+                                blob.map(|blob| {
+                                    let (p1, p2) = rawnode.parents.get_nodes();
+                                    BlobNode::new(blob, p1, p2)
+                                })
+                            })
+                    })
+                    .boxify(),
+            })
+            .boxify()
+    }
+
+    /// Fetch a file content stored for the LFS protocol, by the SHA-256 of the content.
+    pub fn get_lfs_content(&self, oid: &Sha256) -> BoxFuture<Option<Bytes>, Error> {
+        get_content(&self.blobstore, get_lfs_key(oid))
+    }
+
+    /// The size of a file content stored for the LFS protocol, and a stream of it that only
+    /// holds a few chunks of it in memory.
+    pub fn get_lfs_content_stream(
+        &self,
+        oid: &Sha256,
+    ) -> BoxFuture<Option<(u64, BoxStream<Bytes, Error>)>, Error> {
+        get_content_stream(&self.blobstore, get_lfs_key(oid))
+    }
+
+    pub fn lfs_content_exists(&self, oid: &Sha256) -> BoxFuture<bool, Error> {
+        content_exists(&self.blobstore, get_lfs_key(oid))
+    }
+
+    /// The keys of the blobs that a file content stored for the LFS protocol is made of.
+    pub fn get_lfs_content_keys(&self, oid: &Sha256) -> BoxFuture<Vec<String>, Error> {
+        get_content_keys(&self.blobstore, get_lfs_key(oid))
+    }
+
+    /// Store a file content for the LFS protocol. Fails if `oid` isn't the SHA-256 of `content`.
+    pub fn upload_lfs_content(&self, oid: Sha256, content: Bytes) -> BoxFuture<(), Error> {
+        let actual = Sha256::from(content.as_ref());
+        if actual != oid {
+            return Err(ErrorKind::LfsContentMismatch(oid, actual).into())
+                .into_future()
+                .boxify();
+        }
+        put_content(&self.blobstore, get_lfs_key(&oid), content)
+    }
+
+    /// Store a file content of `size` bytes for the LFS protocol as `content` yields it, without
+    /// holding more than a chunk of it in memory. Fails as soon as the content turns out to be
+    /// larger than `size`, and stores nothing if `oid` isn't its SHA-256.
+    pub fn upload_lfs_content_stream<S>(
+        &self,
+        oid: Sha256,
+        size: u64,
+        content: S,
+    ) -> BoxFuture<(), Error>
+    where
+        S: Stream<Item = Bytes, Error = Error> + Send + 'static,
+    {
+        let context = Arc::new(Mutex::new(Sha256Context::new()));
+        let hashed = {
+            let context = context.clone();
+            let mut read = 0;
+            content.and_then(move |part| {
+                read += part.len() as u64;
+                if read > size {
+                    bail_err!(ErrorKind::LfsSizeMismatch(oid, read, size));
+                }
+                context.lock().expect("lock poisoned").update(&part);
+                Ok(part)
+            })
+        };
+
+        put_content_stream(&self.blobstore, get_lfs_key(&oid), hashed, move |read| {
+            if read != size {
+                bail_err!(ErrorKind::LfsSizeMismatch(oid, read, size));
+            }
+            let mut context = context.lock().expect("lock poisoned");
+            let actual = mem::replace(&mut *context, Sha256Context::new()).finish();
+            if actual != oid {
+                bail_err!(ErrorKind::LfsContentMismatch(oid, actual));
+            }
+            Ok(())
+        })
+    }
+
+    /// Fetch the parents and content of a changeset as stored, without parsing it.
+    pub fn get_raw_changeset(
+        &self,
+        changesetid: &HgChangesetId,
+    ) -> BoxFuture<Option<BlobNode>, Error> {
+        load_raw_changeset(&self.blobstore, changesetid).boxify()
+    }
+
+    pub fn get_changeset_blobstore_key(&self, changesetid: &HgChangesetId) -> String {
+        cskey(changesetid)
+    }
+
     pub fn get_heads(&self) -> BoxStream<NodeHash, Error> {
         self.heads.heads().boxify()
     }
@@ -248,6 +493,14 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// Find the changeset whose id starts with `prefix`. Fails if more than one does.
+    pub fn get_changeset_by_prefix(
+        &self,
+        prefix: &HgChangesetIdPrefix,
+    ) -> BoxFuture<Option<HgChangesetId>, Error> {
+        self.changesets.get_by_prefix(self.repoid, prefix)
+    }
+
     pub fn get_changeset_by_changesetid(
         &self,
         changesetid: &HgChangesetId,
@@ -274,21 +527,120 @@ impl BlobRepo {
         Box::new(BlobEntry::new_root(self.blobstore.clone(), *manifestid))
     }
 
-    pub fn get_bookmark_keys(&self) -> BoxStream<Vec<u8>, Error> {
-        self.bookmarks.keys().boxify()
+    pub fn get_bookmark(&self, name: &AsciiString) -> BoxFuture<Option<HgChangesetId>, Error> {
+        self.bookmarks.get(name, &self.repoid)
+    }
+
+    /// List the bookmarks whose names start with `prefix`, or all of them if it is empty.
+    pub fn get_bookmarks_by_prefix(
+        &self,
+        prefix: &AsciiString,
+    ) -> BoxStream<(AsciiString, HgChangesetId), Error> {
+        self.bookmarks.list_by_prefix(prefix, &self.repoid)
+    }
+
+    /// Start a transaction that moves bookmarks of this repo. Nothing changes until it is
+    /// committed.
+    pub fn update_bookmark_transaction(&self) -> Box<Transaction> {
+        self.bookmarks.create_transaction(&self.repoid)
     }
 
-    pub fn get_bookmark_value(
+    /// Moves a bookmark if it still points to `old`. No `old` means that the bookmark must not
+    /// exist yet and no `new` deletes it. Fails if the bookmark has moved in the meantime, or if
+    /// `new` is not a changeset of the repo.
+    pub fn move_bookmark(
         &self,
-        key: &AsRef<[u8]>,
-    ) -> BoxFuture<Option<(HgChangesetId, Version)>, Error> {
-        self.bookmarks.get(key).boxify()
+        name: &AsciiString,
+        old: Option<HgChangesetId>,
+        new: Option<HgChangesetId>,
+        reason: BookmarkUpdateReason,
+        user: Option<String>,
+    ) -> BoxFuture<(), Error> {
+        let mut transaction = self.update_bookmark_transaction();
+        try_boxfuture!(match (old, new) {
+            (None, Some(new)) => transaction.create(name, &new),
+            (Some(old), Some(new)) => transaction.update(name, &new, &old),
+            (Some(old), None) => transaction.delete(name, &old),
+            (None, None) => Err(format_err!(
+                "neither old nor new value is set for bookmark {}",
+                name
+            )),
+        });
+
+        let new_exists = match new {
+            Some(new) => self.changeset_exists(&new)
+                .and_then(move |exists| {
+                    if exists {
+                        Ok(())
+                    } else {
+                        Err(format_err!("changeset {} does not exist", new))
+                    }
+                })
+                .boxify(),
+            None => Ok(()).into_future().boxify(),
+        };
+
+        new_exists
+            .and_then(move |()| transaction.commit(reason, user.as_ref().map(String::as_str)))
+            .boxify()
+    }
+
+    /// Moves of a bookmark, newest first. See `Bookmarks::list_bookmark_log_entries`.
+    pub fn get_bookmark_history(
+        &self,
+        name: &AsciiString,
+        before: Option<i64>,
+        limit: usize,
+    ) -> BoxStream<BookmarkUpdateLogEntry, Error> {
+        self.bookmarks.list_bookmark_log_entries(name, &self.repoid, before, limit)
     }
 
     pub fn get_linknode(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<NodeHash, Error> {
         self.linknodes.get(path, node)
     }
 
+    /// Walk the history of the file at `path` from `startnode`, breadth first, yielding each
+    /// filenode once with its parents, linknode and copy source.
+    pub fn get_file_history(
+        &self,
+        path: MPath,
+        startnode: NodeHash,
+    ) -> BoxStream<(NodeHash, Parents, NodeHash, Option<(MPath, NodeHash)>), Error> {
+        if startnode == NULL_HASH {
+            return stream::empty().boxify();
+        }
+        let mut startstate = VecDeque::new();
+        startstate.push_back(startnode);
+        let seen_nodes: HashSet<_> = [startnode].iter().cloned().collect();
+        let repo = self.clone();
+
+        stream::unfold(
+            (startstate, seen_nodes),
+            move |cur_data: (VecDeque<NodeHash>, HashSet<NodeHash>)| {
+                let (mut nodes, mut seen_nodes) = cur_data;
+                let node = nodes.pop_front()?;
+
+                let parents = repo.get_parents(&node);
+                let copy = repo.get_file_copy(&node);
+
+                let linknode = RepoPath::file(path.clone()).into_future().and_then({
+                    let repo = repo.clone();
+                    move |path| repo.get_linknode(path, &node)
+                });
+
+                let joined = parents
+                    .join(linknode)
+                    .join(copy)
+                    .map(|(pl, c)| (pl.0, pl.1, c));
+
+                Some(joined.map(move |(parents, linknode, copy)| {
+                    nodes.extend(parents.into_iter().filter(|p| seen_nodes.insert(*p)));
+                    ((node, parents, linknode, copy), (nodes, seen_nodes))
+                }))
+            },
+        ).boxify()
+    }
+
     pub fn get_generation_number(&self, cs: &HgChangesetId) -> BoxFuture<Option<u64>, Error> {
         self.changesets
             .get(self.repoid, *cs)
@@ -360,14 +712,14 @@ impl BlobRepo {
         }
 
         // Ensure that content is in the blobstore
-        let content_upload = self.blobstore
-            .put(
-                format!("sha1-{}", blob_hash.sha1()),
-                raw_content
-                    .clone()
-                    .into_inner()
-                    .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?,
-            )
+        let content_upload = put_content(
+            &self.blobstore,
+            format!("sha1-{}", blob_hash.sha1()),
+            raw_content
+                .clone()
+                .into_inner()
+                .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?,
+        )
             .timed({
                 let logger = self.logger.clone();
                 let path = path.clone();
@@ -11,6 +11,7 @@ use bincode;
 
 use blobstore::Blobstore;
 use mercurial_types::{HgBlobHash, NodeHash, Parents};
+use mercurial_types::hash::Sha256;
 
 use errors::*;
 
@@ -25,6 +26,10 @@ pub fn get_node_key(nodeid: NodeHash) -> String {
     format!("node-{}.bincode", nodeid)
 }
 
+pub fn get_lfs_key(oid: &Sha256) -> String {
+    format!("lfs-sha256-{}", oid)
+}
+
 pub fn get_node(blobstore: &Blobstore, nodeid: NodeHash) -> BoxFuture<RawNodeBlob, Error> {
     let key = get_node_key(nodeid);
 
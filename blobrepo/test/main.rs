@@ -15,22 +15,42 @@ extern crate futures_ext;
 extern crate maplit;
 #[macro_use]
 extern crate slog;
+extern crate tempdir;
 
 extern crate blobrepo;
+extern crate bookmarks;
+extern crate bookmarks_old;
 extern crate changesets;
+extern crate dbbookmarks;
+extern crate filebookmarks;
+extern crate fileheads;
+extern crate filelinknodes;
 extern crate many_files_dirs;
 extern crate memblob;
-extern crate membookmarks;
 extern crate memheads;
 extern crate memlinknodes;
 extern crate mercurial_types;
 
+use std::sync::Arc;
+
+use ascii::AsciiString;
 use bytes::Bytes;
-use futures::Future;
+use futures::{Future, Stream};
+use futures::stream;
+use slog::{Discard, Drain, Logger};
+use tempdir::TempDir;
 
 use blobrepo::{compute_changed_files, BlobRepo};
+use bookmarks::BookmarkUpdateReason;
+use bookmarks_old::BookmarksMut;
+use changesets::SqliteChangesets;
+use filebookmarks::FileBookmarks;
+use fileheads::FileHeads;
+use filelinknodes::FileLinknodes;
+use memblob::EagerMemblob;
 use mercurial_types::{manifest, Blob, Changeset, Entry, EntryId, HgChangesetId, HgManifestId,
-                      MPath, MPathElement, RepoPath};
+                      MPath, MPathElement, RepoPath, RepositoryId};
+use mercurial_types::hash::Sha256;
 
 mod stats_units;
 #[macro_use]
@@ -244,6 +264,143 @@ test_both_repotypes!(
     create_bad_changeset_eager
 );
 
+fn check_raw_nodes(repo: BlobRepo) {
+    let fake_file_path = RepoPath::file("file").expect("Can't generate fake RepoPath");
+
+    let (filehash, file_future) = upload_file_no_parents(&repo, "blob", &fake_file_path);
+    let (roothash, root_manifest_future) =
+        upload_manifest_no_parents(&repo, format!("file\0{}\n", filehash), &RepoPath::root());
+
+    let commit = create_changeset_no_parents(&repo, root_manifest_future, vec![file_future]);
+    let cs = run_future(commit.get_completed_changeset()).unwrap();
+
+    // Stored nodes hash back to their ids
+    for nodeid in &[filehash, roothash] {
+        let node = run_future(repo.get_raw_node(nodeid))
+            .unwrap()
+            .expect("node is missing");
+        assert_eq!(node.nodeid(), Some(*nodeid));
+    }
+    let node = run_future(repo.get_raw_changeset(&cs.get_changeset_id()))
+        .unwrap()
+        .expect("changeset is missing");
+    assert_eq!(node.nodeid(), Some(cs.get_changeset_id().into_nodehash()));
+
+    let unknown = string_to_nodehash("c2d60b35a8e7e034042a9467783bbdac88a0d219");
+    assert!(run_future(repo.get_raw_node(&unknown)).unwrap().is_none());
+    assert!(
+        run_future(repo.get_raw_changeset(&HgChangesetId::new(unknown)))
+            .unwrap()
+            .is_none()
+    );
+}
+
+test_both_repotypes!(
+    check_raw_nodes,
+    check_raw_nodes_lazy,
+    check_raw_nodes_eager
+);
+
+fn check_large_file_content(repo: BlobRepo) {
+    let fake_file_path = RepoPath::file("file").expect("Can't generate fake RepoPath");
+
+    // Larger than a chunk, with copy metadata in front of the content
+    let content: String = (0..5 * 1024 * 1024)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    let copyrev = string_to_nodehash("c2d60b35a8e7e034042a9467783bbdac88a0d219");
+    let data = format!("\x01\ncopy: other\ncopyrev: {}\n\x01\n{}", copyrev, content);
+    let data_len = data.len() as u64;
+    let (filehash, file_future) = upload_file_no_parents(&repo, data, &fake_file_path);
+    run_future(file_future).unwrap();
+
+    let fetched = run_future(repo.get_file_content(&filehash)).unwrap();
+    assert_eq!(fetched, Bytes::from(content.as_bytes()));
+
+    let get_range = |offset, len| {
+        let (size, parts) = run_future(
+            repo.get_file_content_range(&filehash, offset, len)
+                .and_then(|(size, stream)| stream.collect().map(move |parts| (size, parts))),
+        ).unwrap();
+        let range: Vec<u8> = parts.iter().flat_map(|part| part.iter().cloned()).collect();
+        (size, range)
+    };
+
+    // Across the end of the first chunk
+    let (size, range) = get_range(4 * 1024 * 1024 - 100, 200);
+    assert_eq!(size, content.len() as u64);
+    assert_eq!(
+        range.as_slice(),
+        &content.as_bytes()[4 * 1024 * 1024 - 100..4 * 1024 * 1024 + 100]
+    );
+
+    // Past the end of the content
+    let (_, range) = get_range(content.len() as u64 - 10, 100);
+    assert_eq!(range.as_slice(), &content.as_bytes()[content.len() - 10..]);
+    let (_, range) = get_range(content.len() as u64 + 10, 100);
+    assert!(range.is_empty());
+
+    // The end of the content, found with the same fetch as its size
+    let (size, parts) = run_future(
+        repo.get_file_content_suffix(&filehash, 100)
+            .and_then(|(size, stream)| stream.collect().map(move |parts| (size, parts))),
+    ).unwrap();
+    let suffix: Vec<u8> = parts.iter().flat_map(|part| part.iter().cloned()).collect();
+    assert_eq!(size, content.len() as u64);
+    assert_eq!(suffix.as_slice(), &content.as_bytes()[content.len() - 100..]);
+
+    // The blob includes the copy metadata
+    let blob_size = run_future(repo.get_file_blob_size(&filehash)).unwrap();
+    assert_eq!(blob_size, data_len);
+}
+
+test_both_repotypes!(
+    check_large_file_content,
+    check_large_file_content_lazy,
+    check_large_file_content_eager
+);
+
+fn check_lfs_content_stream(repo: BlobRepo) {
+    // Larger than a chunk, and received in parts that don't line up with the chunks
+    let content: Vec<u8> = (0..5 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let oid = Sha256::from(content.as_slice());
+    let size = content.len() as u64;
+    let upload = |oid, size| {
+        let parts: Vec<_> = content
+            .chunks(1000 * 1000)
+            .map(|part| Ok(Bytes::from(part)))
+            .collect();
+        repo.upload_lfs_content_stream(oid, size, stream::iter_result(parts))
+    };
+
+    let other = Sha256::from(&b"other"[..]);
+    assert!(run_future(upload(other, size)).is_err());
+    assert!(run_future(upload(oid, size - 1)).is_err());
+    assert!(run_future(upload(oid, size + 1)).is_err());
+    assert!(!run_future(repo.lfs_content_exists(&oid)).unwrap());
+
+    run_future(upload(oid, size)).unwrap();
+    assert!(run_future(repo.lfs_content_exists(&oid)).unwrap());
+
+    let (fetched_size, parts) = run_future(
+        repo.get_lfs_content_stream(&oid)
+            .map(|content| content.expect("LFS content is missing"))
+            .and_then(|(size, stream)| stream.collect().map(move |parts| (size, parts))),
+    ).unwrap();
+    let fetched: Vec<u8> = parts.iter().flat_map(|part| part.iter().cloned()).collect();
+    assert_eq!(fetched_size, size);
+    assert_eq!(fetched, content);
+
+    let fetched = run_future(repo.get_lfs_content(&oid)).unwrap();
+    assert_eq!(fetched, Some(Bytes::from(content.as_slice())));
+}
+
+test_both_repotypes!(
+    check_lfs_content_stream,
+    check_lfs_content_stream_lazy,
+    check_lfs_content_stream_eager
+);
+
 fn create_double_linknode(repo: BlobRepo) {
     let fake_file_path = RepoPath::file("file").expect("Can't generate fake RepoPath");
     let fake_dir_path = RepoPath::dir("dir").expect("Can't generate fake RepoPath");
@@ -417,3 +574,72 @@ fn test_compute_changed_files_one_parent() {
         expected,
     );
 }
+
+fn move_bookmark(repo: BlobRepo) {
+    let fake_file_path = RepoPath::file("file").expect("Can't generate fake RepoPath");
+    let (filehash, file_future) = upload_file_no_parents(&repo, "blob", &fake_file_path);
+    let (_, root_manifest_future) =
+        upload_manifest_no_parents(&repo, format!("file\0{}\n", filehash), &RepoPath::root());
+    let commit = create_changeset_no_parents(&repo, root_manifest_future, vec![file_future]);
+    let csid = run_future(commit.get_completed_changeset())
+        .unwrap()
+        .get_changeset_id();
+    let missing = string_to_nodehash("a6cb7dddec32acaf9a28db46cdb3061682155531");
+    let missing = HgChangesetId::new(missing);
+    let master = AsciiString::from_ascii("master").unwrap();
+    let move_master = |old, new| {
+        run_future(repo.move_bookmark(&master, old, new, BookmarkUpdateReason::TestMove, None))
+    };
+
+    // Only changesets of the repo can be bookmarked
+    assert!(move_master(None, Some(missing)).is_err());
+    assert_eq!(run_future(repo.get_bookmark(&master)).unwrap(), None);
+
+    move_master(None, Some(csid)).unwrap();
+    assert_eq!(run_future(repo.get_bookmark(&master)).unwrap(), Some(csid));
+
+    // The bookmark has to point to the old changeset
+    assert!(move_master(None, None).is_err());
+    assert!(move_master(Some(missing), None).is_err());
+    move_master(Some(csid), None).unwrap();
+    assert_eq!(run_future(repo.get_bookmark(&master)).unwrap(), None);
+}
+
+test_both_repotypes!(move_bookmark, move_bookmark_lazy, move_bookmark_eager);
+
+#[test]
+fn test_legacy_file_bookmarks() {
+    let dir = TempDir::new("legacy_file_bookmarks").expect("tempdir failed");
+    let path = dir.path();
+    FileHeads::create(path.join("heads")).unwrap();
+    FileLinknodes::create(path.join("linknodes")).unwrap();
+    SqliteChangesets::create(path.join("changesets").to_string_lossy()).unwrap();
+
+    let csid = HgChangesetId::new(string_to_nodehash("a6cb7dddec32acaf9a28db46cdb3061682155531"));
+    let legacy = FileBookmarks::create(path.join("books")).unwrap();
+    run_future(legacy.create(&"master", &csid)).unwrap();
+
+    let open = || {
+        BlobRepo::new_local(
+            Logger::root(Discard {}.ignore_res(), o!()),
+            path,
+            Arc::new(EagerMemblob::new()),
+            RepositoryId::new(0),
+            None,
+        ).expect("opening the repo failed")
+    };
+    let master = AsciiString::from_ascii("master").unwrap();
+
+    // The bookmarks of repos created before they moved to SQLite are imported...
+    let repo = open();
+    assert_eq!(run_future(repo.get_bookmark(&master)).unwrap(), Some(csid));
+    assert!(path.join("bookmarks").exists());
+    drop(repo);
+
+    // ... only once: whatever is written to the old store afterwards is ignored
+    run_future(legacy.create(&"stable", &csid)).unwrap();
+    let repo = open();
+    assert_eq!(run_future(repo.get_bookmark(&master)).unwrap(), Some(csid));
+    let stable = AsciiString::from_ascii("stable").unwrap();
+    assert_eq!(run_future(repo.get_bookmark(&stable)).unwrap(), None);
+}
@@ -13,8 +13,8 @@ use slog::{self, Drain, Logger, OwnedKVList, Record, Serializer, KV};
 
 use blobrepo::BlobRepo;
 use changesets::SqliteChangesets;
+use dbbookmarks::SqliteDbBookmarks;
 use memblob::LazyMemblob;
-use membookmarks::MemBookmarks;
 use memheads::MemHeads;
 use memlinknodes::MemLinknodes;
 use mercurial_types::{RepoPath, RepositoryId};
@@ -23,7 +23,7 @@ use utils::{create_changeset_no_parents, run_future, upload_file_no_parents,
             upload_manifest_no_parents};
 
 fn get_logging_blob_repo(logger: Logger) -> BlobRepo {
-    let bookmarks: MemBookmarks = MemBookmarks::new();
+    let bookmarks = SqliteDbBookmarks::in_memory().expect("cannot create in memory bookmarks");
     let heads: MemHeads = MemHeads::new();
     let blobs = LazyMemblob::new();
     let linknodes = MemLinknodes::new();
@@ -0,0 +1,179 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate heapsize;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate stats;
+
+extern crate asyncmemo;
+extern crate blobstore;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::usize;
+
+use bytes::Bytes;
+use failure::Error;
+use futures::Future;
+use futures::future::{self, Either};
+use futures_ext::{BoxFuture, FutureExt};
+use heapsize::HeapSizeOf;
+use stats::Timeseries;
+
+use asyncmemo::{Asyncmemo, Filler};
+use blobstore::Blobstore;
+
+define_stats! {
+    prefix = "mononoke.blobstore.cache";
+    gets: timeseries(RATE, SUM),
+    misses: timeseries(RATE, SUM),
+}
+
+/// Hit and miss counters of a single `CachingBlobstore`. Finding a cached "missing" entry
+/// counts as a hit.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+#[derive(Default)]
+struct Counters {
+    gets: AtomicUsize,
+    fills: AtomicUsize,
+}
+
+#[derive(Clone, Debug)]
+enum CachedBlob {
+    Present(Bytes),
+    Missing(Instant),
+}
+
+impl HeapSizeOf for CachedBlob {
+    fn heap_size_of_children(&self) -> usize {
+        match self {
+            &CachedBlob::Present(ref bytes) => bytes.len(),
+            &CachedBlob::Missing(_) => 0,
+        }
+    }
+}
+
+struct BlobFiller {
+    blobstore: Arc<Blobstore>,
+    counters: Arc<Counters>,
+}
+
+impl Filler for BlobFiller {
+    type Key = String;
+    type Value = BoxFuture<CachedBlob, Error>;
+
+    fn fill(&self, _: &Asyncmemo<Self>, key: &Self::Key) -> Self::Value {
+        self.counters.fills.fetch_add(1, Ordering::Relaxed);
+        STATS::misses.add_value(1);
+        self.blobstore
+            .get(key.clone())
+            .map(|blob| match blob {
+                Some(bytes) => CachedBlob::Present(bytes),
+                None => CachedBlob::Missing(Instant::now()),
+            })
+            .boxify()
+    }
+}
+
+/// Blobstore wrapper that keeps recently fetched blobs in memory.
+///
+/// The cache is bounded by the total size of the cached blobs (plus keys). Since a key always
+/// maps to the same value, cached blobs never go stale. Misses are cached too, but only for
+/// `negative_ttl`, because the blob may be uploaded later.
+pub struct CachingBlobstore {
+    blobstore: Arc<Blobstore>,
+    cache: Asyncmemo<BlobFiller>,
+    negative_ttl: Duration,
+    counters: Arc<Counters>,
+}
+
+impl CachingBlobstore {
+    pub fn new(blobstore: Arc<Blobstore>, size_limit: usize, negative_ttl: Duration) -> Self {
+        let counters = Arc::new(Counters::default());
+        let filler = BlobFiller {
+            blobstore: blobstore.clone(),
+            counters: counters.clone(),
+        };
+
+        CachingBlobstore {
+            blobstore,
+            cache: Asyncmemo::with_limits(filler, usize::MAX, size_limit),
+            negative_ttl,
+            counters,
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let gets = self.counters.gets.load(Ordering::Relaxed);
+        let misses = self.counters.fills.load(Ordering::Relaxed);
+        CacheStats {
+            hits: gets.saturating_sub(misses),
+            misses,
+        }
+    }
+}
+
+impl Blobstore for CachingBlobstore {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        self.counters.gets.fetch_add(1, Ordering::Relaxed);
+        STATS::gets.add_value(1);
+
+        let cache = self.cache.clone();
+        let negative_ttl = self.negative_ttl;
+
+        self.cache
+            .get(key.clone())
+            .and_then(move |cached| match cached {
+                CachedBlob::Present(bytes) => Either::A(future::ok(Some(bytes))),
+                CachedBlob::Missing(at) if at.elapsed() < negative_ttl => {
+                    Either::A(future::ok(None))
+                }
+                CachedBlob::Missing(_) => {
+                    // Expired negative entry - forget it and ask the blobstore again
+                    cache.invalidate(key.clone());
+                    Either::B(cache.get(key).map(|cached| match cached {
+                        CachedBlob::Present(bytes) => Some(bytes),
+                        CachedBlob::Missing(_) => None,
+                    }))
+                }
+            })
+            .boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        // A miss for this key may have been cached; drop it once the blob is actually there.
+        let cache = self.cache.clone();
+        self.blobstore
+            .put(key.clone(), value)
+            .map(move |()| cache.invalidate(key))
+            .boxify()
+    }
+
+    // Sizes and ranges are asked for to avoid fetching whole blobs, so they go straight to the
+    // underlying blobstore instead of filling the cache with those blobs.
+
+    fn get_size(&self, key: String) -> BoxFuture<Option<u64>, Error> {
+        self.blobstore.get_size(key)
+    }
+
+    fn get_range(&self, key: String, offset: u64, len: u64) -> BoxFuture<Option<Bytes>, Error> {
+        self.blobstore.get_range(key, offset, len)
+    }
+}
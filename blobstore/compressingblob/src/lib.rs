@@ -0,0 +1,227 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate futures_ext;
+extern crate zstd;
+
+extern crate async_compression;
+extern crate blobstore;
+
+use std::io::{Cursor, Read, Write};
+use std::sync::Arc;
+
+use bytes::{BigEndian, BufMut, ByteOrder, Bytes, BytesMut};
+use failure::{Error, Fail, Result};
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+
+use async_compression::{Compressor, CompressorType, Decompressor, DecompressorType};
+use blobstore::{range_bounds, Blobstore};
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "Blob {} has unknown compression type {}", _0, _1)]
+    UnknownCompression(String, u8),
+    #[fail(display = "Failed to decompress blob {}", _0)] DecompressionFailed(String),
+}
+
+// Blobs written by CompressingBlobstore may start with a header: MAGIC, a byte saying how the
+// rest of the blob is encoded, and the size of the blob once decoded as a big endian u64, so that
+// it can be answered without decoding anything. Blobs without the header are stored as they are:
+// they were written before compression was enabled, or didn't get any smaller. The few of those
+// that start with MAGIC get a header too, so that they aren't mistaken for encoded ones.
+const MAGIC: &[u8] = b"\xffMNKCMP";
+const HEADER_LEN: usize = 16;
+
+const ENCODING_RAW: u8 = 0;
+const ENCODING_ZSTD: u8 = 1;
+const ENCODING_GZIP: u8 = 2;
+const ENCODING_BZIP2: u8 = 3;
+
+fn encoding_byte(ct: &CompressorType) -> u8 {
+    match ct.decompressor_type() {
+        DecompressorType::Zstd => ENCODING_ZSTD,
+        DecompressorType::Gzip => ENCODING_GZIP,
+        DecompressorType::Bzip2 => ENCODING_BZIP2,
+    }
+}
+
+fn with_header(encoding: u8, size: usize, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + payload.len());
+    buf.extend_from_slice(MAGIC);
+    buf.put_u8(encoding);
+    buf.put_u64::<BigEndian>(size as u64);
+    buf.extend_from_slice(payload);
+    buf.freeze()
+}
+
+/// The encoding and decoded size of a blob that starts with a header.
+fn parse_header(stored: &[u8]) -> Option<(u8, u64)> {
+    if stored.len() < HEADER_LEN || &stored[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let size = <BigEndian as ByteOrder>::read_u64(&stored[MAGIC.len() + 1..HEADER_LEN]);
+    Some((stored[MAGIC.len()], size))
+}
+
+/// Blobstore wrapper that compresses blobs before handing them to the underlying store.
+///
+/// Blobs that don't get smaller are stored uncompressed, so that already-compressed content
+/// doesn't pay the decompression cost on every read, and can be read in part by the underlying
+/// store. Without a compressor type nothing is compressed, but the blobs that were can still be
+/// read.
+#[derive(Clone)]
+pub struct CompressingBlobstore {
+    blobstore: Arc<Blobstore>,
+    compressor_type: Option<CompressorType>,
+}
+
+impl CompressingBlobstore {
+    pub fn new(blobstore: Arc<Blobstore>, compressor_type: Option<CompressorType>) -> Self {
+        CompressingBlobstore {
+            blobstore,
+            compressor_type,
+        }
+    }
+
+    /// Convenience constructor for the common case of zstd with the given level.
+    pub fn new_zstd(blobstore: Arc<Blobstore>, level: i32) -> Self {
+        Self::new(blobstore, Some(CompressorType::Zstd { level }))
+    }
+}
+
+/// Returns the blob to store for `value`.
+fn encode(value: Bytes, compressor_type: Option<CompressorType>) -> Result<Bytes> {
+    if let Some(compressor_type) = compressor_type {
+        let mut compressor = Compressor::new(Cursor::new(Vec::new()), compressor_type);
+        compressor.write_all(value.as_ref())?;
+        let compressed = match compressor.try_finish() {
+            Ok(cursor) => cursor.into_inner(),
+            Err((_, err)) => return Err(err.into()),
+        };
+        if compressed.len() + HEADER_LEN < value.len() {
+            let encoding = encoding_byte(&compressor_type);
+            return Ok(with_header(encoding, value.len(), &compressed));
+        }
+    }
+
+    if value.starts_with(MAGIC) {
+        Ok(with_header(ENCODING_RAW, value.len(), value.as_ref()))
+    } else {
+        Ok(value)
+    }
+}
+
+fn decode(key: &str, stored: Bytes) -> Result<Bytes> {
+    let encoding = match parse_header(stored.as_ref()) {
+        Some((encoding, _)) => encoding,
+        None => return Ok(stored),
+    };
+    let payload = stored.slice_from(HEADER_LEN);
+
+    let decompressor_type = match encoding {
+        ENCODING_RAW => return Ok(payload),
+        // The streaming zstd decoder in async_compression isn't usable, but we have the whole
+        // blob in memory anyway.
+        ENCODING_ZSTD => {
+            return zstd::decode_all(payload.as_ref())
+                .map(Bytes::from)
+                .map_err(|err| {
+                    err.context(ErrorKind::DecompressionFailed(key.to_string()))
+                        .into()
+                })
+        }
+        ENCODING_GZIP => DecompressorType::Gzip,
+        ENCODING_BZIP2 => DecompressorType::Bzip2,
+        unknown => bail_err!(ErrorKind::UnknownCompression(key.to_string(), unknown)),
+    };
+
+    let mut decompressor = Decompressor::new(Cursor::new(payload), decompressor_type);
+    let mut out = Vec::new();
+    decompressor
+        .read_to_end(&mut out)
+        .map_err(|err| err.context(ErrorKind::DecompressionFailed(key.to_string())))?;
+    Ok(Bytes::from(out))
+}
+
+impl Blobstore for CompressingBlobstore {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        self.blobstore
+            .get(key.clone())
+            .and_then(move |stored| match stored {
+                Some(stored) => decode(&key, stored).map(Some),
+                None => Ok(None),
+            })
+            .boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        let stored = try_boxfuture!(encode(value, self.compressor_type));
+        self.blobstore.put(key, stored)
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        // No need to decompress anything to answer this
+        self.blobstore.is_present(key)
+    }
+
+    // Both start by reading the header, if the blob has one. Blobs shorter than a header are
+    // whole in what was read, the other blobs without a header are measured and read in part by
+    // the underlying store.
+
+    fn get_size(&self, key: String) -> BoxFuture<Option<u64>, Error> {
+        let blobstore = self.blobstore.clone();
+        self.blobstore
+            .get_range(key.clone(), 0, HEADER_LEN as u64)
+            .and_then(move |head| match head {
+                None => future::ok(None).boxify(),
+                Some(head) => match parse_header(head.as_ref()) {
+                    Some((_, size)) => future::ok(Some(size)).boxify(),
+                    None if head.len() < HEADER_LEN => future::ok(Some(head.len() as u64)).boxify(),
+                    None => blobstore.get_size(key),
+                },
+            })
+            .boxify()
+    }
+
+    fn get_range(&self, key: String, offset: u64, len: u64) -> BoxFuture<Option<Bytes>, Error> {
+        let this = self.clone();
+        self.blobstore
+            .get_range(key.clone(), 0, HEADER_LEN as u64)
+            .and_then(move |head| match head {
+                None => future::ok(None).boxify(),
+                Some(head) => match parse_header(head.as_ref()) {
+                    Some((ENCODING_RAW, size)) => {
+                        let (start, end) = range_bounds(size, offset, len);
+                        this.blobstore
+                            .get_range(key, HEADER_LEN as u64 + start, end - start)
+                    }
+                    // Compressed blobs can only be decompressed whole
+                    Some(_) => this.get(key)
+                        .map(move |blob| {
+                            blob.map(|blob| {
+                                let (start, end) = range_bounds(blob.len() as u64, offset, len);
+                                blob.slice(start as usize, end as usize)
+                            })
+                        })
+                        .boxify(),
+                    None if head.len() < HEADER_LEN => {
+                        let (start, end) = range_bounds(head.len() as u64, offset, len);
+                        future::ok(Some(head.slice(start as usize, end as usize))).boxify()
+                    }
+                    None => this.blobstore.get_range(key, offset, len),
+                },
+            })
+            .boxify()
+    }
+}
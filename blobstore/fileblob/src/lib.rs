@@ -15,18 +15,18 @@ extern crate url;
 extern crate blobstore;
 extern crate futures_ext;
 
-use std::fs::{create_dir_all, File};
-use std::io::{self, Read, Write};
+use std::fs::{create_dir_all, metadata, read_dir, remove_file, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
 use bytes::Bytes;
 use failure::{Error, Result};
-use futures::Async;
+use futures::{stream, Async};
 use futures::future::{poll_fn, Future};
-use futures_ext::{BoxFuture, FutureExt};
-use url::percent_encoding::{percent_encode, DEFAULT_ENCODE_SET};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use url::percent_encoding::{percent_decode, percent_encode, DEFAULT_ENCODE_SET};
 
-use blobstore::Blobstore;
+use blobstore::{BlobMeta, Blobstore, EnumerableBlobstore};
 
 const PREFIX: &str = "blob";
 
@@ -60,6 +60,18 @@ impl Fileblob {
     }
 }
 
+// Inverse of Fileblob::path - returns None for files that weren't created by Fileblob
+fn key_from_file_name(name: &str) -> Option<String> {
+    if !name.starts_with(PREFIX) || !name[PREFIX.len()..].starts_with('-') {
+        return None;
+    }
+    let encoded = &name[PREFIX.len() + 1..];
+    percent_decode(encoded.as_bytes())
+        .decode_utf8()
+        .ok()
+        .map(|key| key.into_owned())
+}
+
 impl Blobstore for Fileblob {
     fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
         let p = self.path(&key);
@@ -87,4 +99,75 @@ impl Blobstore for Fileblob {
             Ok(Async::Ready(()))
         }).boxify()
     }
+
+    fn get_size(&self, key: String) -> BoxFuture<Option<u64>, Error> {
+        let p = self.path(&key);
+
+        poll_fn(move || {
+            let ret = match metadata(&p) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+                Ok(meta) => Some(meta.len()),
+            };
+            Ok(Async::Ready(ret))
+        }).from_err()
+            .boxify()
+    }
+
+    fn get_range(&self, key: String, offset: u64, len: u64) -> BoxFuture<Option<Bytes>, Error> {
+        let p = self.path(&key);
+
+        poll_fn(move || {
+            let mut v = Vec::new();
+            let ret = match File::open(&p) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+                Ok(mut f) => {
+                    // Seeking past the end is fine, reading from there returns nothing
+                    f.seek(SeekFrom::Start(offset))?;
+                    f.take(len).read_to_end(&mut v)?;
+                    Some(Bytes::from(v))
+                }
+            };
+            Ok(Async::Ready(ret))
+        }).from_err()
+            .boxify()
+    }
+}
+
+impl EnumerableBlobstore for Fileblob {
+    fn enumerate(&self) -> BoxStream<BlobMeta, Error> {
+        let base = self.base.clone();
+
+        // Only the names are kept in memory, so listing the whole directory up front is fine
+        poll_fn::<_, Error, _>(move || {
+            let mut blobs = Vec::new();
+            for entry in read_dir(&base)? {
+                let entry = entry?;
+                let key = match entry.file_name().to_str().and_then(key_from_file_name) {
+                    Some(key) => key,
+                    None => continue,
+                };
+                let modified = entry.metadata()?.modified().ok();
+                blobs.push(BlobMeta { key, modified });
+            }
+            Ok(Async::Ready(blobs))
+        }).map(stream::iter_ok)
+            .flatten_stream()
+            .boxify()
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        let p = self.path(&key);
+
+        poll_fn(move || {
+            match remove_file(&p) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+            Ok(Async::Ready(()))
+        }).from_err()
+            .boxify()
+    }
 }
@@ -18,10 +18,11 @@ use std::sync::{Arc, Mutex};
 
 use bytes::Bytes;
 use failure::Error;
-use futures::future::{lazy, IntoFuture};
-use futures_ext::{BoxFuture, FutureExt};
+use futures::future::{lazy, Future, IntoFuture};
+use futures::stream;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
-use blobstore::Blobstore;
+use blobstore::{BlobMeta, Blobstore, EnumerableBlobstore};
 
 /// In-memory "blob store"
 ///
@@ -68,6 +69,31 @@ impl Blobstore for EagerMemblob {
     }
 }
 
+// In-memory blobs don't keep track of when they were written.
+fn list_keys(hash: &HashMap<String, Bytes>) -> Vec<BlobMeta> {
+    hash.keys()
+        .map(|key| BlobMeta {
+            key: key.clone(),
+            modified: None,
+        })
+        .collect()
+}
+
+impl EnumerableBlobstore for EagerMemblob {
+    fn enumerate(&self) -> BoxStream<BlobMeta, Error> {
+        let inner = self.hash.lock().expect("lock poison");
+
+        stream::iter_ok(list_keys(&inner)).boxify()
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        let mut inner = self.hash.lock().expect("lock poison");
+
+        inner.remove(&key);
+        Ok(()).into_future().boxify()
+    }
+}
+
 impl Blobstore for LazyMemblob {
     fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
         let hash = self.hash.clone();
@@ -89,3 +115,26 @@ impl Blobstore for LazyMemblob {
         }).boxify()
     }
 }
+
+impl EnumerableBlobstore for LazyMemblob {
+    fn enumerate(&self) -> BoxStream<BlobMeta, Error> {
+        let hash = self.hash.clone();
+
+        lazy(move || {
+            let inner = hash.lock().expect("lock poison");
+            Ok(stream::iter_ok(list_keys(&inner)))
+        }).flatten_stream()
+            .boxify()
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        let hash = self.hash.clone();
+
+        lazy(move || {
+            let mut inner = hash.lock().expect("lock poison");
+
+            inner.remove(&key);
+            Ok(()).into_future()
+        }).boxify()
+    }
+}
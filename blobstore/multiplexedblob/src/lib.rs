@@ -0,0 +1,432 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate tokio_core;
+
+extern crate blobstore;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use failure::{Error, Result};
+use futures::{future, stream, Async, Future, IntoFuture, Poll, Stream};
+use futures::future::Executor;
+use futures::stream::FuturesUnordered;
+use futures_ext::{BoxFuture, FutureExt};
+use tokio_core::reactor::{Handle, Interval};
+
+use blobstore::Blobstore;
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "Invalid quorum {} for {} blobstores", _0, _1)] InvalidQuorum(usize, usize),
+    #[fail(display = "Put of {} acknowledged by {} blobstores, {} required", _0, _1, _2)]
+    QuorumNotReached(String, usize, usize),
+    #[fail(display = "Blob {} not found in any healthy blobstore", _0)] NoHealthyCopy(String),
+    #[fail(display = "Healing queue is full ({} entries)", _0)] HealingQueueFull(usize),
+}
+
+/// Identifies one of the blobstores underneath a `MultiplexedBlobstore`. Ids must stay the same
+/// across restarts for a persistent healing queue to make sense.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct BlobstoreId(u32);
+
+impl BlobstoreId {
+    pub fn new(id: u32) -> Self {
+        BlobstoreId(id)
+    }
+}
+
+impl fmt::Display for BlobstoreId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A blob that was written to some, but not all, of the multiplexed blobstores.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HealingEntry {
+    pub key: String,
+    /// Blobstores that are not known to have a copy of the blob
+    pub missing: Vec<BlobstoreId>,
+}
+
+/// Record of partial writes that still need to be repaired.
+pub trait HealingQueue: Send + Sync + 'static {
+    fn add(&self, entry: HealingEntry) -> BoxFuture<(), Error>;
+    /// Remove and return up to `limit` entries from the queue.
+    fn take(&self, limit: usize) -> BoxFuture<Vec<HealingEntry>, Error>;
+}
+
+/// In-memory healing queue holding at most `capacity` entries. Once it is full, the puts that
+/// would need healing fail instead.
+///
+/// Partial writes recorded here are lost on restart, after which nothing knows that some
+/// blobstores miss these blobs. It is only suitable for tests and for setups that can afford
+/// that: production needs a persistent `HealingQueue`.
+pub struct MemHealingQueue {
+    queue: Mutex<VecDeque<HealingEntry>>,
+    capacity: usize,
+}
+
+impl MemHealingQueue {
+    pub fn new(capacity: usize) -> Self {
+        MemHealingQueue {
+            queue: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().expect("lock poison").len()
+    }
+}
+
+impl HealingQueue for MemHealingQueue {
+    fn add(&self, entry: HealingEntry) -> BoxFuture<(), Error> {
+        let mut queue = self.queue.lock().expect("lock poison");
+        if queue.len() >= self.capacity {
+            return Err(ErrorKind::HealingQueueFull(self.capacity).into())
+                .into_future()
+                .boxify();
+        }
+        queue.push_back(entry);
+        Ok(()).into_future().boxify()
+    }
+
+    fn take(&self, limit: usize) -> BoxFuture<Vec<HealingEntry>, Error> {
+        let mut queue = self.queue.lock().expect("lock poison");
+        let count = ::std::cmp::min(limit, queue.len());
+        Ok(queue.drain(..count).collect()).into_future().boxify()
+    }
+}
+
+/// Runs the puts that are still outstanding once a put has reached its quorum.
+pub type PutExecutor = Executor<BoxFuture<(), ()>> + Send + Sync;
+
+/// Blobstore that stores every blob in several underlying blobstores.
+///
+/// `put` is sent to all blobstores and succeeds as soon as `quorum` of them acknowledge it. The
+/// puts that are still outstanding at that point keep running on `executor`. The blobstores
+/// whose put failed, before or after the quorum was reached, are recorded in the healing queue,
+/// which `heal` (or the `healer` future) works through. `get` asks all blobstores and returns the
+/// first copy found, so it keeps working as long as one blobstore with the blob is up.
+#[derive(Clone)]
+pub struct MultiplexedBlobstore {
+    blobstores: Arc<Vec<(BlobstoreId, Arc<Blobstore>)>>,
+    quorum: usize,
+    queue: Arc<HealingQueue>,
+    executor: Arc<PutExecutor>,
+}
+
+impl MultiplexedBlobstore {
+    pub fn new(
+        blobstores: Vec<(BlobstoreId, Arc<Blobstore>)>,
+        quorum: usize,
+        queue: Arc<HealingQueue>,
+        executor: Arc<PutExecutor>,
+    ) -> Result<Self> {
+        if quorum == 0 || quorum > blobstores.len() {
+            bail_err!(ErrorKind::InvalidQuorum(quorum, blobstores.len()));
+        }
+        Ok(MultiplexedBlobstore {
+            blobstores: Arc::new(blobstores),
+            quorum,
+            queue,
+            executor,
+        })
+    }
+
+    /// Heals up to `limit` entries from the healing queue every `interval`, forever. A round
+    /// that fails is retried at the next one.
+    pub fn healer(
+        &self,
+        handle: &Handle,
+        interval: Duration,
+        limit: usize,
+    ) -> Result<BoxFuture<(), Error>> {
+        let this = self.clone();
+        let healer = Interval::new(interval, handle)?
+            .from_err()
+            .for_each(move |()| this.heal(limit).then(|_| Ok::<_, Error>(())));
+        Ok(healer.boxify())
+    }
+
+    /// Repair up to `limit` entries from the healing queue by copying each blob from a blobstore
+    /// that has it to the ones that don't. Entries that can't be repaired yet are put back on
+    /// the queue. Returns the number of entries that were fully repaired.
+    pub fn heal(&self, limit: usize) -> BoxFuture<usize, Error> {
+        let blobstores = self.blobstores.clone();
+        let queue = self.queue.clone();
+
+        self.queue
+            .take(limit)
+            .and_then(move |entries| {
+                stream::iter_ok(entries)
+                    .and_then(move |entry| {
+                        let queue = queue.clone();
+                        heal_entry(blobstores.clone(), entry).and_then(move |remaining| {
+                            match remaining {
+                                None => future::ok(true).boxify(),
+                                Some(entry) => queue.add(entry).map(|()| false).boxify(),
+                            }
+                        })
+                    })
+                    .fold(0, |healed, ok| {
+                        Ok::<_, Error>(if ok { healed + 1 } else { healed })
+                    })
+            })
+            .boxify()
+    }
+}
+
+// Returns the part of the entry that still needs healing, if any.
+fn heal_entry(
+    blobstores: Arc<Vec<(BlobstoreId, Arc<Blobstore>)>>,
+    entry: HealingEntry,
+) -> BoxFuture<Option<HealingEntry>, Error> {
+    let sources = blobstores
+        .iter()
+        .filter(|&&(ref id, _)| !entry.missing.contains(id))
+        .map(|&(_, ref blobstore)| blobstore.get(entry.key.clone()))
+        .collect();
+
+    MultiplexedGet::new(sources)
+        .then(move |res| match res {
+            Ok(Some(value)) => {
+                let puts = blobstores
+                    .iter()
+                    .filter(|&&(ref id, _)| entry.missing.contains(id))
+                    .map(|&(id, ref blobstore)| {
+                        blobstore
+                            .put(entry.key.clone(), value.clone())
+                            .then(move |res| Ok::<_, Error>((id, res.is_ok())))
+                    })
+                    .collect::<Vec<_>>();
+                future::join_all(puts)
+                    .map(move |results| {
+                        let missing: Vec<_> = results
+                            .into_iter()
+                            .filter_map(|(id, ok)| if ok { None } else { Some(id) })
+                            .collect();
+                        if missing.is_empty() {
+                            None
+                        } else {
+                            Some(HealingEntry {
+                                key: entry.key,
+                                missing,
+                            })
+                        }
+                    })
+                    .boxify()
+            }
+            // Nothing to copy from right now; leave it for a later attempt
+            Ok(None) | Err(_) => future::ok(Some(entry)).boxify(),
+        })
+        .boxify()
+}
+
+impl Blobstore for MultiplexedBlobstore {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        let gets = self.blobstores
+            .iter()
+            .map(|&(_, ref blobstore)| blobstore.get(key.clone()))
+            .collect();
+
+        MultiplexedGet::new(gets).boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        let puts = self.blobstores
+            .iter()
+            .map(|&(id, ref blobstore)| {
+                blobstore
+                    .put(key.clone(), value.clone())
+                    .then(move |res| Ok::<_, ()>((id, res.is_ok())))
+                    .boxify()
+            })
+            .collect();
+        let all_ids: Vec<_> = self.blobstores.iter().map(|&(id, _)| id).collect();
+        let queue = self.queue.clone();
+        let executor = self.executor.clone();
+
+        MultiplexedPut::new(key.clone(), puts, self.quorum)
+            .and_then(move |(acked, mut failed, outstanding)| {
+                let background = {
+                    let queue = queue.clone();
+                    let key = key.clone();
+                    outstanding
+                        .filter_map(|(id, ok)| if ok { None } else { Some(id) })
+                        .collect()
+                        .and_then(move |missing| {
+                            if missing.is_empty() {
+                                future::ok(()).boxify()
+                            } else {
+                                // Nobody is waiting for this put anymore, so a full queue can't
+                                // be reported
+                                queue
+                                    .add(HealingEntry { key, missing })
+                                    .map_err(|_| ())
+                                    .boxify()
+                            }
+                        })
+                        .boxify()
+                };
+                if executor.execute(background).is_err() {
+                    // The outstanding puts won't complete, so they need healing too
+                    let outstanding: Vec<_> = all_ids
+                        .into_iter()
+                        .filter(|id| !acked.contains(id) && !failed.contains(id))
+                        .collect();
+                    failed.extend(outstanding);
+                }
+
+                if failed.is_empty() {
+                    future::ok(()).boxify()
+                } else {
+                    queue.add(HealingEntry {
+                        key,
+                        missing: failed,
+                    })
+                }
+            })
+            .boxify()
+    }
+
+    fn get_size(&self, key: String) -> BoxFuture<Option<u64>, Error> {
+        let gets = self.blobstores
+            .iter()
+            .map(|&(_, ref blobstore)| blobstore.get_size(key.clone()))
+            .collect();
+
+        MultiplexedGet::new(gets).boxify()
+    }
+
+    fn get_range(&self, key: String, offset: u64, len: u64) -> BoxFuture<Option<Bytes>, Error> {
+        let gets = self.blobstores
+            .iter()
+            .map(|&(_, ref blobstore)| blobstore.get_range(key.clone(), offset, len))
+            .collect();
+
+        MultiplexedGet::new(gets).boxify()
+    }
+}
+
+/// Resolves to the first `Some` returned by any of the gets (of whole blobs, or of their sizes or
+/// ranges). Resolves to `None` only if every get succeeded with `None`; if some failed and none
+/// found the blob, the last error is returned since the blob may well be in one of the failed
+/// blobstores.
+#[must_use = "futures do nothing unless polled"]
+struct MultiplexedGet<T> {
+    gets: FuturesUnordered<BoxFuture<Option<T>, Error>>,
+    last_error: Option<Error>,
+}
+
+impl<T> MultiplexedGet<T> {
+    fn new(gets: Vec<BoxFuture<Option<T>, Error>>) -> Self {
+        MultiplexedGet {
+            gets: gets.into_iter().collect(),
+            last_error: None,
+        }
+    }
+}
+
+impl<T> Future for MultiplexedGet<T> {
+    type Item = Option<T>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.gets.poll() {
+                Ok(Async::Ready(Some(Some(value)))) => return Ok(Async::Ready(Some(value))),
+                Ok(Async::Ready(Some(None))) => continue,
+                Ok(Async::Ready(None)) => {
+                    return match self.last_error.take() {
+                        Some(err) => Err(err),
+                        None => Ok(Async::Ready(None)),
+                    }
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => self.last_error = Some(err),
+            }
+        }
+    }
+}
+
+type PutFuture = BoxFuture<(BlobstoreId, bool), ()>;
+
+/// Resolves as soon as `quorum` of the puts succeeded, to the ids of the blobstores that
+/// acknowledged the put, the ids of those that failed and the puts that are still outstanding.
+#[must_use = "futures do nothing unless polled"]
+struct MultiplexedPut {
+    key: String,
+    puts: FuturesUnordered<PutFuture>,
+    acked: Vec<BlobstoreId>,
+    failed: Vec<BlobstoreId>,
+    quorum: usize,
+}
+
+impl MultiplexedPut {
+    fn new(key: String, puts: Vec<PutFuture>, quorum: usize) -> Self {
+        MultiplexedPut {
+            key,
+            puts: puts.into_iter().collect(),
+            acked: Vec::new(),
+            failed: Vec::new(),
+            quorum,
+        }
+    }
+}
+
+impl Future for MultiplexedPut {
+    type Item = (
+        Vec<BlobstoreId>,
+        Vec<BlobstoreId>,
+        FuturesUnordered<PutFuture>,
+    );
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // Puts that are already done past the quorum are collected too, so that only the ones
+        // that are really outstanding are left to run in the background
+        let exhausted = loop {
+            match self.puts.poll() {
+                Ok(Async::Ready(Some((id, true)))) => self.acked.push(id),
+                Ok(Async::Ready(Some((id, false)))) => self.failed.push(id),
+                Ok(Async::Ready(None)) => break true,
+                Ok(Async::NotReady) => break false,
+                Err(()) => unreachable!("puts never fail"),
+            }
+        };
+
+        if self.acked.len() >= self.quorum {
+            Ok(Async::Ready((
+                mem::replace(&mut self.acked, Vec::new()),
+                mem::replace(&mut self.failed, Vec::new()),
+                mem::replace(&mut self.puts, FuturesUnordered::new()),
+            )))
+        } else if exhausted {
+            bail_err!(ErrorKind::QuorumNotReached(
+                self.key.clone(),
+                self.acked.len(),
+                self.quorum,
+            ))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
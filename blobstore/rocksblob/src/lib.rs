@@ -18,12 +18,12 @@ use std::path::Path;
 
 use bytes::Bytes;
 use failure::Error;
-use futures::{Async, Future, Poll};
-use futures_ext::{BoxFuture, FutureExt};
+use futures::{stream, Async, Future, Poll};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use rocksdb::{Db, ReadOptions, WriteOptions};
 
-use blobstore::Blobstore;
+use blobstore::{range_bounds, BlobMeta, Blobstore, EnumerableBlobstore};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -57,9 +57,21 @@ impl Rocksblob {
 #[must_use = "futures do nothing unless polled"]
 pub struct GetBlob(Db, String);
 
+#[must_use = "futures do nothing unless polled"]
+pub struct GetBlobSize(Db, String);
+
+#[must_use = "futures do nothing unless polled"]
+pub struct GetBlobRange(Db, String, u64, u64);
+
 #[must_use = "futures do nothing unless polled"]
 pub struct PutBlob(Db, String, Bytes);
 
+#[must_use = "futures do nothing unless polled"]
+pub struct DeleteBlob(Db, String);
+
+#[must_use = "futures do nothing unless polled"]
+pub struct ListBlobs(Db);
+
 impl Future for GetBlob {
     type Item = Option<Bytes>;
     type Error = Error;
@@ -71,6 +83,34 @@ impl Future for GetBlob {
     }
 }
 
+// RocksDB can only read whole values, but at least only the requested part is copied out of
+// them.
+
+impl Future for GetBlobSize {
+    type Item = Option<u64>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let rdopts = ReadOptions::new();
+        let ret = self.0.get(&self.1, &rdopts).map_err(Error::from)?;
+        Ok(Async::Ready(ret.map(|value| value.len() as u64)))
+    }
+}
+
+impl Future for GetBlobRange {
+    type Item = Option<Bytes>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let rdopts = ReadOptions::new();
+        let ret = self.0.get(&self.1, &rdopts).map_err(Error::from)?;
+        Ok(Async::Ready(ret.map(|value| {
+            let (start, end) = range_bounds(value.len() as u64, self.2, self.3);
+            Bytes::from(&value[start as usize..end as usize])
+        })))
+    }
+}
+
 impl Future for PutBlob {
     type Item = ();
     type Error = Error;
@@ -82,6 +122,37 @@ impl Future for PutBlob {
     }
 }
 
+impl Future for DeleteBlob {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let wropts = WriteOptions::new().set_sync(false);
+        self.0.delete(&self.1, &wropts).map_err(Error::from)?;
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Future for ListBlobs {
+    type Item = Vec<BlobMeta>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let rdopts = ReadOptions::new();
+        // RocksDB doesn't keep per-key write times, so modification time is always unknown.
+        // Keys that aren't valid UTF-8 can't have been written through the Blobstore interface.
+        let blobs = self.0
+            .iter(&rdopts)
+            .filter_map(|(key, _value)| String::from_utf8(key.to_vec()).ok())
+            .map(|key| BlobMeta {
+                key,
+                modified: None,
+            })
+            .collect();
+        Ok(Async::Ready(blobs))
+    }
+}
+
 impl Blobstore for Rocksblob where {
     fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
         let db = self.db.clone();
@@ -94,4 +165,30 @@ impl Blobstore for Rocksblob where {
 
         PutBlob(db, key, value).boxify()
     }
+
+    fn get_size(&self, key: String) -> BoxFuture<Option<u64>, Error> {
+        let db = self.db.clone();
+
+        GetBlobSize(db, key).boxify()
+    }
+
+    fn get_range(&self, key: String, offset: u64, len: u64) -> BoxFuture<Option<Bytes>, Error> {
+        let db = self.db.clone();
+
+        GetBlobRange(db, key, offset, len).boxify()
+    }
+}
+
+impl EnumerableBlobstore for Rocksblob {
+    fn enumerate(&self) -> BoxStream<BlobMeta, Error> {
+        let db = self.db.clone();
+
+        ListBlobs(db).map(stream::iter_ok).flatten_stream().boxify()
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        let db = self.db.clone();
+
+        DeleteBlob(db, key).boxify()
+    }
 }
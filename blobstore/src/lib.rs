@@ -14,12 +14,13 @@ extern crate futures_ext;
 extern crate tokio_core;
 
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use bytes::Bytes;
 
 use failure::Error;
 use futures::{future, Future};
-use futures_ext::{BoxFuture, FutureExt};
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
 
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
@@ -44,9 +45,9 @@ pub enum ErrorKind {
 // to check that the blob integrity is OK, even if we don't actually fetch the data.
 //
 // Delete blob?
-// I'll avoid delete for now. The current design for Mononoke doesn't need delete for normal
-// operations. If delete is going to be needed then it will be some maintenance operation like gc,
-// but that opens up a whole pile of other design questions that we haven't got to yet.
+// The current design for Mononoke doesn't need delete for normal operations. Maintenance
+// operations like gc need it, so it lives in the separate `EnumerableBlobstore` trait which only
+// some implementations provide.
 //
 // Metadata?
 // Will definitely need some kind of metadata interface. The open questions there are:
@@ -87,8 +88,10 @@ pub enum ErrorKind {
 // a bug-finding consistency check.
 //
 // How to deal with very large objects?
-// - streaming get/put?
-// - range get/put? (how does range put work? put-put-put-commit?)
+// - range get is `get_range`, which stores that can read part of a blob implement efficiently.
+//   Streaming a blob is a sequence of range gets.
+// - streaming/range put? (how does range put work? put-put-put-commit?) For now, large objects
+//   are split into separate blobs by the layers above, see blobrepo's chunked contents.
 pub trait Blobstore: Send + Sync + 'static {
     fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error>;
     // The underlying implementation is allowed to assume that the value for a given key is always
@@ -110,6 +113,33 @@ pub trait Blobstore: Send + Sync + 'static {
             })
             .boxify()
     }
+    // The size of the blob, without fetching it if the underlying Blobstore can.
+    fn get_size(&self, key: String) -> BoxFuture<Option<u64>, Error> {
+        self.get(key)
+            .map(|opt| opt.map(|blob| blob.len() as u64))
+            .boxify()
+    }
+    // The part of the blob that starts at `offset` and is at most `len` bytes long: shorter if
+    // the blob ends before, empty if it ends before `offset`. The default implementation fetches
+    // the whole blob, implementations that can read part of a blob should override it.
+    fn get_range(&self, key: String, offset: u64, len: u64) -> BoxFuture<Option<Bytes>, Error> {
+        self.get(key)
+            .map(move |opt| {
+                opt.map(|blob| {
+                    let (start, end) = range_bounds(blob.len() as u64, offset, len);
+                    blob.slice(start as usize, end as usize)
+                })
+            })
+            .boxify()
+    }
+}
+
+/// Clamps the range of `len` bytes from `offset` to a blob of `size` bytes. Returns the start
+/// and end offsets.
+pub fn range_bounds(size: u64, offset: u64, len: u64) -> (u64, u64) {
+    let start = offset.min(size);
+    let end = start.saturating_add(len).min(size);
+    (start, end)
 }
 
 impl Blobstore for Arc<Blobstore> {
@@ -125,6 +155,12 @@ impl Blobstore for Arc<Blobstore> {
     fn assert_present(&self, key: String) -> BoxFuture<(), Error> {
         self.as_ref().assert_present(key)
     }
+    fn get_size(&self, key: String) -> BoxFuture<Option<u64>, Error> {
+        self.as_ref().get_size(key)
+    }
+    fn get_range(&self, key: String, offset: u64, len: u64) -> BoxFuture<Option<Bytes>, Error> {
+        self.as_ref().get_range(key, offset, len)
+    }
 }
 
 impl Blobstore for Box<Blobstore> {
@@ -140,4 +176,59 @@ impl Blobstore for Box<Blobstore> {
     fn assert_present(&self, key: String) -> BoxFuture<(), Error> {
         self.as_ref().assert_present(key)
     }
+    fn get_size(&self, key: String) -> BoxFuture<Option<u64>, Error> {
+        self.as_ref().get_size(key)
+    }
+    fn get_range(&self, key: String, offset: u64, len: u64) -> BoxFuture<Option<Bytes>, Error> {
+        self.as_ref().get_range(key, offset, len)
+    }
+}
+
+/// Information about a single blob, as reported by `EnumerableBlobstore::enumerate`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlobMeta {
+    pub key: String,
+    /// Time the blob was last written, if the underlying store keeps track of it.
+    pub modified: Option<SystemTime>,
+}
+
+/// Optional extension of `Blobstore` for stores that can list and remove their blobs.
+///
+/// This is only intended for maintenance operations such as garbage collection; nothing in the
+/// normal read/write path should depend on it. Enumeration is not a snapshot: blobs that are
+/// put or deleted while the stream is being consumed may or may not show up.
+pub trait EnumerableBlobstore: Blobstore {
+    fn enumerate(&self) -> BoxStream<BlobMeta, Error>;
+    // Deleting a key that doesn't exist is not an error.
+    fn delete(&self, key: String) -> BoxFuture<(), Error>;
+}
+
+impl Blobstore for Arc<EnumerableBlobstore> {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        self.as_ref().get(key)
+    }
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        self.as_ref().put(key, value)
+    }
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        self.as_ref().is_present(key)
+    }
+    fn assert_present(&self, key: String) -> BoxFuture<(), Error> {
+        self.as_ref().assert_present(key)
+    }
+    fn get_size(&self, key: String) -> BoxFuture<Option<u64>, Error> {
+        self.as_ref().get_size(key)
+    }
+    fn get_range(&self, key: String, offset: u64, len: u64) -> BoxFuture<Option<Bytes>, Error> {
+        self.as_ref().get_range(key, offset, len)
+    }
+}
+
+impl EnumerableBlobstore for Arc<EnumerableBlobstore> {
+    fn enumerate(&self) -> BoxStream<BlobMeta, Error> {
+        self.as_ref().enumerate()
+    }
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        self.as_ref().delete(key)
+    }
 }
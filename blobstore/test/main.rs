@@ -17,17 +17,33 @@ extern crate tempdir;
 extern crate tokio_core;
 
 extern crate blobstore;
+extern crate cachingblob;
+extern crate compressingblob;
 extern crate fileblob;
 extern crate memblob;
+extern crate multiplexedblob;
 extern crate rocksblob;
 
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
 use bytes::Bytes;
-use futures::Future;
+use failure::Error;
+use futures::{Future, IntoFuture, Stream};
+use futures::future::{ExecuteError, Executor};
+use futures::sync::oneshot;
+use futures_ext::{BoxFuture, FutureExt};
 use tempdir::TempDir;
 
-use blobstore::Blobstore;
+use blobstore::{Blobstore, EnumerableBlobstore};
+use cachingblob::{CacheStats, CachingBlobstore};
+use compressingblob::CompressingBlobstore;
 use fileblob::Fileblob;
 use memblob::EagerMemblob;
+use multiplexedblob::{BlobstoreId, HealingEntry, HealingQueue, MemHealingQueue,
+                      MultiplexedBlobstore};
 use rocksblob::Rocksblob;
 
 fn simple<B>(blobstore: B)
@@ -68,6 +84,76 @@ where
     assert_eq!(out, Bytes::from_static(b"bar"));
 }
 
+fn enumerate_delete<B>(blobstore: B)
+where
+    B: EnumerableBlobstore,
+{
+    let foo = "foo".to_string();
+    let weird = "path/with spaces%".to_string();
+    blobstore
+        .put(foo.clone(), Bytes::from_static(b"bar"))
+        .join(blobstore.put(weird.clone(), Bytes::from_static(b"baz")))
+        .wait()
+        .expect("put failed");
+
+    let mut keys: Vec<_> = blobstore
+        .enumerate()
+        .map(|meta| meta.key)
+        .collect()
+        .wait()
+        .expect("enumerate failed");
+    keys.sort();
+    assert_eq!(keys, vec![foo.clone(), weird.clone()]);
+
+    blobstore.delete(foo.clone()).wait().expect("delete failed");
+    // Deleting a missing blob is fine
+    blobstore.delete(foo.clone()).wait().expect("delete failed");
+
+    assert!(blobstore.get(foo).wait().expect("get failed").is_none());
+    let keys: Vec<_> = blobstore
+        .enumerate()
+        .map(|meta| meta.key)
+        .collect()
+        .wait()
+        .expect("enumerate failed");
+    assert_eq!(keys, vec![weird]);
+}
+
+fn range<B>(blobstore: B)
+where
+    B: Blobstore,
+{
+    let foo = "foo".to_string();
+    blobstore
+        .put(foo.clone(), Bytes::from_static(b"0123456789"))
+        .wait()
+        .expect("put failed");
+
+    let size = blobstore.get_size(foo.clone()).wait().expect("get_size failed");
+    assert_eq!(size, Some(10));
+    let get_range = |offset, len| {
+        blobstore
+            .get_range(foo.clone(), offset, len)
+            .wait()
+            .expect("get_range failed")
+            .expect("missing")
+    };
+    assert_eq!(get_range(0, 10), Bytes::from_static(b"0123456789"));
+    assert_eq!(get_range(2, 3), Bytes::from_static(b"234"));
+    assert_eq!(get_range(8, 5), Bytes::from_static(b"89"));
+    assert_eq!(get_range(12, 5), Bytes::new());
+
+    let missing = "missing".to_string();
+    assert!(blobstore.get_size(missing.clone()).wait().expect("get_size failed").is_none());
+    assert!(
+        blobstore
+            .get_range(missing, 0, 10)
+            .wait()
+            .expect("get_range failed")
+            .is_none()
+    );
+}
+
 macro_rules! blobstore_test_impl {
     ($mod_name: ident => {
         state: $state: expr,
@@ -94,6 +180,18 @@ macro_rules! blobstore_test_impl {
                 let state = $state;
                 boxable($new_cb(&state));
             }
+
+            #[test]
+            fn test_range() {
+                let state = $state;
+                range($new_cb(&state));
+            }
+
+            #[test]
+            fn test_enumerate_delete() {
+                let state = $state;
+                enumerate_delete($new_cb(&state));
+            }
         }
     }
 }
@@ -122,3 +220,525 @@ blobstore_test_impl! {
         persistent: true,
     }
 }
+
+/// Memblob that can be switched into failing every operation, or into holding its puts until
+/// they are released.
+#[derive(Clone)]
+struct FlakyMemblob {
+    inner: EagerMemblob,
+    failing: Arc<AtomicBool>,
+    hold: Arc<Mutex<Option<oneshot::Receiver<()>>>>,
+}
+
+impl FlakyMemblob {
+    fn new() -> Self {
+        FlakyMemblob {
+            inner: EagerMemblob::new(),
+            failing: Arc::new(AtomicBool::new(false)),
+            hold: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The next put only completes once the returned sender is used.
+    fn hold_put(&self) -> oneshot::Sender<()> {
+        let (sender, receiver) = oneshot::channel();
+        *self.hold.lock().unwrap() = Some(receiver);
+        sender
+    }
+
+    fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::SeqCst);
+    }
+
+    fn is_failing(&self) -> bool {
+        self.failing.load(Ordering::SeqCst)
+    }
+}
+
+impl Blobstore for FlakyMemblob {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        if self.is_failing() {
+            return Err(failure::err_msg("get failed")).into_future().boxify();
+        }
+        self.inner.get(key)
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        if let Some(hold) = self.hold.lock().unwrap().take() {
+            let this = self.clone();
+            return hold.map_err(Error::from)
+                .and_then(move |()| this.put(key, value))
+                .boxify();
+        }
+        if self.is_failing() {
+            return Err(failure::err_msg("put failed")).into_future().boxify();
+        }
+        self.inner.put(key, value)
+    }
+}
+
+/// Executor that keeps the futures until the test runs them.
+#[derive(Clone, Default)]
+struct Background {
+    futures: Arc<Mutex<Vec<BoxFuture<(), ()>>>>,
+}
+
+impl Background {
+    fn run(&self) {
+        let futures = mem::replace(&mut *self.futures.lock().unwrap(), Vec::new());
+        for future in futures {
+            future.wait().expect("background future failed");
+        }
+    }
+}
+
+impl Executor<BoxFuture<(), ()>> for Background {
+    fn execute(&self, future: BoxFuture<(), ()>) -> Result<(), ExecuteError<BoxFuture<(), ()>>> {
+        self.futures.lock().unwrap().push(future);
+        Ok(())
+    }
+}
+
+fn new_multiplexed(
+    stores: &[FlakyMemblob],
+    quorum: usize,
+) -> (MultiplexedBlobstore, Arc<MemHealingQueue>, Background) {
+    let queue = Arc::new(MemHealingQueue::new(10));
+    let background = Background::default();
+    let blobstores = stores
+        .iter()
+        .enumerate()
+        .map(|(id, store)| {
+            (
+                BlobstoreId::new(id as u32),
+                Arc::new(store.clone()) as Arc<Blobstore>,
+            )
+        })
+        .collect();
+    let multiplexed = MultiplexedBlobstore::new(
+        blobstores,
+        quorum,
+        queue.clone(),
+        Arc::new(background.clone()),
+    ).expect("invalid multiplexed blobstore");
+    (multiplexed, queue, background)
+}
+
+mod multiplexedblob_test {
+    use super::*;
+
+    #[test]
+    fn test_simple() {
+        simple(new_multiplexed(&[FlakyMemblob::new(), FlakyMemblob::new()], 2).0);
+    }
+
+    #[test]
+    fn test_missing() {
+        missing(new_multiplexed(&[FlakyMemblob::new(), FlakyMemblob::new()], 2).0);
+    }
+
+    #[test]
+    fn test_boxable() {
+        boxable(new_multiplexed(&[FlakyMemblob::new(), FlakyMemblob::new()], 2).0);
+    }
+
+    #[test]
+    fn test_range() {
+        range(new_multiplexed(&[FlakyMemblob::new(), FlakyMemblob::new()], 2).0);
+    }
+
+    #[test]
+    fn test_invalid_quorum() {
+        let queue = Arc::new(MemHealingQueue::new(10));
+        let executor = Arc::new(Background::default());
+        let blobstores = vec![
+            (
+                BlobstoreId::new(0),
+                Arc::new(EagerMemblob::new()) as Arc<Blobstore>,
+            ),
+        ];
+        assert!(
+            MultiplexedBlobstore::new(blobstores.clone(), 0, queue.clone(), executor.clone())
+                .is_err()
+        );
+        assert!(MultiplexedBlobstore::new(blobstores, 2, queue, executor).is_err());
+    }
+
+    #[test]
+    fn test_partial_write() {
+        let stores = [FlakyMemblob::new(), FlakyMemblob::new()];
+        let (multiplexed, queue, _background) = new_multiplexed(&stores, 1);
+
+        stores[1].set_failing(true);
+        multiplexed
+            .put("foo".to_string(), Bytes::from_static(b"bar"))
+            .wait()
+            .expect("put with quorum 1 should succeed");
+
+        let entries = queue.take(10).wait().unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                HealingEntry {
+                    key: "foo".to_string(),
+                    missing: vec![BlobstoreId::new(1)],
+                },
+            ]
+        );
+
+        // Reads are served by whichever store has the blob, even if another one is failing
+        let out = multiplexed.get("foo".to_string()).wait().unwrap();
+        assert_eq!(out, Some(Bytes::from_static(b"bar")));
+    }
+
+    #[test]
+    fn test_quorum_not_reached() {
+        let stores = [FlakyMemblob::new(), FlakyMemblob::new()];
+        let (multiplexed, queue, _background) = new_multiplexed(&stores, 2);
+
+        stores[0].set_failing(true);
+        assert!(
+            multiplexed
+                .put("foo".to_string(), Bytes::from_static(b"bar"))
+                .wait()
+                .is_err()
+        );
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_put_past_quorum() {
+        let stores = [FlakyMemblob::new(), FlakyMemblob::new()];
+        let (multiplexed, queue, background) = new_multiplexed(&stores, 1);
+
+        let release = stores[1].hold_put();
+        multiplexed
+            .put("foo".to_string(), Bytes::from_static(b"bar"))
+            .wait()
+            .expect("put with quorum 1 should succeed");
+        assert_eq!(stores[1].get("foo".to_string()).wait().unwrap(), None);
+
+        // The slow store still gets the blob, so there is nothing to heal
+        release.send(()).unwrap();
+        background.run();
+        let out = stores[1].get("foo".to_string()).wait().unwrap();
+        assert_eq!(out, Some(Bytes::from_static(b"bar")));
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_put_fails_past_quorum() {
+        let stores = [FlakyMemblob::new(), FlakyMemblob::new()];
+        let (multiplexed, queue, background) = new_multiplexed(&stores, 1);
+
+        let release = stores[1].hold_put();
+        multiplexed
+            .put("foo".to_string(), Bytes::from_static(b"bar"))
+            .wait()
+            .expect("put with quorum 1 should succeed");
+        assert_eq!(queue.len(), 0);
+
+        stores[1].set_failing(true);
+        release.send(()).unwrap();
+        background.run();
+        let entries = queue.take(10).wait().unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                HealingEntry {
+                    key: "foo".to_string(),
+                    missing: vec![BlobstoreId::new(1)],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_healing_queue_full() {
+        let stores = [FlakyMemblob::new(), FlakyMemblob::new()];
+        let (multiplexed, queue, _background) = new_multiplexed(&stores, 1);
+
+        stores[1].set_failing(true);
+        for i in 0..10 {
+            multiplexed
+                .put(format!("foo{}", i), Bytes::from_static(b"bar"))
+                .wait()
+                .expect("put failed");
+        }
+        assert_eq!(queue.len(), 10);
+        // The blob would be in a single store without anything recording it
+        assert!(
+            multiplexed
+                .put("foo10".to_string(), Bytes::from_static(b"bar"))
+                .wait()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_get_errors() {
+        let stores = [FlakyMemblob::new(), FlakyMemblob::new()];
+        let (multiplexed, _queue, _background) = new_multiplexed(&stores, 2);
+
+        // A failing store might have the blob, so a miss elsewhere isn't conclusive
+        stores[0].set_failing(true);
+        assert!(multiplexed.get("missing".to_string()).wait().is_err());
+    }
+
+    #[test]
+    fn test_heal() {
+        let stores = [FlakyMemblob::new(), FlakyMemblob::new(), FlakyMemblob::new()];
+        let (multiplexed, queue, _background) = new_multiplexed(&stores, 1);
+
+        stores[1].set_failing(true);
+        stores[2].set_failing(true);
+        multiplexed
+            .put("foo".to_string(), Bytes::from_static(b"bar"))
+            .wait()
+            .expect("put failed");
+        assert_eq!(queue.len(), 1);
+
+        // Only one of the stores is back, so the entry stays on the queue for the other one
+        stores[1].set_failing(false);
+        assert_eq!(multiplexed.heal(10).wait().unwrap(), 0);
+        let out = stores[1].get("foo".to_string()).wait().unwrap();
+        assert_eq!(out, Some(Bytes::from_static(b"bar")));
+        assert_eq!(queue.len(), 1);
+
+        stores[2].set_failing(false);
+        assert_eq!(multiplexed.heal(10).wait().unwrap(), 1);
+        let out = stores[2].get("foo".to_string()).wait().unwrap();
+        assert_eq!(out, Some(Bytes::from_static(b"bar")));
+        assert_eq!(queue.len(), 0);
+    }
+}
+
+mod cachingblob_test {
+    use super::*;
+
+    fn new_caching(backing: &EagerMemblob, negative_ttl: Duration) -> CachingBlobstore {
+        CachingBlobstore::new(Arc::new(backing.clone()), 1024 * 1024, negative_ttl)
+    }
+
+    #[test]
+    fn test_simple() {
+        simple(new_caching(&EagerMemblob::new(), Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_missing() {
+        missing(new_caching(&EagerMemblob::new(), Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_boxable() {
+        boxable(new_caching(&EagerMemblob::new(), Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_range() {
+        range(new_caching(&EagerMemblob::new(), Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_hits() {
+        let caching = new_caching(&EagerMemblob::new(), Duration::from_secs(1));
+
+        caching
+            .put("foo".to_string(), Bytes::from_static(b"bar"))
+            .wait()
+            .expect("put failed");
+        for _ in 0..3 {
+            let out = caching.get("foo".to_string()).wait().expect("get failed");
+            assert_eq!(out, Some(Bytes::from_static(b"bar")));
+        }
+
+        assert_eq!(caching.stats(), CacheStats { hits: 2, misses: 1 });
+    }
+
+    #[test]
+    fn test_negative_caching() {
+        let backing = EagerMemblob::new();
+        let caching = new_caching(&backing, Duration::from_secs(60));
+
+        assert!(caching.get("foo".to_string()).wait().unwrap().is_none());
+
+        // Uploaded behind the cache's back, so the cached miss is still served
+        backing
+            .put("foo".to_string(), Bytes::from_static(b"bar"))
+            .wait()
+            .expect("put failed");
+        assert!(caching.get("foo".to_string()).wait().unwrap().is_none());
+        assert_eq!(caching.stats(), CacheStats { hits: 1, misses: 1 });
+
+        // Putting through the cache drops the cached miss
+        caching
+            .put("foo".to_string(), Bytes::from_static(b"bar"))
+            .wait()
+            .expect("put failed");
+        let out = caching.get("foo".to_string()).wait().unwrap();
+        assert_eq!(out, Some(Bytes::from_static(b"bar")));
+    }
+
+    #[test]
+    fn test_negative_caching_expiry() {
+        let backing = EagerMemblob::new();
+        let caching = new_caching(&backing, Duration::from_secs(0));
+
+        assert!(caching.get("foo".to_string()).wait().unwrap().is_none());
+        backing
+            .put("foo".to_string(), Bytes::from_static(b"bar"))
+            .wait()
+            .expect("put failed");
+
+        let out = caching.get("foo".to_string()).wait().unwrap();
+        assert_eq!(out, Some(Bytes::from_static(b"bar")));
+        assert_eq!(caching.stats(), CacheStats { hits: 0, misses: 2 });
+    }
+}
+
+mod compressingblob_test {
+    use super::*;
+
+    fn new_compressing(backing: &EagerMemblob) -> CompressingBlobstore {
+        CompressingBlobstore::new_zstd(Arc::new(backing.clone()), 3)
+    }
+
+    #[test]
+    fn test_simple() {
+        simple(new_compressing(&EagerMemblob::new()));
+    }
+
+    #[test]
+    fn test_missing() {
+        missing(new_compressing(&EagerMemblob::new()));
+    }
+
+    #[test]
+    fn test_boxable() {
+        boxable(new_compressing(&EagerMemblob::new()));
+    }
+
+    #[test]
+    fn test_range() {
+        range(new_compressing(&EagerMemblob::new()));
+    }
+
+    #[test]
+    fn test_compressed_range() {
+        let compressing = new_compressing(&EagerMemblob::new());
+        let value = Bytes::from((0..64 * 1024).map(|i| (i / 1024) as u8).collect::<Vec<_>>());
+
+        compressing
+            .put("foo".to_string(), value.clone())
+            .wait()
+            .expect("put failed");
+
+        let size = compressing.get_size("foo".to_string()).wait().unwrap();
+        assert_eq!(size, Some(value.len() as u64));
+        let out = compressing
+            .get_range("foo".to_string(), 1000, 100)
+            .wait()
+            .unwrap();
+        assert_eq!(out, Some(value.slice(1000, 1100)));
+    }
+
+    #[test]
+    fn test_compressed() {
+        let backing = EagerMemblob::new();
+        let compressing = new_compressing(&backing);
+        let value = Bytes::from(vec![b'a'; 64 * 1024]);
+
+        compressing
+            .put("foo".to_string(), value.clone())
+            .wait()
+            .expect("put failed");
+
+        let stored = backing
+            .get("foo".to_string())
+            .wait()
+            .unwrap()
+            .expect("missing");
+        assert!(stored.len() < value.len());
+
+        assert!(compressing.is_present("foo".to_string()).wait().unwrap());
+        let out = compressing.get("foo".to_string()).wait().unwrap();
+        assert_eq!(out, Some(value));
+    }
+
+    #[test]
+    fn test_incompressible() {
+        let backing = EagerMemblob::new();
+        let compressing = new_compressing(&backing);
+        // Too short for zstd to do anything useful with
+        let value = Bytes::from_static(b"x");
+
+        compressing
+            .put("foo".to_string(), value.clone())
+            .wait()
+            .expect("put failed");
+
+        let stored = backing.get("foo".to_string()).wait().unwrap();
+        assert_eq!(stored, Some(value.clone()));
+        let out = compressing.get("foo".to_string()).wait().unwrap();
+        assert_eq!(out, Some(value));
+    }
+
+    #[test]
+    fn test_legacy() {
+        let backing = EagerMemblob::new();
+        // Written before compression was enabled, so without a header
+        let value = Bytes::from_static(b"\x01MNKCMPbar");
+        backing
+            .put("foo".to_string(), value.clone())
+            .wait()
+            .expect("put failed");
+
+        let out = new_compressing(&backing).get("foo".to_string()).wait().unwrap();
+        assert_eq!(out, Some(value));
+    }
+
+    #[test]
+    fn test_magic() {
+        let backing = EagerMemblob::new();
+        let compressing = new_compressing(&backing);
+        // Starts like a compressed blob, so it can't be stored as it is
+        let value = Bytes::from_static(b"\xffMNKCMP\x01\x00\x00\x00\x00\x00\x00\x00\x01b");
+
+        compressing
+            .put("foo".to_string(), value.clone())
+            .wait()
+            .expect("put failed");
+
+        let stored = backing.get("foo".to_string()).wait().unwrap();
+        assert_ne!(stored, Some(value.clone()));
+        let out = compressing.get("foo".to_string()).wait().unwrap();
+        assert_eq!(out, Some(value.clone()));
+        let size = compressing.get_size("foo".to_string()).wait().unwrap();
+        assert_eq!(size, Some(value.len() as u64));
+        let out = compressing
+            .get_range("foo".to_string(), 7, 100)
+            .wait()
+            .unwrap();
+        assert_eq!(out, Some(value.slice_from(7)));
+    }
+
+    #[test]
+    fn test_reader() {
+        let backing = EagerMemblob::new();
+        let value = Bytes::from(vec![b'a'; 64 * 1024]);
+        new_compressing(&backing)
+            .put("foo".to_string(), value.clone())
+            .wait()
+            .expect("put failed");
+
+        // Compressed blobs are readable even when nothing is compressed anymore
+        let reader = CompressingBlobstore::new(Arc::new(backing.clone()), None);
+        assert_eq!(reader.get("foo".to_string()).wait().unwrap(), Some(value.clone()));
+        reader
+            .put("bar".to_string(), value.clone())
+            .wait()
+            .expect("put failed");
+        assert_eq!(backing.get("bar".to_string()).wait().unwrap(), Some(value));
+    }
+}
@@ -0,0 +1,19 @@
+CREATE TABLE bookmarks (
+  repo_id INTEGER NOT NULL,
+  name VARBINARY(512) NOT NULL,
+  changeset_id BINARY(20) NOT NULL,
+  PRIMARY KEY (repo_id, name)
+);
+
+CREATE TABLE bookmarks_update_log (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  repo_id INTEGER NOT NULL,
+  name VARBINARY(512) NOT NULL,
+  from_changeset_id BINARY(20),
+  to_changeset_id BINARY(20),
+  reason VARCHAR(32) NOT NULL,
+  timestamp BIGINT NOT NULL,
+  user VARCHAR(255)
+);
+
+CREATE INDEX bookmarks_update_log_name ON bookmarks_update_log (repo_id, name, id);
@@ -0,0 +1,21 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+pub use failure::{Error, Result};
+
+use ascii::AsciiString;
+
+use mercurial_types::HgChangesetId;
+
+#[derive(Debug, Eq, Fail, PartialEq)]
+pub enum ErrorKind {
+    #[fail(display = "Bookmark {} is already changed in this transaction", _0)]
+    DuplicateOperation(AsciiString),
+    #[fail(display = "Bookmark {} already exists", _0)] BookmarkExists(AsciiString),
+    #[fail(display = "Bookmark {} does not point to {}", _0, _1)]
+    BookmarkMismatch(AsciiString, HgChangesetId),
+    #[fail(display = "Invalid data in database")] InvalidStoredData,
+}
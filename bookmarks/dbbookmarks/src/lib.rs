@@ -0,0 +1,567 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate ascii;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate bookmarks;
+extern crate db;
+#[macro_use]
+extern crate futures_ext;
+extern crate mercurial_types;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::result;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ascii::AsciiString;
+use diesel::{delete, insert_into, replace_into, update, Connection, MysqlConnection,
+             SqliteConnection};
+use diesel::backend::Backend;
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::sql_types::HasSqlType;
+use futures::{future, stream, Future};
+
+use bookmarks::{BookmarkUpdateLogEntry, BookmarkUpdateReason, Bookmarks, Transaction};
+use db::ConnectionParams;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use mercurial_types::{HgChangesetId, RepositoryId};
+use mercurial_types::sql_types::NodeHashSql;
+
+mod errors;
+mod models;
+mod schema;
+
+pub use errors::*;
+use models::{BookmarkLogInsertRow, BookmarkLogRow, BookmarkRow};
+use schema::bookmarks as bookmarks_table;
+use schema::bookmarks_update_log as log_table;
+
+pub struct SqliteDbBookmarks {
+    connection: Arc<Mutex<SqliteConnection>>,
+}
+
+impl SqliteDbBookmarks {
+    /// Open a SQLite database. This is synchronous because the SQLite backend hits local
+    /// disk or memory.
+    pub fn open<P: AsRef<str>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let conn = SqliteConnection::establish(path)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Create a new SQLite database.
+    pub fn create<P: AsRef<str>>(path: P) -> Result<Self> {
+        let bookmarks = Self::open(path)?;
+
+        let up_query = include_str!("../schemas/sqlite-bookmarks.sql");
+        bookmarks
+            .connection
+            .lock()
+            .expect("lock poisoned")
+            .batch_execute(&up_query)?;
+
+        Ok(bookmarks)
+    }
+
+    /// Open the SQLite database at `path`, creating it if it doesn't exist yet.
+    pub fn open_or_create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            Self::open(path.to_string_lossy())
+        } else {
+            Self::create(path.to_string_lossy())
+        }
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory() -> Result<Self> {
+        Self::create(":memory:")
+    }
+}
+
+pub struct MysqlDbBookmarks {
+    connection: Arc<Mutex<MysqlConnection>>,
+}
+
+impl MysqlDbBookmarks {
+    pub fn open(params: ConnectionParams) -> Result<Self> {
+        let url = params.to_diesel_url()?;
+        let conn = MysqlConnection::establish(&url)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    pub fn create_test_db<P: AsRef<str>>(prefix: P) -> Result<Self> {
+        let params = db::create_test_db(prefix)?;
+        Self::create(params)
+    }
+
+    fn create(params: ConnectionParams) -> Result<Self> {
+        let bookmarks = Self::open(params)?;
+
+        let up_query = include_str!("../schemas/mysql-bookmarks.sql");
+        bookmarks
+            .connection
+            .lock()
+            .expect("lock poisoned")
+            .batch_execute(&up_query)?;
+
+        Ok(bookmarks)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum BookmarkOp {
+    Create(HgChangesetId),
+    Update {
+        new: HgChangesetId,
+        old: HgChangesetId,
+    },
+    ForceSet(HgChangesetId),
+    Delete(HgChangesetId),
+    ForceDelete,
+}
+
+/// Bookmark changes that are applied in a single SQL transaction on commit, so either all of
+/// them happen or none do.
+pub struct DbBookmarksTransaction<C> {
+    connection: Arc<Mutex<C>>,
+    repo_id: RepositoryId,
+    ops: HashMap<AsciiString, BookmarkOp>,
+}
+
+impl<C> DbBookmarksTransaction<C> {
+    fn new(connection: Arc<Mutex<C>>, repo_id: RepositoryId) -> Self {
+        DbBookmarksTransaction {
+            connection,
+            repo_id,
+            ops: HashMap::new(),
+        }
+    }
+
+    /// Each bookmark can only be changed once per transaction, since the order of operations
+    /// within a transaction isn't defined.
+    fn add_op(&mut self, key: &AsciiString, op: BookmarkOp) -> Result<()> {
+        if self.ops.contains_key(key) {
+            bail_err!(ErrorKind::DuplicateOperation(key.clone()));
+        }
+        self.ops.insert(key.clone(), op);
+        Ok(())
+    }
+}
+
+/// How each backend makes a transaction's reads and writes of the bookmarks it changes atomic,
+/// so that the values read for the update log are the ones that get replaced.
+trait LockingConnection {
+    /// Runs `f` in a transaction that holds the locks needed to write bookmarks from the start.
+    fn write_transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>;
+
+    /// Reads a bookmark within a write transaction, locking it until the transaction ends.
+    fn current_for_update(
+        &self,
+        repo_id: RepositoryId,
+        name: &[u8],
+    ) -> Result<Option<HgChangesetId>>;
+}
+
+impl LockingConnection for SqliteConnection {
+    fn write_transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        // A deferred transaction only takes the write lock at its first write, after the
+        // bookmarks have been read. An immediate one takes it straight away.
+        self.batch_execute("BEGIN IMMEDIATE")?;
+        let result = f().and_then(|value| {
+            self.batch_execute("COMMIT")?;
+            Ok(value)
+        });
+        match result {
+            Ok(value) => Ok(value),
+            Err(err) => {
+                let _ = self.batch_execute("ROLLBACK");
+                Err(err)
+            }
+        }
+    }
+
+    fn current_for_update(
+        &self,
+        repo_id: RepositoryId,
+        name: &[u8],
+    ) -> Result<Option<HgChangesetId>> {
+        // The whole database is locked by the immediate transaction
+        let current = bookmark_query(repo_id, name)
+            .select(bookmarks_table::changeset_id)
+            .first::<HgChangesetId>(self)
+            .optional()?;
+        Ok(current)
+    }
+}
+
+impl LockingConnection for MysqlConnection {
+    fn write_transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        self.transaction::<_, Error, _>(f)
+    }
+
+    fn current_for_update(
+        &self,
+        repo_id: RepositoryId,
+        name: &[u8],
+    ) -> Result<Option<HgChangesetId>> {
+        let current = bookmarks_table::table
+            .filter(bookmarks_table::repo_id.eq(repo_id))
+            .filter(bookmarks_table::name.eq(name.to_vec()))
+            .select(bookmarks_table::changeset_id)
+            .for_update()
+            .first::<HgChangesetId>(self)
+            .optional()?;
+        Ok(current)
+    }
+}
+
+/// Using a macro here is unfortunate, but it appears to be the only way to share this code
+/// between SQLite and MySQL.
+macro_rules! impl_bookmarks {
+    ($struct: ty, $conn: ty) => {
+        impl Bookmarks for $struct {
+            fn get(
+                &self,
+                name: &AsciiString,
+                repo_id: &RepositoryId,
+            ) -> BoxFuture<Option<HgChangesetId>, Error> {
+                // TODO: don't block -- send this to another thread
+                let connection = self.connection.lock().expect("lock poisoned");
+                let query = bookmark_query(*repo_id, name.as_bytes())
+                    .select(bookmarks_table::changeset_id);
+                let result = query
+                    .first::<HgChangesetId>(&*connection)
+                    .optional()
+                    .map_err(failure::Error::from);
+                future::result(result).boxify()
+            }
+
+            fn list_by_prefix(
+                &self,
+                prefix: &AsciiString,
+                repo_id: &RepositoryId,
+            ) -> BoxStream<(AsciiString, HgChangesetId), Error> {
+                let connection = self.connection.lock().expect("lock poisoned");
+                let rows = prefix_query(*repo_id, prefix.as_bytes())
+                    .load::<BookmarkRow>(&*connection)
+                    .map_err(failure::Error::from)
+                    .and_then(|rows| {
+                        rows.into_iter()
+                            .map(|row| {
+                                let name = AsciiString::from_ascii(row.name)
+                                    .map_err(|_| ErrorKind::InvalidStoredData)?;
+                                Ok((name, row.changeset_id))
+                            })
+                            .collect::<Result<Vec<_>>>()
+                    });
+                future::result(rows)
+                    .map(stream::iter_ok)
+                    .flatten_stream()
+                    .boxify()
+            }
+
+            fn create_transaction(&self, repo_id: &RepositoryId) -> Box<Transaction> {
+                Box::new(DbBookmarksTransaction::new(self.connection.clone(), *repo_id))
+            }
+
+            fn list_bookmark_log_entries(
+                &self,
+                name: &AsciiString,
+                repo_id: &RepositoryId,
+                before: Option<i64>,
+                limit: usize,
+            ) -> BoxStream<BookmarkUpdateLogEntry, Error> {
+                let connection = self.connection.lock().expect("lock poisoned");
+                let entries = log_query(*repo_id, name.as_bytes(), before, limit)
+                    .load::<BookmarkLogRow>(&*connection)
+                    .map_err(failure::Error::from)
+                    .and_then(|rows| {
+                        rows.into_iter()
+                            .map(log_entry_from_row)
+                            .collect::<Result<Vec<_>>>()
+                    });
+                future::result(entries)
+                    .map(stream::iter_ok)
+                    .flatten_stream()
+                    .boxify()
+            }
+        }
+
+        impl Transaction for DbBookmarksTransaction<$conn> {
+            fn update(
+                &mut self,
+                key: &AsciiString,
+                new_cs: &HgChangesetId,
+                old_cs: &HgChangesetId,
+            ) -> Result<()> {
+                self.add_op(
+                    key,
+                    BookmarkOp::Update {
+                        new: *new_cs,
+                        old: *old_cs,
+                    },
+                )
+            }
+
+            fn create(&mut self, key: &AsciiString, new_cs: &HgChangesetId) -> Result<()> {
+                self.add_op(key, BookmarkOp::Create(*new_cs))
+            }
+
+            fn force_set(&mut self, key: &AsciiString, new_cs: &HgChangesetId) -> Result<()> {
+                self.add_op(key, BookmarkOp::ForceSet(*new_cs))
+            }
+
+            fn delete(&mut self, key: &AsciiString, old_cs: &HgChangesetId) -> Result<()> {
+                self.add_op(key, BookmarkOp::Delete(*old_cs))
+            }
+
+            fn force_delete(&mut self, key: &AsciiString) -> Result<()> {
+                self.add_op(key, BookmarkOp::ForceDelete)
+            }
+
+            fn commit(
+                &self,
+                reason: BookmarkUpdateReason,
+                user: Option<&str>,
+            ) -> BoxFuture<(), Error> {
+                let repo_id = self.repo_id;
+                let timestamp = try_boxfuture!(now_timestamp());
+                let connection = self.connection.lock().expect("lock poisoned");
+
+                // TODO figure out how to make transactions async. Assuming for now that
+                // the inside of a transaction can be synchronous.
+                let txn_result = connection.write_transaction(|| {
+                    for (key, op) in self.ops.iter() {
+                        let name = key.as_bytes();
+                        let (from, to) = match *op {
+                            BookmarkOp::Create(new) => {
+                                let row = BookmarkRow {
+                                    repo_id,
+                                    name: name.to_vec(),
+                                    changeset_id: new,
+                                };
+                                let result = insert_into(bookmarks_table::table)
+                                    .values(&row)
+                                    .execute(&*connection);
+                                map_create_result(result, key)?;
+                                (None, Some(new))
+                            }
+                            BookmarkOp::Update { new, old } => {
+                                // MySQL counts the rows an update changes rather than the ones
+                                // it matches, so an update to the same value can't tell whether
+                                // the bookmark was there: it is checked with a locking read.
+                                let matched = if new == old {
+                                    connection.current_for_update(repo_id, name)? == Some(old)
+                                } else {
+                                    let updated = update(
+                                        bookmarks_table::table
+                                            .filter(bookmarks_table::repo_id.eq(repo_id))
+                                            .filter(bookmarks_table::name.eq(name))
+                                            .filter(bookmarks_table::changeset_id.eq(old)),
+                                    ).set(bookmarks_table::changeset_id.eq(new))
+                                        .execute(&*connection)?;
+                                    updated > 0
+                                };
+                                if !matched {
+                                    bail_err!(ErrorKind::BookmarkMismatch(key.clone(), old));
+                                }
+                                (Some(old), Some(new))
+                            }
+                            BookmarkOp::ForceSet(new) => {
+                                let current = connection.current_for_update(repo_id, name)?;
+                                let row = BookmarkRow {
+                                    repo_id,
+                                    name: name.to_vec(),
+                                    changeset_id: new,
+                                };
+                                replace_into(bookmarks_table::table)
+                                    .values(&row)
+                                    .execute(&*connection)?;
+                                (current, Some(new))
+                            }
+                            BookmarkOp::Delete(old) => {
+                                let deleted = delete(
+                                    bookmarks_table::table
+                                        .filter(bookmarks_table::repo_id.eq(repo_id))
+                                        .filter(bookmarks_table::name.eq(name))
+                                        .filter(bookmarks_table::changeset_id.eq(old)),
+                                ).execute(&*connection)?;
+                                if deleted == 0 {
+                                    bail_err!(ErrorKind::BookmarkMismatch(key.clone(), old));
+                                }
+                                (Some(old), None)
+                            }
+                            BookmarkOp::ForceDelete => {
+                                let current = connection.current_for_update(repo_id, name)?;
+                                delete(
+                                    bookmarks_table::table
+                                        .filter(bookmarks_table::repo_id.eq(repo_id))
+                                        .filter(bookmarks_table::name.eq(name)),
+                                ).execute(&*connection)?;
+                                (current, None)
+                            }
+                        };
+
+                        // Deleting a bookmark that doesn't exist doesn't move anything
+                        if from.is_some() || to.is_some() {
+                            let log_row = BookmarkLogInsertRow {
+                                repo_id,
+                                name: name.to_vec(),
+                                from_changeset_id: from,
+                                to_changeset_id: to,
+                                reason: reason.as_str().to_string(),
+                                timestamp,
+                                user: user.map(|user| user.to_string()),
+                            };
+                            insert_into(log_table::table)
+                                .values(&log_row)
+                                .execute(&*connection)?;
+                        }
+                    }
+                    Ok(())
+                });
+
+                future::result(txn_result).boxify()
+            }
+        }
+    }
+}
+
+impl_bookmarks!(MysqlDbBookmarks, MysqlConnection);
+impl_bookmarks!(SqliteDbBookmarks, SqliteConnection);
+
+fn bookmark_query<DB>(
+    repo_id: RepositoryId,
+    name: &[u8],
+) -> bookmarks_table::BoxedQuery<'static, DB>
+where
+    DB: Backend,
+    DB: HasSqlType<NodeHashSql>,
+{
+    bookmarks_table::table
+        .filter(bookmarks_table::repo_id.eq(repo_id))
+        .filter(bookmarks_table::name.eq(name.to_vec()))
+        .limit(1)
+        .into_boxed()
+}
+
+/// Bookmarks starting with `prefix`, as a range scan over the primary key.
+fn prefix_query<DB>(
+    repo_id: RepositoryId,
+    prefix: &[u8],
+) -> bookmarks_table::BoxedQuery<'static, DB>
+where
+    DB: Backend,
+    DB: HasSqlType<NodeHashSql>,
+{
+    let query = bookmarks_table::table
+        .filter(bookmarks_table::repo_id.eq(repo_id))
+        .filter(bookmarks_table::name.ge(prefix.to_vec()))
+        .order(bookmarks_table::name.asc())
+        .into_boxed();
+
+    match prefix_upper_bound(prefix) {
+        Some(upper) => query.filter(bookmarks_table::name.lt(upper)),
+        None => query,
+    }
+}
+
+/// The smallest byte string that is greater than every string starting with `prefix`, or `None`
+/// if there is no such string (i.e. the prefix is empty or all 0xff).
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last < u8::max_value() {
+            upper.push(last + 1);
+            return Some(upper);
+        }
+    }
+    None
+}
+
+/// Moves of a bookmark, newest first.
+fn log_query<DB>(
+    repo_id: RepositoryId,
+    name: &[u8],
+    before: Option<i64>,
+    limit: usize,
+) -> log_table::BoxedQuery<'static, DB>
+where
+    DB: Backend,
+    DB: HasSqlType<NodeHashSql>,
+{
+    let query = log_table::table
+        .filter(log_table::repo_id.eq(repo_id))
+        .filter(log_table::name.eq(name.to_vec()))
+        .order(log_table::id.desc())
+        .limit(limit as i64)
+        .into_boxed();
+
+    match before {
+        Some(before) => query.filter(log_table::timestamp.le(before)),
+        None => query,
+    }
+}
+
+fn log_entry_from_row(row: BookmarkLogRow) -> Result<BookmarkUpdateLogEntry> {
+    let name = AsciiString::from_ascii(row.name).map_err(|_| ErrorKind::InvalidStoredData)?;
+    let reason = row.reason
+        .parse()
+        .map_err(|_| ErrorKind::InvalidStoredData)?;
+    Ok(BookmarkUpdateLogEntry {
+        id: row.id,
+        repo_id: row.repo_id,
+        name,
+        from_changeset_id: row.from_changeset_id,
+        to_changeset_id: row.to_changeset_id,
+        reason,
+        timestamp: row.timestamp,
+        user: row.user,
+    })
+}
+
+fn now_timestamp() -> Result<i64> {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    Ok(since_epoch.as_secs() as i64)
+}
+
+#[inline]
+fn map_create_result(
+    result: result::Result<usize, DieselError>,
+    key: &AsciiString,
+) -> Result<()> {
+    match result {
+        Ok(_rows) => Ok(()),
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Err(ErrorKind::BookmarkExists(key.clone()).into())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use mercurial_types::{HgChangesetId, RepositoryId};
+
+use schema::{bookmarks, bookmarks_update_log};
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable, Insertable)]
+#[table_name = "bookmarks"]
+pub(crate) struct BookmarkRow {
+    pub repo_id: RepositoryId,
+    // Bookmark names are ASCII, but they are stored as bytes so that comparisons (and hence
+    // prefix lookups) are case-sensitive in both MySQL and SQLite.
+    pub name: Vec<u8>,
+    pub changeset_id: HgChangesetId,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable)]
+pub(crate) struct BookmarkLogRow {
+    pub id: i64,
+    pub repo_id: RepositoryId,
+    pub name: Vec<u8>,
+    pub from_changeset_id: Option<HgChangesetId>,
+    pub to_changeset_id: Option<HgChangesetId>,
+    pub reason: String,
+    pub timestamp: i64,
+    pub user: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Insertable)]
+#[table_name = "bookmarks_update_log"]
+pub(crate) struct BookmarkLogInsertRow {
+    pub repo_id: RepositoryId,
+    pub name: Vec<u8>,
+    pub from_changeset_id: Option<HgChangesetId>,
+    pub to_changeset_id: Option<HgChangesetId>,
+    pub reason: String,
+    pub timestamp: i64,
+    pub user: Option<String>,
+}
@@ -0,0 +1,38 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The `table!` macros in this module describe the schemas for these tables in SQL storage
+//! (MySQL or SQLite). These descriptions are *not* the source of truth, so if the schema ever
+//! changes it will need to be updated here as well.
+
+table! {
+    use diesel::sql_types::{Binary, Integer};
+
+    use mercurial_types::sql_types::NodeHashSql;
+
+    bookmarks (repo_id, name) {
+        repo_id -> Integer,
+        name -> Binary,
+        changeset_id -> NodeHashSql,
+    }
+}
+
+table! {
+    use diesel::sql_types::{BigInt, Binary, Integer, Nullable, Text};
+
+    use mercurial_types::sql_types::NodeHashSql;
+
+    bookmarks_update_log {
+        id -> BigInt,
+        repo_id -> Integer,
+        name -> Binary,
+        from_changeset_id -> Nullable<NodeHashSql>,
+        to_changeset_id -> Nullable<NodeHashSql>,
+        reason -> Text,
+        timestamp -> BigInt,
+        user -> Nullable<Text>,
+    }
+}
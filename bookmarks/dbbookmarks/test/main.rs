@@ -0,0 +1,411 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the SQL bookmarks store.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate assert_matches;
+extern crate ascii;
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate bookmarks;
+extern crate dbbookmarks;
+extern crate mercurial_types_mocks;
+
+use std::sync::Arc;
+
+use ascii::AsciiString;
+use futures::{Future, Stream};
+
+use bookmarks::{BookmarkUpdateReason, Bookmarks};
+use dbbookmarks::{ErrorKind, MysqlDbBookmarks, SqliteDbBookmarks};
+use mercurial_types_mocks::nodehash::*;
+use mercurial_types_mocks::repo::*;
+
+fn name(s: &str) -> AsciiString {
+    AsciiString::from_ascii(s).expect("bookmark name is not ascii")
+}
+
+fn create_and_get<B: Bookmarks>(bookmarks: B) {
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.create(&name("master"), &ONES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None).wait().expect("commit failed");
+
+    assert_eq!(
+        bookmarks.get(&name("master"), &REPO_ZERO).wait().unwrap(),
+        Some(ONES_CSID)
+    );
+    // Bookmarks are scoped to their repo
+    assert_eq!(bookmarks.get(&name("master"), &REPO_ONE).wait().unwrap(), None);
+}
+
+fn create_existing<B: Bookmarks>(bookmarks: B) {
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.create(&name("master"), &ONES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None).wait().expect("commit failed");
+
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.create(&name("master"), &TWOS_CSID).unwrap();
+    let err = txn.commit(BookmarkUpdateReason::TestMove, None)
+        .wait()
+        .expect_err("creating an existing bookmark succeeded");
+    assert_matches!(err.downcast::<ErrorKind>(), Ok(ErrorKind::BookmarkExists(_)));
+}
+
+fn update<B: Bookmarks>(bookmarks: B) {
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.create(&name("master"), &ONES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None).wait().expect("commit failed");
+
+    // Wrong old value
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.update(&name("master"), &THREES_CSID, &TWOS_CSID).unwrap();
+    let err = txn.commit(BookmarkUpdateReason::TestMove, None)
+        .wait()
+        .expect_err("update with wrong old value succeeded");
+    assert_matches!(
+        err.downcast::<ErrorKind>(),
+        Ok(ErrorKind::BookmarkMismatch(_, ref old)) if old == &TWOS_CSID
+    );
+
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.update(&name("master"), &TWOS_CSID, &ONES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None).wait().expect("update failed");
+    assert_eq!(
+        bookmarks.get(&name("master"), &REPO_ZERO).wait().unwrap(),
+        Some(TWOS_CSID)
+    );
+
+    // Setting a bookmark to the value it already has is fine
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.update(&name("master"), &TWOS_CSID, &TWOS_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None).wait().expect("no-op update failed");
+}
+
+fn racing_updates<B: Bookmarks>(bookmarks: B) {
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.create(&name("master"), &ONES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None).wait().expect("commit failed");
+
+    // Both pushers saw master at ONES, only the first one to commit gets to move it
+    let mut first = bookmarks.create_transaction(&REPO_ZERO);
+    first.update(&name("master"), &TWOS_CSID, &ONES_CSID).unwrap();
+    let mut second = bookmarks.create_transaction(&REPO_ZERO);
+    second.update(&name("master"), &THREES_CSID, &ONES_CSID).unwrap();
+
+    first.commit(BookmarkUpdateReason::TestMove, None).wait().expect("first update failed");
+    let err = second
+        .commit(BookmarkUpdateReason::TestMove, None)
+        .wait()
+        .expect_err("second update of the same value succeeded");
+    assert_matches!(
+        err.downcast::<ErrorKind>(),
+        Ok(ErrorKind::BookmarkMismatch(_, ref old)) if old == &ONES_CSID
+    );
+    assert_eq!(
+        bookmarks.get(&name("master"), &REPO_ZERO).wait().unwrap(),
+        Some(TWOS_CSID)
+    );
+
+    // A no-op update is checked against the current value as well
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.update(&name("master"), &ONES_CSID, &ONES_CSID).unwrap();
+    let err = txn.commit(BookmarkUpdateReason::TestMove, None)
+        .wait()
+        .expect_err("no-op update with wrong old value succeeded");
+    assert_matches!(
+        err.downcast::<ErrorKind>(),
+        Ok(ErrorKind::BookmarkMismatch(_, ref old)) if old == &ONES_CSID
+    );
+}
+
+fn force_set<B: Bookmarks>(bookmarks: B) {
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.force_set(&name("master"), &ONES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None)
+        .wait()
+        .expect("force_set of new bookmark failed");
+
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.force_set(&name("master"), &TWOS_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None)
+        .wait()
+        .expect("force_set of existing bookmark failed");
+
+    assert_eq!(
+        bookmarks.get(&name("master"), &REPO_ZERO).wait().unwrap(),
+        Some(TWOS_CSID)
+    );
+}
+
+fn delete<B: Bookmarks>(bookmarks: B) {
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.create(&name("master"), &ONES_CSID).unwrap();
+    txn.create(&name("stable"), &ONES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None).wait().expect("commit failed");
+
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.delete(&name("master"), &TWOS_CSID).unwrap();
+    let err = txn.commit(BookmarkUpdateReason::TestMove, None)
+        .wait()
+        .expect_err("delete with wrong old value succeeded");
+    assert_matches!(
+        err.downcast::<ErrorKind>(),
+        Ok(ErrorKind::BookmarkMismatch(..))
+    );
+
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.delete(&name("master"), &ONES_CSID).unwrap();
+    txn.force_delete(&name("stable")).unwrap();
+    txn.force_delete(&name("missing")).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None).wait().expect("delete failed");
+
+    assert_eq!(bookmarks.get(&name("master"), &REPO_ZERO).wait().unwrap(), None);
+    assert_eq!(bookmarks.get(&name("stable"), &REPO_ZERO).wait().unwrap(), None);
+}
+
+fn atomic<B: Bookmarks>(bookmarks: B) {
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.create(&name("master"), &ONES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None).wait().expect("commit failed");
+
+    // The second operation fails, so the first one must not be applied either
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.create(&name("stable"), &ONES_CSID).unwrap();
+    txn.update(&name("master"), &THREES_CSID, &TWOS_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None)
+        .wait()
+        .expect_err("transaction with a failing operation succeeded");
+
+    assert_eq!(bookmarks.get(&name("stable"), &REPO_ZERO).wait().unwrap(), None);
+    assert_eq!(
+        bookmarks.get(&name("master"), &REPO_ZERO).wait().unwrap(),
+        Some(ONES_CSID)
+    );
+}
+
+fn duplicate_operation<B: Bookmarks>(bookmarks: B) {
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.create(&name("master"), &ONES_CSID).unwrap();
+    let err = txn.force_set(&name("master"), &TWOS_CSID)
+        .expect_err("changing a bookmark twice in a transaction succeeded");
+    assert_matches!(
+        err.downcast::<ErrorKind>(),
+        Ok(ErrorKind::DuplicateOperation(_))
+    );
+}
+
+fn list_by_prefix<B: Bookmarks>(bookmarks: B) {
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.create(&name("master"), &ONES_CSID).unwrap();
+    txn.create(&name("release/1"), &TWOS_CSID).unwrap();
+    txn.create(&name("release/2"), &THREES_CSID).unwrap();
+    txn.create(&name("releaseX"), &FOURS_CSID).unwrap();
+    txn.create(&name("Release/3"), &FIVES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None).wait().expect("commit failed");
+
+    let mut txn = bookmarks.create_transaction(&REPO_ONE);
+    txn.create(&name("release/other"), &ONES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::TestMove, None).wait().expect("commit failed");
+
+    let listed = bookmarks
+        .list_by_prefix(&name("release/"), &REPO_ZERO)
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(
+        listed,
+        vec![(name("release/1"), TWOS_CSID), (name("release/2"), THREES_CSID)]
+    );
+
+    let all = bookmarks
+        .list_by_prefix(&name(""), &REPO_ZERO)
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(all.len(), 5);
+}
+
+fn update_log<B: Bookmarks>(bookmarks: B) {
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.create(&name("master"), &ONES_CSID).unwrap();
+    txn.create(&name("other"), &ONES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::Push, None).wait().expect("commit failed");
+
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.update(&name("master"), &TWOS_CSID, &ONES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::Pushkey, Some("alice"))
+        .wait()
+        .expect("commit failed");
+
+    // Failed transactions aren't logged
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.update(&name("master"), &THREES_CSID, &ONES_CSID).unwrap();
+    txn.commit(BookmarkUpdateReason::Push, None)
+        .wait()
+        .expect_err("update with wrong old value succeeded");
+
+    let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+    txn.force_delete(&name("master")).unwrap();
+    txn.commit(BookmarkUpdateReason::ManualMove, None).wait().expect("commit failed");
+
+    let history = bookmarks
+        .list_bookmark_log_entries(&name("master"), &REPO_ZERO, None, 10)
+        .collect()
+        .wait()
+        .unwrap();
+    let moves: Vec<_> = history
+        .iter()
+        .map(|entry| {
+            (
+                entry.from_changeset_id,
+                entry.to_changeset_id,
+                entry.reason,
+                entry.user.clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        moves,
+        vec![
+            (Some(TWOS_CSID), None, BookmarkUpdateReason::ManualMove, None),
+            (
+                Some(ONES_CSID),
+                Some(TWOS_CSID),
+                BookmarkUpdateReason::Pushkey,
+                Some("alice".to_string()),
+            ),
+            (None, Some(ONES_CSID), BookmarkUpdateReason::Push, None),
+        ]
+    );
+    assert!(history.iter().all(|entry| entry.name == name("master")));
+
+    let limited = bookmarks
+        .list_bookmark_log_entries(&name("master"), &REPO_ZERO, None, 1)
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(limited, history[..1].to_vec());
+
+    // Nothing was logged before the bookmark was created
+    let first = history.last().unwrap();
+    let before = bookmarks
+        .list_bookmark_log_entries(&name("master"), &REPO_ZERO, Some(first.timestamp - 1), 10)
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(before, vec![]);
+
+    let other_repo = bookmarks
+        .list_bookmark_log_entries(&name("master"), &REPO_ONE, None, 10)
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(other_repo, vec![]);
+}
+
+macro_rules! bookmarks_test_impl {
+    ($mod_name: ident => {
+        new: $new_cb: expr,
+    }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_create_and_get() {
+                create_and_get($new_cb());
+            }
+
+            #[test]
+            fn test_create_existing() {
+                create_existing($new_cb());
+            }
+
+            #[test]
+            fn test_update() {
+                update($new_cb());
+            }
+
+            #[test]
+            fn test_racing_updates() {
+                racing_updates($new_cb());
+            }
+
+            #[test]
+            fn test_force_set() {
+                force_set($new_cb());
+            }
+
+            #[test]
+            fn test_delete() {
+                delete($new_cb());
+            }
+
+            #[test]
+            fn test_atomic() {
+                atomic($new_cb());
+            }
+
+            #[test]
+            fn test_duplicate_operation() {
+                duplicate_operation($new_cb());
+            }
+
+            #[test]
+            fn test_list_by_prefix() {
+                list_by_prefix($new_cb());
+            }
+
+            #[test]
+            fn test_update_log() {
+                update_log($new_cb());
+            }
+        }
+    }
+}
+
+bookmarks_test_impl! {
+    sqlite_test => {
+        new: new_sqlite,
+    }
+}
+
+bookmarks_test_impl! {
+    sqlite_arced_test => {
+        new: new_sqlite_arced,
+    }
+}
+
+bookmarks_test_impl! {
+    mysql_test => {
+        new: new_mysql,
+    }
+}
+
+bookmarks_test_impl! {
+    mysql_arced_test => {
+        new: new_mysql_arced,
+    }
+}
+
+fn new_sqlite() -> SqliteDbBookmarks {
+    SqliteDbBookmarks::in_memory().expect("Creating an in-memory SQLite database failed")
+}
+
+fn new_sqlite_arced() -> Arc<Bookmarks> {
+    Arc::new(new_sqlite())
+}
+
+fn new_mysql() -> MysqlDbBookmarks {
+    MysqlDbBookmarks::create_test_db("bookmarks_test").expect("Failed to create test database")
+}
+
+fn new_mysql_arced() -> Arc<Bookmarks> {
+    Arc::new(new_mysql())
+}
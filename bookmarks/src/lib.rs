@@ -7,15 +7,87 @@
 #![deny(warnings)]
 
 extern crate ascii;
+#[macro_use]
 extern crate failure_ext as failure;
 extern crate futures_ext;
 extern crate mercurial_types;
 
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
 use ascii::AsciiString;
 use failure::{Error, Result};
 use futures_ext::{BoxFuture, BoxStream};
 use mercurial_types::{HgChangesetId, RepositoryId};
 
+/// Why a bookmark was moved. Recorded in the bookmark update log.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BookmarkUpdateReason {
+    /// Moved by a `pushkey` part of a pushed bundle
+    Push,
+    /// Moved by the `pushkey` wire protocol command
+    Pushkey,
+    /// Set while importing a repo
+    Blobimport,
+    /// Moved by hand, e.g. by an administrator fixing a bad push
+    ManualMove,
+    /// Only for tests
+    TestMove,
+}
+
+impl BookmarkUpdateReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &BookmarkUpdateReason::Push => "push",
+            &BookmarkUpdateReason::Pushkey => "pushkey",
+            &BookmarkUpdateReason::Blobimport => "blobimport",
+            &BookmarkUpdateReason::ManualMove => "manualmove",
+            &BookmarkUpdateReason::TestMove => "testmove",
+        }
+    }
+}
+
+impl fmt::Display for BookmarkUpdateReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for BookmarkUpdateReason {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "push" => Ok(BookmarkUpdateReason::Push),
+            "pushkey" => Ok(BookmarkUpdateReason::Pushkey),
+            "blobimport" => Ok(BookmarkUpdateReason::Blobimport),
+            "manualmove" => Ok(BookmarkUpdateReason::ManualMove),
+            "testmove" => Ok(BookmarkUpdateReason::TestMove),
+            _ => bail_msg!("unknown bookmark update reason {}", s),
+        }
+    }
+}
+
+/// A single bookmark move, as recorded in the bookmark update log. All the moves of a
+/// transaction share the same timestamp, reason and user.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BookmarkUpdateLogEntry {
+    /// Increases with every logged move, so it orders moves made within the same second
+    pub id: i64,
+    pub repo_id: RepositoryId,
+    pub name: AsciiString,
+    /// None if the bookmark was created
+    pub from_changeset_id: Option<HgChangesetId>,
+    /// None if the bookmark was deleted
+    pub to_changeset_id: Option<HgChangesetId>,
+    pub reason: BookmarkUpdateReason,
+    /// Seconds since the Unix epoch
+    pub timestamp: i64,
+    /// Who moved the bookmark, if known
+    pub user: Option<String>,
+}
+
 pub trait Bookmarks: Send + Sync + 'static {
     /// Returns Some(HgChangesetId) if bookmark exists, returns None if doesn't
     fn get(
@@ -36,6 +108,49 @@ pub trait Bookmarks: Send + Sync + 'static {
 
     /// Creates a transaction that will be used for write operations.
     fn create_transaction(&self, repoid: &RepositoryId) -> Box<Transaction>;
+
+    /// Lists the moves of a bookmark, newest first. If `before` is set, only moves made at or
+    /// before that time (in seconds since the Unix epoch) are listed, so the first entry tells
+    /// where the bookmark pointed at that time.
+    fn list_bookmark_log_entries(
+        &self,
+        name: &AsciiString,
+        repoid: &RepositoryId,
+        before: Option<i64>,
+        limit: usize,
+    ) -> BoxStream<BookmarkUpdateLogEntry, Error>;
+}
+
+impl Bookmarks for Arc<Bookmarks> {
+    fn get(
+        &self,
+        name: &AsciiString,
+        repoid: &RepositoryId,
+    ) -> BoxFuture<Option<HgChangesetId>, Error> {
+        (**self).get(name, repoid)
+    }
+
+    fn list_by_prefix(
+        &self,
+        prefix: &AsciiString,
+        repoid: &RepositoryId,
+    ) -> BoxStream<(AsciiString, HgChangesetId), Error> {
+        (**self).list_by_prefix(prefix, repoid)
+    }
+
+    fn create_transaction(&self, repoid: &RepositoryId) -> Box<Transaction> {
+        (**self).create_transaction(repoid)
+    }
+
+    fn list_bookmark_log_entries(
+        &self,
+        name: &AsciiString,
+        repoid: &RepositoryId,
+        before: Option<i64>,
+        limit: usize,
+    ) -> BoxStream<BookmarkUpdateLogEntry, Error> {
+        (**self).list_bookmark_log_entries(name, repoid, before, limit)
+    }
 }
 
 pub trait Transaction: Send + Sync + 'static {
@@ -70,5 +185,7 @@ pub trait Transaction: Send + Sync + 'static {
     /// Commits the transaction. Future succeeds if transaction has been
     /// successful, or errors if transaction has failed. Transaction may fail because of the
     /// infra error or logical error i.e. non-existent bookmark was deleted.
-    fn commit(&self) -> BoxFuture<(), Error>;
+    /// Every bookmark move is recorded in the bookmark update log along with `reason` and the
+    /// identity of the `user` that asked for it, if known.
+    fn commit(&self, reason: BookmarkUpdateReason, user: Option<&str>) -> BoxFuture<(), Error>;
 }
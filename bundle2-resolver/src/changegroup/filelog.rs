@@ -11,14 +11,17 @@ use std::sync::Arc;
 use bytes::Bytes;
 use failure::Compat;
 use futures::{Future, Stream};
-use futures::future::{ok, Shared};
+use futures::future::{err, ok, Shared};
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use heapsize::HeapSizeOf;
 use quickcheck::{Arbitrary, Gen};
 
 use blobrepo::{BlobEntry, BlobRepo};
+use mercurial::file::File;
+use mercurial::lfs::{LfsPointer, LFS_REVISION_FLAG};
 use mercurial_bundles::changegroup::CgDeltaChunk;
 use mercurial_types::{delta, manifest, Blob, Delta, MPath, NodeHash, RepoPath};
+use mercurial_types::hash::Sha256;
 use mercurial_types::nodehash::NULL_HASH;
 
 use errors::*;
@@ -56,11 +59,20 @@ impl UploadableBlob for Filelog {
     }
 }
 
-pub fn convert_to_revlog_filelog<S>(repo: Arc<BlobRepo>, deltaed: S) -> BoxStream<Filelog, Error>
+/// Applies the deltas of the file revisions. The contents of at least `lfs_threshold` bytes are
+/// also stored for the LFS protocol, as they are then served as LFS pointers.
+pub fn convert_to_revlog_filelog<S>(
+    repo: Arc<BlobRepo>,
+    deltaed: S,
+    lfs_threshold: Option<u64>,
+) -> BoxStream<Filelog, Error>
 where
     S: Stream<Item = FilelogDeltaed, Error = Error> + Send + 'static,
 {
-    let mut delta_cache = DeltaCache::new(repo);
+    let mut delta_cache = {
+        let repo = repo.clone();
+        DeltaCache::new(move |base: &NodeHash| repo.get_file_content(base))
+    };
     deltaed
         .and_then(move |FilelogDeltaed { path, chunk }| {
             let CgDeltaChunk {
@@ -70,10 +82,23 @@ where
                 p1,
                 p2,
                 linknode,
+                flags,
             } = chunk;
+            let is_lfs = try_boxfuture!(is_lfs(node, flags));
+            let repo = repo.clone();
 
             delta_cache
                 .decode(node.clone(), base.into_option(), delta)
+                .and_then(move |blob| {
+                    if is_lfs {
+                        // Its content was uploaded with the LFS protocol
+                        fetch_lfs_text(&repo, node, blob)
+                    } else {
+                        store_lfs_content(repo, &blob, lfs_threshold)
+                            .map(move |()| blob)
+                            .boxify()
+                    }
+                })
                 .and_then(move |blob| {
                     Ok(Filelog {
                         path: RepoPath::file(path)?,
@@ -89,20 +114,100 @@ where
         .boxify()
 }
 
-struct DeltaCache {
+/// Revlog flags mark revisions whose content is stored in an unusual way (e.g. censored or
+/// stored outside of the revlog), none of which the blob repo supports.
+pub(super) fn ensure_no_flags(node: NodeHash, flags: Option<u16>) -> Result<()> {
+    match flags {
+        None | Some(0) => Ok(()),
+        Some(flags) => bail_msg!("unsupported revlog flags {:#x} on {}", flags, node),
+    }
+}
+
+/// Whether the text of a file revision is an LFS pointer. That is the only revlog flag that file
+/// revisions can have.
+fn is_lfs(node: NodeHash, flags: Option<u16>) -> Result<bool> {
+    match flags {
+        Some(LFS_REVISION_FLAG) => Ok(true),
+        flags => ensure_no_flags(node, flags).map(|()| false),
+    }
+}
+
+/// The client uploads the content of an LFS revision to the LFS server before pushing its
+/// pointer. The filelog gets the full text back, as the hash of the revision is computed from it.
+fn fetch_lfs_text(repo: &BlobRepo, node: NodeHash, pointer: Blob) -> BoxFuture<Blob, Error> {
+    let pointer = match pointer.as_slice() {
+        Some(pointer) => try_boxfuture!(
+            LfsPointer::parse(pointer).with_context(|_| format!("invalid LFS pointer of {}", node))
+        ),
+        None => return err(format_err!("LFS pointer of {} is missing", node)).boxify(),
+    };
+    repo.get_lfs_content(&pointer.oid)
+        .and_then(move |content| {
+            let content = content.ok_or_else(|| {
+                format_err!("LFS content {} of {} was not uploaded", pointer.oid, node)
+            })?;
+            ensure_msg!(
+                content.len() as u64 == pointer.size,
+                "LFS content {} of {} has size {}, the pointer says {}",
+                pointer.oid,
+                node,
+                content.len(),
+                pointer.size
+            );
+            Ok(Blob::from(Bytes::from(pointer.file_text(&content))))
+        })
+        .boxify()
+}
+
+/// Stores the content of a file revision for the LFS protocol, unless it's smaller than
+/// `lfs_threshold` bytes or already stored.
+fn store_lfs_content(
     repo: Arc<BlobRepo>,
+    blob: &Blob,
+    lfs_threshold: Option<u64>,
+) -> BoxFuture<(), Error> {
+    let (threshold, text) = match (lfs_threshold, blob.as_slice()) {
+        (Some(threshold), Some(text)) => (threshold, text),
+        _ => return ok(()).boxify(),
+    };
+    let (_, offset) = File::extract_meta(text);
+    let content = &text[offset..];
+    if (content.len() as u64) < threshold {
+        return ok(()).boxify();
+    }
+
+    let content = Bytes::from(content);
+    let oid = Sha256::from(content.as_ref());
+    repo.lfs_content_exists(&oid)
+        .and_then(move |exists| {
+            if exists {
+                ok(()).boxify()
+            } else {
+                repo.upload_lfs_content(oid, content)
+            }
+        })
+        .boxify()
+}
+
+/// Applies deltas to the full texts of their bases. A base that wasn't part of the changegroup is
+/// fetched with `fetch_base`.
+pub(super) struct DeltaCache<F> {
+    fetch_base: F,
     bytes_cache: HashMap<NodeHash, Shared<BoxFuture<Bytes, Compat<Error>>>>,
 }
 
-impl DeltaCache {
-    fn new(repo: Arc<BlobRepo>) -> Self {
+impl<F> DeltaCache<F>
+where
+    F: FnMut(&NodeHash) -> BoxFuture<Bytes, Error>,
+{
+    pub(super) fn new(fetch_base: F) -> Self {
         Self {
-            repo,
+            fetch_base,
             bytes_cache: HashMap::new(),
         }
     }
 
-    fn decode(
+    pub(super) fn decode(
         &mut self,
         node: NodeHash,
         base: Option<NodeHash>,
@@ -124,8 +229,7 @@ impl DeltaCache {
                                 .map(move |bytes| delta::apply(&bytes, &delta))
                                 .map_err(Error::from)
                                 .boxify(),
-                            None => self.repo
-                                .get_file_content(&base)
+                            None => (self.fetch_base)(&base)
                                 .map(move |bytes| delta::apply(bytes.as_ref(), &delta))
                                 .boxify(),
                         };
@@ -266,6 +370,7 @@ mod tests {
         let result = convert_to_revlog_filelog(
             Arc::new(BlobRepo::new_memblob_empty(None).unwrap()),
             iter_ok(inp.into_iter().collect::<Vec<_>>()),
+            None,
         ).collect()
             .wait()
             .unwrap();
@@ -283,6 +388,7 @@ mod tests {
                 base: NULL_HASH,
                 linknode: f.linknode.clone(),
                 delta: Delta::new_fulltext(f.blob.as_slice().unwrap()),
+                flags: None,
             },
         }
     }
@@ -399,6 +505,7 @@ mod tests {
         let result = convert_to_revlog_filelog(
             Arc::new(BlobRepo::new_memblob_empty(None).unwrap()),
             iter_ok(inp),
+            None,
         ).collect()
             .wait();
 
@@ -414,6 +521,82 @@ mod tests {
         }
     }
 
+    #[test]
+    fn lfs_revision() {
+        use mercurial_types_mocks::nodehash::*;
+
+        let repo = Arc::new(BlobRepo::new_memblob_empty(None).unwrap());
+        let content = Bytes::from("large file content");
+        let pointer = LfsPointer::from_content(&content, None);
+
+        let f = Filelog {
+            path: RepoPath::file(MPath::new(b"large").unwrap()).unwrap(),
+            node: ONES_HASH,
+            p1: None,
+            p2: None,
+            linknode: TWOS_HASH,
+            blob: Blob::from(content.clone()),
+        };
+        let lfs_deltaed = || {
+            let mut deltaed = filelog_to_deltaed(&f);
+            deltaed.chunk.delta = Delta::new_fulltext(pointer.serialize().unwrap());
+            deltaed.chunk.flags = Some(LFS_REVISION_FLAG);
+            deltaed
+        };
+        let convert = |deltaed| {
+            convert_to_revlog_filelog(repo.clone(), iter_ok(vec![deltaed]), None)
+                .collect()
+                .wait()
+        };
+
+        // The content has to be uploaded before the pointer is pushed
+        assert!(convert(lfs_deltaed()).is_err());
+
+        repo.upload_lfs_content(pointer.oid, content.clone())
+            .wait()
+            .unwrap();
+        assert_eq!(convert(lfs_deltaed()).unwrap(), vec![f.clone()]);
+
+        let mut unknown_flags = filelog_to_deltaed(&f);
+        unknown_flags.chunk.flags = Some(1 << 12);
+        assert!(convert(unknown_flags).is_err());
+    }
+
+    #[test]
+    fn lfs_threshold() {
+        use mercurial_types_mocks::nodehash::*;
+
+        let repo = Arc::new(BlobRepo::new_memblob_empty(None).unwrap());
+        let filelog = |node, content: &[u8]| Filelog {
+            path: RepoPath::file(MPath::new(b"file").unwrap()).unwrap(),
+            node,
+            p1: None,
+            p2: None,
+            linknode: TWOS_HASH,
+            blob: Blob::from(Bytes::from(content)),
+        };
+        let small = filelog(ONES_HASH, b"small");
+        let large = filelog(THREES_HASH, b"\x01\ncopy: a\ncopyrev: b\n\x01\nlarge content");
+
+        let result = convert_to_revlog_filelog(
+            repo.clone(),
+            iter_ok(vec![filelog_to_deltaed(&small), filelog_to_deltaed(&large)]),
+            Some(10),
+        ).collect()
+            .wait()
+            .unwrap();
+        assert_eq!(result, vec![small, large]);
+
+        // Only the content of the large file is stored, without its metadata
+        let exists = |content: &[u8]| {
+            repo.lfs_content_exists(&Sha256::from(content))
+                .wait()
+                .unwrap()
+        };
+        assert!(!exists(b"small"));
+        assert!(exists(b"large content"));
+    }
+
     #[test]
     fn files_order_correct() {
         files_check_order(true);
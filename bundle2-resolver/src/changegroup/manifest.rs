@@ -0,0 +1,68 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+
+use futures::{Future, Stream};
+use futures_ext::{BoxStream, FutureExt, StreamExt};
+
+use blobrepo::BlobRepo;
+use mercurial_bundles::changegroup::CgDeltaChunk;
+use mercurial_types::{NodeHash, RepoPath};
+
+use changegroup::filelog::{ensure_no_flags, DeltaCache};
+use errors::*;
+use wirepackparser::TreemanifestEntry;
+
+/// A root or directory manifest of a changegroup3.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ManifestDeltaed {
+    pub path: RepoPath,
+    pub chunk: CgDeltaChunk,
+}
+
+/// Turns the manifests of a changegroup3 into the same entries as the ones of a b2x:treegroup2
+/// part, so that they are uploaded the same way.
+pub fn convert_to_revlog_manifests<S>(
+    repo: Arc<BlobRepo>,
+    deltaed: S,
+) -> BoxStream<TreemanifestEntry, Error>
+where
+    S: Stream<Item = ManifestDeltaed, Error = Error> + Send + 'static,
+{
+    let mut delta_cache = DeltaCache::new(move |base: &NodeHash| {
+        let base = *base;
+        repo.get_raw_node(&base)
+            .and_then(move |raw| {
+                raw.and_then(|raw| raw.as_blob().as_inner().cloned())
+                    .ok_or(format_err!("manifest {} is missing", base))
+            })
+            .boxify()
+    });
+    deltaed
+        .and_then(move |ManifestDeltaed { path, chunk }| {
+            let CgDeltaChunk {
+                node,
+                base,
+                delta,
+                p1,
+                p2,
+                flags,
+                ..
+            } = chunk;
+            try_boxfuture!(ensure_no_flags(node, flags));
+
+            delta_cache
+                .decode(node.clone(), base.into_option(), delta)
+                .and_then(move |blob| {
+                    let data = blob.into_inner()
+                        .ok_or(format_err!("content of manifest {} is missing", node))?;
+                    TreemanifestEntry::new(node, data, p1, p2, path)
+                })
+                .boxify()
+        })
+        .boxify()
+}
@@ -6,8 +6,10 @@
 
 mod filelog;
 mod changeset;
+mod manifest;
 mod split;
 
 pub(crate) use self::changeset::convert_to_revlog_changesets;
 pub(crate) use self::filelog::{convert_to_revlog_filelog, Filelog};
+pub(crate) use self::manifest::convert_to_revlog_manifests;
 pub(crate) use self::split::split_changegroup;
@@ -4,19 +4,32 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::option;
+
 use futures::{Async, Future, Poll, Stream};
+use futures::stream;
 use futures_ext::{BoxStream, StreamExt};
 
-use mercurial_bundles::changegroup::{Part, Section};
+use mercurial_bundles::changegroup::{CgVersion, Part, Section};
+use mercurial_types::RepoPath;
 
 use changegroup::changeset::ChangesetDeltaed;
 use changegroup::filelog::FilelogDeltaed;
+use changegroup::manifest::ManifestDeltaed;
 use errors::*;
 
+/// Splits a changegroup into its changesets, manifests and filelogs. The streams have to be
+/// consumed in that order.
+/// Only changegroup3 parts with the `treemanifest` param may have manifests: they are the root
+/// and directory manifests of a treemanifest. The blob repo only stores tree manifests, so flat
+/// manifests are refused, and changegroup2 pushes send their trees in a b2x:treegroup2 part.
 pub fn split_changegroup<S>(
     cg2s: S,
+    version: CgVersion,
+    treemanifest: bool,
 ) -> (
     BoxStream<ChangesetDeltaed, Error>,
+    BoxStream<ManifestDeltaed, Error>,
     BoxStream<FilelogDeltaed, Error>,
 )
 where
@@ -41,25 +54,40 @@ where
         })
         .boxify();
 
-    let filelogs = remainder
+    let remainder = remainder
         .from_err()
         .map(|take_while_stream| take_while_stream.into_inner())
         .flatten_stream()
-        .skip_while({
-            let mut seen_manifest_end = false;
-            move |part| match part {
-                &Part::SectionEnd(Section::Manifest) if !seen_manifest_end => {
-                    seen_manifest_end = true;
-                    Ok(true)
-                }
-                _ if seen_manifest_end => Ok(false),
-                bad => bail_msg!("Expected Manifest end, found: {:?}", bad),
-            }
+        .boxify();
+    let trees = match version {
+        CgVersion::Cg2 => false,
+        CgVersion::Cg3 => treemanifest,
+    };
+    let (manifests, remainder) = TakeManifests::new(remainder, trees).return_remainder();
+
+    let manifests = manifests
+        .and_then(|part| match part {
+            Part::CgChunk(Section::Manifest, chunk) => Ok(Some(ManifestDeltaed {
+                path: RepoPath::root(),
+                chunk,
+            })),
+            Part::CgChunk(Section::Treemanifest(path), chunk) => Ok(Some(ManifestDeltaed {
+                path: RepoPath::dir(path)?,
+                chunk,
+            })),
+            _ => Ok(None),
         })
         .map_err(|err| {
-            err.context("While skipping Manifests in Changegroup")
+            err.context("While extracting Manifests from Changegroup")
                 .into()
         })
+        .filter_map(|x| x)
+        .boxify();
+
+    let filelogs = remainder
+        .from_err()
+        .map(|take_manifests| take_manifests.into_inner())
+        .flatten_stream()
         .and_then({
             let mut seen_path = None;
             move |part| {
@@ -113,7 +141,76 @@ where
         .filter_map(|x| x)
         .boxify();
 
-    (changesets, filelogs)
+    (changesets, manifests, filelogs)
+}
+
+/// Takes the manifest section and, if the changegroup has tree manifests, the directory manifest
+/// sections that follow it. Unlike take_while, the first part that doesn't belong to them isn't
+/// lost: into_inner returns it along with the rest of the stream.
+struct TakeManifests<S> {
+    inner: S,
+    trees: bool,
+    seen_manifest_end: bool,
+    next: Option<Part>,
+    done: bool,
+}
+
+impl<S> TakeManifests<S>
+where
+    S: Stream<Item = Part, Error = Error>,
+{
+    fn new(inner: S, trees: bool) -> Self {
+        Self {
+            inner,
+            trees,
+            seen_manifest_end: false,
+            next: None,
+            done: false,
+        }
+    }
+
+    fn into_inner(self) -> stream::Chain<stream::IterOk<option::IntoIter<Part>, Error>, S> {
+        stream::iter_ok(self.next).chain(self.inner)
+    }
+}
+
+impl<S> Stream for TakeManifests<S>
+where
+    S: Stream<Item = Part, Error = Error>,
+{
+    type Item = Part;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Part>, Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+
+        let part = match try_ready!(self.inner.poll()) {
+            Some(part) => part,
+            None => return Ok(Async::Ready(None)),
+        };
+        let is_manifest = match (&part, self.trees) {
+            (&Part::CgChunk(Section::Manifest, _), true) if !self.seen_manifest_end => true,
+            (&Part::SectionEnd(Section::Manifest), _) if !self.seen_manifest_end => {
+                self.seen_manifest_end = true;
+                true
+            }
+            (&Part::CgChunk(Section::Treemanifest(_), _), true)
+            | (&Part::SectionEnd(Section::Treemanifest(_)), true) if self.seen_manifest_end => true,
+            _ if self.seen_manifest_end => false,
+            (bad, false) => bail_msg!("Expected Manifest end, found: {:?}", bad),
+            (bad, true) => bail_msg!("Expected Manifest chunk or end, found: {:?}", bad),
+        };
+
+        if is_manifest {
+            Ok(Async::Ready(Some(part)))
+        } else {
+            self.next = Some(part);
+            self.done = true;
+            Ok(Async::Ready(None))
+        }
+    }
 }
 
 /// Wrapper for Stream of Part that is supposed to ensure that there is exactly one Part::End in
@@ -175,12 +272,22 @@ mod tests {
         I: IntoIterator<Item = ChangesetDeltaed>,
         J: IntoIterator<Item = FilelogDeltaed>,
     {
-        let (cs, fs) = split_changegroup(cg2s);
+        let (cs, ms, fs) = split_changegroup(cg2s, CgVersion::Cg2, false);
 
         let cs = cs.collect().wait().expect("error in changesets");
-        let fs = fs.collect().wait().expect("error in changesets");
+        let (ms, fs) = collect_rest(ms, fs).expect("error in manifests or filelogs");
+
+        equal(cs, exp_cs) && ms.is_empty() && equal(fs, exp_fs)
+    }
 
-        equal(cs, exp_cs) && equal(fs, exp_fs)
+    /// Manifests and filelogs, which can only be consumed once the changesets have been.
+    fn collect_rest(
+        ms: BoxStream<ManifestDeltaed, Error>,
+        fs: BoxStream<FilelogDeltaed, Error>,
+    ) -> Result<(Vec<ManifestDeltaed>, Vec<FilelogDeltaed>)> {
+        let ms = ms.collect().wait()?;
+        let fs = fs.collect().wait()?;
+        Ok((ms, fs))
     }
 
     #[test]
@@ -299,149 +406,284 @@ mod tests {
 
         fn splitting_error_filelog_end(f: CgDeltaChunk, f1_p: MPath, f2_p: MPath) -> bool {
             {
-                let (cs, fs) = split_changegroup(iter_ok(
-                    vec![
-                        Part::SectionEnd(Section::Changeset),
-                        Part::SectionEnd(Section::Manifest),
-                        Part::SectionEnd(Section::Filelog(f1_p.clone())),
-                        Part::End,
-                    ].into_iter(),
-                ));
+                let (cs, ms, fs) = split_changegroup(
+                    iter_ok(
+                        vec![
+                            Part::SectionEnd(Section::Changeset),
+                            Part::SectionEnd(Section::Manifest),
+                            Part::SectionEnd(Section::Filelog(f1_p.clone())),
+                            Part::End,
+                        ].into_iter(),
+                    ),
+                    CgVersion::Cg2,
+                    false,
+                );
 
                 assert_equal(cs.collect().wait().unwrap(), vec![]);
-                assert!(fs.collect().wait().is_err());
+                assert!(collect_rest(ms, fs).is_err());
             }
 
             {
-                let (cs, fs) = split_changegroup(iter_ok(
+                let (cs, ms, fs) = split_changegroup(
+                    iter_ok(
+                        vec![
+                            Part::SectionEnd(Section::Changeset),
+                            Part::SectionEnd(Section::Manifest),
+                            Part::CgChunk(Section::Filelog(f1_p.clone()), f.clone()),
+                            Part::End,
+                        ].into_iter(),
+                    ),
+                    CgVersion::Cg2,
+                    false,
+                );
+
+                assert_equal(cs.collect().wait().unwrap(), vec![]);
+                assert!(collect_rest(ms, fs).is_err());
+            }
+
+            {
+                let (cs, ms, fs) = split_changegroup(
+                    iter_ok(
+                        vec![
+                            Part::SectionEnd(Section::Changeset),
+                            Part::SectionEnd(Section::Manifest),
+                            Part::CgChunk(Section::Filelog(f1_p.clone()), f.clone()),
+                            Part::SectionEnd(Section::Filelog(f2_p.clone())),
+                            Part::End,
+                        ].into_iter(),
+                    ),
+                    CgVersion::Cg2,
+                    false,
+                );
+
+                assert_equal(cs.collect().wait().unwrap(), vec![]);
+                assert!(f1_p == f2_p || collect_rest(ms, fs).is_err());
+            }
+
+            true
+        }
+
+        fn splitting_error_manifest(
+            c: CgDeltaChunk,
+            m: CgDeltaChunk,
+            f: CgDeltaChunk,
+            f_p: MPath
+        ) -> bool {
+            let (cs, ms, fs) = split_changegroup(
+                iter_ok(
                     vec![
+                        Part::CgChunk(Section::Changeset, c.clone()),
                         Part::SectionEnd(Section::Changeset),
+                        Part::CgChunk(Section::Manifest, m.clone()),
                         Part::SectionEnd(Section::Manifest),
-                        Part::CgChunk(Section::Filelog(f1_p.clone()), f.clone()),
+                        Part::CgChunk(Section::Filelog(f_p.clone()), f.clone()),
+                        Part::SectionEnd(Section::Filelog(f_p.clone())),
                         Part::End,
                     ].into_iter(),
-                ));
+                ),
+                CgVersion::Cg2,
+                false,
+            );
 
-                assert_equal(cs.collect().wait().unwrap(), vec![]);
-                assert!(fs.collect().wait().is_err());
-            }
+            equal(cs.collect().wait().unwrap(), vec![ChangesetDeltaed { chunk: c }])
+                && collect_rest(ms, fs).is_err()
+        }
 
-            {
-                let (cs, fs) = split_changegroup(iter_ok(
+        fn splitting_cg3(
+            c: CgDeltaChunk,
+            m: CgDeltaChunk,
+            t: CgDeltaChunk,
+            t_p: MPath,
+            f: CgDeltaChunk,
+            f_p: MPath
+        ) -> bool {
+            let (cs, ms, fs) = split_changegroup(
+                iter_ok(
                     vec![
+                        Part::CgChunk(Section::Changeset, c.clone()),
                         Part::SectionEnd(Section::Changeset),
+                        Part::CgChunk(Section::Manifest, m.clone()),
                         Part::SectionEnd(Section::Manifest),
-                        Part::CgChunk(Section::Filelog(f1_p.clone()), f.clone()),
-                        Part::SectionEnd(Section::Filelog(f2_p.clone())),
+                        Part::CgChunk(Section::Treemanifest(t_p.clone()), t.clone()),
+                        Part::SectionEnd(Section::Treemanifest(t_p.clone())),
+                        Part::CgChunk(Section::Filelog(f_p.clone()), f.clone()),
+                        Part::SectionEnd(Section::Filelog(f_p.clone())),
                         Part::End,
                     ].into_iter(),
-                ));
-
-                assert_equal(cs.collect().wait().unwrap(), vec![]);
-                assert!(f1_p == f2_p || fs.collect().wait().is_err());
+                ),
+                CgVersion::Cg3,
+                true,
+            );
+
+            let cs = cs.collect().wait().unwrap();
+            let rest = collect_rest(ms, fs);
+            if t_p.is_empty() {
+                // The root manifest can't be sent as a directory
+                return rest.is_err();
             }
+            let (ms, fs) = rest.unwrap();
 
-            true
+            equal(cs, vec![ChangesetDeltaed { chunk: c }])
+                && equal(
+                    ms,
+                    vec![
+                        ManifestDeltaed {
+                            path: RepoPath::root(),
+                            chunk: m,
+                        },
+                        ManifestDeltaed {
+                            path: RepoPath::dir(t_p).unwrap(),
+                            chunk: t,
+                        },
+                    ],
+                ) && equal(fs, vec![FilelogDeltaed { path: f_p, chunk: f }])
         }
 
-        fn splitting_error_manifest(
+        fn splitting_error_cg3_flat_manifest(
             c: CgDeltaChunk,
             m: CgDeltaChunk,
             f: CgDeltaChunk,
             f_p: MPath
         ) -> bool {
-            let (cs, fs) = split_changegroup(iter_ok(
-                vec![
-                    Part::CgChunk(Section::Changeset, c.clone()),
-                    Part::SectionEnd(Section::Changeset),
-                    Part::CgChunk(Section::Manifest, m.clone()),
-                    Part::SectionEnd(Section::Manifest),
-                    Part::CgChunk(Section::Filelog(f_p.clone()), f.clone()),
-                    Part::SectionEnd(Section::Filelog(f_p.clone())),
-                    Part::End,
-                ].into_iter(),
-            ));
+            // What clients that force changegroup3 (e.g. for LFS) send without treemanifest
+            let (cs, ms, fs) = split_changegroup(
+                iter_ok(
+                    vec![
+                        Part::CgChunk(Section::Changeset, c.clone()),
+                        Part::SectionEnd(Section::Changeset),
+                        Part::CgChunk(Section::Manifest, m.clone()),
+                        Part::SectionEnd(Section::Manifest),
+                        Part::CgChunk(Section::Filelog(f_p.clone()), f.clone()),
+                        Part::SectionEnd(Section::Filelog(f_p.clone())),
+                        Part::End,
+                    ].into_iter(),
+                ),
+                CgVersion::Cg3,
+                false,
+            );
 
             equal(cs.collect().wait().unwrap(), vec![ChangesetDeltaed { chunk: c }])
-                && fs.collect().wait().is_err()
+                && collect_rest(ms, fs).is_err()
+        }
+
+        fn splitting_error_treemanifest_cg2(t: CgDeltaChunk, t_p: MPath) -> bool {
+            let (cs, ms, fs) = split_changegroup(
+                iter_ok(
+                    vec![
+                        Part::SectionEnd(Section::Changeset),
+                        Part::SectionEnd(Section::Manifest),
+                        Part::CgChunk(Section::Treemanifest(t_p.clone()), t.clone()),
+                        Part::SectionEnd(Section::Treemanifest(t_p.clone())),
+                        Part::End,
+                    ].into_iter(),
+                ),
+                CgVersion::Cg2,
+                false,
+            );
+
+            cs.collect().wait().unwrap().is_empty() && collect_rest(ms, fs).is_err()
         }
     }
 
     #[test]
     fn splitting_error_two_ends() {
         {
-            let (cs, fs) = split_changegroup(iter_ok(
-                vec![
-                    Part::SectionEnd(Section::Changeset),
-                    Part::SectionEnd(Section::Changeset),
-                    Part::SectionEnd(Section::Manifest),
-                    Part::End,
-                ].into_iter(),
-            ));
+            let (cs, ms, fs) = split_changegroup(
+                iter_ok(
+                    vec![
+                        Part::SectionEnd(Section::Changeset),
+                        Part::SectionEnd(Section::Changeset),
+                        Part::SectionEnd(Section::Manifest),
+                        Part::End,
+                    ].into_iter(),
+                ),
+                CgVersion::Cg2,
+                false,
+            );
 
             assert_equal(cs.collect().wait().unwrap(), vec![]);
-            assert!(fs.collect().wait().is_err());
+            assert!(collect_rest(ms, fs).is_err());
         }
 
         {
-            let (cs, fs) = split_changegroup(iter_ok(
-                vec![
-                    Part::SectionEnd(Section::Changeset),
-                    Part::SectionEnd(Section::Manifest),
-                    Part::SectionEnd(Section::Manifest),
-                    Part::End,
-                ].into_iter(),
-            ));
+            let (cs, ms, fs) = split_changegroup(
+                iter_ok(
+                    vec![
+                        Part::SectionEnd(Section::Changeset),
+                        Part::SectionEnd(Section::Manifest),
+                        Part::SectionEnd(Section::Manifest),
+                        Part::End,
+                    ].into_iter(),
+                ),
+                CgVersion::Cg2,
+                false,
+            );
 
             assert_equal(cs.collect().wait().unwrap(), vec![]);
-            assert!(fs.collect().wait().is_err());
+            assert!(collect_rest(ms, fs).is_err());
         }
 
         {
-            let (cs, fs) = split_changegroup(iter_ok(
-                vec![
-                    Part::SectionEnd(Section::Changeset),
-                    Part::SectionEnd(Section::Manifest),
-                    Part::End,
-                    Part::End,
-                ].into_iter(),
-            ));
+            let (cs, ms, fs) = split_changegroup(
+                iter_ok(
+                    vec![
+                        Part::SectionEnd(Section::Changeset),
+                        Part::SectionEnd(Section::Manifest),
+                        Part::End,
+                        Part::End,
+                    ].into_iter(),
+                ),
+                CgVersion::Cg2,
+                false,
+            );
 
             assert_equal(cs.collect().wait().unwrap(), vec![]);
-            assert!(fs.collect().wait().is_err());
+            assert!(collect_rest(ms, fs).is_err());
         }
     }
 
     #[test]
     fn splitting_error_missing_end() {
         {
-            let (cs, fs) = split_changegroup(iter_ok(
-                vec![Part::SectionEnd(Section::Manifest), Part::End].into_iter(),
-            ));
+            let (cs, ms, fs) = split_changegroup(
+                iter_ok(
+                    vec![Part::SectionEnd(Section::Manifest), Part::End].into_iter(),
+                ),
+                CgVersion::Cg2,
+                false,
+            );
 
             assert!(cs.collect().wait().is_err());
-            assert!(fs.collect().wait().is_err());
+            assert!(collect_rest(ms, fs).is_err());
         }
 
         {
-            let (cs, fs) = split_changegroup(iter_ok(
-                vec![Part::SectionEnd(Section::Changeset), Part::End].into_iter(),
-            ));
+            let (cs, ms, fs) = split_changegroup(
+                iter_ok(
+                    vec![Part::SectionEnd(Section::Changeset), Part::End].into_iter(),
+                ),
+                CgVersion::Cg2,
+                false,
+            );
 
             assert_equal(cs.collect().wait().unwrap(), vec![]);
-            assert!(fs.collect().wait().is_err());
+            assert!(collect_rest(ms, fs).is_err());
         }
 
         {
-            let (cs, fs) = split_changegroup(iter_ok(
-                vec![
-                    Part::SectionEnd(Section::Changeset),
-                    Part::SectionEnd(Section::Manifest),
-                ].into_iter(),
-            ));
+            let (cs, ms, fs) = split_changegroup(
+                iter_ok(
+                    vec![
+                        Part::SectionEnd(Section::Changeset),
+                        Part::SectionEnd(Section::Manifest),
+                    ].into_iter(),
+                ),
+                CgVersion::Cg2,
+                false,
+            );
 
             assert_equal(cs.collect().wait().unwrap(), vec![]);
-            assert!(fs.collect().wait().is_err());
+            assert!(collect_rest(ms, fs).is_err());
         }
     }
 }
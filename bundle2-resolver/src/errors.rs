@@ -6,7 +6,19 @@
 
 pub use failure::{Error, Result, ResultExt};
 
+use ascii::AsciiString;
+
+use mercurial_types::HgChangesetId;
+
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "Malformed treemanifest part: {}", _0)] MalformedTreemanifestPart(String),
+    #[fail(display = "Repository changed while pushing - please try again")] PushRaced,
+    #[fail(display = "Updating bookmark {} failed", name)]
+    BookmarkPushFailed {
+        part_id: u32,
+        name: AsciiString,
+        old: Option<HgChangesetId>,
+        new: Option<HgChangesetId>,
+    },
 }
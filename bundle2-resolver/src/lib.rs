@@ -8,6 +8,7 @@
 #![feature(conservative_impl_trait)]
 
 extern crate ascii;
+extern crate async_compression;
 extern crate bytes;
 #[macro_use]
 extern crate failure_ext as failure;
@@ -35,6 +36,7 @@ extern crate stats as stats_crate;
 extern crate tokio_io;
 
 extern crate blobrepo;
+extern crate bookmarks;
 extern crate mercurial;
 extern crate mercurial_bundles;
 extern crate mercurial_types;
@@ -4,11 +4,13 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Cursor;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use ascii::AsciiString;
+use async_compression::CompressorType;
 use bytes::Bytes;
 use futures::{Future, IntoFuture, Stream};
 use futures::future::{err, ok};
@@ -17,13 +19,18 @@ use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use slog::Logger;
 
 use blobrepo::{BlobEntry, BlobRepo, ChangesetHandle};
+use bookmarks::BookmarkUpdateReason;
 use mercurial::changeset::RevlogChangeset;
 use mercurial::manifest::revlog::ManifestContent;
-use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item};
-use mercurial_types::{Changeset, HgChangesetId, HgManifestId, MPath, NodeHash, RepoPath};
-
-use changegroup::{convert_to_revlog_changesets, convert_to_revlog_filelog, split_changegroup,
-                  Filelog};
+use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item, Capabilities};
+use mercurial_bundles::ErrorKind as BundleErrorKind;
+use mercurial_bundles::part_encode::PartEncodeBuilder;
+use mercurial_bundles::parts::PushkeyFailure;
+use mercurial_types::{Changeset, HgChangesetId, HgManifestId, MPath, NodeHash, RepoPath,
+                      NULL_HASH};
+
+use changegroup::{convert_to_revlog_changesets, convert_to_revlog_filelog,
+                  convert_to_revlog_manifests, split_changegroup, Filelog};
 use errors::*;
 use upload_blobs::{upload_blobs, UploadBlobsType, UploadableBlob};
 use wirepackparser::{TreemanifestBundle2Parser, TreemanifestEntry};
@@ -36,21 +43,72 @@ type UploadedChangesets = HashMap<NodeHash, ChangesetHandle>;
 
 /// The resolve function takes a bundle2, interprets it's content as Changesets, Filelogs and
 /// Manifests and uploades all of them to the provided BlobRepo in the correct order.
-/// It returns a Future that contains the response that should be send back to the requester.
+/// If the bundle2 moves a bookmark, the move is only committed once all the changesets are
+/// uploaded.
+/// Before anything is uploaded, the heads the client expects (both the `heads` argument and the
+/// check:heads part) are compared to the current heads of the repo. If they don't match, another
+/// push got in first and the client is told so with an error:pushraced part.
+/// It returns a Future that contains the response that should be send back to the requester. If
+/// the push fails, the response is a bundle2 with an error part explaining why.
+/// If `allow_compression` is set and the replycaps of the client list a compression the server
+/// supports, the response is compressed with it.
+/// `user` is the identity of the pusher, recorded in the bookmark update log.
+/// The pushed file contents of at least `lfs_threshold` bytes are also stored for the LFS
+/// protocol.
 pub fn resolve(
     repo: Arc<BlobRepo>,
     logger: Logger,
     heads: Vec<String>,
     bundle2: BoxStream<Bundle2Item, Error>,
+    allow_compression: bool,
+    user: Option<String>,
+    lfs_threshold: Option<u64>,
 ) -> BoxFuture<Bytes, Error> {
     info!(logger, "unbundle heads {:?}", heads);
 
-    let resolver = Bundle2Resolver::new(repo, logger);
+    let resolver = Bundle2Resolver::new(repo, logger, user, lfs_threshold);
+    let logger = resolver.logger.clone();
+
+    resolver
+        .resolve_start_and_replycaps(bundle2)
+        .and_then(move |(replycaps, bundle2)| {
+            let mut resolver = resolver;
+            if allow_compression {
+                resolver.reply_compression = replycaps.reply_compression();
+            }
+            resolve_push(resolver, heads, bundle2)
+        })
+        .or_else(move |error| {
+            error!(logger, "push failed: {}", error_message(&error));
+            prepare_error_response(&error)
+        })
+        .map_err(|err| err.context("bundle2-resolver error").into())
+        .boxify()
+}
 
-    let bundle2 = resolver.resolve_start_and_replycaps(bundle2);
+/// Everything that follows the replycaps part: the heads check, the upload of the pushed
+/// changesets and the bookmark move.
+fn resolve_push(
+    resolver: Bundle2Resolver,
+    heads: Vec<String>,
+    bundle2: BoxStream<Bundle2Item, Error>,
+) -> BoxFuture<Bytes, Error> {
+    let unbundle_heads = parse_unbundle_heads(&heads);
 
     resolver
-        .resolve_changegroup(bundle2)
+        .maybe_resolve_check_heads(bundle2)
+        .and_then({
+            let resolver = resolver.clone();
+            move |(check_heads, bundle2)| {
+                let unbundle_heads = try_boxfuture!(unbundle_heads);
+                let expected = unbundle_heads.into_iter().chain(check_heads).collect();
+                resolver.check_heads(expected).map(move |()| bundle2).boxify()
+            }
+        })
+        .and_then({
+            let resolver = resolver.clone();
+            move |bundle2| resolver.resolve_changegroup(bundle2)
+        })
         .and_then({
             let resolver = resolver.clone();
             move |(cg_push, bundle2)| {
@@ -59,17 +117,19 @@ pub fn resolve(
                     .map(move |(bookmark_push, bundle2)| (cg_push, bookmark_push, bundle2))
             }
         })
-        .and_then(move |(cg_push, _bookmark_push, bundle2)| {
+        .and_then(move |(cg_push, bookmark_push, bundle2)| {
             let changegroup_id = cg_push.part_id;
             let changesets = cg_push.changesets;
             let filelogs = cg_push.filelogs;
+            let mut manifests = cg_push.manifests;
 
             let bundle2 = resolver
-                .resolve_b2xtreegroup2(bundle2)
+                .maybe_resolve_b2xtreegroup2(bundle2)
                 .and_then({
                     let resolver = resolver.clone();
 
-                    move |(manifests, bundle2)| {
+                    move |(treegroup_manifests, bundle2)| {
+                        manifests.extend(treegroup_manifests);
                         resolver
                             .maybe_resolve_infinitepush_bookmarks(bundle2)
                             .map(|(_, bundle2)| (manifests, bundle2))
@@ -87,15 +147,113 @@ pub fn resolve(
                 .flatten_stream()
                 .boxify();
 
-            // TODO(stash): actually push bookmarks
             resolver
                 .ensure_stream_finished(bundle2)
-                .and_then(move |()| resolver.prepare_response(changegroup_id))
+                .and_then({
+                    let resolver = resolver.clone();
+                    move |()| resolver.push_bookmark(bookmark_push)
+                })
+                .and_then(move |bookmark_reply| {
+                    resolver.prepare_response(changegroup_id, bookmark_reply)
+                })
         })
-        .map_err(|err| err.context("bundle2-resolver error").into())
         .boxify()
 }
 
+/// Parses the `heads` argument of unbundle. Returns None if the client forced the push, in which
+/// case the heads aren't checked.
+fn parse_unbundle_heads(heads: &[String]) -> Result<Option<Vec<NodeHash>>> {
+    // The argument is a list of hex-encoded strings, and a forced push sends just "force"
+    let force = "force"
+        .as_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    if heads.len() == 1 && heads[0] == force {
+        return Ok(None);
+    }
+
+    heads
+        .iter()
+        .map(|head| {
+            NodeHash::from_str(head)
+                .map_err(|err| err.context(format!("invalid unbundle head {}", head)).into())
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+/// Turns a failed push into a bundle2 with the matching error part, so that Mercurial can tell
+/// the user what went wrong.
+fn prepare_error_response(error: &Error) -> BoxFuture<Bytes, Error> {
+    let writer = Cursor::new(Vec::new());
+    let mut bundle = Bundle2EncodeBuilder::new(writer);
+    // The push can fail before the replycaps of the client are known, so errors are never
+    // compressed
+    bundle.set_compressor_type(None);
+    bundle.add_part(try_boxfuture!(error_part(error)));
+    bundle
+        .build()
+        .map(|cursor| Bytes::from(cursor.into_inner()))
+        .map_err(|err| err.context("While preparing error response").into())
+        .boxify()
+}
+
+fn error_part(error: &Error) -> Result<PartEncodeBuilder> {
+    for cause in error.causes() {
+        if let Some(kind) = cause.downcast_ref::<ErrorKind>() {
+            match kind {
+                &ErrorKind::PushRaced => {
+                    return parts::error_pushraced_part(format!("{}", kind));
+                }
+                &ErrorKind::BookmarkPushFailed {
+                    part_id,
+                    ref name,
+                    ref old,
+                    ref new,
+                } => {
+                    let hex = |cs: &Option<HgChangesetId>| {
+                        Bytes::from(cs.map(|cs| cs.to_hex().to_string()).unwrap_or_default())
+                    };
+                    let failure = PushkeyFailure {
+                        namespace: Some(Bytes::from("bookmarks")),
+                        key: Some(Bytes::from(name.as_str())),
+                        new: Some(hex(new)),
+                        old: Some(hex(old)),
+                        ret: Some(Bytes::from("0")),
+                    };
+                    return parts::error_pushkey_part(part_id, failure);
+                }
+                &ErrorKind::MalformedTreemanifestPart(_) => {}
+            }
+        }
+        if let Some(kind) = cause.downcast_ref::<BundleErrorKind>() {
+            match kind {
+                &BundleErrorKind::BundleUnknownPart(ref header) => {
+                    return parts::error_unsupportedcontent_part(
+                        Some(*header.part_type()),
+                        Vec::new(),
+                    );
+                }
+                &BundleErrorKind::BundleUnknownPartParams(part_type, ref params) => {
+                    return parts::error_unsupportedcontent_part(Some(part_type), params.clone());
+                }
+                _ => {}
+            }
+        }
+    }
+    parts::error_abort_part(error_message(error), None::<Bytes>)
+}
+
+/// The error along with everything that caused it, on a single line
+fn error_message(error: &Error) -> String {
+    error
+        .causes()
+        .map(|cause| cause.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
 fn next_item(
     bundle2: BoxStream<Bundle2Item, Error>,
 ) -> BoxFuture<(Option<Bundle2Item>, BoxStream<Bundle2Item, Error>), Error> {
@@ -106,76 +264,163 @@ struct ChangegroupPush {
     part_id: PartId,
     changesets: Changesets,
     filelogs: Filelogs,
+    // Only changegroup3 has manifests, changegroup2 pushes send them in b2x:treegroup2
+    manifests: Manifests,
 }
 
 struct BookmarkPush {
-    _part_id: PartId,
-    _name: AsciiString,
-    _old: Option<HgChangesetId>,
-    _new: Option<HgChangesetId>,
+    part_id: PartId,
+    mandatory: bool,
+    name: AsciiString,
+    old: Option<HgChangesetId>,
+    new: Option<HgChangesetId>,
 }
 
+/// Id of the pushkey part being replied to and whether the bookmark was moved
+type BookmarkReply = (PartId, bool);
+
 /// Holds repo and logger for convienience access from it's methods
 #[derive(Clone)]
 struct Bundle2Resolver {
     repo: Arc<BlobRepo>,
     logger: Logger,
+    user: Option<String>,
+    lfs_threshold: Option<u64>,
+    // Compression of the successful response, negotiated from the replycaps of the client
+    reply_compression: Option<CompressorType>,
 }
 
 impl Bundle2Resolver {
-    fn new(repo: Arc<BlobRepo>, logger: Logger) -> Self {
-        Self { repo, logger }
+    fn new(
+        repo: Arc<BlobRepo>,
+        logger: Logger,
+        user: Option<String>,
+        lfs_threshold: Option<u64>,
+    ) -> Self {
+        Self {
+            repo,
+            logger,
+            user,
+            lfs_threshold,
+            reply_compression: None,
+        }
     }
 
-    /// Parse Start and Replycaps and ignore their content
+    /// Parse Start and Replycaps, returning the capabilities of the client and the rest of the
+    /// bundle2
     fn resolve_start_and_replycaps(
         &self,
         bundle2: BoxStream<Bundle2Item, Error>,
-    ) -> BoxStream<Bundle2Item, Error> {
+    ) -> BoxFuture<(Capabilities, BoxStream<Bundle2Item, Error>), Error> {
         next_item(bundle2)
             .and_then(|(start, bundle2)| match start {
                 Some(Bundle2Item::Start(_)) => next_item(bundle2),
                 _ => err(format_err!("Expected Bundle2 Start")).boxify(),
             })
             .and_then(|(replycaps, bundle2)| match replycaps {
-                Some(Bundle2Item::Replycaps(_, part)) => part.map(|_| bundle2).boxify(),
+                Some(Bundle2Item::Replycaps(_, part)) => part.map(|caps| (caps, bundle2)).boxify(),
                 _ => err(format_err!("Expected Bundle2 Replycaps")).boxify(),
             })
-            .flatten_stream()
+            .boxify()
+    }
+
+    /// Parses check:heads part if it exists
+    fn maybe_resolve_check_heads(
+        &self,
+        bundle2: BoxStream<Bundle2Item, Error>,
+    ) -> BoxFuture<(Option<Vec<NodeHash>>, BoxStream<Bundle2Item, Error>), Error> {
+        next_item(bundle2)
+            .and_then(|(newpart, bundle2)| match newpart {
+                Some(Bundle2Item::CheckHeads(_, heads)) => heads
+                    .map(move |heads| (Some(heads), bundle2))
+                    .boxify(),
+                Some(part) => ok((None, stream::once(Ok(part)).chain(bundle2).boxify())).boxify(),
+                None => ok((None, bundle2.boxify())).boxify(),
+            })
+            .map_err(|err| err.context("While resolving CheckHeads").into())
+            .boxify()
+    }
+
+    /// Fails with PushRaced unless each of the `expected` sets of heads is the same as the
+    /// current heads of the repo.
+    /// The null hash stands for an empty repo, which has no heads.
+    fn check_heads(&self, expected: Vec<Vec<NodeHash>>) -> BoxFuture<(), Error> {
+        if expected.is_empty() {
+            return ok(()).boxify();
+        }
+        let logger = self.logger.clone();
+
+        self.repo
+            .get_heads()
+            .collect()
+            .and_then(move |actual| {
+                let actual: HashSet<_> = actual.into_iter().collect();
+                for heads in expected {
+                    let heads: HashSet<_> = heads.into_iter().filter(|h| h != &NULL_HASH).collect();
+                    if heads != actual {
+                        warn!(
+                            logger,
+                            "push raced: client expected heads {:?}, but repo has {:?}",
+                            heads,
+                            actual
+                        );
+                        bail_err!(ErrorKind::PushRaced);
+                    }
+                }
+                Ok(())
+            })
             .boxify()
     }
 
     /// Parse changegroup.
     /// The ChangegroupId will be used in the last step for preparing response
     /// The Changesets should be parsed as RevlogChangesets and used for uploading changesets
-    /// The Filelogs should be scheduled for uploading to BlobRepo and the Future resolving in
-    /// their upload should be used for uploading changesets
+    /// The Manifests (changegroup3 only) and Filelogs should be scheduled for uploading to
+    /// BlobRepo and the Future resolving in their upload should be used for uploading changesets
     fn resolve_changegroup(
         &self,
         bundle2: BoxStream<Bundle2Item, Error>,
     ) -> BoxFuture<(ChangegroupPush, BoxStream<Bundle2Item, Error>), Error> {
         let repo = self.repo.clone();
+        let lfs_threshold = self.lfs_threshold;
 
         next_item(bundle2)
             .and_then(move |(changegroup, bundle2)| match changegroup {
                 Some(Bundle2Item::Changegroup(header, parts))
                 | Some(Bundle2Item::B2xInfinitepush(header, parts)) => {
                     let part_id = header.part_id();
-                    let (c, f) = split_changegroup(parts);
+                    let version = try_boxfuture!(header.cg_version());
+                    let treemanifest = header.cg_treemanifest();
+                    let (c, m, f) = split_changegroup(parts, version, treemanifest);
                     convert_to_revlog_changesets(c)
                         .collect()
-                        .join(
+                        .and_then({
+                            let repo = repo.clone();
+                            move |changesets| {
+                                upload_blobs(
+                                    repo.clone(),
+                                    convert_to_revlog_manifests(repo, m),
+                                    UploadBlobsType::IgnoreDuplicates,
+                                ).map_err(|err| {
+                                    err.context("While uploading Manifest Blobs").into()
+                                })
+                                    .map(move |manifests| (changesets, manifests))
+                            }
+                        })
+                        .and_then(move |(changesets, manifests)| {
                             upload_blobs(
                                 repo.clone(),
-                                convert_to_revlog_filelog(repo, f),
+                                convert_to_revlog_filelog(repo, f, lfs_threshold),
                                 UploadBlobsType::EnsureNoDuplicates,
-                            ).map_err(|err| err.context("While uploading File Blobs").into()),
-                        )
-                        .map(move |(changesets, filelogs)| {
+                            ).map_err(|err| err.context("While uploading File Blobs").into())
+                                .map(move |filelogs| (changesets, manifests, filelogs))
+                        })
+                        .map(move |(changesets, manifests, filelogs)| {
                             let cg_push = ChangegroupPush {
                                 part_id,
                                 changesets,
                                 filelogs,
+                                manifests,
                             };
                             (cg_push, bundle2)
                         })
@@ -217,10 +462,11 @@ impl Bundle2Resolver {
                     let new = try_boxfuture!(get_optional_changeset_param(mparams, "new"));
 
                     let bookmark_push = BookmarkPush {
-                        _part_id: part_id,
-                        _name: name,
-                        _old: old,
-                        _new: new,
+                        part_id,
+                        mandatory: header.mandatory(),
+                        name,
+                        old,
+                        new,
                     };
                     emptypart
                         .map(move |_| (Some(bookmark_push), bundle2.boxify()))
@@ -233,10 +479,11 @@ impl Bundle2Resolver {
             .boxify()
     }
 
-    /// Parse b2xtreegroup2.
+    /// Parse b2xtreegroup2 if it exists. A changegroup3 push may have sent its manifests in the
+    /// changegroup instead.
     /// The Manifests should be scheduled for uploading to BlobRepo and the Future resolving in
     /// their upload as well as their parsed content should be used for uploading changesets.
-    fn resolve_b2xtreegroup2(
+    fn maybe_resolve_b2xtreegroup2(
         &self,
         bundle2: BoxStream<Bundle2Item, Error>,
     ) -> BoxFuture<(Manifests, BoxStream<Bundle2Item, Error>), Error> {
@@ -253,7 +500,11 @@ impl Bundle2Resolver {
                         .map(move |manifests| (manifests, bundle2))
                         .boxify()
                 }
-                _ => err(format_err!("Expected Bundle2 B2xTreegroup2")).boxify(),
+                Some(part) => ok((
+                    HashMap::new(),
+                    stream::once(Ok(part)).chain(bundle2).boxify(),
+                )).boxify(),
+                None => ok((HashMap::new(), bundle2.boxify())).boxify(),
             })
             .map_err(|err| err.context("While resolving B2xTreegroup2").into())
             .boxify()
@@ -372,6 +623,54 @@ impl Bundle2Resolver {
             .boxify()
     }
 
+    /// Moves the bookmark, if the push asked for it. The changesets must have been uploaded
+    /// already, so that the bookmark never points to a changeset that doesn't exist.
+    /// If the bookmark couldn't be moved (for example because it has been moved by someone else
+    /// in the meantime), the push fails if the pushkey part was mandatory. Otherwise the failure
+    /// is just reported back to the client, the same way Mercurial does it.
+    fn push_bookmark(
+        &self,
+        bookmark_push: Option<BookmarkPush>,
+    ) -> BoxFuture<Option<BookmarkReply>, Error> {
+        let bookmark_push = match bookmark_push {
+            Some(bookmark_push) => bookmark_push,
+            None => return ok(None).boxify(),
+        };
+        let BookmarkPush {
+            part_id,
+            mandatory,
+            name,
+            old,
+            new,
+        } = bookmark_push;
+
+        // The pushed changesets are uploaded by now, so a missing changeset can only be one that
+        // neither the repo nor the push has
+        let logger = self.logger.clone();
+        self.repo
+            .move_bookmark(&name, old, new, BookmarkUpdateReason::Push, self.user.clone())
+            .then(move |res| match res {
+                Ok(()) => {
+                    info!(logger, "moved bookmark {} from {:?} to {:?}", name, old, new);
+                    Ok(Some((part_id, true)))
+                }
+                Err(err) => {
+                    warn!(logger, "failed to move bookmark {}: {}", name, err);
+                    if mandatory {
+                        Err(err.context(ErrorKind::BookmarkPushFailed {
+                            part_id,
+                            name,
+                            old,
+                            new,
+                        }).into())
+                    } else {
+                        Ok(Some((part_id, false)))
+                    }
+                }
+            })
+            .boxify()
+    }
+
     /// Ensures that the next item in stream is None
     fn ensure_stream_finished(
         &self,
@@ -386,18 +685,28 @@ impl Bundle2Resolver {
     }
 
     /// Takes a changegroup id and prepares a Bytes response containing Bundle2 with reply to
-    /// changegroup part saying that the push was successful
-    fn prepare_response(&self, changegroup_id: PartId) -> BoxFuture<Bytes, Error> {
+    /// changegroup part saying that the push was successful, and a reply to the pushkey part if
+    /// there was one
+    fn prepare_response(
+        &self,
+        changegroup_id: PartId,
+        bookmark_reply: Option<BookmarkReply>,
+    ) -> BoxFuture<Bytes, Error> {
         let writer = Cursor::new(Vec::new());
         let mut bundle = Bundle2EncodeBuilder::new(writer);
-        // Mercurial currently hangs while trying to read compressed bundles over the wire:
-        // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
-        // TODO: possibly enable compression support once this is fixed.
-        bundle.set_compressor_type(None);
+        // Mercurial versions that hang while reading compressed bundles over the wire
+        // (https://bz.mercurial-scm.org/show_bug.cgi?id=5646) don't ask for compression
+        bundle.set_compressor_type(self.reply_compression);
         bundle.add_part(try_boxfuture!(parts::replychangegroup_part(
             parts::ChangegroupApplyResult::Success { heads_num_diff: 0 },
             changegroup_id,
         )));
+        if let Some((pushkey_id, success)) = bookmark_reply {
+            bundle.add_part(try_boxfuture!(parts::replypushkey_part(
+                success,
+                pushkey_id
+            )));
+        }
         bundle
             .build()
             .map(|cursor| Bytes::from(cursor.into_inner()))
@@ -532,3 +841,32 @@ fn get_optional_changeset_param(
         Ok(Some(HgChangesetId::from_ascii_str(&val)?))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mercurial_types_mocks::nodehash::{ONES_HASH, TWOS_HASH};
+
+    #[test]
+    fn test_parse_unbundle_heads() {
+        assert_eq!(
+            parse_unbundle_heads(&["666f726365".to_string()]).unwrap(),
+            None
+        );
+
+        let heads = vec![ONES_HASH.to_string(), TWOS_HASH.to_string()];
+        assert_eq!(
+            parse_unbundle_heads(&heads).unwrap(),
+            Some(vec![ONES_HASH, TWOS_HASH])
+        );
+
+        assert!(parse_unbundle_heads(&["notahash".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_error_message() {
+        let error: Error = format_err!("root cause").context("While doing things").into();
+        assert_eq!(error_message(&error), "While doing things: root cause");
+    }
+}
@@ -66,7 +66,7 @@ pub struct TreemanifestEntry {
 }
 
 impl TreemanifestEntry {
-    fn new(
+    pub(crate) fn new(
         node: NodeHash,
         data: Bytes,
         p1: NodeHash,
@@ -8,10 +8,15 @@ pub use failure::{Error, Result};
 
 use mercurial_types::HgChangesetId;
 
+use HgChangesetIdPrefix;
+
 #[derive(Debug, Eq, Fail, PartialEq)]
 pub enum ErrorKind {
     #[fail(display = "Connection error")] ConnectionError,
     #[fail(display = "Changeset already in database")] DuplicateChangeset,
     #[fail(display = "Invalid data in database")] InvalidStoredData,
     #[fail(display = "Missing parents")] MissingParents(Vec<HgChangesetId>),
+    #[fail(display = "Invalid changeset id prefix {:?}", _0)] InvalidPrefix(String),
+    #[fail(display = "Ambiguous changeset id prefix {}", _0)]
+    AmbiguousPrefix(HgChangesetIdPrefix, Vec<HgChangesetId>),
 }
@@ -19,7 +19,9 @@ extern crate mercurial_types;
 
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
+use std::fmt::{self, Display};
 use std::result;
+use std::str::FromStr;
 use std::sync::Mutex;
 
 use diesel::{insert_into, Connection, MysqlConnection, SqliteConnection};
@@ -60,6 +62,51 @@ pub struct ChangesetInsert {
     pub parents: Vec<HgChangesetId>,
 }
 
+/// At most this many candidates are reported for an ambiguous prefix.
+const MAX_PREFIX_CANDIDATES: i64 = 10;
+
+/// A hex prefix of a changeset id, as typed by users (`hg pull -r abc123`).
+///
+/// Changeset ids are stored as binary, so the changesets matching a prefix are the range between
+/// the prefix padded with `0`s and the prefix padded with `f`s.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct HgChangesetIdPrefix {
+    prefix: String,
+    min: HgChangesetId,
+    max: HgChangesetId,
+}
+
+impl HgChangesetIdPrefix {
+    pub fn from_hex<S: AsRef<str>>(prefix: S) -> Result<Self> {
+        let prefix = prefix.as_ref();
+        if prefix.is_empty() || prefix.len() > 40 || !prefix.chars().all(|c| c.is_digit(16)) {
+            bail_err!(ErrorKind::InvalidPrefix(prefix.to_string()));
+        }
+
+        let prefix = prefix.to_lowercase();
+        let padding = 40 - prefix.len();
+        let min = HgChangesetId::from_str(&format!("{}{}", prefix, "0".repeat(padding)))?;
+        let max = HgChangesetId::from_str(&format!("{}{}", prefix, "f".repeat(padding)))?;
+        Ok(HgChangesetIdPrefix { prefix, min, max })
+    }
+
+    /// The smallest changeset id that has this prefix.
+    pub fn min(&self) -> HgChangesetId {
+        self.min
+    }
+
+    /// The largest changeset id that has this prefix.
+    pub fn max(&self) -> HgChangesetId {
+        self.max
+    }
+}
+
+impl Display for HgChangesetIdPrefix {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.prefix)
+    }
+}
+
 /// Interface to storage of changesets that have been completely stored in Mononoke.
 pub trait Changesets: Send + Sync {
     /// Add a new entry to the changesets table.
@@ -71,6 +118,14 @@ pub trait Changesets: Send + Sync {
         repo_id: RepositoryId,
         cs_id: HgChangesetId,
     ) -> BoxFuture<Option<ChangesetEntry>, Error>;
+
+    /// Find the changeset whose id starts with `prefix`, if there is one. Fails with
+    /// `ErrorKind::AmbiguousPrefix` if several changesets match.
+    fn get_by_prefix(
+        &self,
+        repo_id: RepositoryId,
+        prefix: &HgChangesetIdPrefix,
+    ) -> BoxFuture<Option<HgChangesetId>, Error>;
 }
 
 pub struct SqliteChangesets {
@@ -189,6 +244,33 @@ macro_rules! impl_changesets {
                 future::result(entry).boxify()
             }
 
+            /// Find the changeset with the given prefix by scanning the range of ids that start
+            /// with it.
+            fn get_by_prefix(
+                &self,
+                repo_id: RepositoryId,
+                prefix: &HgChangesetIdPrefix,
+            ) -> BoxFuture<Option<HgChangesetId>, Error> {
+                let query = changesets::table
+                    .filter(changesets::repo_id.eq(repo_id))
+                    .filter(changesets::cs_id.ge(prefix.min()))
+                    .filter(changesets::cs_id.le(prefix.max()))
+                    .order(changesets::cs_id.asc())
+                    .limit(MAX_PREFIX_CANDIDATES);
+                let connection = self.connection.lock().expect("lock poisoned");
+
+                let rows = query.load::<ChangesetRow>(&*connection);
+                let cs_id = rows.map_err(failure::Error::from).and_then(|rows| {
+                    let mut cs_ids: Vec<_> = rows.into_iter().map(|row| row.cs_id).collect();
+                    match cs_ids.len() {
+                        0 => Ok(None),
+                        1 => Ok(cs_ids.pop()),
+                        _ => Err(ErrorKind::AmbiguousPrefix(prefix.clone(), cs_ids).into()),
+                    }
+                });
+                future::result(cs_id).boxify()
+            }
+
             /// Insert a new changeset into this table. Checks that all parents are already in
             /// storage.
             fn add(&self, cs: &ChangesetInsert) -> BoxFuture<(), Error> {
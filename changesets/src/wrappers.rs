@@ -11,7 +11,7 @@ use std::sync::Arc;
 use futures_ext::BoxFuture;
 use mercurial_types::{HgChangesetId, RepositoryId};
 
-use {ChangesetEntry, ChangesetInsert, Changesets};
+use {ChangesetEntry, ChangesetInsert, Changesets, HgChangesetIdPrefix};
 use errors::*;
 
 impl Changesets for Arc<Changesets> {
@@ -26,4 +26,12 @@ impl Changesets for Arc<Changesets> {
     ) -> BoxFuture<Option<ChangesetEntry>, Error> {
         (**self).get(repo_id, cs_id)
     }
+
+    fn get_by_prefix(
+        &self,
+        repo_id: RepositoryId,
+        prefix: &HgChangesetIdPrefix,
+    ) -> BoxFuture<Option<HgChangesetId>, Error> {
+        (**self).get_by_prefix(repo_id, prefix)
+    }
 }
@@ -15,14 +15,17 @@ extern crate failure_ext as failure;
 extern crate futures;
 
 extern crate changesets;
+extern crate mercurial_types;
 extern crate mercurial_types_mocks;
 
+use std::str::FromStr;
 use std::sync::Arc;
 
 use futures::Future;
 
-use changesets::{ChangesetEntry, ChangesetInsert, Changesets, ErrorKind, MysqlChangesets,
-                 SqliteChangesets};
+use changesets::{ChangesetEntry, ChangesetInsert, Changesets, ErrorKind, HgChangesetIdPrefix,
+                 MysqlChangesets, SqliteChangesets};
+use mercurial_types::HgChangesetId;
 use mercurial_types_mocks::nodehash::*;
 use mercurial_types_mocks::repo::*;
 
@@ -199,6 +202,59 @@ fn complex<C: Changesets>(changesets: C) {
     );
 }
 
+fn get_by_prefix<C: Changesets>(changesets: C) {
+    let ones_twos = HgChangesetId::from_str("1111222222222222222222222222222222222222")
+        .expect("Invalid changeset id");
+    for cs_id in vec![ONES_CSID, ones_twos, TWOS_CSID] {
+        let row = ChangesetInsert {
+            repo_id: REPO_ZERO,
+            cs_id,
+            parents: vec![],
+        };
+        changesets.add(&row).wait().expect("Adding new entry failed");
+    }
+
+    let lookup = |prefix: &str| {
+        let prefix = HgChangesetIdPrefix::from_hex(prefix).expect("Invalid prefix");
+        changesets.get_by_prefix(REPO_ZERO, &prefix).wait()
+    };
+
+    assert_eq!(lookup("11111").expect("Lookup failed"), Some(ONES_CSID));
+    assert_eq!(lookup("11112").expect("Lookup failed"), Some(ones_twos));
+    assert_eq!(lookup("2").expect("Lookup failed"), Some(TWOS_CSID));
+    assert_eq!(
+        lookup(TWOS_CSID.to_hex().as_str()).expect("Lookup failed"),
+        Some(TWOS_CSID)
+    );
+    assert_eq!(lookup("3").expect("Lookup failed"), None);
+    assert_eq!(
+        changesets
+            .get_by_prefix(REPO_ONE, &HgChangesetIdPrefix::from_hex("2").unwrap())
+            .wait()
+            .expect("Lookup failed"),
+        None
+    );
+
+    let result = lookup("1111").expect_err("Lookup of ambiguous prefix succeeded");
+    assert_matches!(
+        result.downcast::<ErrorKind>(),
+        Ok(ErrorKind::AmbiguousPrefix(_, ref x)) if x == &vec![ONES_CSID, ones_twos]
+    );
+}
+
+fn invalid_prefix() {
+    for prefix in vec!["", "xyz", "11111111111111111111111111111111111111111"] {
+        let result = HgChangesetIdPrefix::from_hex(prefix)
+            .expect_err("Parsing an invalid prefix succeeded");
+        assert_matches!(result.downcast::<ErrorKind>(), Ok(ErrorKind::InvalidPrefix(_)));
+    }
+}
+
+#[test]
+fn test_invalid_prefix() {
+    invalid_prefix();
+}
+
 macro_rules! changesets_test_impl {
     ($mod_name: ident => {
         new: $new_cb: expr,
@@ -230,6 +286,11 @@ macro_rules! changesets_test_impl {
             fn test_complex() {
                 complex($new_cb());
             }
+
+            #[test]
+            fn test_get_by_prefix() {
+                get_by_prefix($new_cb());
+            }
         }
     }
 }
@@ -0,0 +1,381 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Mark-and-sweep garbage collector for blob repos.
+//!
+//! Everything reachable from the heads and bookmarks of the repo is marked, then all `node-*`,
+//! `sha1-*` and chunked content blobs that weren't marked and are older than the grace period are
+//! deleted. LFS contents are only collected when the LFS threshold of the repo is given: only the
+//! files at least that large are hashed to mark their LFS blobs. Otherwise all of them are kept,
+//! along with the chunks of the large ones.
+//!
+//! The grace period protects blobs uploaded by pushes that are still in flight: their changesets
+//! aren't reachable yet, but their blobs must not go away. Rocksdb blobstores don't record when
+//! blobs were written, so they can only be collected without a grace period.
+
+#![deny(warnings)]
+
+extern crate ascii;
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+extern crate tokio_core;
+
+extern crate blobrepo;
+extern crate blobstore;
+extern crate cmdlib;
+extern crate futures_ext;
+extern crate mercurial_types;
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use ascii::AsciiString;
+use clap::{App, ArgMatches};
+use failure::{Error, Result, SlogKVError};
+use futures::{Future, Stream};
+use futures::future::{self, Either};
+use slog::Logger;
+use tokio_core::reactor::Core;
+
+use blobrepo::BlobRepo;
+use blobstore::{BlobMeta, EnumerableBlobstore};
+use cmdlib::BlobstoreType;
+use futures_ext::{BoxFuture, FutureExt, StreamExt};
+use mercurial_types::{Changeset, Entry, HgChangesetId, NodeHash, RepositoryId, Type, NULL_HASH};
+use mercurial_types::hash::Sha256;
+use mercurial_types::manifest::Content;
+
+const DEFAULT_GRACE_PERIOD_SECS: u64 = 24 * 60 * 60;
+// How many changesets and manifest entries are walked concurrently
+const MARK_CONCURRENCY: usize = 100;
+const SWEEP_CONCURRENCY: usize = 100;
+
+// Only these kinds of blobs are collected. Changeset blobs are only written once all of their
+// entries are uploaded, so they are never left behind by an aborted push.
+const COLLECTABLE_PREFIXES: &[&str] = &["node-", "sha1-", "chunked-sha1-", "chunk-sha1-"];
+const LFS_PREFIXES: &[&str] = &["lfs-sha256-", "chunked-lfs-sha256-"];
+const CHUNKED_LFS_PREFIX: &str = "chunked-lfs-sha256-";
+
+#[derive(Default)]
+struct Marked {
+    // Nodes that have already been walked, so shared subtrees are only visited once
+    nodes: HashSet<NodeHash>,
+    keys: HashSet<String>,
+}
+
+type SharedMarked = Arc<Mutex<Marked>>;
+
+#[derive(Debug, Default)]
+struct SweepStats {
+    reachable: usize,
+    too_recent: usize,
+    deleted: usize,
+}
+
+/// Marks the LFS blobs of a file revision. Only the contents of at least `lfs_threshold` bytes are
+/// stored for the LFS protocol, so the smaller files don't need to be fetched and hashed.
+fn mark_lfs_key(
+    repo: BlobRepo,
+    marked: SharedMarked,
+    nodeid: NodeHash,
+    lfs_threshold: u64,
+) -> BoxFuture<(), Error> {
+    // The blob is the content along with any copy metadata, so it's never smaller
+    repo.get_file_blob_size(&nodeid)
+        .and_then(move |size| {
+            if size < lfs_threshold {
+                return Either::A(future::ok(()));
+            }
+            let keys = repo.get_file_content(&nodeid).and_then(move |content| {
+                if (content.len() as u64) < lfs_threshold {
+                    return Either::A(future::ok(Vec::new()));
+                }
+                Either::B(repo.get_lfs_content_keys(&Sha256::from(content.as_ref())))
+            });
+            Either::B(keys.map(move |keys| marked.lock().expect("lock poisoned").keys.extend(keys)))
+        })
+        .boxify()
+}
+
+/// The chunks of all the large LFS contents, which must be kept when LFS contents aren't
+/// collected: they are collectable `chunk-sha1-*` blobs like the chunks of any other content.
+fn lfs_chunk_keys(
+    repo: BlobRepo,
+    blobstore: Arc<EnumerableBlobstore>,
+) -> BoxFuture<HashSet<String>, Error> {
+    blobstore
+        .enumerate()
+        .filter_map(|meta| {
+            if meta.key.starts_with(CHUNKED_LFS_PREFIX) {
+                Some(meta.key[CHUNKED_LFS_PREFIX.len()..].to_string())
+            } else {
+                None
+            }
+        })
+        .and_then(|oid| oid.parse::<Sha256>())
+        .map(move |oid| repo.get_lfs_content_keys(&oid))
+        .buffer_unordered(MARK_CONCURRENCY)
+        .fold(HashSet::new(), |mut marked, keys| {
+            marked.extend(keys);
+            Ok::<_, Error>(marked)
+        })
+        .boxify()
+}
+
+fn mark_entry(
+    repo: BlobRepo,
+    marked: SharedMarked,
+    entry: Box<Entry + Sync>,
+    lfs_threshold: Option<u64>,
+) -> BoxFuture<(), Error> {
+    let nodeid = entry.get_hash().into_nodehash();
+    if nodeid == NULL_HASH || !marked.lock().expect("lock poisoned").nodes.insert(nodeid) {
+        return future::ok(()).boxify();
+    }
+
+    let keys = repo.get_node_blobstore_keys(&nodeid).map({
+        let marked = marked.clone();
+        move |keys| marked.lock().expect("lock poisoned").keys.extend(keys)
+    });
+
+    if entry.get_type() != Type::Tree {
+        let lfs_key = match lfs_threshold {
+            Some(lfs_threshold) => mark_lfs_key(repo, marked, nodeid, lfs_threshold),
+            None => future::ok(()).boxify(),
+        };
+        return keys.join(lfs_key).map(|_| ()).boxify();
+    }
+
+    let children = entry.get_content().and_then(move |content| match content {
+        Content::Tree(manifest) => Either::A(
+            manifest
+                .list()
+                .map(move |child| {
+                    mark_entry(repo.clone(), marked.clone(), child, lfs_threshold)
+                })
+                .buffer_unordered(MARK_CONCURRENCY)
+                .for_each(|()| Ok(())),
+        ),
+        _ => Either::B(future::err(format_err!("{} is not a manifest", nodeid))),
+    });
+
+    keys.join(children).map(|_| ()).boxify()
+}
+
+fn mark(logger: Logger, repo: BlobRepo, lfs_threshold: Option<u64>) -> BoxFuture<Marked, Error> {
+    let bookmarks = repo.get_bookmarks_by_prefix(&AsciiString::new())
+        .map(|(_name, csid)| csid.into_nodehash())
+        .boxify();
+    let roots = repo.get_heads().select(bookmarks).boxify();
+
+    let marked = Arc::new(Mutex::new(Marked::default()));
+
+    repo.get_ancestor_changesets(roots)
+        .enumerate()
+        .map({
+            let repo = repo.clone();
+            let marked = marked.clone();
+            move |(seq, csid)| {
+                debug!(logger, "{}: marking changeset {}", seq, csid);
+                let repo = repo.clone();
+                let marked = marked.clone();
+                repo.get_changeset_by_changesetid(&HgChangesetId::new(csid))
+                    .and_then(move |cs| {
+                        let root = repo.get_root_entry(cs.manifestid());
+                        mark_entry(repo, marked, root, lfs_threshold)
+                    })
+            }
+        })
+        .buffer_unordered(MARK_CONCURRENCY)
+        .for_each(|()| Ok(()))
+        .map(move |()| {
+            let mut marked = marked.lock().expect("lock poisoned");
+            std::mem::replace(&mut *marked, Marked::default())
+        })
+        .boxify()
+}
+
+fn is_old_enough(meta: &BlobMeta, cutoff: Option<SystemTime>) -> bool {
+    match (cutoff, meta.modified) {
+        (None, _) => true,
+        (Some(cutoff), Some(modified)) => modified < cutoff,
+        // If the store can't tell us how old a blob is, err on the side of keeping it
+        (Some(_), None) => false,
+    }
+}
+
+fn sweep(
+    logger: Logger,
+    blobstore: Arc<EnumerableBlobstore>,
+    reachable: HashSet<String>,
+    cutoff: Option<SystemTime>,
+    collect_lfs: bool,
+    dry_run: bool,
+) -> BoxFuture<SweepStats, Error> {
+    blobstore
+        .enumerate()
+        .filter(move |meta| {
+            COLLECTABLE_PREFIXES
+                .iter()
+                .any(|prefix| meta.key.starts_with(prefix))
+                || (collect_lfs && LFS_PREFIXES.iter().any(|prefix| meta.key.starts_with(prefix)))
+        })
+        .map(move |meta| {
+            let mut stats = SweepStats::default();
+            if reachable.contains(&meta.key) {
+                stats.reachable += 1;
+            } else if !is_old_enough(&meta, cutoff) {
+                stats.too_recent += 1;
+            } else {
+                stats.deleted += 1;
+                if dry_run {
+                    info!(logger, "would delete {}", meta.key);
+                } else {
+                    debug!(logger, "deleting {}", meta.key);
+                    return blobstore.delete(meta.key).map(move |()| stats).boxify();
+                }
+            }
+            future::ok(stats).boxify()
+        })
+        .buffer_unordered(SWEEP_CONCURRENCY)
+        .fold(SweepStats::default(), |mut total, stats| {
+            total.reachable += stats.reachable;
+            total.too_recent += stats.too_recent;
+            total.deleted += stats.deleted;
+            Ok::<_, Error>(total)
+        })
+        .boxify()
+}
+
+fn run_gc(
+    logger: &Logger,
+    path: &Path,
+    blobtype: BlobstoreType,
+    repoid: RepositoryId,
+    grace_period: Duration,
+    lfs_threshold: Option<u64>,
+    dry_run: bool,
+) -> Result<()> {
+    // Rocksdb doesn't record when blobs were written, so there is no telling the blobs of the
+    // pushes that are still in flight from garbage
+    if blobtype == BlobstoreType::Rocksdb && grace_period != Duration::from_secs(0) {
+        bail_msg!(
+            "rocksdb blobstores don't record the age of blobs: run with --grace-period 0, \
+             while no pushes are in flight"
+        );
+    }
+
+    let mut core = Core::new()?;
+
+    // Anything written after this point is considered too new to be collected.
+    let start = SystemTime::now();
+    let cutoff = if grace_period == Duration::from_secs(0) {
+        None
+    } else {
+        Some(start - grace_period)
+    };
+
+    let (repo, blobstore) = cmdlib::open_repo(logger, path, blobtype, repoid)?;
+
+    info!(logger, "Marking reachable blobs");
+    let mut marked = core.run(mark(logger.clone(), repo.clone(), lfs_threshold))?;
+    if lfs_threshold.is_none() {
+        let chunks = core.run(lfs_chunk_keys(repo, blobstore.clone()))?;
+        marked.keys.extend(chunks);
+    }
+    info!(
+        logger,
+        "Marked {} blobs reachable from {} nodes",
+        marked.keys.len(),
+        marked.nodes.len()
+    );
+
+    info!(logger, "Sweeping unreachable blobs");
+    let stats = core.run(sweep(
+        logger.clone(),
+        blobstore,
+        marked.keys,
+        cutoff,
+        lfs_threshold.is_some(),
+        dry_run,
+    ))?;
+    info!(
+        logger,
+        "{} {} blobs, kept {} reachable and {} recent blobs",
+        if dry_run { "Would delete" } else { "Deleted" },
+        stats.deleted,
+        stats.reachable,
+        stats.too_recent
+    );
+
+    Ok(())
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("blob repo garbage collector")
+        .version("0.0.0")
+        .about("delete blobs that are not reachable from heads or bookmarks")
+        .args_from_usage(
+            r#"
+            <REPO>                       'path to the blob repo'
+
+            --repo-id [ID]               'numerical id of the repo. Default: 0'
+            --grace-period [SECONDS]     'only delete blobs older than this. Default: 86400.
+                                          Must be 0 for rocksdb, which doesn't know how old
+                                          blobs are'
+            --lfs-threshold [SIZE]       'LFS threshold of the repo, in bytes. LFS blobs are
+                                          only collected when it is given'
+            --dry-run                    'only report what would be deleted'
+
+            -d, --debug                  'print debug level output'
+        "#,
+        )
+        .arg(cmdlib::blobstore_arg())
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let root_log = cmdlib::get_logger(&matches);
+
+    fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<()> {
+        let path = Path::new(matches.value_of("REPO").unwrap());
+
+        let grace_period = matches
+            .value_of("grace-period")
+            .map(|secs| {
+                secs.parse()
+                    .expect("grace-period must be positive integer")
+            })
+            .unwrap_or(DEFAULT_GRACE_PERIOD_SECS);
+
+        let lfs_threshold = matches.value_of("lfs-threshold").map(|size| {
+            size.parse()
+                .expect("lfs-threshold must be positive integer")
+        });
+
+        run_gc(
+            root_log,
+            path,
+            cmdlib::get_blobstore_type(&matches),
+            cmdlib::get_repo_id(&matches),
+            Duration::from_secs(grace_period),
+            lfs_threshold,
+            matches.is_present("dry-run"),
+        )
+    }
+
+    if let Err(e) = run(&root_log, matches) {
+        error!(root_log, "Blob gc failed"; SlogKVError(e));
+        std::process::exit(1);
+    }
+}
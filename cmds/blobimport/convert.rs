@@ -36,6 +36,7 @@ pub(crate) struct ConvertContext<H> {
     pub logger: Logger,
     pub skip: Option<u64>,
     pub commits_limit: Option<u64>,
+    pub lfs_threshold: Option<u64>,
 }
 
 impl<H> ConvertContext<H>
@@ -50,6 +51,7 @@ where
         let headstore = self.headstore;
         let skip = self.skip;
         let commits_limit = self.commits_limit;
+        let lfs_threshold = self.lfs_threshold;
 
         let changesets: BoxStream<NodeHash, mercurial::Error> = if let Some(skip) = skip {
             self.repo.changesets().skip(skip).boxify()
@@ -75,7 +77,13 @@ where
                 move |(seq, csid)| {
                     debug!(logger, "{}: changeset {}", seq, csid);
                     STATS::changesets.add_value(1);
-                    copy_changeset(repo.clone(), sender.clone(), linknodes_store.clone(), HgChangesetId::new(csid))
+                    copy_changeset(
+                        repo.clone(),
+                        sender.clone(),
+                        linknodes_store.clone(),
+                        HgChangesetId::new(csid),
+                        lfs_threshold,
+                    )
                 }
             }) // Stream<Future<()>>
             .map(|copy| cpupool.spawn(copy))
@@ -119,6 +127,7 @@ fn copy_changeset<L>(
     sender: SyncSender<BlobstoreEntry>,
     linknodes_store: L,
     csid: HgChangesetId,
+    lfs_threshold: Option<u64>,
 ) -> impl Future<Item = (), Error = Error> + Send + 'static
 where
     Error: Send + 'static,
@@ -154,6 +163,7 @@ where
                 linknodes_store,
                 mfid.clone().into_nodehash(),
                 linkrev,
+                lfs_threshold,
             )
         })
         .map_err(move |err| {
@@ -175,6 +185,7 @@ fn put_blobs<L>(
     linknodes_store: L,
     mfid: NodeHash,
     linkrev: RevIdx,
+    lfs_threshold: Option<u64>,
 ) -> impl Future<Item = (), Error = Error> + Send + 'static
 where
     L: Linknodes,
@@ -221,7 +232,8 @@ where
                                 &entry.get_hash().into_nodehash(),
                                 &linknode,
                             );
-                            let copy_future = manifest::copy_entry(entry, sender.clone());
+                            let copy_future =
+                                manifest::copy_entry(entry, sender.clone(), lfs_threshold);
                             copy_future.join(linknode_future).map(|_| ())
                         })
                 })
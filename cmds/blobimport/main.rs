@@ -114,6 +114,7 @@ fn run_blobimport<In, Out>(
     commits_limit: Option<u64>,
     max_blob_size: Option<usize>,
     inmemory_logs_capacity: Option<usize>,
+    lfs_threshold: Option<u64>,
 ) -> Result<()>
 where
     In: Into<PathBuf>,
@@ -192,6 +193,7 @@ where
         logger: logger.clone(),
         skip: skip,
         commits_limit: commits_limit,
+        lfs_threshold,
     };
     let res = if write_linknodes {
         info!(logger, "Opening linknodes store: {:?}", output);
@@ -370,6 +372,7 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
             --commits-limit [LIMIT]  'import only LIMIT first commits from revlog repo'
             --max-blob-size [LIMIT]  'max size of the blob to be inserted'
             --inmemory-logs-capacity [CAPACITY]  'max number of filelogs and treelogs in memory'
+            --lfs-threshold [SIZE]   'also store the files of at least SIZE bytes for LFS'
         "#,
         )
         .arg(
@@ -497,6 +500,10 @@ fn main() {
                     .parse()
                     .expect("inmemory_logs_capacity must be positive integer")
             }),
+            matches.value_of("lfs-threshold").map(|size| {
+                size.parse()
+                    .expect("lfs-threshold must be positive integer")
+            }),
         )?;
 
         if matches.value_of("blobstore").unwrap() == "rocksdb" && postpone_compaction {
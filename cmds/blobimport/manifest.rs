@@ -11,11 +11,13 @@ use bytes::Bytes;
 use failure::{self, Error};
 use futures::{self, Future, IntoFuture, Stream};
 
-use blobrepo::RawNodeBlob;
+use blobrepo::{get_lfs_key, RawNodeBlob};
 use futures_ext::StreamExt;
 use mercurial::RevlogRepo;
+use mercurial::file::File;
 use mercurial::revlog::RevIdx;
 use mercurial_types::{self, Blob, Entry, HgBlobHash, MPath, NodeHash, Parents, RepoPath, Type};
+use mercurial_types::hash::Sha256;
 
 use BlobstoreEntry;
 
@@ -53,19 +55,49 @@ where
     })
 }
 
-// Copy a single manifest entry into the blobstore
+// The blob that stores the content of a file for the LFS protocol, if the content is at least
+// `lfs_threshold` bytes long
+fn lfs_entry(blob: &Blob, lfs_threshold: Option<u64>) -> Option<BlobstoreEntry> {
+    let threshold = lfs_threshold?;
+    let text = blob.as_slice()?;
+    let (_, offset) = File::extract_meta(text);
+    let content = &text[offset..];
+    if (content.len() as u64) < threshold {
+        return None;
+    }
+    let key = get_lfs_key(&Sha256::from(content));
+    Some(BlobstoreEntry::ManifestEntry((key, Bytes::from(content))))
+}
+
+// Copy a single manifest entry into the blobstore, and the content of a file into the LFS blobs
+// too if it's at least `lfs_threshold` bytes long
 // TODO: #[async]
 pub(crate) fn copy_entry(
     entry: Box<Entry>,
     sender: SyncSender<BlobstoreEntry>,
+    lfs_threshold: Option<u64>,
 ) -> impl Future<Item = (), Error = Error> + Send + 'static {
     let hash = (*entry).get_hash().into_nodehash();
+    let is_file = entry.get_type() != Type::Tree;
 
     let blobfuture = entry.get_raw_content().map_err(Error::from);
 
     blobfuture
         .join(entry.get_parents().map_err(Error::from))
-        .and_then(move |(blob, parents)| put_entry(sender, hash, blob, parents))
+        .and_then(move |(blob, parents)| {
+            let lfs = if is_file {
+                lfs_entry(&blob, lfs_threshold)
+            } else {
+                None
+            };
+            let put_lfs = match lfs {
+                Some(lfs) => sender.send(lfs).map_err(Error::from),
+                None => Ok(()),
+            };
+            put_lfs
+                .into_future()
+                .and_then(move |()| put_entry(sender, hash, blob, parents))
+        })
 }
 
 pub(crate) fn get_entry_stream(
@@ -0,0 +1,131 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Prints the bookmark update log of a blob repo: why a bookmark was moved, from where, to where
+//! and when. With `--at`, the first line printed tells where the bookmark pointed at that time.
+
+#![deny(warnings)]
+
+extern crate ascii;
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+extern crate time;
+
+extern crate bookmarks;
+extern crate dbbookmarks;
+extern crate mercurial_types;
+
+use std::path::Path;
+
+use ascii::AsciiString;
+use clap::{App, ArgMatches};
+use failure::{Result, SlogKVError};
+use futures::{Future, Stream};
+use slog::{Drain, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+
+use bookmarks::{BookmarkUpdateLogEntry, Bookmarks};
+use dbbookmarks::SqliteDbBookmarks;
+use mercurial_types::{HgChangesetId, RepositoryId};
+
+const DEFAULT_LIMIT: usize = 50;
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("bookmark history")
+        .version("0.0.0")
+        .about("print the moves of a bookmark in a blob repo, newest first")
+        .args_from_usage(
+            r#"
+            <REPO>                       'path to the blob repo'
+            <BOOKMARK>                   'name of the bookmark'
+
+            --repo-id [ID]               'numerical id of the repo. Default: 0'
+            --at [TIME]                  'only show moves made at or before TIME, either seconds
+                                          since the epoch or "YYYY-MM-DD HH:MM:SS" in UTC'
+            --limit [N]                  'show at most N moves. Default: 50'
+        "#,
+        )
+}
+
+/// Parses either a Unix timestamp or a UTC date and time.
+fn parse_time(time: &str) -> Result<i64> {
+    if let Ok(secs) = time.parse::<i64>() {
+        return Ok(secs);
+    }
+    match time::strptime(time, "%Y-%m-%d %H:%M:%S") {
+        Ok(tm) => Ok(tm.to_timespec().sec),
+        Err(err) => bail_msg!("invalid time {}: {}", time, err),
+    }
+}
+
+fn format_csid(csid: Option<HgChangesetId>) -> String {
+    match csid {
+        Some(csid) => csid.to_string(),
+        None => "(none)".to_string(),
+    }
+}
+
+fn format_entry(entry: &BookmarkUpdateLogEntry) -> String {
+    let tm = time::at_utc(time::Timespec::new(entry.timestamp, 0));
+    format!(
+        "{} {:<10} {:<12} {} -> {}",
+        tm.rfc3339(),
+        entry.reason,
+        entry.user.as_ref().map(String::as_str).unwrap_or("(unknown)"),
+        format_csid(entry.from_changeset_id),
+        format_csid(entry.to_changeset_id),
+    )
+}
+
+fn run<'a>(matches: ArgMatches<'a>) -> Result<()> {
+    let path = Path::new(matches.value_of("REPO").unwrap());
+    let name = AsciiString::from_ascii(matches.value_of("BOOKMARK").unwrap())
+        .map_err(|_| format_err!("bookmark name must be ASCII"))?;
+
+    let repoid = match matches.value_of("repo-id") {
+        Some(id) => id.parse()?,
+        None => 0,
+    };
+    let at = match matches.value_of("at") {
+        Some(at) => Some(parse_time(at)?),
+        None => None,
+    };
+    let limit = match matches.value_of("limit") {
+        Some(limit) => limit.parse()?,
+        None => DEFAULT_LIMIT,
+    };
+
+    let bookmarks_path = path.join("bookmarks");
+    if !bookmarks_path.exists() {
+        bail_msg!("{} has no bookmarks database", path.display());
+    }
+    let bookmarks = SqliteDbBookmarks::open(bookmarks_path.to_string_lossy())?;
+
+    bookmarks
+        .list_bookmark_log_entries(&name, &RepositoryId::new(repoid), at, limit)
+        .for_each(|entry| {
+            println!("{}", format_entry(&entry));
+            Ok(())
+        })
+        .wait()
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let drain = glog_drain().fuse();
+    let root_log = Logger::root(drain, o![]);
+
+    if let Err(e) = run(matches) {
+        error!(root_log, "Listing bookmark history failed"; SlogKVError(e));
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Writes full bundles of a blob repo for clients to clone from.
+//!
+//! Every bundle is an uncompressed bundle2 (`none-v2`) with all the changesets of the repo and
+//! its bookmarks, named after the time it was generated and a hash of the changesets it has, so
+//! that bundles written in the same second don't replace each other unless they have the same
+//! changesets. The server's `clonebundles` command
+//! advertises the bundles found in the output directory, newest first, so new clones can fetch
+//! them as static files instead of asking the server for the whole history.
+
+#![deny(warnings)]
+
+extern crate ascii;
+extern crate bytes;
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+extern crate tokio_core;
+
+extern crate blobrepo;
+extern crate cmdlib;
+extern crate futures_ext;
+extern crate mercurial;
+extern crate mercurial_bundles;
+extern crate mercurial_types;
+
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ascii::AsciiString;
+use bytes::Bytes;
+use clap::{App, ArgMatches};
+use failure::{Error, Result, ResultExt, SlogKVError};
+use futures::{stream, Future, Stream};
+use slog::Logger;
+use tokio_core::reactor::Core;
+
+use blobrepo::BlobRepo;
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_bundles::{parts, Bundle2EncodeBuilder};
+use mercurial_types::{BlobNode, Changeset, HgChangesetId};
+use mercurial_types::hash::{Context, Sha1};
+
+// How many changesets are fetched from the blob repo at the same time
+const FETCH_CONCURRENCY: usize = 100;
+const DEFAULT_KEEP: usize = 2;
+
+// Bundles are only given their final name once they are complete, so that the server never
+// advertises a partially written one
+const BUNDLE_SUFFIX: &str = ".hg";
+const TMP_SUFFIX: &str = ".tmp";
+// How many hex digits of the hash of the changesets go in the bundle name
+const HASH_LEN: usize = 12;
+
+/// All the changesets of the repo, parents first.
+fn sorted_changesets(repo: Arc<BlobRepo>) -> BoxFuture<Vec<HgChangesetId>, Error> {
+    repo.get_changesets()
+        .map({
+            let repo = repo.clone();
+            move |node| {
+                let csid = HgChangesetId::new(node);
+                repo.get_generation_number(&csid)
+                    .and_then(move |gen| match gen {
+                        Some(gen) => Ok((gen, csid)),
+                        None => Err(format_err!("changeset {} has no generation number", csid)),
+                    })
+            }
+        })
+        .buffered(FETCH_CONCURRENCY)
+        .collect()
+        .map(|mut changesets| {
+            // A changeset's generation number is greater than its parents'
+            changesets.sort();
+            changesets.into_iter().map(|(_, csid)| csid).collect()
+        })
+        .boxify()
+}
+
+/// Identifies the set of changesets in a bundle.
+fn hash_changesets(changesets: &[HgChangesetId]) -> Sha1 {
+    let mut context = Context::new();
+    for csid in changesets {
+        context.update(csid.as_nodehash());
+    }
+    context.finish()
+}
+
+/// An uncompressed bundle2 with a changegroup of every changeset and the bookmarks of the repo,
+/// along with the number of changesets in it and their hash.
+fn create_bundle(repo: Arc<BlobRepo>) -> BoxFuture<(usize, Sha1, Bytes), Error> {
+    sorted_changesets(repo.clone())
+        .and_then(move |changesets| {
+            let count = changesets.len();
+            let hash = hash_changesets(&changesets);
+            let changelogentries = stream::iter_ok(changesets)
+                .map({
+                    let repo = repo.clone();
+                    move |csid| repo.get_changeset_by_changesetid(&csid)
+                })
+                .buffered(FETCH_CONCURRENCY)
+                .and_then(|cs| {
+                    let mut v = Vec::new();
+                    mercurial::changeset::serialize_cs(&cs, &mut v)?;
+                    let parents = cs.parents().get_nodes();
+                    Ok(BlobNode::new(Bytes::from(v), parents.0, parents.1))
+                });
+
+            let bookmarks = repo.get_bookmarks_by_prefix(&AsciiString::new())
+                .map(|(name, cs)| {
+                    let name: Vec<u8> = name.into();
+                    let hash: Vec<u8> = cs.to_hex().into();
+                    (name, hash)
+                });
+
+            let mut bundle = Bundle2EncodeBuilder::new(Cursor::new(Vec::new()));
+            bundle.set_compressor_type(None);
+            bundle.add_part(parts::changegroup_part(changelogentries)?);
+            bundle.add_part(parts::listkey_part("bookmarks", bookmarks)?);
+            Ok((count, hash, bundle))
+        })
+        .and_then(|(count, hash, bundle)| {
+            bundle
+                .build()
+                .map(move |cursor| (count, hash, Bytes::from(cursor.into_inner())))
+                .from_err()
+        })
+        .boxify()
+}
+
+fn write_bundle(
+    logger: &Logger,
+    core: &mut Core,
+    repo: Arc<BlobRepo>,
+    outdir: &Path,
+) -> Result<()> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let (count, hash, bundle) = core.run(create_bundle(repo))?;
+
+    // Zero-padded, so that the names still sort by time
+    let name = format!(
+        "{:010}-{}{}",
+        secs,
+        &hash.to_string()[..HASH_LEN],
+        BUNDLE_SUFFIX
+    );
+    let path = outdir.join(&name);
+    let tmp_path = outdir.join(format!("{}{}", name, TMP_SUFFIX));
+
+    {
+        let mut file = fs::File::create(&tmp_path)
+            .with_context(|_| format!("failed to create {}", tmp_path.display()))?;
+        file.write_all(&bundle)?;
+        file.sync_all()?;
+    }
+    // Renaming within a directory is atomic: the bundle is either not there or complete, and a
+    // bundle of the same changesets written in the same second is replaced by an identical one
+    fs::rename(&tmp_path, &path)
+        .with_context(|_| format!("failed to rename {}", tmp_path.display()))?;
+    fs::File::open(outdir)?.sync_all()?;
+
+    info!(
+        logger,
+        "wrote {} changesets ({} bytes) to {}",
+        count,
+        bundle.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Delete all but the `keep` newest bundles. Bundle names start with timestamps, so the newest
+/// ones sort last.
+fn remove_old_bundles(logger: &Logger, outdir: &Path, keep: usize) -> Result<()> {
+    let mut bundles: Vec<PathBuf> = Vec::new();
+    for entry in fs::read_dir(outdir)? {
+        let path = entry?.path();
+        let is_bundle = path.file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.ends_with(BUNDLE_SUFFIX))
+            .unwrap_or(false);
+        if is_bundle {
+            bundles.push(path);
+        }
+    }
+    bundles.sort();
+
+    let old = bundles.len().saturating_sub(keep);
+    for path in bundles.drain(..old) {
+        info!(logger, "removing old bundle {}", path.display());
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("clone bundle generator")
+        .version("0.0.0")
+        .about("write full bundles of a blob repo for clients to clone from")
+        .args_from_usage(
+            r#"
+            <REPO>                       'path to the blob repo'
+            <OUTPUT>                     'directory to write the bundles to'
+
+            --repo-id [ID]               'numerical id of the repo. Default: 0'
+            --interval [SECONDS]         'keep running and write a new bundle this often'
+            --keep [N]                   'number of bundles to keep in OUTPUT. Default: 2'
+
+            -d, --debug                  'print debug level output'
+        "#,
+        )
+        .arg(cmdlib::blobstore_arg())
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let root_log = cmdlib::get_logger(&matches);
+
+    fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<()> {
+        let path = Path::new(matches.value_of("REPO").unwrap());
+        let outdir = Path::new(matches.value_of("OUTPUT").unwrap());
+
+        let interval = matches.value_of("interval").map(|secs| {
+            Duration::from_secs(secs.parse().expect("interval must be a positive integer"))
+        });
+
+        let keep = matches
+            .value_of("keep")
+            .map(|keep| keep.parse().expect("keep must be a positive integer"))
+            .unwrap_or(DEFAULT_KEEP);
+        if keep == 0 {
+            bail_msg!("keep must be at least 1");
+        }
+
+        let (repo, _blobstore) = cmdlib::open_repo(
+            root_log,
+            path,
+            cmdlib::get_blobstore_type(&matches),
+            cmdlib::get_repo_id(&matches),
+        )?;
+        let repo = Arc::new(repo);
+
+        fs::create_dir_all(outdir)
+            .with_context(|_| format!("failed to create {}", outdir.display()))?;
+
+        let mut core = Core::new()?;
+        loop {
+            let res = write_bundle(root_log, &mut core, repo.clone(), outdir)
+                .and_then(|()| remove_old_bundles(root_log, outdir, keep));
+
+            match interval {
+                // A failed run is retried at the next interval, the older bundles are still there
+                Some(interval) => {
+                    if let Err(e) = res {
+                        error!(root_log, "Writing clone bundle failed"; SlogKVError(e));
+                    }
+                    thread::sleep(interval)
+                }
+                None => return res,
+            }
+        }
+    }
+
+    if let Err(e) = run(&root_log, matches) {
+        error!(root_log, "Writing clone bundles failed"; SlogKVError(e));
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Command line handling and repo opening shared by the tools that work on a local blob repo.
+
+#![deny(warnings)]
+
+extern crate clap;
+extern crate failure_ext as failure;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+
+extern crate blobrepo;
+extern crate blobstore;
+extern crate fileblob;
+extern crate mercurial_types;
+extern crate rocksblob;
+extern crate rocksdb;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use clap::{Arg, ArgMatches};
+use failure::{Error, Result, ResultExt};
+use slog::{Drain, Level, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+
+use blobrepo::BlobRepo;
+use blobstore::EnumerableBlobstore;
+use fileblob::Fileblob;
+use mercurial_types::RepositoryId;
+use rocksblob::Rocksblob;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BlobstoreType {
+    Files,
+    Rocksdb,
+}
+
+/// The `--blobstore` argument. The tools also take `--repo-id [ID]` and `--debug`, which are
+/// read by `get_repo_id` and `get_logger`.
+pub fn blobstore_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("blobstore")
+        .long("blobstore")
+        .short("B")
+        .takes_value(true)
+        .possible_values(&["files", "rocksdb"])
+        .required(true)
+        .help("blobstore type")
+}
+
+pub fn get_blobstore_type<'a>(matches: &ArgMatches<'a>) -> BlobstoreType {
+    match matches.value_of("blobstore").unwrap() {
+        "files" => BlobstoreType::Files,
+        "rocksdb" => BlobstoreType::Rocksdb,
+        bad => panic!("unexpected blobstore type {}", bad),
+    }
+}
+
+pub fn get_repo_id<'a>(matches: &ArgMatches<'a>) -> RepositoryId {
+    let repoid = matches
+        .value_of("repo-id")
+        .map(|id| id.parse().expect("repo-id must be an integer"))
+        .unwrap_or(0);
+    RepositoryId::new(repoid)
+}
+
+pub fn get_logger<'a>(matches: &ArgMatches<'a>) -> Logger {
+    let level = if matches.is_present("debug") {
+        Level::Debug
+    } else {
+        Level::Info
+    };
+
+    let drain = glog_drain().filter_level(level).fuse();
+    Logger::root(drain, o![])
+}
+
+/// Opens the blobstore of the repo at `path`, as it is on disk.
+pub fn open_blobstore(path: &Path, ty: BlobstoreType) -> Result<Arc<EnumerableBlobstore>> {
+    let path = path.join("blobs");
+    let blobstore: Arc<EnumerableBlobstore> = match ty {
+        BlobstoreType::Files => Arc::new(Fileblob::open(path)
+            .map_err(Error::from)
+            .context("Failed to open file blob store")?),
+        BlobstoreType::Rocksdb => {
+            let options = rocksdb::Options::new().create_if_missing(false);
+            Arc::new(Rocksblob::open_with_options(path, options)
+                .map_err(Error::from)
+                .context("Failed to open rocksdb blob store")?)
+        }
+    };
+    Ok(blobstore)
+}
+
+/// Opens the repo at `path`. Its blobstore is returned too, for the tools that need to work on
+/// the blobs themselves, as they are stored: compressed or not.
+pub fn open_repo(
+    logger: &Logger,
+    path: &Path,
+    ty: BlobstoreType,
+    repoid: RepositoryId,
+) -> Result<(BlobRepo, Arc<EnumerableBlobstore>)> {
+    let blobstore = open_blobstore(path, ty)?;
+    // The tools don't compress the few blobs they write, but read the compressed ones
+    let repo = BlobRepo::new_local(
+        logger.clone(),
+        path,
+        Arc::new(blobstore.clone()),
+        repoid,
+        None,
+    )?;
+    Ok((repo, blobstore))
+}
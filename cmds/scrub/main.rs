@@ -0,0 +1,484 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Integrity checker for blob repos.
+//!
+//! Walks every changeset of the repo along with all the manifests and files it refers to, and
+//! checks that each node's stored parents and content still hash to the node's id. Missing or
+//! corrupt blobs are reported, and can be repaired by copying them from a secondary blob repo
+//! (for instance a backup, or another replica) where they are intact.
+//!
+//! When the LFS threshold of the repo is given, the contents of the files at least that large are
+//! also checked against the blob they are stored in for the LFS protocol. A corrupt LFS blob is
+//! rewritten from the content of the file.
+
+#![deny(warnings)]
+
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+extern crate tokio_core;
+
+extern crate bytes;
+
+extern crate blobrepo;
+extern crate blobstore;
+extern crate cmdlib;
+extern crate futures_ext;
+extern crate mercurial;
+extern crate mercurial_types;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use clap::{App, ArgMatches};
+use failure::{Error, Result, SlogKVError};
+use futures::{Future, Stream};
+use futures::future::{self, Either};
+use slog::Logger;
+use tokio_core::reactor::Core;
+
+use blobrepo::BlobRepo;
+use blobstore::Blobstore;
+use cmdlib::BlobstoreType;
+use futures_ext::{BoxFuture, FutureExt, StreamExt};
+use mercurial::file::File;
+use mercurial_types::{BlobNode, Changeset, HgChangesetId, NodeHash, RepositoryId, Type, NULL_HASH};
+use mercurial_types::hash::Sha256;
+
+// How many changesets and manifest entries are checked concurrently
+const SCRUB_CONCURRENCY: usize = 100;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum NodeKind {
+    Changeset,
+    Manifest,
+    File,
+}
+
+impl fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            &NodeKind::Changeset => "changeset",
+            &NodeKind::Manifest => "manifest",
+            &NodeKind::File => "file",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug)]
+enum Problem {
+    Missing,
+    /// Parents and content hash to something else (or to nothing at all)
+    Corrupt(Option<NodeHash>),
+    /// The blobs are there but can't be decoded
+    Unreadable(Error),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Problem::Missing => write!(f, "missing"),
+            &Problem::Corrupt(Some(ref actual)) => write!(f, "corrupt (hashes to {})", actual),
+            &Problem::Corrupt(None) => write!(f, "corrupt (content can't be hashed)"),
+            &Problem::Unreadable(ref err) => write!(f, "unreadable ({})", err),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ScrubStats {
+    checked: usize,
+    missing: usize,
+    corrupt: usize,
+    repaired: usize,
+    lfs_checked: usize,
+    lfs_repaired: usize,
+}
+
+/// Where broken blobs can be copied from.
+#[derive(Clone)]
+struct Secondary {
+    repo: BlobRepo,
+    blobstore: Arc<Blobstore>,
+}
+
+#[derive(Clone)]
+struct Scrubber {
+    logger: Logger,
+    repo: BlobRepo,
+    blobstore: Arc<Blobstore>,
+    secondary: Option<Secondary>,
+    lfs_threshold: Option<u64>,
+    // Nodes that have already been checked, so shared subtrees are only visited once
+    visited: Arc<Mutex<HashSet<NodeHash>>>,
+    stats: Arc<Mutex<ScrubStats>>,
+}
+
+fn get_raw(
+    repo: &BlobRepo,
+    kind: NodeKind,
+    nodeid: NodeHash,
+) -> BoxFuture<Option<BlobNode>, Error> {
+    match kind {
+        NodeKind::Changeset => repo.get_raw_changeset(&HgChangesetId::new(nodeid)),
+        NodeKind::Manifest | NodeKind::File => repo.get_raw_node(&nodeid),
+    }
+}
+
+/// Check that the node's blobs are present in the repo and hash to `nodeid`. Resolves to the
+/// node if they do.
+fn verify(
+    repo: &BlobRepo,
+    kind: NodeKind,
+    nodeid: NodeHash,
+) -> BoxFuture<::std::result::Result<BlobNode, Problem>, Error> {
+    get_raw(repo, kind, nodeid)
+        .then(move |res| {
+            let verified = match res {
+                Ok(Some(node)) => {
+                    let actual = node.nodeid();
+                    if actual == Some(nodeid) {
+                        Ok(node)
+                    } else {
+                        Err(Problem::Corrupt(actual))
+                    }
+                }
+                Ok(None) => Err(Problem::Missing),
+                Err(err) => Err(Problem::Unreadable(err)),
+            };
+            Ok::<_, Error>(verified)
+        })
+        .boxify()
+}
+
+impl Scrubber {
+    /// Check a single node, repairing it if possible. Resolves to the node if it is intact
+    /// afterwards, i.e. if it is safe to look inside it.
+    fn scrub_node(&self, kind: NodeKind, nodeid: NodeHash) -> BoxFuture<Option<BlobNode>, Error> {
+        let this = self.clone();
+
+        verify(&self.repo, kind, nodeid)
+            .and_then(move |verified| {
+                this.stats.lock().expect("lock poisoned").checked += 1;
+                let problem = match verified {
+                    Ok(node) => return future::ok(Some(node)).boxify(),
+                    Err(problem) => problem,
+                };
+
+                error!(this.logger, "{} {} is {}", kind, nodeid, problem);
+                {
+                    let mut stats = this.stats.lock().expect("lock poisoned");
+                    match problem {
+                        Problem::Missing => stats.missing += 1,
+                        Problem::Corrupt(_) | Problem::Unreadable(_) => stats.corrupt += 1,
+                    }
+                }
+
+                match this.secondary.clone() {
+                    None => future::ok(None).boxify(),
+                    Some(secondary) => this.repair(secondary, kind, nodeid),
+                }
+            })
+            .boxify()
+    }
+
+    /// Copy the node's blobs over from the secondary repo, provided they are intact there.
+    fn repair(
+        &self,
+        secondary: Secondary,
+        kind: NodeKind,
+        nodeid: NodeHash,
+    ) -> BoxFuture<Option<BlobNode>, Error> {
+        let this = self.clone();
+
+        verify(&secondary.repo, kind, nodeid)
+            .and_then(move |verified| {
+                if let Err(problem) = verified {
+                    warn!(
+                        this.logger,
+                        "can't repair {} {}: {} in secondary", kind, nodeid, problem
+                    );
+                    return future::ok(None).boxify();
+                }
+
+                let keys = match kind {
+                    NodeKind::Changeset => future::ok(vec![
+                        secondary
+                            .repo
+                            .get_changeset_blobstore_key(&HgChangesetId::new(nodeid)),
+                    ]).boxify(),
+                    NodeKind::Manifest | NodeKind::File => {
+                        secondary.repo.get_node_blobstore_keys(&nodeid)
+                    }
+                };
+
+                let blobstore = this.blobstore.clone();
+                let copied = keys.and_then(move |keys| {
+                    let copies = keys.into_iter().map(move |key| {
+                        let blobstore = blobstore.clone();
+                        secondary
+                            .blobstore
+                            .get(key.clone())
+                            .and_then(move |value| match value {
+                                Some(value) => Either::A(blobstore.put(key, value)),
+                                None => Either::B(future::err(format_err!(
+                                    "{} vanished from secondary blobstore",
+                                    key
+                                ))),
+                            })
+                    });
+                    future::join_all(copies)
+                });
+
+                let repo = this.repo.clone();
+                copied
+                    .and_then(move |_| verify(&repo, kind, nodeid))
+                    .map(move |verified| match verified {
+                        Ok(node) => {
+                            info!(this.logger, "repaired {} {}", kind, nodeid);
+                            this.stats.lock().expect("lock poisoned").repaired += 1;
+                            Some(node)
+                        }
+                        Err(problem) => {
+                            warn!(
+                                this.logger,
+                                "{} {} is still {} after repair", kind, nodeid, problem
+                            );
+                            None
+                        }
+                    })
+                    .boxify()
+            })
+            .boxify()
+    }
+
+    /// Check the LFS blob of an intact file, if its content is large enough to be stored for the
+    /// LFS protocol.
+    fn scrub_lfs(&self, nodeid: NodeHash, node: BlobNode) -> BoxFuture<(), Error> {
+        let content = match (self.lfs_threshold, File::new(node).content()) {
+            (Some(threshold), Some(content)) if content.len() as u64 >= threshold => {
+                Bytes::from(content)
+            }
+            _ => return future::ok(()).boxify(),
+        };
+        let oid = Sha256::from(content.as_ref());
+        let this = self.clone();
+
+        self.repo
+            .get_lfs_content(&oid)
+            .then(move |res| {
+                let intact = match res {
+                    // Files pushed before the threshold was set aren't stored for LFS
+                    Ok(None) => return future::ok(()).boxify(),
+                    Ok(Some(lfs)) => Sha256::from(lfs.as_ref()) == oid,
+                    Err(_) => false,
+                };
+                this.stats.lock().expect("lock poisoned").lfs_checked += 1;
+                if intact {
+                    return future::ok(()).boxify();
+                }
+
+                error!(this.logger, "LFS blob {} of file {} is corrupt", oid, nodeid);
+                this.repo
+                    .upload_lfs_content(oid, content)
+                    .map(move |()| {
+                        info!(this.logger, "repaired LFS blob {}", oid);
+                        this.stats.lock().expect("lock poisoned").lfs_repaired += 1;
+                    })
+                    .boxify()
+            })
+            .boxify()
+    }
+
+    fn scrub_tree_entry(&self, kind: NodeKind, nodeid: NodeHash) -> BoxFuture<(), Error> {
+        if nodeid == NULL_HASH || !self.visited.lock().expect("lock poisoned").insert(nodeid) {
+            return future::ok(()).boxify();
+        }
+
+        let this = self.clone();
+        self.scrub_node(kind, nodeid)
+            .and_then(move |node| {
+                let node = match node {
+                    Some(node) => node,
+                    None => return future::ok(()).boxify(),
+                };
+                if kind == NodeKind::File {
+                    return this.scrub_lfs(nodeid, node);
+                }
+                let repo = this.repo.clone();
+                repo.get_manifest_by_nodeid(&nodeid)
+                    .and_then(move |manifest| {
+                        manifest
+                            .list()
+                            .map(move |child| {
+                                let kind = match child.get_type() {
+                                    Type::Tree => NodeKind::Manifest,
+                                    Type::File | Type::Executable | Type::Symlink => NodeKind::File,
+                                };
+                                this.scrub_tree_entry(kind, child.get_hash().into_nodehash())
+                            })
+                            .buffer_unordered(SCRUB_CONCURRENCY)
+                            .for_each(|()| Ok(()))
+                    })
+                    .boxify()
+            })
+            .boxify()
+    }
+
+    fn scrub_changeset(&self, csid: NodeHash) -> BoxFuture<(), Error> {
+        let this = self.clone();
+
+        self.scrub_node(NodeKind::Changeset, csid)
+            .and_then(move |node| {
+                if node.is_none() {
+                    return future::ok(()).boxify();
+                }
+                this.repo
+                    .get_changeset_by_changesetid(&HgChangesetId::new(csid))
+                    .and_then(move |cs| {
+                        this.scrub_tree_entry(NodeKind::Manifest, cs.manifestid().into_nodehash())
+                    })
+                    .boxify()
+            })
+            .boxify()
+    }
+
+    fn scrub(&self) -> BoxFuture<(), Error> {
+        let this = self.clone();
+
+        self.repo
+            .get_changesets()
+            .enumerate()
+            .map(move |(seq, csid)| {
+                debug!(this.logger, "{}: scrubbing changeset {}", seq, csid);
+                this.scrub_changeset(csid)
+            })
+            .buffer_unordered(SCRUB_CONCURRENCY)
+            .for_each(|()| Ok(()))
+            .boxify()
+    }
+}
+
+fn open_repo(
+    logger: &Logger,
+    path: &Path,
+    blobtype: BlobstoreType,
+    repoid: RepositoryId,
+) -> Result<(BlobRepo, Arc<Blobstore>)> {
+    let (repo, blobstore) = cmdlib::open_repo(logger, path, blobtype, repoid)?;
+    // Blobs are copied as they are stored, compressed or not
+    Ok((repo, Arc::new(blobstore)))
+}
+
+fn run_scrub(
+    logger: &Logger,
+    path: &Path,
+    secondary_path: Option<&Path>,
+    blobtype: BlobstoreType,
+    repoid: RepositoryId,
+    lfs_threshold: Option<u64>,
+) -> Result<()> {
+    let mut core = Core::new()?;
+
+    let (repo, blobstore) = open_repo(logger, path, blobtype, repoid)?;
+    let secondary = match secondary_path {
+        Some(secondary_path) => {
+            let (repo, blobstore) = open_repo(logger, secondary_path, blobtype, repoid)?;
+            Some(Secondary { repo, blobstore })
+        }
+        None => None,
+    };
+
+    let scrubber = Scrubber {
+        logger: logger.clone(),
+        repo,
+        blobstore,
+        secondary,
+        lfs_threshold,
+        visited: Arc::new(Mutex::new(HashSet::new())),
+        stats: Arc::new(Mutex::new(ScrubStats::default())),
+    };
+
+    core.run(scrubber.scrub())?;
+
+    let stats = scrubber.stats.lock().expect("lock poisoned");
+    info!(
+        logger,
+        "Checked {} nodes: {} missing, {} corrupt, {} repaired",
+        stats.checked,
+        stats.missing,
+        stats.corrupt,
+        stats.repaired
+    );
+    info!(
+        logger,
+        "Checked {} LFS blobs: {} corrupt ones repaired",
+        stats.lfs_checked,
+        stats.lfs_repaired
+    );
+
+    let broken = stats.missing + stats.corrupt - stats.repaired;
+    if broken > 0 {
+        bail_msg!("{} nodes are missing or corrupt", broken);
+    }
+    Ok(())
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("blob repo scrubber")
+        .version("0.0.0")
+        .about("check that every node in a blob repo matches its hash")
+        .args_from_usage(
+            r#"
+            <REPO>                       'path to the blob repo'
+
+            --repo-id [ID]               'numerical id of the repo. Default: 0'
+            --repair-from [SECONDARY]    'path to a blob repo (with the same blobstore type)
+                                          to copy missing or corrupt blobs from'
+            --lfs-threshold [SIZE]       'LFS threshold of the repo, in bytes. The LFS blobs
+                                          of the files at least that large are checked too'
+
+            -d, --debug                  'print debug level output'
+        "#,
+        )
+        .arg(cmdlib::blobstore_arg())
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let root_log = cmdlib::get_logger(&matches);
+
+    fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<()> {
+        let path = Path::new(matches.value_of("REPO").unwrap());
+        let secondary_path = matches.value_of("repair-from").map(Path::new);
+
+        run_scrub(
+            root_log,
+            path,
+            secondary_path,
+            cmdlib::get_blobstore_type(&matches),
+            cmdlib::get_repo_id(&matches),
+            matches.value_of("lfs-threshold").map(|size| {
+                size.parse()
+                    .expect("lfs-threshold must be positive integer")
+            }),
+        )
+    }
+
+    if let Err(e) = run(&root_log, matches) {
+        error!(root_log, "Scrub failed"; SlogKVError(e));
+        std::process::exit(1);
+    }
+}
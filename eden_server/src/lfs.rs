@@ -0,0 +1,166 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Git LFS batch API, which tells the LFS clients where they can download the large files served
+//! as LFS pointers and where they can upload the ones they push.
+//!
+//! See https://github.com/git-lfs/git-lfs/blob/master/docs/api/batch.md
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use mercurial_types::hash::Sha256;
+
+use {Error, Result};
+
+pub const LFS_CONTENT_TYPE: &str = "application/vnd.git-lfs+json";
+
+/// Largest object that can be uploaded.
+pub const MAX_OBJECT_SIZE: u64 = 1 << 30;
+
+/// The only transfer adapter that is supported.
+const TRANSFER_BASIC: &str = "basic";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Download,
+    Upload,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    pub operation: Operation,
+    #[serde(default)]
+    pub transfers: Vec<String>,
+    pub objects: Vec<RequestObject>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestObject {
+    pub oid: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    pub transfer: &'static str,
+    pub objects: Vec<ResponseObject>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResponseObject {
+    pub oid: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actions: Option<Actions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ObjectError>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Actions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<Action>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<Action>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Action {
+    pub href: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ObjectError {
+    pub code: u16,
+    pub message: String,
+}
+
+impl BatchRequest {
+    /// Answers the request. `url_prefix` is the url of the repo that the links of the response
+    /// start with.
+    pub fn respond(
+        self,
+        repo: Arc<BlobRepo>,
+        url_prefix: String,
+    ) -> BoxFuture<BatchResponse, Error> {
+        if !self.transfers.is_empty() && !self.transfers.iter().any(|t| t == TRANSFER_BASIC) {
+            return future::err(format_err!(
+                "unsupported transfer adapters {:?}, only {} is supported",
+                self.transfers,
+                TRANSFER_BASIC
+            )).boxify();
+        }
+
+        let operation = self.operation;
+        let objects: Result<Vec<_>> = self.objects
+            .into_iter()
+            .map(|object| Ok((Sha256::from_str(&object.oid)?, object.size)))
+            .collect();
+        let objects = match objects {
+            Ok(objects) => objects,
+            Err(err) => return future::err(err).boxify(),
+        };
+        let objects = objects.into_iter().map(move |(oid, size)| {
+            let url_prefix = url_prefix.clone();
+            repo.lfs_content_exists(&oid).map(move |exists| {
+                let (actions, error) = match (operation, exists) {
+                    (Operation::Download, true) => {
+                        let href = format!("{}/lfs/download/{}", url_prefix, oid);
+                        let actions = Actions {
+                            download: Some(Action { href }),
+                            ..Default::default()
+                        };
+                        (Some(actions), None)
+                    }
+                    (Operation::Download, false) => {
+                        let error = ObjectError {
+                            code: 404,
+                            message: "object does not exist".into(),
+                        };
+                        (None, Some(error))
+                    }
+                    // Nothing to do, the server already has it
+                    (Operation::Upload, true) => (None, None),
+                    (Operation::Upload, false) if size > MAX_OBJECT_SIZE => {
+                        let error = ObjectError {
+                            code: 413,
+                            message: format!("object is larger than {} bytes", MAX_OBJECT_SIZE),
+                        };
+                        (None, Some(error))
+                    }
+                    (Operation::Upload, false) => {
+                        // The size is checked when the object is uploaded
+                        let href = format!("{}/lfs/upload/{}/{}", url_prefix, oid, size);
+                        let actions = Actions {
+                            upload: Some(Action { href }),
+                            ..Default::default()
+                        };
+                        (Some(actions), None)
+                    }
+                };
+                ResponseObject {
+                    oid: oid.to_string(),
+                    size,
+                    actions,
+                    error,
+                }
+            })
+        });
+
+        future::join_all(objects)
+            .map(|objects| BatchResponse {
+                transfer: TRANSFER_BASIC,
+                objects,
+            })
+            .boxify()
+    }
+}
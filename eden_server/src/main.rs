@@ -13,6 +13,16 @@
 /// # Request examples
 /// ```
 /// /REPO/cs/HASH/roottreemanifestid - returns root tree manifest node for the HASH
+/// /REPO/cs/HASH - returns the metadata of the changeset HASH
+/// /REPO/log/HASH?limit=N - returns the metadata of HASH and of its ancestors, newest first
+/// /REPO/bookmarks - returns the bookmarks and the changesets they point to
+/// /REPO/batch/treenode - returns the content of many trees, see `BatchRequest`
+/// /REPO/batch/blob - returns the content of many blobs, see `BatchRequest`
+/// /REPO/diff/BASE/TARGET?path=PREFIX - returns what changed from changeset BASE to TARGET,
+///                                     under PREFIX if it's given, as one JSON record per line
+/// /REPO/history/HASH/PATH?limit=N&skip=M - returns the history of the file revision HASH at PATH
+/// /REPO/objects/batch - git LFS batch API, the large files are then downloaded from
+///                       /REPO/lfs/download/OID and uploaded to /REPO/lfs/upload/OID/SIZE
 /// ```
 extern crate ascii;
 extern crate blobrepo;
@@ -31,6 +41,8 @@ extern crate mercurial_types;
 extern crate native_tls;
 extern crate openssl;
 extern crate regex;
+extern crate repoinfo;
+extern crate revset;
 extern crate scuba;
 extern crate secure_utils;
 extern crate serde;
@@ -44,41 +56,67 @@ extern crate tokio_core;
 extern crate tokio_proto;
 extern crate tokio_tls;
 extern crate toml;
+extern crate url;
 
-use std::collections::HashMap;
+mod lfs;
+
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsString;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
 use std::os::unix::ffi::OsStringExt;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::string::ToString;
 use std::sync::Arc;
+use std::u64;
 use tokio_core::reactor::Core;
 
-use blobrepo::BlobRepo;
-use bytes::Bytes;
+use ascii::AsciiString;
+use blobrepo::{BlobChangeset, BlobRepo};
+use blobrepo::ErrorKind as BlobRepoErrorKind;
+use bytes::{BufMut, Bytes, BytesMut};
 use clap::App;
-use futures::{Future, IntoFuture, Stream};
-use futures::sync::oneshot;
+use futures::{Future, IntoFuture, Sink, Stream};
+use futures::stream;
+use futures::sync::{mpsc, oneshot};
 use futures_cpupool::CpuPool;
-use futures_ext::{BoxFuture, FutureExt};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use futures_stats::{Stats, Timed};
-use hyper::StatusCode;
+use hyper::{Body, Chunk, Method, StatusCode};
+use hyper::header::{AcceptRanges, ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec,
+                    Host, Range, RangeUnit};
 use hyper::server::{Http, Request, Response, Service};
-use mercurial_types::{Changeset, MPathElement, NodeHash, RepositoryId};
+use mercurial_types::{Changeset, Entry, MPath, MPathElement, Manifest, NodeHash, Parents,
+                      PathMatcher, RepositoryId};
+use mercurial_types::hash::Sha256;
+use mercurial_types::manifest_utils::{changed_entry_stream_with_pruner, ChangedEntry,
+                                      EntryStatus};
 use mercurial_types::nodehash::HgChangesetId;
 use native_tls::TlsAcceptor;
 use native_tls::backend::openssl::TlsAcceptorBuilderExt;
 use openssl::ssl::{SSL_VERIFY_FAIL_IF_NO_PEER_CERT, SSL_VERIFY_PEER};
 use regex::{Captures, Regex};
+use repoinfo::RepoGenCache;
+use revset::AncestorsNodeStream;
 use scuba::{ScubaClient, ScubaSample};
 use slog::{Drain, Level, Logger};
 use tokio_proto::TcpServer;
 use tokio_tls::proto;
+use url::form_urlencoded;
+use url::percent_encoding::percent_decode;
 
 pub use failure::{DisplayChain, Error, Result, ResultExt};
 
+/// Errors that are answered with their own status code, the other ones get a 404.
+#[derive(Debug, Fail)]
+enum ErrorKind {
+    #[fail(display = "request body is larger than {} bytes", _0)] BodyTooLarge(u64),
+    #[fail(display = "batch request has {} keys, at most {} are allowed", _0, _1)]
+    TooManyBatchKeys(usize, usize),
+    #[fail(display = "no LFS object {}", _0)] LfsObjectNotFound(Sha256),
+}
+
 type NameToRepo = HashMap<String, Arc<BlobRepo>>;
 type UrlParseFunc = fn(Captures) -> Result<ParsedUrl>;
 
@@ -96,6 +134,31 @@ const SCUBA_OPERATION_GET_TREE_CONTENT: &'static str = "get_tree_content";
 const SCUBA_OPERATION_GET_TREE_CONTENT_LIGHT: &'static str = "get_tree_content_light";
 const SCUBA_OPERATION_GET_MENIFEST: &'static str = "get_root_tree_manifest_id";
 const SCUBA_OPERATION_GET_BLOB_CONTENT: &'static str = "get_blob_content";
+const SCUBA_OPERATION_GET_CHANGESET: &'static str = "get_changeset";
+const SCUBA_OPERATION_GET_LOG: &'static str = "get_log";
+const SCUBA_OPERATION_GET_BOOKMARKS: &'static str = "get_bookmarks";
+const SCUBA_OPERATION_BATCH_GET_TREE_CONTENT: &'static str = "batch_get_tree_content";
+const SCUBA_OPERATION_BATCH_GET_BLOB_CONTENT: &'static str = "batch_get_blob_content";
+const SCUBA_OPERATION_GET_DIFF: &'static str = "get_diff";
+const SCUBA_OPERATION_GET_FILE_HISTORY: &'static str = "get_file_history";
+const SCUBA_OPERATION_LFS_BATCH: &'static str = "lfs_batch";
+const SCUBA_OPERATION_LFS_DOWNLOAD: &'static str = "lfs_download";
+const SCUBA_OPERATION_LFS_UPLOAD: &'static str = "lfs_upload";
+
+/// Number of trees or blobs of a batch request that are fetched at the same time
+const BATCH_FETCHES: usize = 100;
+/// Largest number of keys of a batch request
+const MAX_BATCH_KEYS: usize = 10_000;
+/// Header of the response to a batch request that says how many records follow
+const BATCH_RECORDS_HEADER: &'static str = "X-Batch-Records";
+/// Number of entries of a tree whose metadata is fetched at the same time for /REPO/treenode
+const TREE_ENTRY_FETCHES: usize = 100;
+/// Number of changesets returned by /REPO/log if the request has no limit
+const DEFAULT_LOG_LIMIT: u64 = 100;
+/// Number of changesets that are fetched at the same time for /REPO/log
+const LOG_CHANGESET_FETCHES: usize = 100;
+/// Largest body of the requests that are sent as JSON
+const MAX_REQUEST_SIZE: u64 = 16 * 1024 * 1024;
 
 fn parse_capture<T>(caps: &Captures, index: usize) -> Result<T>
 where
@@ -115,6 +178,23 @@ fn parse_root_treemanifest_id_url(caps: Captures) -> Result<ParsedUrl> {
     Ok(ParsedUrl::RootTreeHgManifestId(repo, hash))
 }
 
+fn parse_changeset_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let hash = parse_capture::<NodeHash>(&caps, 2)?;
+    Ok(ParsedUrl::Changeset(repo, hash))
+}
+
+fn parse_log_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let hash = parse_capture::<NodeHash>(&caps, 2)?;
+    Ok(ParsedUrl::Log(repo, hash))
+}
+
+fn parse_bookmarks_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    Ok(ParsedUrl::Bookmarks(repo))
+}
+
 fn parse_tree_content_url(caps: Captures) -> Result<ParsedUrl> {
     let repo = parse_capture::<String>(&caps, 1)?;
     let hash = parse_capture::<NodeHash>(&caps, 2)?;
@@ -133,6 +213,70 @@ fn parse_blob_content_url(caps: Captures) -> Result<ParsedUrl> {
     Ok(ParsedUrl::BlobContent(repo, hash))
 }
 
+fn parse_batch_tree_content_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    Ok(ParsedUrl::BatchTreeContent(repo))
+}
+
+fn parse_batch_blob_content_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    Ok(ParsedUrl::BatchBlobContent(repo))
+}
+
+fn parse_diff_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let base = parse_capture::<NodeHash>(&caps, 2)?;
+    let target = parse_capture::<NodeHash>(&caps, 3)?;
+    Ok(ParsedUrl::Diff(repo, base, target))
+}
+
+fn parse_file_history_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let hash = parse_capture::<NodeHash>(&caps, 2)?;
+    let path = caps.get(3).expect("incorrect url parsing regex").as_str();
+    let path = MPath::new(percent_decode(path.as_bytes()).collect::<Vec<_>>())?;
+    Ok(ParsedUrl::FileHistory(repo, hash, path))
+}
+
+fn parse_lfs_batch_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    Ok(ParsedUrl::LfsBatch(repo))
+}
+
+fn parse_lfs_download_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let oid = parse_capture::<Sha256>(&caps, 2)?;
+    Ok(ParsedUrl::LfsDownload(repo, oid))
+}
+
+fn parse_lfs_upload_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let oid = parse_capture::<Sha256>(&caps, 2)?;
+    let size = parse_capture::<u64>(&caps, 3)?;
+    Ok(ParsedUrl::LfsUpload(repo, oid, size))
+}
+
+/// Parses the value of the query string parameter `name`, if it's there.
+fn parse_query_param<T>(query: Option<&str>, name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    let query = match query {
+        Some(query) => query,
+        None => return Ok(None),
+    };
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        if key == name {
+            let value = str::parse::<T>(&value)
+                .map_err(Error::from)
+                .with_context(|_| format!("invalid value of query parameter {}", name))?;
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
 /// Generic url-handling function
 /// Accepts vector of tuples (regex, url handling function)
 /// If url matches regex then url handling function is called
@@ -147,9 +291,32 @@ fn parse_url(url: &str, routes: &[Route]) -> Result<ParsedUrl> {
 
 enum ParsedUrl {
     RootTreeHgManifestId(String, NodeHash),
+    Changeset(String, NodeHash),
+    Log(String, NodeHash),
+    Bookmarks(String),
     TreeContent(String, NodeHash),
     TreeContentLight(String, NodeHash),
     BlobContent(String, NodeHash),
+    BatchTreeContent(String),
+    BatchBlobContent(String),
+    Diff(String, NodeHash, NodeHash),
+    FileHistory(String, NodeHash, MPath),
+    LfsBatch(String),
+    LfsDownload(String, Sha256),
+    LfsUpload(String, Sha256, u64),
+}
+
+impl ParsedUrl {
+    /// The requests that send data have to use the method that the LFS protocol expects.
+    fn allows_method(&self, method: &Method) -> bool {
+        match *self {
+            ParsedUrl::BatchTreeContent(..) => *method == Method::Post,
+            ParsedUrl::BatchBlobContent(..) => *method == Method::Post,
+            ParsedUrl::LfsBatch(..) => *method == Method::Post,
+            ParsedUrl::LfsUpload(..) => *method == Method::Put,
+            _ => true,
+        }
+    }
 }
 
 lazy_static! {
@@ -158,9 +325,20 @@ lazy_static! {
             // Workaround for https://github.com/rust-lang/rust/issues/20178
             (r"^/(\w+)/cs/(\w+)/roottreemanifestid/?$",
             parse_root_treemanifest_id_url as UrlParseFunc),
+            (r"^/(\w+)/cs/(\w+)/?$", parse_changeset_url as UrlParseFunc),
+            (r"^/(\w+)/log/(\w+)/?$", parse_log_url as UrlParseFunc),
+            (r"^/(\w+)/bookmarks/?$", parse_bookmarks_url as UrlParseFunc),
             (r"^/(\w+)/treenode/(\w+)/?$", parse_tree_content_url as UrlParseFunc),
             (r"^/(\w+)/treenode_simple/(\w+)/?$", parse_tree_content_light_url as UrlParseFunc),
             (r"^/(\w+)/blob/(\w+)/?$", parse_blob_content_url as UrlParseFunc),
+            (r"^/(\w+)/batch/treenode/?$", parse_batch_tree_content_url as UrlParseFunc),
+            (r"^/(\w+)/batch/blob/?$", parse_batch_blob_content_url as UrlParseFunc),
+            (r"^/(\w+)/diff/(\w+)/(\w+)/?$", parse_diff_url as UrlParseFunc),
+            (r"^/(\w+)/history/(\w+)/(.+)$", parse_file_history_url as UrlParseFunc),
+            (r"^/(\w+)/objects/batch/?$", parse_lfs_batch_url as UrlParseFunc),
+            (r"^/(\w+)/lfs/download/([0-9a-fA-F]{64})/?$", parse_lfs_download_url as UrlParseFunc),
+            (r"^/(\w+)/lfs/upload/([0-9a-fA-F]{64})/(\d+)/?$",
+            parse_lfs_upload_url as UrlParseFunc),
         ].into_iter().map(|(re, func)| Route(Regex::new(re).expect("bad regex"), func)).collect()
     };
 }
@@ -205,12 +383,221 @@ impl TreeMetadata {
     }
 }
 
+#[derive(Serialize)]
+struct ChangesetMetadata {
+    id: NodeHash,
+    manifest: NodeHash,
+    author: String,
+    /// Seconds since the epoch
+    time: u64,
+    /// Offset from UTC in seconds, as in Mercurial (positive west of UTC)
+    tz: i32,
+    message: String,
+    extras: BTreeMap<String, String>,
+    parents: Vec<NodeHash>,
+    files: Vec<PathBuf>,
+}
+
+impl ChangesetMetadata {
+    fn new(cs: &BlobChangeset) -> ChangesetMetadata {
+        let lossy = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        let (p1, p2) = cs.parents().get_nodes();
+        ChangesetMetadata {
+            id: cs.get_changeset_id().into_nodehash(),
+            manifest: cs.manifestid().clone().into_nodehash(),
+            author: lossy(cs.user()),
+            time: cs.time().time,
+            tz: cs.time().tz,
+            message: lossy(cs.comments()),
+            extras: cs.extra()
+                .iter()
+                .map(|(key, value)| (lossy(key), lossy(value)))
+                .collect(),
+            parents: p1.into_iter().chain(p2).cloned().collect(),
+            files: cs.files()
+                .iter()
+                .map(|path| PathBuf::from(OsString::from_vec(path.to_vec())))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DiffEntry {
+    path: PathBuf,
+    status: &'static str,
+    old_node: Option<NodeHash>,
+    new_node: Option<NodeHash>,
+    #[serde(rename = "type")]
+    ty: mercurial_types::Type,
+}
+
+impl DiffEntry {
+    fn new(changed_entry: ChangedEntry) -> DiffEntry {
+        let (path, ty) = {
+            let (entry, path) = changed_entry_path(&changed_entry);
+            (path, entry.get_type())
+        };
+        let (status, old, new) = match changed_entry.status {
+            EntryStatus::Added(new) => ("added", None, Some(new)),
+            EntryStatus::Deleted(old) => ("deleted", Some(old), None),
+            EntryStatus::Modified(new, old) => ("modified", Some(old), Some(new)),
+        };
+
+        DiffEntry {
+            path: PathBuf::from(OsString::from_vec(path.to_vec())),
+            status,
+            old_node: old.map(|old| old.get_hash().into_nodehash()),
+            new_node: new.map(|new| new.get_hash().into_nodehash()),
+            ty,
+        }
+    }
+}
+
+/// The entry of a changed entry that is in the target of a diff, or in its base if it was
+/// deleted, and its path.
+fn changed_entry_path(changed_entry: &ChangedEntry) -> (&Box<Entry + Sync>, MPath) {
+    let entry = match changed_entry.status {
+        EntryStatus::Added(ref entry) => entry,
+        EntryStatus::Deleted(ref entry) => entry,
+        EntryStatus::Modified(ref entry, _) => entry,
+    };
+    (entry, changed_entry.path.join_element(entry.get_name()))
+}
+
+fn get_root_manifest(
+    repo: Arc<BlobRepo>,
+    changesetid: NodeHash,
+) -> BoxFuture<Box<Manifest + Sync>, Error> {
+    repo.get_changeset_by_changesetid(&HgChangesetId::new(changesetid))
+        .and_then(move |cs| {
+            let manifestid = cs.manifestid().clone().into_nodehash();
+            repo.get_manifest_by_nodeid(&manifestid)
+        })
+        .boxify()
+}
+
+#[derive(Serialize)]
+struct FileHistoryEntry {
+    filenode: NodeHash,
+    p1: Option<NodeHash>,
+    p2: Option<NodeHash>,
+    linknode: NodeHash,
+    copyfrom: Option<CopyFrom>,
+}
+
+#[derive(Serialize)]
+struct CopyFrom {
+    path: PathBuf,
+    filenode: NodeHash,
+}
+
+impl FileHistoryEntry {
+    fn new(
+        filenode: NodeHash,
+        parents: Parents,
+        linknode: NodeHash,
+        copy: Option<(MPath, NodeHash)>,
+    ) -> FileHistoryEntry {
+        let (p1, p2) = parents.get_nodes();
+        FileHistoryEntry {
+            filenode,
+            p1: p1.cloned(),
+            p2: p2.cloned(),
+            linknode,
+            copyfrom: copy.map(|(path, filenode)| CopyFrom {
+                path: PathBuf::from(OsString::from_vec(path.to_vec())),
+                filenode,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
 struct TreeMetadataOptions {
     fetch_size: bool,
 }
 
+/// Body of the batch requests: the trees or blobs to fetch, identified the way Eden does.
+///
+/// The response has one record per key, in the same order, made of the node (20 bytes), the
+/// length of the path (big-endian u32), the path, the length of the content (big-endian u64)
+/// and the content. The content of a tree is the same as the one returned by /REPO/treenode.
+/// The number of records is sent in the `BATCH_RECORDS_HEADER` header, so that a response that
+/// was cut short can be told from a complete one.
+#[derive(Deserialize)]
+struct BatchRequest {
+    keys: Vec<BatchKey>,
+}
+
+#[derive(Deserialize)]
+struct BatchKey {
+    path: String,
+    node: NodeHash,
+}
+
+/// The number of records of the response to a batch request, and the records.
+type BatchResult = BoxFuture<(usize, BoxStream<Bytes, Error>), Error>;
+
+/// The response to a batch request, with the number of records that follow in its
+/// `BATCH_RECORDS_HEADER` header.
+fn batch_response(
+    mut resp: Response,
+    batch: BatchResult,
+) -> BoxFuture<(Response, BoxStream<Bytes, Error>), Error> {
+    batch
+        .map(move |(count, records)| {
+            resp.headers_mut()
+                .set_raw(BATCH_RECORDS_HEADER, count.to_string());
+            (resp, records)
+        })
+        .boxify()
+}
+
+fn batch_record(key: &BatchKey, content: &[u8]) -> Bytes {
+    let path = key.path.as_bytes();
+    let mut record = BytesMut::with_capacity(20 + 4 + path.len() + 8 + content.len());
+    record.put_slice(key.node.sha1().as_ref());
+    record.put_u32::<bytes::BigEndian>(path.len() as u32);
+    record.put_slice(path);
+    record.put_u64::<bytes::BigEndian>(content.len() as u64);
+    record.put_slice(content);
+    record.freeze()
+}
+
+/// Fetches the metadata of the entries of a tree on the cpupool, at most `entry_fetches` at a
+/// time.
+fn fetch_tree_content(
+    repo: Arc<BlobRepo>,
+    cpupool: Arc<CpuPool>,
+    hash: &NodeHash,
+    options: TreeMetadataOptions,
+    entry_fetches: usize,
+) -> BoxFuture<Bytes, Error> {
+    repo.get_manifest_by_nodeid(&hash)
+        .map(|manifest| manifest.list())
+        .flatten_stream()
+        .map(move |entry| cpupool.spawn(TreeMetadata::from_entry(entry, &options)))
+        .buffer_unordered(entry_fetches)
+        .from_err()
+        .map(|metadata| {
+            let err_msg = format!(
+                "failed to get metadata for {}",
+                metadata.path.to_string_lossy()
+            );
+            serde_json::to_value(&metadata).unwrap_or(err_msg.into())
+        })
+        .collect()
+        .map(|entries| {
+            let x: serde_json::Value = entries.into();
+            Bytes::from(x.to_string().into_bytes())
+        })
+        .boxify()
+}
+
 struct EdenServer {
     name_to_repo: NameToRepo,
+    repo_generation: RepoGenCache,
     cpupool: Arc<CpuPool>,
     logger: Logger,
     scuba: Arc<ScubaClient>,
@@ -220,9 +607,15 @@ impl EdenServer
 where
     EdenServer: Service,
 {
-    fn new(name_to_repo: NameToRepo, cpupool: Arc<CpuPool>, logger: Logger) -> EdenServer {
+    fn new(
+        name_to_repo: NameToRepo,
+        repo_generation: RepoGenCache,
+        cpupool: Arc<CpuPool>,
+        logger: Logger,
+    ) -> EdenServer {
         EdenServer {
             name_to_repo,
+            repo_generation,
             cpupool,
             logger,
             scuba: Arc::new(ScubaClient::new(SCUBA_TABLE)),
@@ -254,6 +647,74 @@ where
             .boxify()
     }
 
+    fn get_changeset(
+        &self,
+        reponame: String,
+        changesetid: &HgChangesetId,
+    ) -> Box<futures::Future<Item = Bytes, Error = Error> + Send> {
+        let repo = match self.name_to_repo.get(&reponame) {
+            Some(repo) => repo,
+            None => {
+                return futures::future::err(failure::err_msg("unknown repo")).boxify();
+            }
+        };
+
+        repo.get_changeset_by_changesetid(changesetid)
+            .and_then(|cs| {
+                let metadata = ChangesetMetadata::new(&cs);
+                Ok(Bytes::from(serde_json::to_vec(&metadata)?))
+            })
+            .boxify()
+    }
+
+    fn get_log(
+        &self,
+        reponame: String,
+        hash: NodeHash,
+        query: Option<String>,
+    ) -> Box<futures::Future<Item = Bytes, Error = Error> + Send> {
+        let repo = match self.name_to_repo.get(&reponame) {
+            Some(repo) => repo.clone(),
+            None => {
+                return futures::future::err(failure::err_msg("unknown repo")).boxify();
+            }
+        };
+        let limit = match parse_query_param::<u64>(query.as_ref().map(String::as_str), "limit") {
+            Ok(limit) => limit.unwrap_or(DEFAULT_LOG_LIMIT),
+            Err(err) => return futures::future::err(err).boxify(),
+        };
+
+        AncestorsNodeStream::new(&repo, self.repo_generation.clone(), hash)
+            .take(limit)
+            .map(move |hash| repo.get_changeset_by_changesetid(&HgChangesetId::new(hash)))
+            .buffered(LOG_CHANGESET_FETCHES)
+            .map(|cs| ChangesetMetadata::new(&cs))
+            .collect()
+            .and_then(|log| Ok(Bytes::from(serde_json::to_vec(&log)?)))
+            .boxify()
+    }
+
+    fn get_bookmarks(
+        &self,
+        reponame: String,
+    ) -> Box<futures::Future<Item = Bytes, Error = Error> + Send> {
+        let repo = match self.name_to_repo.get(&reponame) {
+            Some(repo) => repo,
+            None => {
+                return futures::future::err(failure::err_msg("unknown repo")).boxify();
+            }
+        };
+
+        repo.get_bookmarks_by_prefix(&AsciiString::new())
+            .map(|(name, changesetid)| (name.to_string(), changesetid.into_nodehash()))
+            .collect()
+            .and_then(|bookmarks| {
+                let bookmarks: BTreeMap<_, _> = bookmarks.into_iter().collect();
+                Ok(Bytes::from(serde_json::to_vec(&bookmarks)?))
+            })
+            .boxify()
+    }
+
     fn get_tree_content(
         &self,
         reponame: String,
@@ -261,38 +722,262 @@ where
         options: TreeMetadataOptions,
     ) -> Box<futures::Future<Item = Bytes, Error = Error> + Send> {
         let repo = match self.name_to_repo.get(&reponame) {
-            Some(repo) => repo,
+            Some(repo) => repo.clone(),
+            None => {
+                return futures::future::err(failure::err_msg("unknown repo")).boxify();
+            }
+        };
+
+        fetch_tree_content(repo, self.cpupool.clone(), hash, options, TREE_ENTRY_FETCHES)
+    }
+
+    fn batch_get_tree_content(&self, reponame: String, body: Body) -> BatchResult {
+        let repo = match self.name_to_repo.get(&reponame) {
+            Some(repo) => repo.clone(),
             None => {
                 return futures::future::err(failure::err_msg("unknown repo")).boxify();
             }
         };
 
         let cpupool = self.cpupool.clone();
-        repo.get_manifest_by_nodeid(&hash)
-            .map(|manifest| manifest.list())
-            .flatten_stream()
-            .map(move |entry| cpupool.spawn(TreeMetadata::from_entry(entry, &options)))
-            .buffer_unordered(100) // Schedules 100 futures on cpupool
-            .from_err()
-            .map(|metadata| {
-                let err_msg = format!(
-                    "failed to get metadata for {}",
-                    metadata.path.to_string_lossy()
-                );
-                serde_json::to_value(&metadata).unwrap_or(err_msg.into())
+        self.batch_fetch(body, move |key| {
+            let options = TreeMetadataOptions { fetch_size: true };
+            // The entries of a tree are fetched one at a time, so that there are never more than
+            // BATCH_FETCHES fetches going on for the whole request
+            fetch_tree_content(repo.clone(), cpupool.clone(), &key.node, options, 1)
+                .map(move |content| batch_record(&key, &content))
+                .boxify()
+        })
+    }
+
+    fn batch_get_blob_content(&self, reponame: String, body: Body) -> BatchResult {
+        let repo = match self.name_to_repo.get(&reponame) {
+            Some(repo) => repo.clone(),
+            None => {
+                return futures::future::err(failure::err_msg("unknown repo")).boxify();
+            }
+        };
+
+        self.batch_fetch(body, move |key| {
+            repo.get_file_content(&key.node)
+                .map(move |content| batch_record(&key, &content))
+                .boxify()
+        })
+    }
+
+    /// Fetches the keys of a batch request on the cpupool, at most `BATCH_FETCHES` at a time, and
+    /// outputs the records in the order of the request, along with how many there are.
+    fn batch_fetch<F>(&self, body: Body, fetch: F) -> BatchResult
+    where
+        F: Fn(BatchKey) -> BoxFuture<Bytes, Error> + Send + 'static,
+    {
+        let cpupool = self.cpupool.clone();
+        read_body(body, MAX_REQUEST_SIZE)
+            .and_then(|body| {
+                serde_json::from_slice::<BatchRequest>(&body)
+                    .context("invalid batch request")
+                    .map_err(Error::from)
             })
-            .collect()
-            .map(|entries| {
-                let x: serde_json::Value = entries.into();
-                Bytes::from(x.to_string().into_bytes())
+            .and_then(|request| {
+                let count = request.keys.len();
+                if count > MAX_BATCH_KEYS {
+                    bail_err!(ErrorKind::TooManyBatchKeys(count, MAX_BATCH_KEYS));
+                }
+                Ok(request)
+            })
+            .map(move |request| {
+                let count = request.keys.len();
+                let records = stream::iter_ok(request.keys)
+                    .map(move |key| cpupool.spawn(fetch(key)))
+                    .buffered(BATCH_FETCHES)
+                    .boxify();
+                (count, records)
+            })
+            .boxify()
+    }
+
+    /// Responds with `response`, whose body is the output of its stream as it is generated. The
+    /// status is only known once the first chunk is: an error after that aborts the response.
+    fn streaming_response(
+        &self,
+        mut sample: ScubaSample,
+        response: BoxFuture<(Response, BoxStream<Bytes, Error>), Error>,
+    ) -> futures_ext::BoxFuture<Response, hyper::Error> {
+        let scuba = self.scuba.clone();
+        let cpupool = self.cpupool.clone();
+        let logger = self.logger.clone();
+
+        response
+            .and_then(|(resp, stream)| {
+                stream
+                    .into_future()
+                    .map(move |(first, rest)| (resp, first, rest))
+                    .map_err(|(err, _)| err)
+            })
+            .timed(move |stats, _| {
+                // Only the time to the first chunk: the rest is sent in the background
+                add_common_stats(&mut sample, &stats);
+                scuba.log(&sample);
+            })
+            .then(move |res| {
+                let resp = match res {
+                    Ok((mut resp, first, rest)) => {
+                        let (sender, body) = Body::pair();
+                        let chunks = stream::iter_ok(first)
+                            .chain(rest)
+                            .map(|bytes| Ok(Chunk::from(bytes)))
+                            .or_else(move |err| {
+                                error!(logger, "streaming response failed: {}", err);
+                                let err = io::Error::new(
+                                    io::ErrorKind::Other,
+                                    format!("{}", DisplayChain::from(&err)),
+                                );
+                                Ok::<_, mpsc::SendError<_>>(Err(hyper::Error::Io(err)))
+                            });
+                        // Nothing to do if the client went away
+                        cpupool
+                            .spawn(sender.send_all(chunks).then(|_| Ok::<_, ()>(())))
+                            .forget();
+                        resp.set_body(body);
+                        resp
+                    }
+                    Err(e) => {
+                        let error_msg = format!("{}", DisplayChain::from(&e));
+                        Response::new()
+                            .with_body(error_msg)
+                            .with_status(error_status(&e))
+                    }
+                };
+                futures::future::ok(resp)
             })
             .boxify()
     }
 
+    /// Streams the content of a file. A single byte range can be asked for with a `Range`
+    /// header, other ranges are ignored and the whole content is sent.
     fn get_blob_content(
         &self,
         reponame: String,
-        hash: &NodeHash,
+        hash: NodeHash,
+        range: Option<Range>,
+    ) -> BoxFuture<(Response, BoxStream<Bytes, Error>), Error> {
+        let repo = match self.name_to_repo.get(&reponame) {
+            Some(repo) => repo.clone(),
+            None => {
+                return futures::future::err(failure::err_msg("unknown repo")).boxify();
+            }
+        };
+
+        // Several ranges would need a multipart response
+        let spec = match range {
+            Some(Range::Bytes(ref specs)) if specs.len() == 1 => specs[0].clone(),
+            _ => {
+                return repo.get_file_content_range(&hash, 0, u64::MAX)
+                    .map(|(size, content)| {
+                        let resp = Response::new()
+                            .with_header(AcceptRanges(vec![RangeUnit::Bytes]))
+                            .with_header(ContentLength(size));
+                        (resp, content)
+                    })
+                    .boxify();
+            }
+        };
+
+        let content = match spec {
+            ByteRangeSpec::FromTo(from, to) => {
+                let len = to.saturating_sub(from).saturating_add(1);
+                repo.get_file_content_range(&hash, from, len)
+            }
+            ByteRangeSpec::AllFrom(from) => repo.get_file_content_range(&hash, from, u64::MAX),
+            ByteRangeSpec::Last(len) => repo.get_file_content_suffix(&hash, len),
+        };
+
+        content
+            .map(move |(size, content)| match byte_range_bounds(&spec, size) {
+                Some((start, end)) => {
+                    let resp = Response::new()
+                        .with_status(StatusCode::PartialContent)
+                        .with_header(AcceptRanges(vec![RangeUnit::Bytes]))
+                        .with_header(ContentLength(end - start))
+                        .with_header(ContentRange(ContentRangeSpec::Bytes {
+                            range: Some((start, end - 1)),
+                            instance_length: Some(size),
+                        }));
+                    (resp, content)
+                }
+                None => {
+                    let resp = Response::new()
+                        .with_status(StatusCode::RangeNotSatisfiable)
+                        .with_header(ContentRange(ContentRangeSpec::Bytes {
+                            range: None,
+                            instance_length: Some(size),
+                        }));
+                    (resp, stream::empty().boxify())
+                }
+            })
+            .boxify()
+    }
+
+    fn get_diff(
+        &self,
+        reponame: String,
+        base: NodeHash,
+        target: NodeHash,
+        query: Option<String>,
+    ) -> BoxStream<Bytes, Error> {
+        let repo = match self.name_to_repo.get(&reponame) {
+            Some(repo) => repo.clone(),
+            None => {
+                return stream::once(Err(failure::err_msg("unknown repo"))).boxify();
+            }
+        };
+        let matcher = match parse_query_param::<String>(query.as_ref().map(String::as_str), "path")
+        {
+            Ok(Some(prefix)) => PathMatcher::new(&[format!("path:{}", prefix)], &[] as &[&str]),
+            Ok(None) => Ok(PathMatcher::always()),
+            Err(err) => Err(err),
+        };
+        let matcher = match matcher {
+            Ok(matcher) => Arc::new(matcher),
+            Err(err) => return stream::once(Err(err)).boxify(),
+        };
+
+        // Only go into the directories that may contain paths under the prefix
+        let pruner = {
+            let matcher = matcher.clone();
+            move |changed_entry: &ChangedEntry| {
+                let (entry, path) = changed_entry_path(changed_entry);
+                match entry.get_type() {
+                    mercurial_types::Type::Tree => matcher.visit_dir(&path),
+                    _ => matcher.matches_file(&path),
+                }
+            }
+        };
+
+        get_root_manifest(repo.clone(), base)
+            .join(get_root_manifest(repo, target))
+            .map(move |(base, target)| {
+                changed_entry_stream_with_pruner(&target, &base, MPath::empty(), pruner)
+            })
+            .flatten_stream()
+            .filter(move |changed_entry| {
+                let (_, path) = changed_entry_path(changed_entry);
+                matcher.matches_file(&path)
+            })
+            .and_then(|changed_entry| {
+                let mut record = serde_json::to_vec(&DiffEntry::new(changed_entry))?;
+                record.push(b'\n');
+                Ok(Bytes::from(record))
+            })
+            .boxify()
+    }
+
+    fn get_file_history(
+        &self,
+        reponame: String,
+        hash: NodeHash,
+        path: MPath,
+        query: Option<String>,
     ) -> Box<futures::Future<Item = Bytes, Error = Error> + Send> {
         let repo = match self.name_to_repo.get(&reponame) {
             Some(repo) => repo,
@@ -300,14 +985,152 @@ where
                 return futures::future::err(failure::err_msg("unknown repo")).boxify();
             }
         };
+        let (limit, skip) = {
+            let query = query.as_ref().map(String::as_str);
+            match (
+                parse_query_param::<u64>(query, "limit"),
+                parse_query_param::<u64>(query, "skip"),
+            ) {
+                (Ok(limit), Ok(skip)) => (limit.unwrap_or(u64::max_value()), skip.unwrap_or(0)),
+                (Err(err), _) | (_, Err(err)) => return futures::future::err(err).boxify(),
+            }
+        };
 
-        repo.get_file_content(hash)
-            .from_err()
-            .and_then(|content| futures::future::ok(content))
+        repo.get_file_history(path, hash)
+            .skip(skip)
+            .take(limit)
+            .map(|(filenode, parents, linknode, copy)| {
+                FileHistoryEntry::new(filenode, parents, linknode, copy)
+            })
+            .collect()
+            .and_then(|entries| Ok(Bytes::from(serde_json::to_vec(&entries)?)))
+            .boxify()
+    }
+
+    fn lfs_batch(
+        &self,
+        reponame: String,
+        host: Option<Host>,
+        body: Body,
+    ) -> Box<futures::Future<Item = Bytes, Error = Error> + Send> {
+        let repo = match self.name_to_repo.get(&reponame) {
+            Some(repo) => repo.clone(),
+            None => {
+                return futures::future::err(failure::err_msg("unknown repo")).boxify();
+            }
+        };
+        let url_prefix = match host {
+            Some(host) => match host.port() {
+                Some(port) => format!("https://{}:{}/{}", host.hostname(), port, reponame),
+                None => format!("https://{}/{}", host.hostname(), reponame),
+            },
+            None => {
+                return futures::future::err(failure::err_msg("missing Host header")).boxify();
+            }
+        };
+
+        read_body(body, MAX_REQUEST_SIZE)
+            .and_then(|body| {
+                serde_json::from_slice::<lfs::BatchRequest>(&body)
+                    .context("invalid LFS batch request")
+                    .map_err(Error::from)
+            })
+            .and_then(move |request| request.respond(repo, url_prefix))
+            .and_then(|response| Ok(Bytes::from(serde_json::to_vec(&response)?)))
+            .boxify()
+    }
+
+    /// Streams the content of the LFS object `oid`.
+    fn lfs_download(
+        &self,
+        reponame: String,
+        oid: Sha256,
+    ) -> BoxFuture<(Response, BoxStream<Bytes, Error>), Error> {
+        let repo = match self.name_to_repo.get(&reponame) {
+            Some(repo) => repo,
+            None => {
+                return futures::future::err(failure::err_msg("unknown repo")).boxify();
+            }
+        };
+
+        repo.get_lfs_content_stream(&oid)
+            .and_then(move |content| {
+                content.ok_or_else(|| Error::from(ErrorKind::LfsObjectNotFound(oid)))
+            })
+            .map(|(size, content)| (Response::new().with_header(ContentLength(size)), content))
+            .boxify()
+    }
+
+    /// Stores the content of the LFS object `oid` of `size` bytes as it is received. Nothing is
+    /// stored unless it turns out to be that object.
+    fn lfs_upload(
+        &self,
+        reponame: String,
+        oid: Sha256,
+        size: u64,
+        body: Body,
+    ) -> Box<futures::Future<Item = Bytes, Error = Error> + Send> {
+        let repo = match self.name_to_repo.get(&reponame) {
+            Some(repo) => repo.clone(),
+            None => {
+                return futures::future::err(failure::err_msg("unknown repo")).boxify();
+            }
+        };
+
+        if size > lfs::MAX_OBJECT_SIZE {
+            return futures::future::err(ErrorKind::BodyTooLarge(lfs::MAX_OBJECT_SIZE).into())
+                .boxify();
+        }
+
+        let content = body.from_err().map(|chunk: Chunk| Bytes::from(chunk.as_ref()));
+        repo.upload_lfs_content_stream(oid, size, content)
+            .map(|()| Bytes::new())
             .boxify()
     }
 }
 
+/// The part of a content of `size` bytes that a byte range asks for, as start and end offsets.
+/// Returns `None` if the range doesn't overlap the content.
+fn byte_range_bounds(spec: &ByteRangeSpec, size: u64) -> Option<(u64, u64)> {
+    match *spec {
+        ByteRangeSpec::FromTo(from, to) if from < size && from <= to => {
+            Some((from, to.saturating_add(1).min(size)))
+        }
+        ByteRangeSpec::AllFrom(from) if from < size => Some((from, size)),
+        ByteRangeSpec::Last(len) if len > 0 && size > 0 => Some((size - len.min(size), size)),
+        _ => None,
+    }
+}
+
+/// Reads the whole body of a request, which fails as soon as it's larger than `limit` bytes.
+fn read_body(body: Body, limit: u64) -> BoxFuture<Bytes, Error> {
+    body.from_err()
+        .fold(Vec::new(), move |mut content, chunk| {
+            if (content.len() + chunk.len()) as u64 > limit {
+                bail_err!(ErrorKind::BodyTooLarge(limit));
+            }
+            content.extend_from_slice(&chunk);
+            Ok(content)
+        })
+        .map(Bytes::from)
+        .boxify()
+}
+
+/// The status code of the response to a request that failed with `err`.
+fn error_status(err: &Error) -> StatusCode {
+    match err.downcast_ref::<ErrorKind>() {
+        Some(&ErrorKind::BodyTooLarge(_)) => return StatusCode::PayloadTooLarge,
+        Some(&ErrorKind::TooManyBatchKeys(..)) => return StatusCode::PayloadTooLarge,
+        Some(&ErrorKind::LfsObjectNotFound(_)) => return StatusCode::NotFound,
+        None => {}
+    }
+    match err.downcast_ref::<BlobRepoErrorKind>() {
+        Some(&BlobRepoErrorKind::LfsContentMismatch(..)) => StatusCode::BadRequest,
+        Some(&BlobRepoErrorKind::LfsSizeMismatch(..)) => StatusCode::BadRequest,
+        _ => StatusCode::NotFound,
+    }
+}
+
 /// Add values from the given Stats struct to the given Scuba sample.
 fn add_common_stats(sample: &mut ScubaSample, stats: &Stats) {
     sample.add(
@@ -342,6 +1165,14 @@ impl Service for EdenServer {
                 return futures::future::ok(resp).boxify();
             }
         };
+        if !parsed_req.allows_method(req.method()) {
+            resp.set_status(StatusCode::MethodNotAllowed);
+            return futures::future::ok(resp).boxify();
+        }
+        let query = req.uri().query().map(String::from);
+        let host = req.headers().get::<Host>().cloned();
+        let range = req.headers().get::<Range>().cloned();
+        let body = req.body();
 
         let result_future = match parsed_req {
             ParsedUrl::RootTreeHgManifestId(reponame, hash) => {
@@ -350,6 +1181,23 @@ impl Service for EdenServer {
                 sample.add(SCUBA_COL_REPO, reponame.clone());
                 self.get_root_tree_manifest_id(reponame, &HgChangesetId::new(hash))
             }
+            ParsedUrl::Changeset(reponame, hash) => {
+                sample.add(SCUBA_COL_HASH, hash.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_CHANGESET);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_changeset(reponame, &HgChangesetId::new(hash))
+            }
+            ParsedUrl::Log(reponame, hash) => {
+                sample.add(SCUBA_COL_HASH, hash.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_LOG);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_log(reponame, hash, query)
+            }
+            ParsedUrl::Bookmarks(reponame) => {
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_BOOKMARKS);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_bookmarks(reponame)
+            }
             ParsedUrl::TreeContent(reponame, hash) => {
                 sample.add(SCUBA_COL_HASH, hash.to_string());
                 sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_TREE_CONTENT);
@@ -370,7 +1218,54 @@ impl Service for EdenServer {
                 sample.add(SCUBA_COL_HASH, hash.to_string());
                 sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_BLOB_CONTENT);
                 sample.add(SCUBA_COL_REPO, reponame.clone());
-                self.get_blob_content(reponame, &hash)
+                let response = self.get_blob_content(reponame, hash, range);
+                return self.streaming_response(sample, response);
+            }
+            ParsedUrl::BatchTreeContent(reponame) => {
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_BATCH_GET_TREE_CONTENT);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                let batch = self.batch_get_tree_content(reponame, body);
+                return self.streaming_response(sample, batch_response(resp, batch));
+            }
+            ParsedUrl::BatchBlobContent(reponame) => {
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_BATCH_GET_BLOB_CONTENT);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                let batch = self.batch_get_blob_content(reponame, body);
+                return self.streaming_response(sample, batch_response(resp, batch));
+            }
+            ParsedUrl::Diff(reponame, base, target) => {
+                sample.add(SCUBA_COL_HASH, target.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_DIFF);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                let stream = self.get_diff(reponame, base, target, query);
+                let response = futures::future::ok((resp, stream)).boxify();
+                return self.streaming_response(sample, response);
+            }
+            ParsedUrl::FileHistory(reponame, hash, path) => {
+                sample.add(SCUBA_COL_HASH, hash.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_FILE_HISTORY);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_file_history(reponame, hash, path, query)
+            }
+            ParsedUrl::LfsBatch(reponame) => {
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_LFS_BATCH);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                resp.headers_mut()
+                    .set_raw("Content-Type", lfs::LFS_CONTENT_TYPE);
+                self.lfs_batch(reponame, host, body)
+            }
+            ParsedUrl::LfsDownload(reponame, oid) => {
+                sample.add(SCUBA_COL_HASH, oid.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_LFS_DOWNLOAD);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                let response = self.lfs_download(reponame, oid);
+                return self.streaming_response(sample, response);
+            }
+            ParsedUrl::LfsUpload(reponame, oid, size) => {
+                sample.add(SCUBA_COL_HASH, oid.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_LFS_UPLOAD);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.lfs_upload(reponame, oid, size, body)
             }
         };
 
@@ -383,7 +1278,7 @@ impl Service for EdenServer {
                     Err(e) => {
                         let error_msg = format!("{}", DisplayChain::from(&e));
                         resp.set_body(error_msg);
-                        resp.set_status(StatusCode::NotFound);
+                        resp.set_status(error_status(&e));
                     }
                 };
                 futures::future::ok(resp)
@@ -421,7 +1316,14 @@ fn build_tls_acceptor(ssl: Ssl) -> Result<TlsAcceptor> {
     tlsacceptor_builder.build().map_err(Error::from)
 }
 
-fn start_server(addr: &str, reponame: String, repo: BlobRepo, logger: Logger, ssl: Ssl) {
+fn start_server(
+    addr: &str,
+    reponame: String,
+    repo: BlobRepo,
+    generation_cache_size: usize,
+    logger: Logger,
+    ssl: Ssl,
+) {
     let addr = addr.parse().expect("Failed to parse address");
     let mut map = HashMap::new();
     map.insert(reponame, Arc::new(repo));
@@ -436,6 +1338,7 @@ fn start_server(addr: &str, reponame: String, repo: BlobRepo, logger: Logger, ss
     };
 
     let cpupool = Arc::new(CpuPool::new_num_cpus());
+    let repo_generation = RepoGenCache::new(generation_cache_size);
     let protoserver = proto::Server::new(Http::new(), tlsacceptor);
     let tcpserver = TcpServer::new(protoserver, addr);
 
@@ -443,6 +1346,7 @@ fn start_server(addr: &str, reponame: String, repo: BlobRepo, logger: Logger, ss
     tcpserver.serve(move || {
         Ok(EdenServer::new(
             map.clone(),
+            repo_generation.clone(),
             cpupool.clone(),
             logger.clone(),
         ))
@@ -474,6 +1378,8 @@ struct RawRepoConfig {
     addr: String,
     ssl: Ssl,
     repoid: i32,
+    generation_cache_size: Option<usize>,
+    blobstore_compression_level: Option<i32>,
 }
 
 fn main() {
@@ -497,6 +1403,8 @@ fn main() {
     let config =
         toml::from_slice::<RawRepoConfig>(&config_bytes).expect("reading config file failed");
 
+    let generation_cache_size = config.generation_cache_size.unwrap_or(10 * 1024 * 1024);
+
     let root_logger = {
         let level = if matches.is_present("debug") {
             Level::Debug
@@ -515,8 +1423,13 @@ fn main() {
             start_server(
                 &config.addr,
                 config.reponame,
-                BlobRepo::new_files(repo_logger, &path, RepositoryId::new(config.repoid))
-                    .expect("couldn't open blob state"),
+                BlobRepo::new_files(
+                    repo_logger,
+                    &path,
+                    RepositoryId::new(config.repoid),
+                    config.blobstore_compression_level,
+                ).expect("couldn't open blob state"),
+                generation_cache_size,
                 root_logger.clone(),
                 config.ssl,
             )
@@ -527,8 +1440,13 @@ fn main() {
             start_server(
                 &config.addr,
                 config.reponame,
-                BlobRepo::new_rocksdb(repo_logger, &path, RepositoryId::new(config.repoid))
-                    .expect("couldn't open blob state"),
+                BlobRepo::new_rocksdb(
+                    repo_logger,
+                    &path,
+                    RepositoryId::new(config.repoid),
+                    config.blobstore_compression_level,
+                ).expect("couldn't open blob state"),
+                generation_cache_size,
                 root_logger.clone(),
                 config.ssl,
             )
@@ -563,7 +1481,9 @@ fn main() {
                     &config.manifold_prefix.unwrap_or("".into()),
                     &remote,
                     RepositoryId::new(config.repoid),
+                    config.blobstore_compression_level,
                 ).expect("couldn't open blob state"),
+                generation_cache_size,
                 root_logger.clone(),
                 config.ssl,
             )
@@ -588,4 +1508,142 @@ mod test {
         let incorrect_url = format!("/repo/cs/{}/roottreemanifestid", badhash);
         assert!(parse_url(&incorrect_url, &routes).is_err());
     }
+
+    #[test]
+    fn test_changeset_url_parsing() {
+        let routes = &ROUTES;
+        let hash = std::iter::repeat("a").take(40).collect::<String>();
+
+        let url = format!("/repo/cs/{}", hash);
+        match parse_url(&url, &routes) {
+            Ok(ParsedUrl::Changeset(..)) => {}
+            _ => panic!("{} isn't a changeset url", url),
+        }
+        let url = format!("/repo/log/{}/", hash);
+        match parse_url(&url, &routes) {
+            Ok(ParsedUrl::Log(..)) => {}
+            _ => panic!("{} isn't a log url", url),
+        }
+        match parse_url("/repo/bookmarks", &routes) {
+            Ok(ParsedUrl::Bookmarks(repo)) => assert_eq!(repo, "repo"),
+            _ => panic!("/repo/bookmarks isn't a bookmarks url"),
+        }
+    }
+
+    #[test]
+    fn test_batch_record() {
+        let key = BatchKey {
+            path: "dir/file".into(),
+            node: NodeHash::from_str("0123456789abcdef0123456789abcdef01234567").unwrap(),
+        };
+        let record = batch_record(&key, b"content");
+        assert_eq!(record.len(), 20 + 4 + 8 + 8 + 7);
+        assert_eq!(&record[..20], key.node.sha1().as_ref());
+        assert_eq!(&record[20..24], &[0, 0, 0, 8]);
+        assert_eq!(&record[24..32], b"dir/file");
+        assert_eq!(&record[32..40], &[0, 0, 0, 0, 0, 0, 0, 7]);
+        assert_eq!(&record[40..], b"content");
+    }
+
+    #[test]
+    fn test_byte_range_bounds() {
+        assert_eq!(
+            byte_range_bounds(&ByteRangeSpec::FromTo(10, 19), 100),
+            Some((10, 20))
+        );
+        assert_eq!(
+            byte_range_bounds(&ByteRangeSpec::FromTo(90, 199), 100),
+            Some((90, 100))
+        );
+        assert_eq!(byte_range_bounds(&ByteRangeSpec::FromTo(100, 199), 100), None);
+        assert_eq!(
+            byte_range_bounds(&ByteRangeSpec::AllFrom(10), 100),
+            Some((10, 100))
+        );
+        assert_eq!(byte_range_bounds(&ByteRangeSpec::AllFrom(100), 100), None);
+        assert_eq!(
+            byte_range_bounds(&ByteRangeSpec::Last(10), 100),
+            Some((90, 100))
+        );
+        assert_eq!(
+            byte_range_bounds(&ByteRangeSpec::Last(200), 100),
+            Some((0, 100))
+        );
+        assert_eq!(byte_range_bounds(&ByteRangeSpec::Last(0), 100), None);
+        assert_eq!(byte_range_bounds(&ByteRangeSpec::Last(10), 0), None);
+    }
+
+    #[test]
+    fn test_diff_url_parsing() {
+        let routes = &ROUTES;
+        let base = std::iter::repeat("a").take(40).collect::<String>();
+        let target = std::iter::repeat("b").take(40).collect::<String>();
+        let url = format!("/repo/diff/{}/{}", base, target);
+        match parse_url(&url, &routes) {
+            Ok(ParsedUrl::Diff(repo, parsed_base, parsed_target)) => {
+                assert_eq!(repo, "repo");
+                assert_eq!(parsed_base.to_string(), base);
+                assert_eq!(parsed_target.to_string(), target);
+            }
+            _ => panic!("{} isn't a diff url", url),
+        }
+
+        let url = format!("/repo/diff/{}", base);
+        assert!(parse_url(&url, &routes).is_err());
+    }
+
+    #[test]
+    fn test_file_history_url_parsing() {
+        let routes = &ROUTES;
+        let hash = std::iter::repeat("a").take(40).collect::<String>();
+        let url = format!("/repo/history/{}/dir/file%20name", hash);
+        match parse_url(&url, &routes) {
+            Ok(ParsedUrl::FileHistory(repo, parsed, path)) => {
+                assert_eq!(repo, "repo");
+                assert_eq!(parsed.to_string(), hash);
+                assert_eq!(path, MPath::new("dir/file name").unwrap());
+            }
+            _ => panic!("{} isn't a file history url", url),
+        }
+
+        let url = format!("/repo/history/{}/", hash);
+        assert!(parse_url(&url, &routes).is_err());
+    }
+
+    #[test]
+    fn test_query_param_parsing() {
+        let query = Some("skip=10&limit=5");
+        assert_eq!(parse_query_param::<u64>(query, "limit").unwrap(), Some(5));
+        assert_eq!(parse_query_param::<u64>(query, "skip").unwrap(), Some(10));
+        assert_eq!(parse_query_param::<u64>(query, "other").unwrap(), None);
+        assert_eq!(parse_query_param::<u64>(None, "limit").unwrap(), None);
+        assert!(parse_query_param::<u64>(Some("limit=x"), "limit").is_err());
+    }
+
+    #[test]
+    fn test_lfs_url_parsing() {
+        let routes = &ROUTES;
+        assert!(parse_url("/repo/objects/batch", &routes).is_ok());
+
+        let oid = std::iter::repeat("a").take(64).collect::<String>();
+        let download_url = format!("/repo/lfs/download/{}", oid);
+        match parse_url(&download_url, &routes) {
+            Ok(ParsedUrl::LfsDownload(repo, parsed)) => {
+                assert_eq!(repo, "repo");
+                assert_eq!(parsed.to_string(), oid);
+            }
+            _ => panic!("{} isn't an LFS download url", download_url),
+        }
+        let upload_url = format!("/repo/lfs/upload/{}/1024", oid);
+        match parse_url(&upload_url, &routes) {
+            Ok(ParsedUrl::LfsUpload(_, _, size)) => assert_eq!(size, 1024),
+            _ => panic!("{} isn't an LFS upload url", upload_url),
+        }
+        let unsized_url = format!("/repo/lfs/upload/{}", oid);
+        assert!(parse_url(&unsized_url, &routes).is_err());
+
+        let shortoid = std::iter::repeat("a").take(40).collect::<String>();
+        let incorrect_url = format!("/repo/lfs/download/{}", shortoid);
+        assert!(parse_url(&incorrect_url, &routes).is_err());
+    }
 }
@@ -197,7 +197,7 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
             } => (
                 hgcmds
                     .pushkey(namespace, key, old, new)
-                    .map(|_| SingleResponse::Pushkey)
+                    .map(SingleResponse::Pushkey)
                     .map_err(self::Error::into)
                     .into_stream()
                     .boxify(),
@@ -206,9 +206,8 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
             SingleRequest::Streamout => (
                 hgcmds
                     .stream_out()
-                    .map(|_| SingleResponse::Streamout)
+                    .map(SingleResponse::Streamout)
                     .map_err(self::Error::into)
-                    .into_stream()
                     .boxify(),
                 ok(instream).boxify(),
             ),
@@ -578,20 +577,21 @@ pub trait HgCommands {
     }
 
     // @wireprotocommand('pushkey', 'namespace key old new')
+    // Resolves to whether the key was updated
     fn pushkey(
         &self,
         _namespace: String,
         _key: String,
-        _old: NodeHash,
-        _new: NodeHash,
-    ) -> HgCommandRes<()> {
+        _old: String,
+        _new: String,
+    ) -> HgCommandRes<bool> {
         unimplemented("pushkey")
     }
 
     // @wireprotocommand('stream_out')
-    fn stream_out(&self) -> HgCommandRes<BoxStream<Vec<u8>, Error>> {
-        // XXX raw streaming?
-        unimplemented("stream_out")
+    // The stream is sent to the client as is
+    fn stream_out(&self) -> BoxStream<Bytes, Error> {
+        once(Err(ErrorKind::Unimplemented("stream_out".into()).into())).boxify()
     }
 
     // @wireprotocommand('unbundle', 'heads')
@@ -107,11 +107,13 @@ pub enum SingleRequest {
     Known {
         nodes: Vec<NodeHash>,
     },
+    /// `old` and `new` are namespace specific. For bookmarks they are hex hashes, and an empty
+    /// `old` or `new` means that the bookmark is created or deleted.
     Pushkey {
         namespace: String,
         key: String,
-        old: NodeHash,
-        new: NodeHash,
+        old: String,
+        new: String,
     },
     Streamout,
     Unbundle {
@@ -129,6 +131,10 @@ pub struct GetbundleArgs {
     pub common: Vec<NodeHash>,
     pub bundlecaps: Vec<Vec<u8>>,
     pub listkeys: Vec<Vec<u8>>,
+    /// Patterns of the paths the client wants (everything if empty).
+    pub includepattern: Vec<Vec<u8>>,
+    /// Patterns of the paths the client doesn't want.
+    pub excludepattern: Vec<Vec<u8>>,
 }
 
 impl Debug for GetbundleArgs {
@@ -141,11 +147,21 @@ impl Debug for GetbundleArgs {
             .iter()
             .map(|s| String::from_utf8_lossy(&s))
             .collect();
+        let includepattern: Vec<_> = self.includepattern
+            .iter()
+            .map(|s| String::from_utf8_lossy(&s))
+            .collect();
+        let excludepattern: Vec<_> = self.excludepattern
+            .iter()
+            .map(|s| String::from_utf8_lossy(&s))
+            .collect();
         fmt.debug_struct("GetbundleArgs")
             .field("heads", &self.heads)
             .field("common", &self.common)
             .field("bundlecaps", &bcaps)
             .field("listkeys", &listkeys)
+            .field("includepattern", &includepattern)
+            .field("excludepattern", &excludepattern)
             .finish()
     }
 }
@@ -164,6 +180,10 @@ pub struct GettreepackArgs {
     ///  The fullpath (not relative path) of directories underneath
     /// the rootdir that should be sent.
     pub directories: Vec<Bytes>,
+    /// Patterns of the paths of the trees the client wants (everything if empty).
+    pub includepattern: Vec<Vec<u8>>,
+    /// Patterns of the paths of the trees the client doesn't want.
+    pub excludepattern: Vec<Vec<u8>>,
 }
 
 #[derive(Debug)]
@@ -188,8 +208,8 @@ pub enum SingleResponse {
     Listkeys(HashMap<Vec<u8>, Vec<u8>>),
     Lookup(Bytes),
     Known(Vec<bool>),
-    Pushkey,
-    Streamout, /* (BoxStream<Vec<u8>, Error>) */
+    Pushkey(bool),
+    Streamout(Bytes),
     ReadyForStream,
     Unbundle(Bytes),
     Gettreepack(Bytes),
@@ -206,6 +226,7 @@ impl SingleResponse {
             &ReadyForStream => true,
             &Unbundle(_) => true,
             &Gettreepack(_) => true,
+            &Streamout(_) => true,
             _ => false,
         }
     }
@@ -483,6 +483,8 @@ fn parse_with_params(
                 common: parseval_default(&kv, "common", hashlist)?,
                 bundlecaps: parseval_default(&kv, "bundlecaps", commavalues)?,
                 listkeys: parseval_default(&kv, "listkeys", commavalues)?,
+                includepattern: parseval_default(&kv, "includepattern", commavalues)?,
+                excludepattern: parseval_default(&kv, "excludepattern", commavalues)?,
             })))
         | command!("heads", Heads, parse_params, {})
         | command!("hello", Hello, parse_params, {})
@@ -497,11 +499,11 @@ fn parse_with_params(
           })
         | command!("pushkey", Pushkey, parse_params, {
               namespace => ident_string,
-              key => ident_string,
-              old => nodehash,
-              new => nodehash,
+              key => utf8_string_complete,
+              old => utf8_string_complete,
+              new => utf8_string_complete,
           })
-        | command!("streamout", Streamout, parse_params, {})
+        | command!("stream_out", Streamout, parse_params, {})
         | command!("unbundle", Unbundle, parse_params, {
               heads => stringlist,
           })
@@ -511,6 +513,8 @@ fn parse_with_params(
                 mfnodes: parseval(&kv, "mfnodes", hashlist)?,
                 basemfnodes: parseval(&kv, "basemfnodes", hashlist)?,
                 directories: parseval(&kv, "directories", gettreepack_directories)?,
+                includepattern: parseval_default(&kv, "includepattern", commavalues)?,
+                excludepattern: parseval_default(&kv, "excludepattern", commavalues)?,
             })))
         | command!("getfiles", Getfiles, parse_params, {})
     )
@@ -1245,6 +1249,8 @@ mod test_parse {
                 common: vec![],
                 bundlecaps: vec![],
                 listkeys: vec![],
+                includepattern: vec![],
+                excludepattern: vec![],
             })),
         );
 
@@ -1269,6 +1275,29 @@ mod test_parse {
                 common: vec![hash_twos(), hash_threes()],
                 bundlecaps: vec![b"cap1".to_vec(), b"CAP2".to_vec(), b"cap3".to_vec()],
                 listkeys: vec![b"key1".to_vec(), b"key2".to_vec()],
+                includepattern: vec![],
+                excludepattern: vec![],
+            })),
+        );
+
+        // with patterns
+        let inp = "getbundle\n\
+                   * 3\n\
+                   heads 40\n\
+                   1111111111111111111111111111111111111111\
+                   includepattern 21\n\
+                   path:dir,glob:**/*.rs\
+                   excludepattern 15\n\
+                   rootfilesin:dir";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: vec![hash_ones()],
+                common: vec![],
+                bundlecaps: vec![],
+                listkeys: vec![],
+                includepattern: vec![b"path:dir".to_vec(), b"glob:**/*.rs".to_vec()],
+                excludepattern: vec![b"rootfilesin:dir".to_vec()],
             })),
         );
     }
@@ -1347,6 +1376,8 @@ mod test_parse {
                 mfnodes: vec![hash_ones()],
                 basemfnodes: vec![hash_ones()],
                 directories: vec![],
+                includepattern: vec![],
+                excludepattern: vec![],
             })),
         );
 
@@ -1369,6 +1400,8 @@ mod test_parse {
                 mfnodes: vec![hash_ones(), hash_twos()],
                 basemfnodes: vec![hash_twos(), hash_ones()],
                 directories: vec![Bytes::from(",".as_bytes()), Bytes::from(";".as_bytes())],
+                includepattern: vec![],
+                excludepattern: vec![],
             })),
         );
     }
@@ -1414,15 +1447,37 @@ mod test_parse {
             Request::Single(SingleRequest::Pushkey {
                 namespace: "bookmarks".to_string(),
                 key: "foobar".to_string(),
-                old: hash_ones(),
-                new: hash_twos(),
+                old: "1111111111111111111111111111111111111111".to_string(),
+                new: "2222222222222222222222222222222222222222".to_string(),
+            }),
+        );
+    }
+
+    #[test]
+    fn test_parse_pushkey_create() {
+        let inp = "pushkey\n\
+                   namespace 9\n\
+                   bookmarks\
+                   key 9\n\
+                   release/1\
+                   old 0\n\
+                   new 40\n\
+                   2222222222222222222222222222222222222222";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Pushkey {
+                namespace: "bookmarks".to_string(),
+                key: "release/1".to_string(),
+                old: "".to_string(),
+                new: "2222222222222222222222222222222222222222".to_string(),
             }),
         );
     }
 
     #[test]
     fn test_parse_streamout() {
-        let inp = "streamout\n";
+        let inp = "stream_out\n";
 
         test_parse(inp, Request::Single(SingleRequest::Streamout {}));
     }
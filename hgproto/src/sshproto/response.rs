@@ -107,6 +107,25 @@ fn encode_cmd(response: &SingleResponse) -> Bytes {
             Bytes::from(out)
         }
 
+        &Listkeys(ref keys) => {
+            let out: Vec<_> = keys
+                .iter()
+                .map(|(key, value)| {
+                    let mut line = key.clone();
+                    line.push(b'\t');
+                    line.extend_from_slice(value);
+                    line
+                })
+                .collect();
+
+            Bytes::from(out.join(&b'\n'))
+        }
+
+        &Pushkey(res) => {
+            let out: &[u8] = if res { b"1\n" } else { b"0\n" };
+            Bytes::from(out)
+        }
+
         &ReadyForStream => Bytes::from(b"0\n".as_ref()),
 
         // TODO(luk, T25574469) The response for Unbundle should be chunked stream of bundle2
@@ -118,8 +137,12 @@ fn encode_cmd(response: &SingleResponse) -> Bytes {
 
         &Getfiles(ref res) => res.clone(),
 
+        &Streamout(ref res) => res.clone(),
+
         &Lookup(ref res) => res.clone(),
 
+        &Clonebundles(ref res) => Bytes::from(res.as_bytes()),
+
         &Branchmap(ref _res) => {
             // We have no plans to support mercurial branches and hence no plans for branchmap,
             // so just return fake response.
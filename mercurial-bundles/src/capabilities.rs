@@ -9,17 +9,94 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 
+use async_compression::{CompressorType, FlateCompression};
 use bytes::BytesMut;
 use tokio_io::codec::Decoder;
 use url::percent_encoding::percent_decode;
 
 use errors::*;
 
+/// Name of the capability that lists the stream compressions a client can read, by the value of
+/// their `Compression` stream parameter.
+pub const COMPRESSION_CAP: &str = "compression";
+
+/// Stream compressions that bundle2 replies can use, most preferred first.
+pub const SUPPORTED_COMPRESSIONS: &[&str] = &["ZS", "GZ"];
+
+// Fast enough to not slow down large pulls, while still shrinking them a lot
+const ZSTD_LEVEL: i32 = 3;
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Capabilities {
     caps: HashMap<String, Vec<String>>,
 }
 
+impl Capabilities {
+    /// Decode a capabilities blob, as sent in a replycaps part.
+    pub fn decode(blob: &[u8]) -> Result<Self> {
+        let mut caps = HashMap::new();
+        for kv in blob.split(|b| b == &b'\n') {
+            let mut kv = kv.splitn(2, |b| b == &b'=');
+            let key = percent_decode(kv.next().expect("must have at least 1 element"))
+                .decode_utf8()?
+                .into_owned();
+            let values = {
+                match kv.next() {
+                    None => Vec::new(),
+                    Some(values) => {
+                        let res: ::std::result::Result<Vec<_>, _> = values
+                            .split(|b| b == &b',')
+                            .filter(|v| !v.is_empty())
+                            .map(|v| percent_decode(v).decode_utf8().map(Cow::into_owned))
+                            .collect();
+                        res?
+                    }
+                }
+            };
+            caps.insert(key, values);
+        }
+        Ok(Capabilities { caps })
+    }
+
+    /// Find the `bundle2=` entry of the `bundlecaps` argument of getbundle and decode the bundle2
+    /// capabilities of the client from it. Returns `None` if the client didn't send any.
+    pub fn from_bundlecaps<T: AsRef<[u8]>>(bundlecaps: &[T]) -> Result<Option<Self>> {
+        let prefix = b"bundle2=";
+        for cap in bundlecaps {
+            let cap = cap.as_ref();
+            if cap.starts_with(prefix) {
+                // The whole blob is url encoded once more
+                let blob: Vec<u8> = percent_decode(&cap[prefix.len()..]).collect();
+                return Self::decode(&blob).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.caps.contains_key(key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[String]> {
+        self.caps.get(key).map(|values| values.as_slice())
+    }
+
+    /// The preferred stream compression for a bundle2 sent to a client with these capabilities.
+    /// Clients that don't list the compressions they can read get an uncompressed bundle, as
+    /// some Mercurial versions hang while reading compressed bundles over the wire.
+    pub fn reply_compression(&self) -> Option<CompressorType> {
+        let accepted = self.get(COMPRESSION_CAP)?;
+        let name = SUPPORTED_COMPRESSIONS
+            .iter()
+            .find(|name| accepted.iter().any(|accepted| accepted == *name))?;
+        match *name {
+            "ZS" => Some(CompressorType::Zstd { level: ZSTD_LEVEL }),
+            "GZ" => Some(CompressorType::Gzip(FlateCompression::default())),
+            _ => None,
+        }
+    }
+}
+
 /// This is a tokio_io Decoder for capabilities used f.e. in "replycaps" part of bundle2
 ///
 /// The format is as follows:
@@ -41,30 +118,52 @@ impl Decoder for CapabilitiesUnpacker {
     }
 
     fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
-        let mut caps = HashMap::new();
-        for kv in buf.split(|b| b == &b'\n') {
-            let mut kv = kv.splitn(2, |b| b == &b'=');
-            let key = percent_decode(kv.next().expect("must have at least 1 element"))
-                .decode_utf8()?
-                .into_owned();
-            let values = {
-                match kv.next() {
-                    None => Vec::new(),
-                    Some(values) => {
-                        let res: ::std::result::Result<Vec<_>, _> = values
-                            .split(|b| b == &b',')
-                            .filter(|v| !v.is_empty())
-                            .map(|v| percent_decode(v).decode_utf8().map(Cow::into_owned))
-                            .collect();
-                        res?
-                    }
-                }
-            };
-            caps.insert(key, values);
-        }
+        let caps = Capabilities::decode(&buf[..])?;
 
         buf.clear(); // all buf was consumed
 
-        Ok(Some(Capabilities { caps }))
+        Ok(Some(caps))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use utils::get_compression_param;
+
+    #[test]
+    fn test_from_bundlecaps() {
+        let bundlecaps = vec![
+            b"HG20".to_vec(),
+            b"bundle2=HG20%0Achangegroup%3D01%2C02%0Acompression%3DGZ%2CUN".to_vec(),
+        ];
+        let caps = Capabilities::from_bundlecaps(&bundlecaps)
+            .unwrap()
+            .expect("bundle2 caps are missing");
+        assert!(caps.contains("HG20"));
+        assert_eq!(
+            caps.get("changegroup"),
+            Some(&["01".to_string(), "02".to_string()][..])
+        );
+        assert_eq!(caps.get("pushkey"), None);
+
+        assert_eq!(
+            Capabilities::from_bundlecaps(&[b"HG20".to_vec()]).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_reply_compression() {
+        let compression = |blob: &[u8]| {
+            let caps = Capabilities::decode(blob).unwrap();
+            get_compression_param(&caps.reply_compression())
+        };
+
+        assert_eq!(compression(b"HG20"), "UN");
+        assert_eq!(compression(b"compression=UN"), "UN");
+        assert_eq!(compression(b"compression=BZ,GZ,UN"), "GZ");
+        assert_eq!(compression(b"compression=GZ,ZS"), "ZS");
     }
 }
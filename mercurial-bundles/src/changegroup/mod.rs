@@ -4,15 +4,55 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::str::FromStr;
+
 use mercurial_types::{Delta, MPath, NodeHash};
 
+use errors::*;
+
 pub mod packer;
 pub mod unpacker;
 
+/// Changegroup formats that can be encoded and decoded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CgVersion {
+    Cg2,
+    /// Adds revlog flags to every delta and a section for each directory of a treemanifest.
+    Cg3,
+}
+
+impl CgVersion {
+    /// The `version` parameter of a changegroup part in this format.
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            &CgVersion::Cg2 => "02",
+            &CgVersion::Cg3 => "03",
+        }
+    }
+}
+
+impl FromStr for CgVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "02" => Ok(CgVersion::Cg2),
+            "03" => Ok(CgVersion::Cg3),
+            _ => bail_err!(ErrorKind::Cg2Decode(format!(
+                "unsupported changegroup version '{}'",
+                s
+            ))),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Section {
     Changeset,
+    /// The flat manifest, or the root manifest of a treemanifest.
     Manifest,
+    /// A directory manifest other than the root. Only in changegroup3.
+    Treemanifest(MPath),
     Filelog(MPath),
 }
 
@@ -40,13 +80,15 @@ pub struct CgDeltaChunk {
     pub base: NodeHash,
     pub linknode: NodeHash,
     pub delta: Delta,
+    /// Revlog flags of the revision. Only changegroup3 has them.
+    pub flags: Option<u16>,
 }
 
 #[cfg(test)]
 mod test {
     use std::io::{self, Cursor};
 
-    use futures::Stream;
+    use futures::{stream, Future, Stream};
     use quickcheck::{QuickCheck, StdGen, TestResult};
     use rand;
     use slog::{Drain, Logger};
@@ -55,10 +97,12 @@ mod test {
     use tokio_io::codec::{FramedRead, FramedWrite};
 
     use futures_ext::StreamLayeredExt;
+    use mercurial_types::NULL_HASH;
+    use mercurial_types_mocks::nodehash::ONES_HASH;
     use partial_io::{GenWouldBlock, PartialAsyncRead, PartialAsyncWrite, PartialWithErrors};
 
     use chunk::{ChunkDecoder, ChunkEncoder};
-    use quickcheck_types::Cg2PartSequence;
+    use quickcheck_types::CgPartSequence;
 
     use super::*;
 
@@ -76,7 +120,7 @@ mod test {
         quickcheck.quickcheck(
             roundtrip
                 as fn(
-                    Cg2PartSequence,
+                    CgPartSequence,
                     PartialWithErrors<GenWouldBlock>,
                     PartialWithErrors<GenWouldBlock>,
                 ) -> TestResult,
@@ -91,7 +135,7 @@ mod test {
         quickcheck.quickcheck(
             roundtrip
                 as fn(
-                    Cg2PartSequence,
+                    CgPartSequence,
                     PartialWithErrors<GenWouldBlock>,
                     PartialWithErrors<GenWouldBlock>,
                 ) -> TestResult,
@@ -99,14 +143,14 @@ mod test {
     }
 
     fn roundtrip(
-        seq: Cg2PartSequence,
+        seq: CgPartSequence,
         write_ops: PartialWithErrors<GenWouldBlock>,
         read_ops: PartialWithErrors<GenWouldBlock>,
     ) -> TestResult {
         // Encode this sequence.
         let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
         let partial_write = PartialAsyncWrite::new(cursor, write_ops);
-        let packer = packer::Cg2Packer::new(seq.to_stream().and_then(|x| x));
+        let packer = packer::CgPacker::new(seq.version(), seq.to_stream().and_then(|x| x));
         let sink = FramedWrite::new(partial_write, ChunkEncoder);
         let encode_fut = packer.forward(sink);
 
@@ -122,7 +166,7 @@ mod test {
             .map(|chunk| chunk.into_bytes().expect("expected normal chunk"));
 
         let logger = make_root_logger();
-        let unpacker = unpacker::Cg2Unpacker::new(logger);
+        let unpacker = unpacker::CgUnpacker::new(logger, seq.version(), true);
         let part_stream = chunks.decode(unpacker);
 
         let parts = Vec::new();
@@ -139,6 +183,56 @@ mod test {
         TestResult::passed()
     }
 
+    #[test]
+    fn test_cg3_without_treemanifest() {
+        let chunk = CgDeltaChunk {
+            node: ONES_HASH,
+            p1: NULL_HASH,
+            p2: NULL_HASH,
+            base: NULL_HASH,
+            linknode: ONES_HASH,
+            delta: Delta::new_fulltext(b"content".to_vec()),
+            flags: Some(0),
+        };
+        let file = MPath::new(b"file").unwrap();
+        let mut parts = vec![
+            Part::CgChunk(Section::Changeset, chunk.clone()),
+            Part::SectionEnd(Section::Changeset),
+            Part::CgChunk(Section::Manifest, chunk.clone()),
+            Part::SectionEnd(Section::Manifest),
+            Part::CgChunk(Section::Filelog(file.clone()), chunk.clone()),
+            Part::SectionEnd(Section::Filelog(file)),
+            Part::End,
+        ];
+
+        // The section of the directory manifests is still ended
+        let packed = pack_cg3(&parts);
+        assert_eq!(unpack_cg3(packed.clone(), false).unwrap(), parts);
+        assert_eq!(unpack_cg3(packed, true).unwrap(), parts);
+
+        let dir = MPath::new(b"dir").unwrap();
+        parts.insert(4, Part::CgChunk(Section::Treemanifest(dir.clone()), chunk));
+        parts.insert(5, Part::SectionEnd(Section::Treemanifest(dir)));
+        let packed = pack_cg3(&parts);
+        assert_eq!(unpack_cg3(packed.clone(), true).unwrap(), parts);
+        assert!(unpack_cg3(packed, false).is_err());
+    }
+
+    fn pack_cg3(parts: &[Part]) -> Vec<u8> {
+        let parts = stream::iter_ok::<_, Error>(parts.to_vec());
+        let packer = packer::CgPacker::new(CgVersion::Cg3, parts);
+        let sink = FramedWrite::new(Cursor::new(Vec::new()), ChunkEncoder);
+        let (_, sink) = packer.forward(sink).wait().unwrap();
+        sink.into_inner().into_inner()
+    }
+
+    fn unpack_cg3(data: Vec<u8>, treemanifest: bool) -> Result<Vec<Part>> {
+        let chunks = FramedRead::new(Cursor::new(data), ChunkDecoder)
+            .map(|chunk| chunk.into_bytes().expect("expected normal chunk"));
+        let unpacker = unpacker::CgUnpacker::new(make_root_logger(), CgVersion::Cg3, treemanifest);
+        chunks.decode(unpacker).collect().wait()
+    }
+
     fn make_root_logger() -> Logger {
         let plain = slog_term::PlainSyncDecorator::new(io::stdout());
         Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
@@ -15,23 +15,42 @@ use chunk::Chunk;
 use delta;
 use errors::*;
 
-use super::{CgDeltaChunk, Part, Section};
+use super::{CgDeltaChunk, CgVersion, Part, Section};
 
-pub struct Cg2Packer<S> {
+pub struct CgPacker<S> {
+    version: CgVersion,
     delta_stream: S,
     last_seen: Section,
+    // In changegroup3, the directory manifests that follow the root manifest are ended by an
+    // empty chunk, even if there are none
+    treemanifests_ended: bool,
 }
 
-impl<S> Cg2Packer<S> {
-    pub fn new(delta_stream: S) -> Self {
-        Cg2Packer {
+impl<S> CgPacker<S> {
+    pub fn new(version: CgVersion, delta_stream: S) -> Self {
+        CgPacker {
+            version: version,
             delta_stream: delta_stream,
             last_seen: Section::Changeset,
+            treemanifests_ended: version == CgVersion::Cg2,
         }
     }
+
+    /// Whether the end of the directory manifests must be encoded before `part`.
+    fn ends_treemanifests(&mut self, part: &Part) -> bool {
+        if self.treemanifests_ended {
+            return false;
+        }
+        let ends = match part {
+            &Part::CgChunk(Section::Filelog(_), _) | &Part::End => true,
+            _ => false,
+        };
+        self.treemanifests_ended = ends;
+        ends
+    }
 }
 
-impl<S> Stream for Cg2Packer<S>
+impl<S> Stream for CgPacker<S>
 where
     S: Stream<Item = Part>,
     Error: From<S::Error>,
@@ -42,90 +61,126 @@ where
     fn poll(&mut self) -> Poll<Option<Chunk>, Error> {
         use self::Part::*;
 
-        match try_ready!(self.delta_stream.poll()) {
-            None => Ok(Async::Ready(None)),
-            Some(CgChunk(section, delta_chunk)) => {
-                let mut builder = ChunkBuilder::new();
+        let part = match try_ready!(self.delta_stream.poll()) {
+            None => return Ok(Async::Ready(None)),
+            Some(part) => part,
+        };
+
+        let mut builder = ChunkBuilder::new(self.version);
+        if self.ends_treemanifests(&part) {
+            builder.encode_empty_chunk();
+        }
+        match part {
+            CgChunk(section, delta_chunk) => {
                 if self.last_seen != section {
                     builder.encode_section(&section)?;
                     self.last_seen = section;
                 }
-                builder.encode_delta_chunk(delta_chunk);
-                Ok(Async::Ready(Some(builder.build()?)))
+                builder.encode_delta_chunk(delta_chunk)?;
+            }
+            SectionEnd(_section) => {
+                builder.encode_empty_chunk();
+            }
+            End => {
+                builder.encode_empty_chunk();
             }
-            Some(SectionEnd(_section)) => Ok(Async::Ready(Some(empty_cg_chunk()))),
-            Some(End) => Ok(Async::Ready(Some(empty_cg_chunk()))),
         }
+        Ok(Async::Ready(Some(builder.build()?)))
     }
 }
 
-/// Produce an empty changegroup chunk.
-///
-/// Note that this is distinct from Chunk::empty() -- this is an actual chunk
-/// with a 4-byte payload.
-fn empty_cg_chunk() -> Chunk {
-    Chunk::new(vec![0, 0, 0, 0]).expect("Chunk::new should not fail for a 4-byte chunk")
-}
-
 #[derive(Debug)]
 struct ChunkBuilder {
+    version: CgVersion,
     inner: Vec<u8>,
-    len_offset: usize,
+    // Where the length of the delta chunk being encoded goes, if there is one
+    len_offset: Option<usize>,
 }
 
 impl ChunkBuilder {
-    pub fn new() -> Self {
+    pub fn new(version: CgVersion) -> Self {
         ChunkBuilder {
-            // Reserve four bytes in the beginning for the length.
-            inner: vec![0, 0, 0, 0],
-            len_offset: 0,
+            version: version,
+            inner: Vec::new(),
+            len_offset: None,
         }
     }
 
+    /// Encode an empty changegroup chunk, which ends a section.
+    pub fn encode_empty_chunk(&mut self) -> &mut Self {
+        self.inner.put_slice(&[0, 0, 0, 0]);
+        self
+    }
+
     /// Encode the beginning of a section. This should always happen before any
     /// delta chunks are encoded.
     pub fn encode_section(&mut self, section: &Section) -> Result<&mut Self> {
-        assert_eq!(
-            self.inner.len(),
-            4,
-            "encode_section must only be called once at the start"
+        assert!(
+            self.len_offset.is_none(),
+            "encode_section must be called before encode_delta_chunk"
         );
         // Changeset and manifest sections are implicitly encoded, so we don't
         // need to do anything there.
-        // TODO: will need to encode tree manifests here as well
-        if let &Section::Filelog(ref f) = section {
-            let f_vec = f.to_vec();
-            if f_vec.len() == 0 {
-                bail_err!(ErrorKind::Cg2Encode(
-                    "attempted to encode a zero-length path".into(),
-                ));
+        let name = match section {
+            &Section::Filelog(ref f) => f.to_vec(),
+            &Section::Treemanifest(ref dir) => {
+                if self.version == CgVersion::Cg2 {
+                    bail_err!(ErrorKind::Cg2Encode(
+                        "changegroup2 can't have directory manifests".into(),
+                    ));
+                }
+                // Mercurial marks directories with a trailing slash
+                let mut name = dir.to_vec();
+                name.push(b'/');
+                name
             }
-            // Note that the filename length must include the four bytes for itself.
-            BigEndian::write_i32(&mut self.inner[0..], (f_vec.len() + 4) as i32);
-            self.inner.put_slice(f_vec.as_slice());
-            // Add four more bytes for the start of the section.
-            self.len_offset = self.inner.len();
-            self.inner.put_slice(&[0, 0, 0, 0]);
+            _ => return Ok(self),
+        };
+        if name.is_empty() || name == b"/" {
+            bail_err!(ErrorKind::Cg2Encode(
+                "attempted to encode a zero-length path".into(),
+            ));
         }
+        // Note that the filename length must include the four bytes for itself.
+        self.inner.put_i32_be((name.len() + 4) as i32);
+        self.inner.put_slice(name.as_slice());
         Ok(self)
     }
 
-    pub fn encode_delta_chunk(&mut self, chunk: CgDeltaChunk) -> &mut Self {
+    pub fn encode_delta_chunk(&mut self, chunk: CgDeltaChunk) -> Result<&mut Self> {
+        // Reserve four bytes for the length of the chunk.
+        self.len_offset = Some(self.inner.len());
+        self.inner.put_slice(&[0, 0, 0, 0]);
+
         self.inner.put_slice(chunk.node.as_ref());
         self.inner.put_slice(chunk.p1.as_ref());
         self.inner.put_slice(chunk.p2.as_ref());
         self.inner.put_slice(chunk.base.as_ref());
         self.inner.put_slice(chunk.linknode.as_ref());
+        match self.version {
+            CgVersion::Cg2 => {
+                if chunk.flags.unwrap_or(0) != 0 {
+                    bail_err!(ErrorKind::Cg2Encode(format!(
+                        "changegroup2 can't have revlog flags ({:#x} for {})",
+                        chunk.flags.unwrap_or(0),
+                        chunk.node
+                    )));
+                }
+            }
+            CgVersion::Cg3 => self.inner.put_u16_be(chunk.flags.unwrap_or(0)),
+        }
 
         delta::encode_delta(&chunk.delta, &mut self.inner);
 
-        self
+        Ok(self)
     }
 
     pub fn build(self) -> Result<Chunk> {
-        let len = self.inner.len() - self.len_offset;
         let mut inner = self.inner;
-        BigEndian::write_i32(&mut inner[self.len_offset..], len as i32);
+        if let Some(len_offset) = self.len_offset {
+            let len = inner.len() - len_offset;
+            BigEndian::write_i32(&mut inner[len_offset..], len as i32);
+        }
         Chunk::new(inner)
     }
 }
@@ -137,7 +192,7 @@ mod test {
 
     #[test]
     fn test_empty_filelog_path() {
-        let mut builder = ChunkBuilder::new();
+        let mut builder = ChunkBuilder::new(CgVersion::Cg2);
         let section = Section::Filelog(MPath::new("").unwrap());
         assert_matches!(
             builder
@@ -148,4 +203,18 @@ mod test {
             ErrorKind::Cg2Encode(_)
         );
     }
+
+    #[test]
+    fn test_treemanifest_in_cg2() {
+        let mut builder = ChunkBuilder::new(CgVersion::Cg2);
+        let section = Section::Treemanifest(MPath::new("dir").unwrap());
+        assert_matches!(
+            builder
+                .encode_section(&section)
+                .unwrap_err()
+                .downcast::<ErrorKind>()
+                .unwrap(),
+            ErrorKind::Cg2Encode(_)
+        );
+    }
 }
@@ -20,11 +20,13 @@ use delta;
 use errors::*;
 use utils::BytesExt;
 
-use super::{CgDeltaChunk, Part, Section};
+use super::{CgDeltaChunk, CgVersion, Part, Section};
 
 #[derive(Debug)]
-pub struct Cg2Unpacker {
+pub struct CgUnpacker {
     logger: slog::Logger,
+    version: CgVersion,
+    treemanifest: bool,
     state: State,
 }
 
@@ -58,13 +60,15 @@ impl Part {
 // See the chunk header definition below for the first 100 bytes. The last 4 is
 // for the length field itself.
 const CHUNK_HEADER_LEN: usize = 20 + 20 + 20 + 20 + 20 + 4;
+// changegroup3 adds 2 bytes of revlog flags.
+const CHUNK_HEADER3_LEN: usize = CHUNK_HEADER_LEN + 2;
 
-impl Decoder for Cg2Unpacker {
+impl Decoder for CgUnpacker {
     type Item = Part;
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
-        match Self::decode_next(buf, self.state.take()) {
+        match Self::decode_next(buf, self.version, self.treemanifest, self.state.take()) {
             Err(e) => {
                 self.state = State::Invalid;
                 Err(e)
@@ -106,17 +110,26 @@ impl Decoder for Cg2Unpacker {
     }
 }
 
-impl Cg2Unpacker {
-    pub fn new(logger: slog::Logger) -> Self {
-        Cg2Unpacker {
+impl CgUnpacker {
+    /// `treemanifest` is whether the part says that it has directory manifests. The section of
+    /// the directory manifests is still ended in every changegroup3, but is empty in the others.
+    pub fn new(logger: slog::Logger, version: CgVersion, treemanifest: bool) -> Self {
+        CgUnpacker {
             logger: logger,
+            version: version,
+            treemanifest: treemanifest,
             state: State::Changeset,
         }
     }
 
-    fn decode_next(buf: &mut BytesMut, state: State) -> Result<(Option<Part>, State)> {
+    fn decode_next(
+        buf: &mut BytesMut,
+        version: CgVersion,
+        treemanifest: bool,
+        state: State,
+    ) -> Result<(Option<Part>, State)> {
         match state {
-            State::Changeset => match Self::decode_chunk(buf)? {
+            State::Changeset => match Self::decode_chunk(buf, version)? {
                 None => Ok((None, State::Changeset)),
                 Some(CgChunk::Empty) => {
                     Ok((Some(Part::SectionEnd(Section::Changeset)), State::Manifest))
@@ -126,32 +139,78 @@ impl Cg2Unpacker {
                     State::Changeset,
                 )),
             },
-            State::Manifest => match Self::decode_chunk(buf)? {
+            State::Manifest => match Self::decode_chunk(buf, version)? {
                 None => Ok((None, State::Manifest)),
                 Some(CgChunk::Empty) => {
-                    Ok((Some(Part::SectionEnd(Section::Manifest)), State::Filename))
+                    let next = match version {
+                        CgVersion::Cg2 => State::Filename,
+                        CgVersion::Cg3 => State::Dirname,
+                    };
+                    Ok((Some(Part::SectionEnd(Section::Manifest)), next))
                 }
                 Some(CgChunk::Delta(chunk)) => Ok((
                     Some(Part::CgChunk(Section::Manifest, chunk)),
                     State::Manifest,
                 )),
             },
+            State::Dirname => {
+                let dirname = Self::decode_dirname(buf)?;
+                match dirname {
+                    DecodeRes::None => Ok((None, State::Dirname)),
+                    DecodeRes::Some(d) => {
+                        if !treemanifest {
+                            let msg = format!(
+                                "directory manifest {} in a changegroup without treemanifest",
+                                d
+                            );
+                            bail_err!(ErrorKind::Cg2Decode(msg));
+                        }
+                        Self::decode_treemanifest_chunk(buf, version, d)
+                    }
+                    // The end of the directory manifests isn't a Part of its own
+                    DecodeRes::End => {
+                        Self::decode_next(buf, version, treemanifest, State::Filename)
+                    }
+                }
+            }
+            State::Treemanifest(dirname) => Self::decode_treemanifest_chunk(buf, version, dirname),
             State::Filename => {
                 let filename = Self::decode_filename(buf)?;
                 match filename {
                     DecodeRes::None => Ok((None, State::Filename)),
-                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, f),
+                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, version, f),
                     DecodeRes::End => Ok((Some(Part::End), State::End)),
                 }
             }
-            State::Filelog(filename) => Self::decode_filelog_chunk(buf, filename),
+            State::Filelog(filename) => Self::decode_filelog_chunk(buf, version, filename),
             State::End => Ok((None, State::End)),
             State::Invalid => Err(ErrorKind::Cg2Decode("byte stream corrupt".into()).into()),
         }
     }
 
-    fn decode_filelog_chunk(buf: &mut BytesMut, f: MPath) -> Result<(Option<Part>, State)> {
-        match Self::decode_chunk(buf)? {
+    fn decode_treemanifest_chunk(
+        buf: &mut BytesMut,
+        version: CgVersion,
+        d: MPath,
+    ) -> Result<(Option<Part>, State)> {
+        match Self::decode_chunk(buf, version)? {
+            None => Ok((None, State::Treemanifest(d))),
+            Some(CgChunk::Empty) => {
+                Ok((Some(Part::SectionEnd(Section::Treemanifest(d))), State::Dirname))
+            }
+            Some(CgChunk::Delta(chunk)) => Ok((
+                Some(Part::CgChunk(Section::Treemanifest(d.clone()), chunk)),
+                State::Treemanifest(d),
+            )),
+        }
+    }
+
+    fn decode_filelog_chunk(
+        buf: &mut BytesMut,
+        version: CgVersion,
+        f: MPath,
+    ) -> Result<(Option<Part>, State)> {
+        match Self::decode_chunk(buf, version)? {
             None => Ok((None, State::Filelog(f))),
             Some(CgChunk::Empty) => {
                 Ok((Some(Part::SectionEnd(Section::Filelog(f))), State::Filename))
@@ -163,10 +222,14 @@ impl Cg2Unpacker {
         }
     }
 
-    fn decode_chunk(buf: &mut BytesMut) -> Result<Option<CgChunk>> {
+    fn decode_chunk(buf: &mut BytesMut, version: CgVersion) -> Result<Option<CgChunk>> {
         if buf.len() < 4 {
             return Ok(None);
         }
+        let header_len = match version {
+            CgVersion::Cg2 => CHUNK_HEADER_LEN,
+            CgVersion::Cg3 => CHUNK_HEADER3_LEN,
+        };
 
         let chunk_len = buf.peek_i32();
         // Note that chunk_len includes the 4 bytes consumed by itself
@@ -176,10 +239,10 @@ impl Cg2Unpacker {
             let _ = buf.drain_i32();
             return Ok(Some(CgChunk::Empty));
         }
-        if chunk_len < CHUNK_HEADER_LEN {
+        if chunk_len < header_len {
             let msg = format!(
                 "invalid chunk: length >= {} required, found {}",
-                CHUNK_HEADER_LEN, chunk_len
+                header_len, chunk_len
             );
             bail_err!(ErrorKind::Cg2Decode(msg));
         }
@@ -196,6 +259,7 @@ impl Cg2Unpacker {
         // p2: NodeHash (20 bytes) -- NULL_HASH if only 1 parent
         // base node: NodeHash (20 bytes) (new in changegroup2)
         // link node: NodeHash (20 bytes)
+        // flags: u16 (2 bytes) (new in changegroup3)
         // ---
 
         let node = buf.drain_node();
@@ -203,8 +267,12 @@ impl Cg2Unpacker {
         let p2 = buf.drain_node();
         let base = buf.drain_node();
         let linknode = buf.drain_node();
+        let flags = match version {
+            CgVersion::Cg2 => None,
+            CgVersion::Cg3 => Some(buf.drain_u16()),
+        };
 
-        let delta = delta::decode_delta(buf.split_to(chunk_len - CHUNK_HEADER_LEN))?;
+        let delta = delta::decode_delta(buf.split_to(chunk_len - header_len))?;
         return Ok(Some(CgChunk::Delta(CgDeltaChunk {
             node: node,
             p1: p1,
@@ -212,6 +280,7 @@ impl Cg2Unpacker {
             base: base,
             linknode: linknode,
             delta: delta,
+            flags: flags,
         })));
     }
 
@@ -237,6 +306,39 @@ impl Cg2Unpacker {
         })?;
         Ok(DecodeRes::Some(filename))
     }
+
+    /// Directory names are encoded like file names, but with a trailing slash.
+    fn decode_dirname(buf: &mut BytesMut) -> Result<DecodeRes<MPath>> {
+        if buf.len() < 4 {
+            return Ok(DecodeRes::None);
+        }
+        let dirname_len = buf.peek_i32();
+        if dirname_len == 0 {
+            let _ = buf.split_to(4);
+            return Ok(DecodeRes::End);
+        }
+        let dirname_len = dirname_len as usize;
+        // dirname_len includes the 4 bytes for the length field and the trailing slash.
+        if dirname_len < 4 + 2 {
+            let msg = format!("invalid directory name length {}", dirname_len);
+            bail_err!(ErrorKind::Cg2Decode(msg));
+        }
+        if buf.len() < dirname_len {
+            return Ok(DecodeRes::None);
+        }
+        let _ = buf.split_to(4);
+        let mut dirname = buf.split_to(dirname_len - 4);
+        if dirname.last() != Some(&b'/') {
+            let msg = format!("directory name {:?} has no trailing slash", dirname);
+            bail_err!(ErrorKind::Cg2Decode(msg));
+        }
+        let len = dirname.len() - 1;
+        let dirname = dirname.drain_path(len).with_context(|_| {
+            let msg = format!("invalid directory name of length {}", dirname_len);
+            ErrorKind::Cg2Decode(msg)
+        })?;
+        Ok(DecodeRes::Some(dirname))
+    }
 }
 
 enum DecodeRes<T> {
@@ -254,6 +356,8 @@ enum CgChunk {
 enum State {
     Changeset,
     Manifest,
+    Dirname,
+    Treemanifest(MPath),
     Filename,
     Filelog(MPath),
     End,
@@ -54,7 +54,7 @@ pub mod bundle2;
 pub mod bundle2_encode;
 pub mod changegroup;
 pub mod infinitepush;
-mod capabilities;
+pub mod capabilities;
 mod chunk;
 mod delta;
 pub mod parts;
@@ -78,6 +78,7 @@ use std::fmt;
 use futures_ext::{BoxFuture, BoxStream};
 
 pub use bundle2_encode::Bundle2EncodeBuilder;
+pub use capabilities::Capabilities;
 pub use part_header::{PartHeader, PartHeaderType};
 pub use types::StreamHeader;
 
@@ -90,6 +91,7 @@ pub enum Bundle2Item {
     B2xInfinitepushBookmarks(PartHeader, BoxStream<bytes::Bytes, Error>),
     Replycaps(PartHeader, BoxFuture<capabilities::Capabilities, Error>),
     Pushkey(PartHeader, BoxFuture<(), Error>),
+    CheckHeads(PartHeader, BoxFuture<Vec<mercurial_types::NodeHash>, Error>),
 }
 
 impl Bundle2Item {
@@ -128,6 +130,7 @@ impl fmt::Debug for Bundle2Item {
             }
             &Replycaps(ref header, _) => write!(f, "Bundle2Item::Replycaps({:?}, ...)", header),
             &Pushkey(ref header, _) => write!(f, "Bundle2Item::Pushkey({:?}, ...)", header),
+            &CheckHeads(ref header, _) => write!(f, "Bundle2Item::CheckHeads({:?}, ...)", header),
         }
     }
 }
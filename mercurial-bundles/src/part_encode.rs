@@ -15,6 +15,7 @@ use futures::{Async, Future, Poll, Stream};
 use chunk::Chunk;
 use errors::*;
 use part_header::{PartHeader, PartHeaderBuilder, PartHeaderType};
+use parts::error_abort_part;
 
 /// Represents a stream of chunks produced by the individual part handler.
 pub struct ChunkStream(Box<Stream<Item = Chunk, Error = Error> + Send>);
@@ -47,6 +48,8 @@ enum GenerationState {
     NotStarted(PartHeader, PartEncodeData),
     Fixed(Chunk),
     Generating(ChunkStream),
+    Interrupting(Box<PartEncode>, Error),
+    Failed(Error),
     EmptyChunk,
     Done,
     Invalid,
@@ -150,6 +153,8 @@ impl PartEncode {
         // NotStarted = header not output yet
         // Generating = payload currently being generated by inner stream
         // Fixed = fixed-length payload (no generation, just one chunk)
+        // Interrupting = payload generation failed, sending an error:abort part to the client
+        // Failed = error:abort part sent, the error is returned next
         // EmptyChunk = end of payload (or no payload)
         // Done = chunk completed
         // Invalid = some sort of error occured
@@ -175,10 +180,30 @@ impl PartEncode {
                     }
                     Ok(Async::Ready(None)) => (Ok(Async::Ready(Some(Chunk::empty()))), Done),
                     Ok(Async::NotReady) => (Ok(Async::NotReady), Generating(ChunkStream(stream))),
-                    // TODO: produce an error part for (some kinds of?) errors
-                    Err(e) => (Err(e), Generating(ChunkStream(stream))),
+                    // Like Mercurial, interrupt the part with an error:abort part so that the
+                    // client can tell the user what went wrong.
+                    Err(e) => {
+                        let message = format!("unexpected error: {}", e);
+                        match error_abort_part(message, None::<Bytes>) {
+                            Ok(part) => (
+                                Ok(Async::Ready(Some(Chunk::error()))),
+                                Interrupting(Box::new(part.build(0)), e),
+                            ),
+                            Err(_) => (Err(e), Generating(ChunkStream(stream))),
+                        }
+                    }
                 }
             }
+            Interrupting(mut part, e) => match part.poll() {
+                Ok(Async::Ready(Some(chunk))) => {
+                    (Ok(Async::Ready(Some(chunk))), Interrupting(part, e))
+                }
+                // The interrupted part's payload still has to be terminated
+                Ok(Async::Ready(None)) => (Ok(Async::Ready(Some(Chunk::empty()))), Failed(e)),
+                Ok(Async::NotReady) => (Ok(Async::NotReady), Interrupting(part, e)),
+                Err(_) => (Err(e), Done),
+            },
+            Failed(e) => (Err(e), Done),
             Fixed(chunk) => (Ok(Async::Ready(Some(chunk))), EmptyChunk),
             EmptyChunk => (Ok(Async::Ready(Some(Chunk::empty()))), Done),
             Done => (Ok(Async::Ready(None)), Done),
@@ -7,10 +7,12 @@
 //! Construct and serialize headers for bundle2 parts.
 
 use std::collections::HashMap;
+use std::str;
 
 use bytes::{BigEndian, BufMut, Bytes};
 use quickcheck::{Arbitrary, Gen};
 
+use changegroup::CgVersion;
 use chunk::Chunk;
 use errors::*;
 use utils::BytesExt;
@@ -30,9 +32,8 @@ pub enum PartHeaderType {
     Listkeys,
     /// Contains wirepacks that are encoded TreeManifests required in the push.
     B2xTreegroup2,
-    /// In Mercurial this is used to verify that during the push the heads did not change. In
-    /// Mononoke this parameter will be ignored, because it does not provide transaction during
-    /// push
+    /// Contains the heads the client saw before pushing. The push is refused if the heads have
+    /// changed since then, so that racing pushes can't create unexpected heads.
     CheckHeads,
     /// Contains changegroup for infinitepush commits
     B2xInfinitepush,
@@ -42,20 +43,26 @@ pub enum PartHeaderType {
     /// Pushkey part is used to update different namespaces: phases, bookmarks, etc.
     /// In Mononoke it's used to update bookmarks.
     Pushkey,
+    /// When responding for bundle2 this part says whether the corresponding Pushkey succeeded.
+    ReplyPushkey,
+    /// Sent back instead of the replies if the push failed, with a message for the user.
+    ErrorAbort,
+    /// Sent back instead of the replies if a mandatory Pushkey part failed.
+    ErrorPushkey,
+    /// Sent back instead of the replies if the bundle2 contained a mandatory part or part
+    /// parameter that the receiver doesn't support.
+    ErrorUnsupportedContent,
+    /// Sent back instead of the replies if the push lost a race with another push, i.e. the
+    /// heads in CheckHeads don't match the current heads.
+    ErrorPushRaced,
     // RemoteChangegroup,       // We don't wish to support this functionality
     // CheckBookmarks,          // TODO Do we want to support this?
-    // CheckHeads,              // TODO Do we want to support this?
     // CheckUpdatedHeads,       // TODO Do we want to support this?
     // CheckPhases,             // TODO Do we want to support this?
     // Output,                  // TODO Do we want to support this?
-    // ErrorAbort,              // TODO Do we want to support this?
-    // ErrorPushkey,            // TODO Do we want to support this?
-    // ErrorUnsupportedContent, // TODO Do we want to support this?
-    // ErrorPushRaced,          // TODO Do we want to support this?
     // Pushkey,                 // TODO Do we want to support this?
     // Bookmarks,               // TODO Do we want to support this?
     // PhaseHeads,              // TODO Do we want to support this?
-    // ReplyPushkey,            // TODO Do we want to support this?
     // Obsmarkers,              // TODO Do we want to support this?
     // ReplyObsmarkers,         // TODO Do we want to support this?
     // HgtagsFnodes,            // TODO Do we want to support this?
@@ -75,11 +82,16 @@ impl PartHeaderType {
             "b2x:infinitepushscratchbookmarks" => Ok(B2xInfinitepushBookmarks),
             "check:heads" => Ok(CheckHeads),
             "pushkey" => Ok(Pushkey),
+            "reply:pushkey" => Ok(ReplyPushkey),
+            "error:abort" => Ok(ErrorAbort),
+            "error:pushkey" => Ok(ErrorPushkey),
+            "error:unsupportedcontent" => Ok(ErrorUnsupportedContent),
+            "error:pushraced" => Ok(ErrorPushRaced),
             bad => bail_msg!("unknown header type {}", bad),
         }
     }
 
-    fn as_str(&self) -> &str {
+    pub fn as_str(&self) -> &str {
         use self::PartHeaderType::*;
         match *self {
             Changegroup => "changegroup",
@@ -91,6 +103,11 @@ impl PartHeaderType {
             B2xInfinitepushBookmarks => "b2x:infinitepushscratchbookmarks",
             CheckHeads => "check:heads",
             Pushkey => "pushkey",
+            ReplyPushkey => "reply:pushkey",
+            ErrorAbort => "error:abort",
+            ErrorPushkey => "error:pushkey",
+            ErrorUnsupportedContent => "error:unsupportedcontent",
+            ErrorPushRaced => "error:pushraced",
         }
     }
 }
@@ -132,6 +149,29 @@ impl PartHeader {
         self.mandatory
     }
 
+    /// The format of the changegroup in a Changegroup or B2xInfinitepush part. Parts that don't
+    /// say are changegroup2.
+    pub fn cg_version(&self) -> Result<CgVersion> {
+        let param = match self.part_type {
+            PartHeaderType::Changegroup => "version",
+            PartHeaderType::B2xInfinitepush => "cgversion",
+            _ => bail_msg!("{:?} part has no changegroup", self.part_type),
+        };
+        let version = self.mparams.get(param).or_else(|| self.aparams.get(param));
+        match version {
+            None => Ok(CgVersion::Cg2),
+            Some(version) => str::from_utf8(version)
+                .context("changegroup version is not UTF-8")?
+                .parse(),
+        }
+    }
+
+    /// Whether the changegroup in a Changegroup or B2xInfinitepush part can have directory
+    /// manifests.
+    pub fn cg_treemanifest(&self) -> bool {
+        self.mparams.contains_key("treemanifest") || self.aparams.contains_key("treemanifest")
+    }
+
     pub fn encode(self) -> Chunk {
         let mut out_buf: Vec<u8> = Vec::new();
 
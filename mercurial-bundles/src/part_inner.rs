@@ -14,7 +14,7 @@ use std::str;
 use slog;
 
 use bytes::{Bytes, BytesMut};
-use futures::{future, Future, Stream};
+use futures::{future, stream, Future, Stream};
 use futures_ext::{BoxFuture, FutureExt, StreamWrapper};
 use tokio_io::AsyncRead;
 use tokio_io::codec::Decoder;
@@ -25,6 +25,7 @@ use changegroup;
 use errors::*;
 use futures_ext::{StreamExt, StreamLayeredExt};
 use infinitepush;
+use mercurial_types::NodeHash;
 use part_header::{PartHeader, PartHeaderType};
 use part_outer::{OuterFrame, OuterStream};
 use wirepack;
@@ -45,6 +46,7 @@ lazy_static! {
         m.insert(PartHeaderType::B2xTreegroup2, hashset!{"version", "cache", "category"});
         m.insert(PartHeaderType::Replycaps, hashset!{});
         m.insert(PartHeaderType::Pushkey, hashset!{ "namespace", "key", "old", "new" });
+        m.insert(PartHeaderType::CheckHeads, hashset!{});
         m
     };
 }
@@ -89,16 +91,30 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
 
     let bundle2item = match header.part_type() {
         &PartHeaderType::Changegroup => {
-            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::Cg2Unpacker::new(
-                logger.new(o!("stream" => "cg2")),
-            ));
-            Bundle2Item::Changegroup(header, Box::new(cg2_stream))
+            let cg_stream = match header.cg_version() {
+                Ok(version) => wrapped_stream
+                    .decode(changegroup::unpacker::CgUnpacker::new(
+                        logger.new(o!("stream" => "cg")),
+                        version,
+                        header.cg_treemanifest(),
+                    ))
+                    .boxify(),
+                Err(err) => stream::once(Err(err)).boxify(),
+            };
+            Bundle2Item::Changegroup(header, cg_stream)
         }
         &PartHeaderType::B2xInfinitepush => {
-            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::Cg2Unpacker::new(
-                logger.new(o!("stream" => "cg2")),
-            ));
-            Bundle2Item::B2xInfinitepush(header, Box::new(cg2_stream))
+            let cg_stream = match header.cg_version() {
+                Ok(version) => wrapped_stream
+                    .decode(changegroup::unpacker::CgUnpacker::new(
+                        logger.new(o!("stream" => "cg")),
+                        version,
+                        header.cg_treemanifest(),
+                    ))
+                    .boxify(),
+                Err(err) => stream::once(Err(err)).boxify(),
+            };
+            Bundle2Item::B2xInfinitepush(header, cg_stream)
         }
         &PartHeaderType::B2xInfinitepushBookmarks => {
             let bookmarks_stream =
@@ -130,6 +146,10 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
             let empty = wrapped_stream.decode(EmptyUnpacker).for_each(|_| Ok(()));
             Bundle2Item::Pushkey(header, Box::new(empty))
         }
+        &PartHeaderType::CheckHeads => {
+            let heads = wrapped_stream.decode(CheckHeadsUnpacker).collect();
+            Bundle2Item::CheckHeads(header, Box::new(heads))
+        }
         _ => panic!("TODO: make this an error"),
     };
 
@@ -153,3 +173,20 @@ impl Decoder for EmptyUnpacker {
         Ok(None)
     }
 }
+
+// Decoder for the check:heads part, which is just the binary hashes of the heads one after the
+// other
+pub struct CheckHeadsUnpacker;
+
+impl Decoder for CheckHeadsUnpacker {
+    type Item = NodeHash;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if buf.len() < 20 {
+            return Ok(None);
+        }
+        let node = buf.split_to(20);
+        NodeHash::from_bytes(&node).map(Some)
+    }
+}
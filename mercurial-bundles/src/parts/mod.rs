@@ -11,8 +11,8 @@ use failure::err_msg;
 use futures::{Future, Stream};
 use futures::stream::{iter_ok, once};
 
-use super::changegroup::{CgDeltaChunk, Part, Section};
-use super::changegroup::packer::Cg2Packer;
+use super::changegroup::{CgDeltaChunk, CgVersion, Part, Section};
+use super::changegroup::packer::CgPacker;
 use super::wirepack;
 use super::wirepack::packer::WirePackPacker;
 
@@ -53,7 +53,7 @@ where
     S: Stream<Item = BlobNode, Error = Error> + Send + 'static,
 {
     let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::Changegroup)?;
-    builder.add_mparam("version", "02")?;
+    builder.add_mparam("version", CgVersion::Cg2.to_str())?;
 
     let changelogentries = changelogentries.map(|blobnode| {
         let node = blobnode.nodeid().expect("blobnode should store data");
@@ -73,6 +73,7 @@ where
             base,
             linknode,
             delta,
+            flags: None,
         };
         Part::CgChunk(Section::Changeset, deltachunk)
     });
@@ -84,7 +85,7 @@ where
         .chain(once(Ok(Part::SectionEnd(Section::Filelog(MPath::empty())))))
         .chain(once(Ok(Part::End)));
 
-    let cgdata = Cg2Packer::new(changelogentries);
+    let cgdata = CgPacker::new(CgVersion::Cg2, changelogentries);
     builder.set_data_generated(cgdata);
 
     Ok(builder)
@@ -212,3 +213,107 @@ pub fn replychangegroup_part(
 
     Ok(builder)
 }
+
+// Part parameter values are limited to 255 bytes, which error messages can easily exceed
+const MAX_PARAM_LEN: usize = 255;
+
+fn truncate_param<S: Into<Bytes>>(value: S) -> Bytes {
+    let value = value.into();
+    if value.len() <= MAX_PARAM_LEN {
+        return value;
+    }
+    let mut truncated = value.slice_to(MAX_PARAM_LEN - 3).to_vec();
+    truncated.extend_from_slice(b"...");
+    Bytes::from(truncated)
+}
+
+/// Makes the client abort with `message`, and optionally a `hint` on how to fix the problem.
+/// Messages that are too long to fit in a part parameter are truncated.
+pub fn error_abort_part<M, H>(message: M, hint: Option<H>) -> Result<PartEncodeBuilder>
+where
+    M: Into<Bytes>,
+    H: Into<Bytes>,
+{
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::ErrorAbort)?;
+    builder.add_mparam("message", truncate_param(message))?;
+    if let Some(hint) = hint {
+        builder.add_aparam("hint", truncate_param(hint))?;
+    }
+
+    Ok(builder)
+}
+
+/// Details of a failed pushkey, sent back in an error:pushkey part.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PushkeyFailure {
+    pub namespace: Option<Bytes>,
+    pub key: Option<Bytes>,
+    pub new: Option<Bytes>,
+    pub old: Option<Bytes>,
+    pub ret: Option<Bytes>,
+}
+
+/// Tells the client that the mandatory pushkey part `in_reply_to` failed.
+pub fn error_pushkey_part(
+    in_reply_to: u32,
+    failure: PushkeyFailure,
+) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::ErrorPushkey)?;
+    builder.add_mparam("in-reply-to", format!("{}", in_reply_to))?;
+    let PushkeyFailure {
+        namespace,
+        key,
+        new,
+        old,
+        ret,
+    } = failure;
+    let params = vec![
+        ("namespace", namespace),
+        ("key", key),
+        ("new", new),
+        ("old", old),
+        ("ret", ret),
+    ];
+    for (name, value) in params {
+        if let Some(value) = value {
+            builder.add_aparam(name, truncate_param(value))?;
+        }
+    }
+
+    Ok(builder)
+}
+
+/// Tells the client that the bundle2 it sent had a mandatory part (or mandatory parameters of a
+/// part) that aren't supported.
+pub fn error_unsupportedcontent_part(
+    part_type: Option<PartHeaderType>,
+    params: Vec<String>,
+) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::ErrorUnsupportedContent)?;
+    if let Some(part_type) = part_type {
+        builder.add_mparam("parttype", part_type.as_str().to_string())?;
+    }
+    if !params.is_empty() {
+        builder.add_mparam("params", truncate_param(params.join("\0")))?;
+    }
+
+    Ok(builder)
+}
+
+/// Tells the client that the push was refused because the repo changed while it was being
+/// prepared. Mercurial shows `message` to the user.
+pub fn error_pushraced_part<S: Into<Bytes>>(message: S) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::ErrorPushRaced)?;
+    builder.add_mparam("message", truncate_param(message))?;
+
+    Ok(builder)
+}
+
+/// Reply to a pushkey part. Mercurial expects "1" if the key was updated and "0" otherwise.
+pub fn replypushkey_part(res: bool, in_reply_to: u32) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::ReplyPushkey)?;
+    builder.add_mparam("return", if res { "1" } else { "0" })?;
+    builder.add_mparam("in-reply-to", format!("{}", in_reply_to))?;
+
+    Ok(builder)
+}
@@ -47,17 +47,24 @@ impl Arbitrary for QCBytes {
 }
 
 #[derive(Clone, Debug)]
-pub struct Cg2PartSequence {
+pub struct CgPartSequence {
+    version: changegroup::CgVersion,
     // Storing the ends in here bypasses a number of lifetime issues.
     changesets: Vec<changegroup::Part>,
     changesets_end: changegroup::Part,
     manifests: Vec<changegroup::Part>,
     manifests_end: changegroup::Part,
+    // Only in changegroup3.
+    treemanifests: Vec<(Vec<changegroup::Part>, changegroup::Part)>,
     filelogs: Vec<(Vec<changegroup::Part>, changegroup::Part)>,
     end: changegroup::Part,
 }
 
-impl Cg2PartSequence {
+impl CgPartSequence {
+    pub fn version(&self) -> changegroup::CgVersion {
+        self.version
+    }
+
     /// Combine all the changesets, manifests and filelogs into a single iterator.
     pub fn as_iter<'a>(&'a self) -> Box<Iterator<Item = &'a changegroup::Part> + 'a> {
         // Trying to describe the type here is madness. Just box it.
@@ -67,21 +74,27 @@ impl Cg2PartSequence {
                 .chain(iter::once(&self.changesets_end))
                 .chain(self.manifests.iter())
                 .chain(iter::once(&self.manifests_end))
-                .chain(
-                    self.filelogs
-                        .iter()
-                        .filter(|&&(ref parts, _)| {
-                            // If there are no filelog parts, it isn't valid to return a
-                            // SectionEnd since that won't be referring to anything. So
-                            // just skip the whole filelog.
-                            !parts.is_empty()
-                        })
-                        .flat_map(|&(ref parts, ref end)| parts.iter().chain(iter::once(end))),
-                )
+                .chain(Self::nonempty_sections(&self.treemanifests))
+                .chain(Self::nonempty_sections(&self.filelogs))
                 .chain(iter::once(&self.end)),
         )
     }
 
+    fn nonempty_sections<'a>(
+        sections: &'a [(Vec<changegroup::Part>, changegroup::Part)],
+    ) -> Box<Iterator<Item = &'a changegroup::Part> + 'a> {
+        Box::new(
+            sections
+                .iter()
+                .filter(|&&(ref parts, _)| {
+                    // If there are no parts, it isn't valid to return a SectionEnd since that
+                    // won't be referring to anything. So just skip the whole section.
+                    !parts.is_empty()
+                })
+                .flat_map(|&(ref parts, ref end)| parts.iter().chain(iter::once(end))),
+        )
+    }
+
     /// Combine all the changesets, manifests and filelogs into a single stream.
     ///
     /// This returns a clone of everything because streams can't really return
@@ -95,42 +108,39 @@ impl Cg2PartSequence {
     }
 }
 
-impl PartialEq<[changegroup::Part]> for Cg2PartSequence {
+impl PartialEq<[changegroup::Part]> for CgPartSequence {
     fn eq(&self, other: &[changegroup::Part]) -> bool {
         self.as_iter().eq(other.iter())
     }
 }
 
-impl Arbitrary for Cg2PartSequence {
+impl Arbitrary for CgPartSequence {
     fn arbitrary<G: Gen>(g: &mut G) -> Self {
         use changegroup::*;
 
-        // Generate a valid part sequence (changegroup, then manifest, then filelogs).
-        let size = g.size();
-
-        let changesets = gen_parts(Section::Changeset, g);
-        let manifests = gen_parts(Section::Manifest, g);
+        // Generate a valid part sequence (changegroup, then manifest, then directory manifests
+        // for changegroup3, then filelogs).
+        let version = if g.gen() {
+            CgVersion::Cg3
+        } else {
+            CgVersion::Cg2
+        };
 
-        let nfilelogs = g.gen_range(0, size);
-        let mut filelogs = Vec::with_capacity(nfilelogs);
+        let changesets = gen_parts(Section::Changeset, version, g);
+        let manifests = gen_parts(Section::Manifest, version, g);
+        let treemanifests = match version {
+            CgVersion::Cg2 => Vec::new(),
+            CgVersion::Cg3 => gen_sections(Section::Treemanifest, version, g),
+        };
+        let filelogs = gen_sections(Section::Filelog, version, g);
 
-        for _ in 0..nfilelogs {
-            // Changegroups can't support empty paths, so skip over those.
-            let path = loop {
-                let path = MPath::arbitrary(g);
-                if !path.is_empty() {
-                    break path;
-                }
-            };
-            let section_end = Part::SectionEnd(Section::Filelog(path.clone()));
-            filelogs.push((gen_parts(Section::Filelog(path), g), section_end));
-        }
-
-        Cg2PartSequence {
+        CgPartSequence {
+            version: version,
             changesets: changesets,
             changesets_end: Part::SectionEnd(Section::Changeset),
             manifests: manifests,
             manifests_end: Part::SectionEnd(Section::Manifest),
+            treemanifests: treemanifests,
             filelogs: filelogs,
             end: Part::End,
         }
@@ -142,17 +152,21 @@ impl Arbitrary for Cg2PartSequence {
         // All the parts can be shrinked independently as long as the section
         // remains the same (ensured in the impl of Arbitrary for
         // changegroup::Part).
+        let version = self.version;
         Box::new(
             (
                 self.changesets.clone(),
                 self.manifests.clone(),
+                self.treemanifests.clone(),
                 self.filelogs.clone(),
             ).shrink()
-                .map(|(c, m, f)| Cg2PartSequence {
+                .map(move |(c, m, t, f)| CgPartSequence {
+                    version: version,
                     changesets: c,
                     changesets_end: Part::SectionEnd(Section::Changeset),
                     manifests: m,
                     manifests_end: Part::SectionEnd(Section::Manifest),
+                    treemanifests: t,
                     filelogs: f,
                     end: Part::End,
                 }),
@@ -160,11 +174,44 @@ impl Arbitrary for Cg2PartSequence {
     }
 }
 
-fn gen_parts<G: Gen>(section: changegroup::Section, g: &mut G) -> Vec<changegroup::Part> {
+fn gen_sections<F, G>(
+    section: F,
+    version: changegroup::CgVersion,
+    g: &mut G,
+) -> Vec<(Vec<changegroup::Part>, changegroup::Part)>
+where
+    F: Fn(MPath) -> changegroup::Section,
+    G: Gen,
+{
+    let size = g.size();
+    (0..g.gen_range(0, size))
+        .map(|_| {
+            // Changegroups can't support empty paths, so skip over those.
+            let path = loop {
+                let path = MPath::arbitrary(g);
+                if !path.is_empty() {
+                    break path;
+                }
+            };
+            let section_end = changegroup::Part::SectionEnd(section(path.clone()));
+            (gen_parts(section(path), version, g), section_end)
+        })
+        .collect()
+}
+
+fn gen_parts<G: Gen>(
+    section: changegroup::Section,
+    version: changegroup::CgVersion,
+    g: &mut G,
+) -> Vec<changegroup::Part> {
     let size = g.size();
     (0..g.gen_range(0, size))
         .map(|_| {
-            changegroup::Part::CgChunk(section.clone(), changegroup::CgDeltaChunk::arbitrary(g))
+            let mut chunk = changegroup::CgDeltaChunk::arbitrary(g);
+            if version == changegroup::CgVersion::Cg3 {
+                chunk.flags = Some(g.gen());
+            }
+            changegroup::Part::CgChunk(section.clone(), chunk)
         })
         .collect()
 }
@@ -202,6 +249,7 @@ impl Arbitrary for changegroup::CgDeltaChunk {
             base: NodeHash::arbitrary(g),
             linknode: NodeHash::arbitrary(g),
             delta: Delta::arbitrary(g),
+            flags: None,
         }
     }
 
@@ -218,6 +266,7 @@ impl Arbitrary for changegroup::CgDeltaChunk {
                     base: clone.base.clone(),
                     linknode: clone.linknode.clone(),
                     delta: delta,
+                    flags: clone.flags,
                 }),
         )
     }
@@ -11,7 +11,7 @@ use std::io::{self, BufRead, BufReader, Cursor};
 use std::iter::Iterator;
 use std::str::FromStr;
 
-use futures::stream::Stream;
+use futures::stream::{self, Stream};
 use futures_ext::BoxStream;
 use slog::{Drain, Logger};
 use slog_term;
@@ -29,9 +29,10 @@ use Bundle2Item;
 use bundle2::{Bundle2Stream, StreamEvent};
 use bundle2_encode::Bundle2EncodeBuilder;
 use changegroup;
+use chunk::Chunk;
 use errors::*;
 use part_encode::PartEncodeBuilder;
-use part_header::{PartHeaderBuilder, PartHeaderType};
+use part_header::{self, PartHeaderBuilder, PartHeaderType};
 use types::StreamHeader;
 use utils::get_compression_param;
 use wirepack;
@@ -202,6 +203,43 @@ fn unknown_part(ct: Option<CompressorType>) {
                     if header.part_type() == &PartHeaderType::Listkeys && header.mandatory());
 }
 
+#[test]
+fn test_part_interrupted_by_error() {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::Changegroup).unwrap();
+    builder.set_data_generated(stream::iter_result(vec![
+        Ok(Chunk::new("payload").unwrap()),
+        Err(format_err!("generation failed")),
+    ]));
+    let part = builder.build(1);
+
+    let mut core = Core::new().unwrap();
+    let results = core.run(part.then(|res| Ok::<_, ()>(res)).collect()).unwrap();
+    let mut results = results.into_iter();
+
+    // Header and payload of the part come through as usual
+    assert!(results.next().unwrap().is_ok());
+    assert_eq!(
+        results.next().unwrap().unwrap(),
+        Chunk::new("payload").unwrap()
+    );
+
+    // Then the part is interrupted by an error:abort part
+    assert!(results.next().unwrap().unwrap().is_error());
+    let abort_header = results.next().unwrap().unwrap().into_bytes().unwrap();
+    let abort_header = part_header::decode(abort_header).unwrap();
+    assert_eq!(abort_header.part_type(), &PartHeaderType::ErrorAbort);
+    assert_eq!(
+        abort_header.mparams().get("message").unwrap(),
+        &"unexpected error: generation failed"
+    );
+    assert!(results.next().unwrap().unwrap().is_empty());
+
+    // The interrupted part is terminated before the error is returned
+    assert!(results.next().unwrap().unwrap().is_empty());
+    assert!(results.next().unwrap().is_err());
+    assert!(results.next().is_none());
+}
+
 fn parse_bundle(
     input: &[u8],
     compression: Option<&str>,
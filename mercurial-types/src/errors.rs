@@ -9,7 +9,9 @@ pub use failure::{Error, ResultExt};
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "invalid sha-1 input: {}", _0)] InvalidSha1Input(String),
+    #[fail(display = "invalid sha-256 input: {}", _0)] InvalidSha256Input(String),
     #[fail(display = "invalid fragment list: {}", _0)] InvalidFragmentList(String),
+    #[fail(display = "invalid path pattern '{}': {}", _0, _1)] InvalidPathPattern(String, String),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
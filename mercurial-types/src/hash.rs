@@ -11,6 +11,7 @@ use ascii::{AsciiStr, AsciiString};
 use quickcheck::{single_shrinker, Arbitrary, Gen};
 use rust_crypto::digest::Digest;
 use rust_crypto::sha1;
+use rust_crypto::sha2;
 
 use errors::*;
 
@@ -161,10 +162,125 @@ impl Context {
     }
 }
 
+/// Raw SHA-256 hash
+///
+/// Mercurial doesn't use it for its own hashes, but large files stored with the LFS protocol
+/// are addressed by the SHA-256 of their content.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Serialize, Deserialize, HeapSizeOf)]
+pub struct Sha256([u8; 32]);
+
+impl Sha256 {
+    /// Construct a `Sha256` from an array of 32 bytes containing a
+    /// SHA-256 (ie, *not* a hash of the bytes).
+    pub fn from_bytes<B: AsRef<[u8]>>(bytes: B) -> Result<Sha256> {
+        let bytes = bytes.as_ref();
+        if bytes.len() != 32 {
+            bail!(ErrorKind::InvalidSha256Input("need exactly 32 bytes".into()));
+        } else {
+            let mut ret = Sha256([0; 32]);
+            &mut ret.0[..].copy_from_slice(bytes);
+            Ok(ret)
+        }
+    }
+
+    pub fn to_hex(&self) -> AsciiString {
+        let mut v = Vec::with_capacity(64);
+        for &byte in self.as_ref() {
+            v.push(HEX_CHARS[(byte >> 4) as usize]);
+            v.push(HEX_CHARS[(byte & 0xf) as usize]);
+        }
+
+        unsafe {
+            // A hex string is always a pure ASCII string.
+            AsciiString::from_ascii_unchecked(v)
+        }
+    }
+}
+
+/// Compute the `Sha256` for a slice of bytes.
+impl<'a> From<&'a [u8]> for Sha256 {
+    fn from(data: &[u8]) -> Sha256 {
+        let mut sha256 = sha2::Sha256::new();
+        sha256.input(data);
+
+        let mut ret = Sha256([0; 32]);
+        sha256.result(&mut ret.0[..]);
+        ret
+    }
+}
+
+/// Context for incrementally computing a `Sha256` hash, e.g. of a content that is streamed.
+#[derive(Clone)]
+pub struct Sha256Context(sha2::Sha256);
+
+impl Sha256Context {
+    /// Construct a `Sha256Context`
+    pub fn new() -> Sha256Context {
+        Sha256Context(sha2::Sha256::new())
+    }
+
+    /// Update a context from something that can be turned into a `&[u8]`
+    pub fn update<T>(&mut self, data: T)
+    where
+        T: AsRef<[u8]>,
+    {
+        self.0.input(data.as_ref())
+    }
+
+    pub fn finish(mut self) -> Sha256 {
+        let mut ret = Sha256([0; 32]);
+        self.0.result(&mut ret.0[..]);
+        ret
+    }
+}
+
+/// Get a reference to the underlying bytes of a `Sha256`
+impl AsRef<[u8]> for Sha256 {
+    fn as_ref(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+impl FromStr for Sha256 {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Sha256> {
+        if s.len() != 64 {
+            bail!(ErrorKind::InvalidSha256Input(
+                "need exactly 64 hex digits".into()
+            ));
+        }
+
+        let mut ret = Sha256([0; 32]);
+
+        for idx in 0..ret.0.len() {
+            ret.0[idx] = match u8::from_str_radix(&s[(idx * 2)..(idx * 2 + 2)], 16) {
+                Ok(v) => v,
+                Err(_) => bail!(ErrorKind::InvalidSha256Input("bad digit".into())),
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+impl Display for Sha256 {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(&self.to_hex(), fmt)
+    }
+}
+
+/// Custom `Debug` output for `Sha256` so it prints in hex.
+impl Debug for Sha256 {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Sha256({})", self)
+    }
+}
 
 #[cfg(test)]
 mod test {
-    use super::{Sha1, NULL};
+    use super::{Sha1, Sha256, Sha256Context, NULL};
     use quickcheck::TestResult;
     use std::str::FromStr;
 
@@ -186,6 +302,29 @@ mod test {
         assert_eq!(nil, NILHASH);
     }
 
+    #[test]
+    fn test_sha256() {
+        let nil = Sha256::from(&[][..]);
+        assert_eq!(
+            format!("{}", nil),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            nil,
+            Sha256::from_str("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+                .unwrap()
+        );
+        assert!(Sha256::from_str("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca4").is_err());
+    }
+
+    #[test]
+    fn test_sha256_context() {
+        let mut context = Sha256Context::new();
+        context.update(b"hello ");
+        context.update(b"world");
+        assert_eq!(context.finish(), Sha256::from(&b"hello world"[..]));
+    }
+
     #[test]
     fn parse_ok() {
         assert_eq!(
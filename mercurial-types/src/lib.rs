@@ -87,6 +87,7 @@ pub mod nodehash;
 pub mod utils;
 pub mod manifest;
 pub mod manifest_utils;
+pub mod pathmatcher;
 pub mod blob;
 pub mod blobnode;
 pub mod changeset;
@@ -102,6 +103,7 @@ pub use fsencode::{fncache_fsencode, simple_fsencode};
 pub use manifest::{Entry, Manifest, Type};
 pub use node::Node;
 pub use nodehash::{EntryId, HgChangesetId, HgManifestId, NodeHash, NULL_HASH};
+pub use pathmatcher::PathMatcher;
 pub use repo::RepositoryId;
 pub use utils::percent_encode;
 
@@ -4,10 +4,10 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use futures::future::Future;
+use futures::future::{join_all, Future};
 use futures::stream::{empty, iter_ok, once, Stream};
-use futures_ext::{BoxStream, StreamExt};
-use std::collections::VecDeque;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use std::collections::{HashMap, VecDeque};
 
 use super::{Entry, MPath, MPathElement, Manifest};
 use super::manifest::{Content, Type};
@@ -64,24 +64,174 @@ pub fn changed_entry_stream<TM, FM>(
 where
     TM: Manifest,
     FM: Manifest,
+{
+    changed_entry_stream_with_pruner(to, from, path, |_| true)
+}
+
+/// Same as changed_entry_stream, but the entries for which `pruner` returns false are skipped,
+/// and so are their subentries: the trees they point to aren't even fetched.
+pub fn changed_entry_stream_with_pruner<TM, FM, P>(
+    to: &TM,
+    from: &FM,
+    path: MPath,
+    pruner: P,
+) -> BoxStream<ChangedEntry, Error>
+where
+    TM: Manifest,
+    FM: Manifest,
+    P: FnMut(&ChangedEntry) -> bool + Send + Clone + 'static,
 {
     diff_manifests(path, to, from)
-        .map(recursive_changed_entry_stream)
+        .filter(pruner.clone())
+        .map(move |entry| recursive_changed_entry_stream(entry, pruner.clone()))
         .flatten()
         .boxify()
 }
 
+/// Given a manifest and a set of base manifests, returns the entries of the manifest that are in
+/// none of the bases at the same path, i.e. what is missing from a client that has all the bases.
+/// The subentries of an entry that one of the bases has aren't returned either.
+/// An entry is Added if none of the bases has an entry of the same type at its path, and Modified
+/// (against the first base that has one) otherwise. Deleted entries aren't returned, as whether
+/// an entry is deleted depends on the base it is compared to.
+/// Entries for which `pruner` returns false are skipped, with their subentries.
+pub fn changed_entry_stream_against_bases<TM, FM, P>(
+    to: &TM,
+    bases: &[FM],
+    path: MPath,
+    pruner: P,
+) -> BoxStream<ChangedEntry, Error>
+where
+    TM: Manifest,
+    FM: Manifest,
+    P: FnMut(&ChangedEntry) -> bool + Send + Clone + 'static,
+{
+    list_with_bases(to, bases)
+        .map(move |(to_entries, bases_entries)| {
+            diff_against_bases(path, to_entries, bases_entries, pruner)
+        })
+        .flatten_stream()
+        .boxify()
+}
+
+/// Lists the entries of a manifest and of its bases.
+fn list_with_bases<TM, FM>(
+    to: &TM,
+    bases: &[FM],
+) -> BoxFuture<(Vec<Box<Entry + Sync>>, Vec<Vec<Box<Entry + Sync>>>), Error>
+where
+    TM: Manifest,
+    FM: Manifest,
+{
+    let to_entries = to.list().collect();
+    let bases_entries = join_all(
+        bases
+            .iter()
+            .map(|base| base.list().collect())
+            .collect::<Vec<_>>(),
+    );
+    to_entries.join(bases_entries).boxify()
+}
+
+/// Non-recursive part of changed_entry_stream_against_bases: compares the entries of a manifest
+/// to the ones of its bases, and returns the changed ones followed by their changed subentries.
+fn diff_against_bases<P>(
+    path: MPath,
+    to_entries: Vec<Box<Entry + Sync>>,
+    bases_entries: Vec<Vec<Box<Entry + Sync>>>,
+    mut pruner: P,
+) -> BoxStream<ChangedEntry, Error>
+where
+    P: FnMut(&ChangedEntry) -> bool + Send + Clone + 'static,
+{
+    let mut bases_by_name: HashMap<Option<MPathElement>, Vec<Box<Entry + Sync>>> = HashMap::new();
+    for base_entry in bases_entries.into_iter().flat_map(|entries| entries) {
+        bases_by_name
+            .entry(base_entry.get_name().clone())
+            .or_insert_with(Vec::new)
+            .push(base_entry);
+    }
+
+    let mut streams = vec![];
+    for entry in to_entries {
+        let mut base_entries: Vec<_> = bases_by_name
+            .remove(entry.get_name())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|base_entry| base_entry.get_type() == entry.get_type())
+            .collect();
+        if base_entries
+            .iter()
+            .any(|base_entry| base_entry.get_hash() == entry.get_hash())
+        {
+            continue;
+        }
+
+        let subpath = path.join_element(entry.get_name());
+        let contents = if entry.get_type() == Type::Tree {
+            let base_contents: Vec<_> = base_entries
+                .iter()
+                .map(|base_entry| base_entry.get_content())
+                .collect();
+            Some(entry.get_content().join(join_all(base_contents)))
+        } else {
+            None
+        };
+
+        let changed_entry = if base_entries.is_empty() {
+            ChangedEntry::new_added(path.clone(), entry)
+        } else {
+            ChangedEntry::new_modified(path.clone(), entry, base_entries.remove(0))
+        };
+        if !pruner(&changed_entry) {
+            continue;
+        }
+
+        let substream = match contents {
+            Some(contents) => {
+                let pruner = pruner.clone();
+                contents
+                    .and_then(move |(content, base_contents)| {
+                        let bases: Vec<_> = base_contents
+                            .into_iter()
+                            .map(get_tree_content)
+                            .collect();
+                        list_with_bases(&get_tree_content(content), &bases)
+                    })
+                    .map(move |(to_entries, bases_entries)| {
+                        diff_against_bases(subpath, to_entries, bases_entries, pruner)
+                    })
+                    .flatten_stream()
+                    .boxify()
+            }
+            None => empty().boxify(),
+        };
+        streams.push(once(Ok(changed_entry)).chain(substream));
+    }
+
+    iter_ok::<_, Error>(streams).flatten().boxify()
+}
+
 /// Given a ChangedEntry, return a stream that consists of this entry, and all subentries
 /// that differ. If input isn't a tree, then a stream with a single entry is returned, otherwise
 /// subtrees are recursively compared.
-fn recursive_changed_entry_stream(changed_entry: ChangedEntry) -> BoxStream<ChangedEntry, Error> {
+fn recursive_changed_entry_stream<P>(
+    changed_entry: ChangedEntry,
+    pruner: P,
+) -> BoxStream<ChangedEntry, Error>
+where
+    P: FnMut(&ChangedEntry) -> bool + Send + Clone + 'static,
+{
     match changed_entry.status {
-        EntryStatus::Added(entry) => recursive_entry_stream(changed_entry.path, entry)
-            .map(|(path, entry)| ChangedEntry::new_added(path, entry))
-            .boxify(),
-        EntryStatus::Deleted(entry) => recursive_entry_stream(changed_entry.path, entry)
-            .map(|(path, entry)| ChangedEntry::new_deleted(path, entry))
-            .boxify(),
+        EntryStatus::Added(entry) => {
+            recursive_subentries_stream(changed_entry.path, entry, ChangedEntry::new_added, pruner)
+        }
+        EntryStatus::Deleted(entry) => recursive_subentries_stream(
+            changed_entry.path,
+            entry,
+            ChangedEntry::new_deleted,
+            pruner,
+        ),
         EntryStatus::Modified(left, right) => {
             debug_assert!(left.get_type() == right.get_type());
 
@@ -95,11 +245,13 @@ fn recursive_changed_entry_stream(changed_entry: ChangedEntry) -> BoxStream<Chan
                         let left_manifest = get_tree_content(left_content);
                         let right_manifest = get_tree_content(right_content);
 
-                        diff_manifests(
+                        let diff = diff_manifests(
                             path.join_element(&entry_path),
                             &left_manifest,
                             &right_manifest,
-                        ).map(recursive_changed_entry_stream)
+                        );
+                        diff.filter(pruner.clone())
+                            .map(move |entry| recursive_changed_entry_stream(entry, pruner.clone()))
                     })
                     .flatten_stream()
                     .flatten();
@@ -119,6 +271,55 @@ fn recursive_changed_entry_stream(changed_entry: ChangedEntry) -> BoxStream<Chan
     }
 }
 
+/// Same as recursive_entry_stream, but for an added or deleted entry, with a pruner.
+fn recursive_subentries_stream<P>(
+    rootpath: MPath,
+    entry: Box<Entry + Sync>,
+    new_changed_entry: fn(MPath, Box<Entry + Sync>) -> ChangedEntry,
+    pruner: P,
+) -> BoxStream<ChangedEntry, Error>
+where
+    P: FnMut(&ChangedEntry) -> bool + Send + Clone + 'static,
+{
+    let subentries = match entry.get_type() {
+        Type::File | Type::Symlink | Type::Executable => empty().boxify(),
+        Type::Tree => {
+            let path = rootpath.join_element(entry.get_name());
+
+            entry
+                .get_content()
+                .map(move |content| {
+                    get_tree_content(content)
+                        .list()
+                        .map(move |entry| new_changed_entry(path.clone(), entry))
+                        .filter(pruner.clone())
+                        .map(move |changed_entry| {
+                            match changed_entry.status {
+                                EntryStatus::Added(entry) | EntryStatus::Deleted(entry) => {
+                                    recursive_subentries_stream(
+                                        changed_entry.path,
+                                        entry,
+                                        new_changed_entry,
+                                        pruner.clone(),
+                                    )
+                                }
+                                EntryStatus::Modified(..) => {
+                                    unreachable!("added or deleted entry expected")
+                                }
+                            }
+                        })
+                })
+                .flatten_stream()
+                .flatten()
+                .boxify()
+        }
+    };
+
+    once(Ok(new_changed_entry(rootpath, entry)))
+        .chain(subentries)
+        .boxify()
+}
+
 /// Given an entry and path from the root of the repo to this entry, returns all subentries with
 /// their path from the root of the repo.
 /// For a non-tree entry returns a stream with a single (entry, path) pair.
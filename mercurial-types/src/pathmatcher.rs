@@ -0,0 +1,502 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Include/exclude path patterns, as sent by Mercurial clients that only want part of the repo
+//! (e.g. `includepattern`/`excludepattern` in getbundle).
+//!
+//! The supported kinds are the ones Mercurial accepts from the wire:
+//! - `path:dir/file` matches the path and everything under it;
+//! - `rootfilesin:dir` matches the files directly in the directory;
+//! - `glob:dir/*.c` (the default kind) matches the paths that match the glob, and everything
+//!   under them. `*` and `?` don't match `/`, `**` does, and `**/` matches any number of
+//!   directories.
+
+use mononoke_types::{MPath, MPathElement};
+
+use errors::*;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum GlobToken {
+    Literal(u8),
+    // ?
+    AnyChar,
+    // *
+    Star,
+    // **
+    AnyString,
+    // **/
+    AnyDirs,
+    // [...], [!...]
+    Class { negated: bool, ranges: Vec<(u8, u8)> },
+}
+
+/// A glob with its `{a,b}` alternatives expanded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Glob {
+    alternatives: Vec<Vec<GlobToken>>,
+    // Leading literal directories of each alternative, to know which directories can't contain a
+    // match
+    roots: Vec<Vec<MPathElement>>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum PathPattern {
+    Path(Vec<MPathElement>),
+    RootFilesIn(Vec<MPathElement>),
+    Glob(Glob),
+}
+
+/// Decides which files and directories of a repo a client is interested in.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PathMatcher {
+    // None means "everything"
+    include: Option<Vec<PathPattern>>,
+    exclude: Vec<PathPattern>,
+}
+
+impl PathMatcher {
+    /// A matcher for the whole repo.
+    pub fn always() -> Self {
+        PathMatcher {
+            include: None,
+            exclude: vec![],
+        }
+    }
+
+    /// Builds a matcher from Mercurial patterns. No include patterns means that everything is
+    /// included.
+    pub fn new<I, E>(include: I, exclude: E) -> Result<Self>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+        E: IntoIterator,
+        E::Item: AsRef<[u8]>,
+    {
+        let include = include
+            .into_iter()
+            .map(|pattern| PathPattern::parse(pattern.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+        let exclude = exclude
+            .into_iter()
+            .map(|pattern| PathPattern::parse(pattern.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(PathMatcher {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(include)
+            },
+            exclude,
+        })
+    }
+
+    /// True if this matcher matches every path.
+    pub fn is_always(&self) -> bool {
+        self.include.is_none() && self.exclude.is_empty()
+    }
+
+    /// True if the file at `path` is matched.
+    pub fn matches_file(&self, path: &MPath) -> bool {
+        let elements: Vec<_> = path.into_iter().cloned().collect();
+        let included = match self.include {
+            Some(ref include) => include.iter().any(|pattern| pattern.matches_file(&elements)),
+            None => true,
+        };
+        included
+            && !self.exclude
+                .iter()
+                .any(|pattern| pattern.matches_file(&elements))
+    }
+
+    /// True if the directory at `path` (empty for the root) may contain matched files, i.e. if a
+    /// manifest traversal should descend into it.
+    pub fn visit_dir(&self, path: &MPath) -> bool {
+        let elements: Vec<_> = path.into_iter().cloned().collect();
+        // Excluding a directory excludes everything under it, but the files directly in a
+        // directory excluded by a rootfilesin: pattern don't exclude its subdirectories
+        let excluded = !elements.is_empty()
+            && self.exclude.iter().any(|pattern| match *pattern {
+                PathPattern::RootFilesIn(_) => false,
+                _ => pattern.matches_file(&elements),
+            });
+        if excluded {
+            return false;
+        }
+        match self.include {
+            Some(ref include) => include.iter().any(|pattern| pattern.visit_dir(&elements)),
+            None => true,
+        }
+    }
+}
+
+impl PathPattern {
+    fn parse(pattern: &[u8]) -> Result<Self> {
+        let (kind, value) = match pattern.iter().position(|c| *c == b':') {
+            Some(idx) => (&pattern[..idx], &pattern[idx + 1..]),
+            None => (&b"glob"[..], pattern),
+        };
+        match kind {
+            b"path" => Ok(PathPattern::Path(parse_dir(value)?)),
+            b"rootfilesin" => Ok(PathPattern::RootFilesIn(parse_dir(value)?)),
+            b"glob" => Ok(PathPattern::Glob(Glob::parse(value)?)),
+            _ => Err(ErrorKind::InvalidPathPattern(
+                String::from_utf8_lossy(pattern).into_owned(),
+                format!(
+                    "unsupported pattern kind '{}'",
+                    String::from_utf8_lossy(kind)
+                ),
+            ).into()),
+        }
+    }
+
+    fn matches_file(&self, path: &[MPathElement]) -> bool {
+        match *self {
+            PathPattern::Path(ref prefix) => path.starts_with(prefix),
+            PathPattern::RootFilesIn(ref dir) => {
+                !path.is_empty() && &path[..path.len() - 1] == &dir[..]
+            }
+            PathPattern::Glob(ref glob) => glob.matches(path),
+        }
+    }
+
+    fn visit_dir(&self, dir: &[MPathElement]) -> bool {
+        match *self {
+            PathPattern::Path(ref prefix) => dir.starts_with(prefix) || prefix.starts_with(dir),
+            PathPattern::RootFilesIn(ref parent) => parent.starts_with(dir),
+            PathPattern::Glob(ref glob) => glob.roots
+                .iter()
+                .any(|root| dir.starts_with(root) || root.starts_with(dir)),
+        }
+    }
+}
+
+impl Glob {
+    fn parse(glob: &[u8]) -> Result<Self> {
+        let err = |msg: &str| {
+            ErrorKind::InvalidPathPattern(
+                format!("glob:{}", String::from_utf8_lossy(glob)),
+                msg.into(),
+            )
+        };
+
+        let mut alternatives = vec![vec![]];
+        let mut idx = 0;
+        while idx < glob.len() {
+            let tokens = match glob[idx] {
+                b'*' if glob[idx..].starts_with(b"**/") => {
+                    idx += 3;
+                    vec![vec![GlobToken::AnyDirs]]
+                }
+                b'*' if glob[idx..].starts_with(b"**") => {
+                    idx += 2;
+                    vec![vec![GlobToken::AnyString]]
+                }
+                b'*' => {
+                    idx += 1;
+                    vec![vec![GlobToken::Star]]
+                }
+                b'?' => {
+                    idx += 1;
+                    vec![vec![GlobToken::AnyChar]]
+                }
+                b'[' => {
+                    let (token, len) =
+                        parse_class(&glob[idx..]).ok_or_else(|| err("unclosed ["))?;
+                    idx += len;
+                    vec![vec![token]]
+                }
+                b'{' => {
+                    let end = glob[idx..]
+                        .iter()
+                        .position(|c| *c == b'}')
+                        .ok_or_else(|| err("unclosed {"))?;
+                    let group = &glob[idx + 1..idx + end];
+                    if group.contains(&b'{') {
+                        return Err(err("nested { are not supported").into());
+                    }
+                    idx += end + 1;
+                    group
+                        .split(|c| *c == b',')
+                        .map(|alternative| Glob::parse(alternative))
+                        .collect::<Result<Vec<_>>>()?
+                        .into_iter()
+                        .flat_map(|glob| glob.alternatives)
+                        .collect()
+                }
+                b'\\' if idx + 1 < glob.len() => {
+                    idx += 2;
+                    vec![vec![GlobToken::Literal(glob[idx - 1])]]
+                }
+                c => {
+                    idx += 1;
+                    vec![vec![GlobToken::Literal(c)]]
+                }
+            };
+
+            alternatives = alternatives
+                .into_iter()
+                .flat_map(|prefix: Vec<GlobToken>| {
+                    tokens.iter().map(move |suffix| {
+                        let mut alternative = prefix.clone();
+                        alternative.extend(suffix.iter().cloned());
+                        alternative
+                    })
+                })
+                .collect();
+        }
+
+        let roots = alternatives.iter().map(|tokens| glob_root(tokens)).collect();
+        Ok(Glob {
+            alternatives,
+            roots,
+        })
+    }
+
+    /// Like Mercurial, a glob matches a path if it matches the path or one of its directories.
+    fn matches(&self, path: &[MPathElement]) -> bool {
+        let path: Vec<u8> = {
+            let elements: Vec<_> = path.iter().map(|element| element.as_bytes()).collect();
+            elements.join(&b'/')
+        };
+        let mut ends = path.iter()
+            .enumerate()
+            .filter(|&(_, c)| *c == b'/')
+            .map(|(idx, _)| idx)
+            .chain(Some(path.len()));
+
+        ends.any(|end| {
+            self.alternatives
+                .iter()
+                .any(|tokens| match_tokens(tokens, &path[..end]))
+        })
+    }
+}
+
+fn parse_dir(dir: &[u8]) -> Result<Vec<MPathElement>> {
+    let dir = if dir == b"." { &b""[..] } else { dir };
+    Ok(MPath::new(dir)?.into_iter().collect())
+}
+
+/// Parses a `[...]` character class, returns it with the number of bytes it takes.
+fn parse_class(glob: &[u8]) -> Option<(GlobToken, usize)> {
+    let mut idx = 1;
+    let negated = glob.get(idx) == Some(&b'!');
+    if negated {
+        idx += 1;
+    }
+
+    let mut ranges = vec![];
+    // A ] right after the [ (or [!) is part of the class
+    let mut first = true;
+    loop {
+        let c = *glob.get(idx)?;
+        if c == b']' && !first {
+            return Some((GlobToken::Class { negated, ranges }, idx + 1));
+        }
+        first = false;
+        match (glob.get(idx + 1), glob.get(idx + 2)) {
+            (Some(&b'-'), Some(&end)) if end != b']' => {
+                ranges.push((c, end));
+                idx += 3;
+            }
+            _ => {
+                ranges.push((c, c));
+                idx += 1;
+            }
+        }
+    }
+}
+
+/// The literal directories a glob starts with: nothing outside of them can match.
+fn glob_root(tokens: &[GlobToken]) -> Vec<MPathElement> {
+    let mut root = vec![];
+    let mut current = vec![];
+    for token in tokens {
+        match *token {
+            GlobToken::Literal(b'/') => {
+                if !current.is_empty() {
+                    // Literal slashes and null bytes can't be in there, the element is valid
+                    if let Ok(element) = MPathElement::new(current.clone()) {
+                        root.push(element);
+                    }
+                    current.clear();
+                }
+            }
+            GlobToken::Literal(c) => current.push(c),
+            _ => break,
+        }
+    }
+    root
+}
+
+fn match_tokens(tokens: &[GlobToken], path: &[u8]) -> bool {
+    let (token, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return path.is_empty(),
+    };
+
+    match *token {
+        GlobToken::Literal(c) => path.first() == Some(&c) && match_tokens(rest, &path[1..]),
+        GlobToken::AnyChar => match path.first() {
+            Some(&c) if c != b'/' => match_tokens(rest, &path[1..]),
+            _ => false,
+        },
+        GlobToken::Class {
+            negated,
+            ref ranges,
+        } => match path.first() {
+            Some(&c) => {
+                let in_class = ranges.iter().any(|&(start, end)| start <= c && c <= end);
+                in_class != negated && match_tokens(rest, &path[1..])
+            }
+            None => false,
+        },
+        GlobToken::Star => {
+            let component_len = path.iter().position(|c| *c == b'/').unwrap_or(path.len());
+            (0..component_len + 1).any(|len| match_tokens(rest, &path[len..]))
+        }
+        GlobToken::AnyString => (0..path.len() + 1).any(|len| match_tokens(rest, &path[len..])),
+        GlobToken::AnyDirs => {
+            match_tokens(rest, path)
+                || path.iter()
+                    .enumerate()
+                    .filter(|&(_, c)| *c == b'/')
+                    .any(|(idx, _)| match_tokens(rest, &path[idx + 1..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn path(p: &str) -> MPath {
+        MPath::new(p).unwrap()
+    }
+
+    fn matcher(include: &[&str], exclude: &[&str]) -> PathMatcher {
+        PathMatcher::new(include, exclude).expect("valid patterns")
+    }
+
+    #[test]
+    fn test_always() {
+        let m = matcher(&[], &[]);
+        assert!(m.is_always());
+        assert_eq!(m, PathMatcher::always());
+        assert!(m.matches_file(&path("dir/file")));
+        assert!(m.visit_dir(&MPath::empty()));
+    }
+
+    #[test]
+    fn test_path() {
+        let m = matcher(&["path:dir/sub"], &[]);
+        assert!(m.matches_file(&path("dir/sub/file")));
+        assert!(m.matches_file(&path("dir/sub/deeper/file")));
+        assert!(m.matches_file(&path("dir/sub")));
+        assert!(!m.matches_file(&path("dir/subfile")));
+        assert!(!m.matches_file(&path("dir/file")));
+
+        assert!(m.visit_dir(&MPath::empty()));
+        assert!(m.visit_dir(&path("dir")));
+        assert!(m.visit_dir(&path("dir/sub/deeper")));
+        assert!(!m.visit_dir(&path("dir/other")));
+        assert!(!m.visit_dir(&path("other")));
+
+        let m = matcher(&["path:."], &[]);
+        assert!(m.matches_file(&path("file")));
+        assert!(m.visit_dir(&path("dir")));
+    }
+
+    #[test]
+    fn test_rootfilesin() {
+        let m = matcher(&["rootfilesin:dir"], &[]);
+        assert!(m.matches_file(&path("dir/file")));
+        assert!(!m.matches_file(&path("dir/sub/file")));
+        assert!(!m.matches_file(&path("file")));
+
+        assert!(m.visit_dir(&MPath::empty()));
+        assert!(m.visit_dir(&path("dir")));
+        assert!(!m.visit_dir(&path("dir/sub")));
+
+        let m = matcher(&["rootfilesin:"], &[]);
+        assert!(m.matches_file(&path("file")));
+        assert!(!m.matches_file(&path("dir/file")));
+        assert!(!m.visit_dir(&path("dir")));
+    }
+
+    #[test]
+    fn test_glob() {
+        let m = matcher(&["glob:dir/*.c"], &[]);
+        assert!(m.matches_file(&path("dir/file.c")));
+        assert!(!m.matches_file(&path("dir/sub/file.c")));
+        assert!(!m.matches_file(&path("dir/file.h")));
+        assert!(m.visit_dir(&path("dir")));
+        assert!(m.visit_dir(&path("dir/sub")));
+        assert!(!m.visit_dir(&path("other")));
+
+        let m = matcher(&["dir/**.c"], &[]);
+        assert!(m.matches_file(&path("dir/file.c")));
+        assert!(m.matches_file(&path("dir/sub/file.c")));
+
+        let m = matcher(&["**/file.?"], &[]);
+        assert!(m.matches_file(&path("file.c")));
+        assert!(m.matches_file(&path("dir/sub/file.h")));
+        assert!(!m.matches_file(&path("dir/file.cc")));
+        assert!(m.visit_dir(&path("dir")));
+
+        // A glob matching a directory matches everything under it
+        let m = matcher(&["glob:d?r"], &[]);
+        assert!(m.matches_file(&path("dir/sub/file")));
+        assert!(!m.matches_file(&path("dir2/file")));
+    }
+
+    #[test]
+    fn test_glob_classes_and_alternatives() {
+        let m = matcher(&["file[0-9]"], &[]);
+        assert!(m.matches_file(&path("file1")));
+        assert!(!m.matches_file(&path("filex")));
+
+        let m = matcher(&["{a,b/c}/*.[!o]"], &[]);
+        assert!(m.matches_file(&path("a/x.c")));
+        assert!(m.matches_file(&path("b/c/x.h")));
+        assert!(!m.matches_file(&path("b/c/x.o")));
+        assert!(!m.matches_file(&path("b/x.c")));
+
+        assert!(m.visit_dir(&path("a")));
+        assert!(m.visit_dir(&path("b")));
+        assert!(!m.visit_dir(&path("c")));
+    }
+
+    #[test]
+    fn test_exclude() {
+        let m = matcher(&["path:dir"], &["path:dir/sub", "rootfilesin:dir/other", "**.o"]);
+        assert!(m.matches_file(&path("dir/file")));
+        assert!(!m.matches_file(&path("dir/sub/file")));
+        assert!(!m.matches_file(&path("dir/other/file")));
+        assert!(m.matches_file(&path("dir/other/deeper/file")));
+        assert!(!m.matches_file(&path("dir/file.o")));
+        assert!(!m.matches_file(&path("file")));
+
+        assert!(m.visit_dir(&path("dir")));
+        assert!(!m.visit_dir(&path("dir/sub")));
+        assert!(m.visit_dir(&path("dir/other")));
+
+        // Only excludes: everything else is included
+        let m = matcher(&[], &["path:dir"]);
+        assert!(!m.is_always());
+        assert!(m.matches_file(&path("file")));
+        assert!(!m.matches_file(&path("dir/file")));
+        assert!(!m.visit_dir(&path("dir")));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(PathMatcher::new(&["re:.*"], &[] as &[&str]).is_err());
+        assert!(PathMatcher::new(&["glob:[abc"], &[] as &[&str]).is_err());
+        assert!(PathMatcher::new(&["glob:{a,b"], &[] as &[&str]).is_err());
+    }
+}
@@ -14,12 +14,13 @@ extern crate mercurial_types;
 extern crate mercurial_types_mocks;
 
 use blobrepo::BlobRepo;
-use futures::Future;
+use futures::{Future, Stream};
 use futures::executor::spawn;
 use mercurial_types::{Changeset, Entry, MPath, Manifest, RepoPath, Type, NULL_HASH};
 use mercurial_types::manifest::Content;
-use mercurial_types::manifest_utils::{changed_entry_stream, diff_sorted_vecs, ChangedEntry,
-                                      EntryStatus};
+use mercurial_types::manifest_utils::{changed_entry_stream, changed_entry_stream_against_bases,
+                                      changed_entry_stream_with_pruner, diff_sorted_vecs,
+                                      ChangedEntry, EntryStatus};
 use mercurial_types::nodehash::{EntryId, HgChangesetId, NodeHash};
 use mercurial_types_mocks::manifest::{ContentFactory, MockEntry};
 use mercurial_types_mocks::nodehash;
@@ -380,6 +381,95 @@ fn test_recursive_changed_entry_stream_dirs_replaced_with_file() {
     );
 }
 
+#[test]
+fn test_recursive_changed_entry_stream_with_pruner() {
+    let repo = Arc::new(many_files_dirs::getrepo(None));
+    let main_hash = NodeHash::from_str("ecafdc4a4b6748b7a7215c6995f14c837dc1ebec").unwrap();
+    let base_hash = NodeHash::from_str("5a28e25f924a5d209b82ce0713d8d83e68982bc8").unwrap();
+    let manifest = get_root_manifest(repo.clone(), &HgChangesetId::new(main_hash));
+    let base_manifest = get_root_manifest(repo.clone(), &HgChangesetId::new(base_hash));
+
+    // Skip dir1/subdir1 and everything under it
+    let pruner = |changed_entry: &ChangedEntry| {
+        let entry = match changed_entry.status {
+            EntryStatus::Added(ref entry) => entry,
+            _ => panic!("only added entries are expected"),
+        };
+        changed_entry.path.join_element(entry.get_name())
+            != MPath::try_from("dir1/subdir1").unwrap()
+    };
+    let res = changed_entry_stream_with_pruner(
+        &manifest,
+        &base_manifest,
+        MPath::empty(),
+        pruner,
+    ).collect()
+        .wait()
+        .unwrap();
+
+    let expected_added = vec![
+        "2",
+        "dir1",
+        "dir1/file_1_in_dir1",
+        "dir1/file_2_in_dir1",
+        "dir2",
+        "dir2/file_1_in_dir2",
+    ];
+    check_changed_paths(res, expected_added, vec![], vec![]);
+}
+
+#[test]
+fn test_changed_entry_stream_against_bases() {
+    let repo = Arc::new(many_files_dirs::getrepo(None));
+    let get_manifest = |hash: &str| {
+        let hash = NodeHash::from_str(hash).unwrap();
+        get_root_manifest(repo.clone(), &HgChangesetId::new(hash))
+    };
+    let changes_against_bases = |to: &str, bases: Vec<&str>| {
+        let bases: Vec<_> = bases.into_iter().map(|base| get_manifest(base)).collect();
+        changed_entry_stream_against_bases(&get_manifest(to), &bases, MPath::empty(), |_| true)
+            .collect()
+            .wait()
+            .unwrap()
+    };
+
+    // The first base doesn't have dir1 and dir2, the second one has everything but the
+    // subsubdirs of dir1/subdir1
+    let res = changes_against_bases(
+        "473b2e715e0df6b2316010908879a3c78e275dd9",
+        vec![
+            "5a28e25f924a5d209b82ce0713d8d83e68982bc8",
+            "ecafdc4a4b6748b7a7215c6995f14c837dc1ebec",
+        ],
+    );
+    let expected_added = vec![
+        "dir1/subdir1/subsubdir1",
+        "dir1/subdir1/subsubdir1/file_1",
+        "dir1/subdir1/subsubdir2",
+        "dir1/subdir1/subsubdir2/file_1",
+        "dir1/subdir1/subsubdir2/file_2",
+    ];
+    let expected_modified = vec!["dir1", "dir1/subdir1"];
+    check_changed_paths(res, expected_added, vec![], expected_modified);
+
+    // Nothing is missing if one of the bases is the manifest itself
+    let res = changes_against_bases(
+        "473b2e715e0df6b2316010908879a3c78e275dd9",
+        vec![
+            "a6cb7dddec32acaf9a28db46cdb3061682155531",
+            "473b2e715e0df6b2316010908879a3c78e275dd9",
+        ],
+    );
+    check_changed_paths(res, vec![], vec![], vec![]);
+
+    // Everything is missing without a base
+    let res = changes_against_bases("5a28e25f924a5d209b82ce0713d8d83e68982bc8", vec![]);
+    let (added, modified, deleted) = count_entries(&res);
+    assert!(added > 0);
+    assert_eq!(deleted, 0);
+    assert_eq!(modified, 0);
+}
+
 #[test]
 fn nodehash_option() {
     assert_eq!(NULL_HASH.into_option(), None);
@@ -0,0 +1,198 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Pointers to file contents stored out of the repo with the LFS protocol, in the format written
+//! by Mercurial's lfs extension.
+
+use std::io::Write;
+use std::str;
+
+use mercurial_types::{MPath, NodeHash};
+use mercurial_types::hash::Sha256;
+
+use errors::*;
+
+/// The LFS spec that the pointers follow.
+pub const LFS_POINTER_VERSION: &str = "https://git-lfs.github.com/spec/v1";
+
+/// Revision flag of the file revisions whose text is an LFS pointer (REVIDX_EXTSTORED).
+pub const LFS_REVISION_FLAG: u16 = 1 << 13;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LfsPointer {
+    /// SHA-256 of the content
+    pub oid: Sha256,
+    pub size: u64,
+    /// The copy information that is otherwise in the filelog metadata
+    pub copy_from: Option<(MPath, NodeHash)>,
+    /// Mercurial considers the contents with a null byte as binary
+    pub is_binary: bool,
+}
+
+impl LfsPointer {
+    pub fn from_content(content: &[u8], copy_from: Option<(MPath, NodeHash)>) -> Self {
+        LfsPointer {
+            oid: Sha256::from(content),
+            size: content.len() as u64,
+            copy_from,
+            is_binary: content.contains(&0),
+        }
+    }
+
+    /// Parses a pointer written by Mercurial. Keys it doesn't know about are ignored.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let data = str::from_utf8(data).context("LFS pointer is not UTF-8")?;
+        let mut version = None;
+        let mut oid = None;
+        let mut size = None;
+        let mut copy = None;
+        let mut copyrev = None;
+        let mut is_binary = true;
+        for line in data.lines() {
+            let mut kv = line.splitn(2, ' ');
+            let key = kv.next().expect("must have at least 1 element");
+            let value = match kv.next() {
+                Some(value) => value,
+                None => bail_msg!("invalid line in LFS pointer: {:?}", line),
+            };
+            match key {
+                "version" => version = Some(value),
+                "oid" => match value.split_at(value.find(':').unwrap_or(0)) {
+                    ("sha256", hash) => oid = Some(hash[1..].parse()?),
+                    _ => bail_msg!("unsupported LFS oid {}", value),
+                },
+                "size" => size = Some(value.parse().context("invalid size in LFS pointer")?),
+                "x-hg-copy" => copy = Some(MPath::new(value)?),
+                "x-hg-copyrev" => copyrev = Some(value.parse()?),
+                "x-is-binary" => is_binary = value != "0",
+                _ => {}
+            }
+        }
+
+        if version != Some(LFS_POINTER_VERSION) {
+            bail_msg!("unsupported LFS pointer version {:?}", version);
+        }
+        let copy_from = match (copy, copyrev) {
+            (Some(path), Some(node)) => Some((path, node)),
+            (None, None) => None,
+            _ => bail_msg!("LFS pointer has only one of x-hg-copy and x-hg-copyrev"),
+        };
+        match (oid, size) {
+            (Some(oid), Some(size)) => Ok(LfsPointer {
+                oid,
+                size,
+                copy_from,
+                is_binary,
+            }),
+            _ => bail_msg!("LFS pointer has no oid or size"),
+        }
+    }
+
+    /// The text of the file revision that this pointer stands for, given its content: the copy
+    /// information goes back into the filelog metadata.
+    pub fn file_text(&self, content: &[u8]) -> Vec<u8> {
+        let mut text = vec![];
+        match self.copy_from {
+            Some((ref path, ref node)) => {
+                text.extend_from_slice(b"\x01\ncopy: ");
+                text.extend_from_slice(&path.to_vec());
+                text.extend_from_slice(format!("\ncopyrev: {}\n\x01\n", node).as_bytes());
+            }
+            // Content that looks like metadata has to be escaped with empty metadata
+            None if content.starts_with(b"\x01\n") => text.extend_from_slice(b"\x01\n\x01\n"),
+            None => {}
+        }
+        text.extend_from_slice(content);
+        text
+    }
+
+    /// Serializes the pointer the way Mercurial does: `version` first, then the other keys
+    /// sorted.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut out = vec![];
+        write!(out, "version {}\n", LFS_POINTER_VERSION)?;
+        write!(out, "oid sha256:{}\n", self.oid)?;
+        write!(out, "size {}\n", self.size)?;
+        if let Some((ref path, ref node)) = self.copy_from {
+            out.extend_from_slice(b"x-hg-copy ");
+            out.extend_from_slice(&path.to_vec());
+            write!(out, "\nx-hg-copyrev {}\n", node)?;
+        }
+        if !self.is_binary {
+            write!(out, "x-is-binary 0\n")?;
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    #[test]
+    fn test_serialize() {
+        let pointer = LfsPointer::from_content(b"", None);
+        assert_eq!(
+            pointer.serialize().unwrap(),
+            &b"version https://git-lfs.github.com/spec/v1\n\
+               oid sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\n\
+               size 0\n\
+               x-is-binary 0\n"[..]
+        );
+
+        let node = NodeHash::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let copy_from = Some((MPath::new("dir/file").unwrap(), node));
+        let pointer = LfsPointer::from_content(b"\0\0", copy_from);
+        assert_eq!(
+            pointer.serialize().unwrap(),
+            &b"version https://git-lfs.github.com/spec/v1\n\
+               oid sha256:96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7\n\
+               size 2\n\
+               x-hg-copy dir/file\n\
+               x-hg-copyrev 0123456789abcdef0123456789abcdef01234567\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let node = NodeHash::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let copy_from = Some((MPath::new("dir/file").unwrap(), node));
+        for pointer in vec![
+            LfsPointer::from_content(b"content", None),
+            LfsPointer::from_content(b"\0\0", copy_from),
+        ] {
+            let serialized = pointer.serialize().unwrap();
+            assert_eq!(LfsPointer::parse(&serialized).unwrap(), pointer);
+        }
+
+        let no_oid = b"version https://git-lfs.github.com/spec/v1\nsize 0\n";
+        assert!(LfsPointer::parse(no_oid).is_err());
+        let bad_oid = b"version https://git-lfs.github.com/spec/v1\noid sha256:0\nsize 0\n";
+        assert!(LfsPointer::parse(bad_oid).is_err());
+    }
+
+    #[test]
+    fn test_file_text() {
+        let pointer = LfsPointer::from_content(b"content", None);
+        assert_eq!(pointer.file_text(b"content"), b"content".to_vec());
+        assert_eq!(
+            pointer.file_text(b"\x01\ncontent"),
+            b"\x01\n\x01\n\x01\ncontent".to_vec()
+        );
+
+        let node = NodeHash::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let copy_from = Some((MPath::new("dir/file").unwrap(), node));
+        let pointer = LfsPointer::from_content(b"content", copy_from);
+        assert_eq!(
+            pointer.file_text(b"content"),
+            b"\x01\ncopy: dir/file\n\
+              copyrev: 0123456789abcdef0123456789abcdef01234567\n\x01\ncontent"
+                .to_vec()
+        );
+    }
+}
@@ -47,6 +47,7 @@ pub mod manifest;
 pub mod changeset;
 pub mod revlogrepo;
 pub mod file;
+pub mod lfs;
 pub mod symlink;
 mod errors;
 pub use errors::*;
@@ -0,0 +1,170 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Generation of revlogs from scratch
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+
+use errors::*;
+use mercurial_types::{NodeHash, NULL_HASH};
+
+use super::parser::{self, Features, Version};
+use super::revidx::RevIdx;
+
+// Mercurial doesn't bother compressing anything shorter than this
+const MIN_COMPRESS_LEN: usize = 44;
+
+// Written instead of a revision number when there is no parent
+const NULL_REV: u32 = !0;
+
+/// `RevlogBuilder` writes a new inline RevlogNG, one revision at a time.
+///
+/// Every revision is stored as a full text (zlib compressed if that saves space) rather than as
+/// a delta, so the revlog is bigger than the one Mercurial would write for the same history, but
+/// it can be generated in a single pass and Mercurial reads it just fine.
+#[derive(Debug)]
+pub struct RevlogBuilder {
+    index: BytesMut,
+    // Size of the part of the revlog that was already taken out of `index`
+    taken: u64,
+    data_len: u64,
+    nodes: HashMap<NodeHash, RevIdx>,
+    next: RevIdx,
+}
+
+impl RevlogBuilder {
+    pub fn new() -> Self {
+        RevlogBuilder {
+            index: BytesMut::new(),
+            taken: 0,
+            data_len: 0,
+            nodes: HashMap::new(),
+            next: RevIdx::zero(),
+        }
+    }
+
+    /// Number of revisions added so far.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn contains(&self, node: &NodeHash) -> bool {
+        self.nodes.contains_key(node)
+    }
+
+    /// Size of the revlog generated so far, in bytes.
+    pub fn size(&self) -> u64 {
+        self.taken + self.index.len() as u64
+    }
+
+    /// The part of the revlog generated since the last call, so that it can be written out as it
+    /// is generated instead of being held in memory. `into_bytes` only returns what is left.
+    pub fn take_bytes(&mut self) -> Bytes {
+        self.taken += self.index.len() as u64;
+        self.index.take().freeze()
+    }
+
+    /// Append a revision. Its parents (if any) must have been added already. Returns the index of
+    /// the new revision.
+    pub fn add_revision(
+        &mut self,
+        node: NodeHash,
+        p1: Option<NodeHash>,
+        p2: Option<NodeHash>,
+        linkrev: RevIdx,
+        text: &[u8],
+    ) -> Result<RevIdx> {
+        if self.nodes.contains_key(&node) {
+            bail_err!(ErrorKind::Revlog(format!("node {} added twice", node)));
+        }
+        let p1 = self.parent_rev(&node, p1)?;
+        let p2 = self.parent_rev(&node, p2)?;
+
+        let rev = self.next;
+        let chunk = compress(text)?;
+
+        self.index.reserve(parser::indexng_size() + chunk.len());
+        if rev == RevIdx::zero() {
+            // The header takes the place of the (always 0) offset of the first revision
+            let features = Features::INLINE;
+            self.index.put_u16_be(features.bits());
+            self.index.put_u16_be(Version::RevlogNG as u16);
+            self.index.put_u16_be(0);
+        } else {
+            self.index.put_uint_be(self.data_len, 6);
+        }
+        self.index.put_u16_be(0); // revision flags
+        self.index.put_u32_be(chunk.len() as u32);
+        self.index.put_u32_be(text.len() as u32);
+        // A revision that is its own delta base is a full text
+        self.index.put_u32_be(rev.into());
+        self.index.put_u32_be(linkrev.into());
+        self.index.put_u32_be(p1);
+        self.index.put_u32_be(p2);
+        self.index.put_slice(node.as_ref());
+        self.index.put_slice(&[0; 12]);
+        self.index.put_slice(&chunk);
+
+        self.data_len += chunk.len() as u64;
+        self.nodes.insert(node, rev);
+        self.next = rev.succ();
+        Ok(rev)
+    }
+
+    /// The content of the `.i` file.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.index.to_vec()
+    }
+
+    fn parent_rev(&self, node: &NodeHash, parent: Option<NodeHash>) -> Result<u32> {
+        match parent {
+            None => Ok(NULL_REV),
+            Some(ref parent) if parent == &NULL_HASH => Ok(NULL_REV),
+            Some(parent) => match self.nodes.get(&parent) {
+                Some(rev) => Ok((*rev).into()),
+                None => Err(ErrorKind::Revlog(format!(
+                    "parent {} of {} must be added first",
+                    parent, node
+                )).into()),
+            },
+        }
+    }
+}
+
+/// Encode a full text the way Mercurial does: zlib compressed if that is worth it, or stored
+/// as is with a `u` marker unless it starts with a NUL byte.
+fn compress(text: &[u8]) -> Result<Vec<u8>> {
+    if text.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    if text.len() >= MIN_COMPRESS_LEN {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(text)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() < text.len() {
+            return Ok(compressed);
+        }
+    }
+
+    if text[0] == b'\0' {
+        Ok(text.to_vec())
+    } else {
+        let mut chunk = Vec::with_capacity(text.len() + 1);
+        chunk.push(b'u');
+        chunk.extend_from_slice(text);
+        Ok(chunk)
+    }
+}
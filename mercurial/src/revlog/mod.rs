@@ -26,6 +26,7 @@ pub use mercurial_types::delta;
 use mercurial_types::nodehash::EntryId;
 
 // Submodules
+mod builder;
 mod parser;
 mod revidx;
 mod lz4;
@@ -34,6 +35,7 @@ mod lz4;
 mod test;
 
 use self::parser::{Header, Version};
+pub use self::builder::RevlogBuilder;
 pub use self::parser::Entry;
 pub use self::revidx::RevIdx;
 
@@ -59,6 +59,13 @@ impl From<usize> for RevIdx {
     }
 }
 
+// Extract the `u32` from a `RevIdx`, for example to write it out
+impl From<RevIdx> for u32 {
+    fn from(v: RevIdx) -> Self {
+        v.0
+    }
+}
+
 // Construct a `RevIdx` from a string (which may fail)
 impl FromStr for RevIdx {
     type Err = <u32 as FromStr>::Err;
@@ -17,3 +17,73 @@ fn emptyrev() {
 
     assert_eq!(node.size(), Some(0));
 }
+
+#[test]
+fn builder_roundtrip() {
+    let n1 = NodeHash::from_bytes(&[1; 20]).unwrap();
+    let n2 = NodeHash::from_bytes(&[2; 20]).unwrap();
+    let n3 = NodeHash::from_bytes(&[3; 20]).unwrap();
+    let long = vec![b'a'; 1000];
+
+    let mut builder = RevlogBuilder::new();
+    builder
+        .add_revision(n1, None, None, RevIdx::from(0u32), b"")
+        .expect("adding rev 0 failed");
+    builder
+        .add_revision(n2, Some(n1), None, RevIdx::from(2u32), b"\0binary")
+        .expect("adding rev 1 failed");
+    builder
+        .add_revision(n3, Some(n2), Some(n1), RevIdx::from(5u32), &long)
+        .expect("adding rev 2 failed");
+    assert_eq!(builder.len(), 3);
+
+    let missing_parent = NodeHash::from_bytes(&[4; 20]).unwrap();
+    assert!(
+        builder
+            .add_revision(missing_parent, Some(missing_parent), None, RevIdx::zero(), b"")
+            .is_err()
+    );
+
+    let revlog = Revlog::new(builder.into_bytes(), None).expect("construction failed");
+
+    let node = revlog.get_rev_by_nodeid(&n1).expect("failed to get rev 0");
+    assert_eq!(node.size(), Some(0));
+    let node = revlog.get_rev_by_nodeid(&n2).expect("failed to get rev 1");
+    assert_eq!(node.as_blob().as_slice(), Some(&b"\0binary"[..]));
+    let node = revlog.get_rev_by_nodeid(&n3).expect("failed to get rev 2");
+    assert_eq!(node.as_blob().as_slice(), Some(&long[..]));
+
+    let entry = revlog
+        .get_entry(RevIdx::from(2u32))
+        .expect("failed to get entry 2");
+    assert_eq!(entry.linkrev, RevIdx::from(5u32));
+    assert_eq!(entry.p1, Some(RevIdx::from(1u32)));
+    assert_eq!(entry.p2, Some(RevIdx::from(0u32)));
+    assert_eq!(entry.baserev, None);
+}
+
+#[test]
+fn builder_take_bytes() {
+    let n1 = NodeHash::from_bytes(&[1; 20]).unwrap();
+    let n2 = NodeHash::from_bytes(&[2; 20]).unwrap();
+
+    let mut whole = RevlogBuilder::new();
+    let mut taken = RevlogBuilder::new();
+    for builder in vec![&mut whole, &mut taken] {
+        builder
+            .add_revision(n1, None, None, RevIdx::zero(), b"first")
+            .expect("adding rev 0 failed");
+    }
+    let first = taken.take_bytes();
+    assert_eq!(taken.size(), first.len() as u64);
+    for builder in vec![&mut whole, &mut taken] {
+        builder
+            .add_revision(n2, Some(n1), None, RevIdx::from(1u32), b"second")
+            .expect("adding rev 1 failed");
+    }
+    assert_eq!(taken.size(), whole.size());
+
+    let mut content = first.to_vec();
+    content.extend_from_slice(&taken.into_bytes());
+    assert_eq!(content, whole.into_bytes());
+}
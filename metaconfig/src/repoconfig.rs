@@ -11,6 +11,7 @@ use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::path::PathBuf;
 use std::str::from_utf8;
+use std::time::Duration;
 
 use futures::{future, Future, IntoFuture};
 
@@ -35,6 +36,35 @@ pub struct RepoConfig {
     pub repoid: i32,
     /// Scuba table for logging performance of operations
     pub scuba_table: Option<String>,
+    /// In-memory cache in front of the blobstore, if any
+    pub blobstore_cache: Option<BlobstoreCacheConfig>,
+    /// If set, blobs are zstd-compressed with this level before being stored
+    pub blobstore_compression_level: Option<i32>,
+    /// Pre-generated bundles that clients can clone from, if any
+    pub clonebundles: Option<ClonebundlesConfig>,
+    /// Whether bundle2 replies are compressed for clients that say they can read them
+    pub bundle2_compression: bool,
+    /// Files at least this large (in bytes) are stored for the LFS protocol when they are pushed,
+    /// and sent to remotefilelog clients as LFS pointers
+    pub lfs_threshold: Option<u64>,
+}
+
+/// Configuration of the in-memory cache of blobs
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlobstoreCacheConfig {
+    /// Maximum total size of cached blobs in bytes
+    pub size: usize,
+    /// How long a blob that wasn't found is remembered as missing
+    pub negative_ttl: Duration,
+}
+
+/// Where clone bundles are written to and where clients download them from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ClonebundlesConfig {
+    /// Directory with the bundles
+    pub dir: PathBuf,
+    /// URL that the files of `dir` are served under
+    pub url: String,
 }
 
 /// Types of repositories supported
@@ -187,6 +217,13 @@ struct RawRepoConfig {
     manifold_prefix: Option<String>,
     repoid: i32,
     scuba_table: Option<String>,
+    blobstore_cache_size: Option<usize>,
+    blobstore_cache_negative_ttl_ms: Option<u64>,
+    blobstore_compression_level: Option<i32>,
+    clonebundles_dir: Option<PathBuf>,
+    clonebundles_url: Option<String>,
+    bundle2_compression: Option<bool>,
+    lfs_threshold: Option<u64>,
 }
 
 /// Types of repositories supported
@@ -223,12 +260,32 @@ impl TryFrom<RawRepoConfig> for RepoConfig {
         let generation_cache_size = this.generation_cache_size.unwrap_or(10 * 1024 * 1024);
         let repoid = this.repoid;
         let scuba_table = this.scuba_table;
+        let negative_ttl_ms = this.blobstore_cache_negative_ttl_ms.unwrap_or(1000);
+        let blobstore_cache = this.blobstore_cache_size.map(|size| BlobstoreCacheConfig {
+            size,
+            negative_ttl: Duration::from_millis(negative_ttl_ms),
+        });
+        let clonebundles = match (this.clonebundles_dir, this.clonebundles_url) {
+            (Some(dir), Some(url)) => Some(ClonebundlesConfig {
+                dir,
+                url: url.trim_right_matches('/').to_string(),
+            }),
+            (None, None) => None,
+            _ => Err(ErrorKind::InvalidConfig(
+                "clonebundles_dir and clonebundles_url must be specified together".into(),
+            ))?,
+        };
 
         Ok(RepoConfig {
             repotype,
             generation_cache_size,
             repoid,
             scuba_table,
+            blobstore_cache,
+            blobstore_compression_level: this.blobstore_compression_level,
+            clonebundles,
+            bundle2_compression: this.bundle2_compression.unwrap_or(true),
+            lfs_threshold: this.lfs_threshold,
         })
     }
 }
@@ -250,6 +307,13 @@ mod test {
             generation_cache_size=1048576
             repoid=0
             scuba_table="scuba_table"
+            blobstore_cache_size=1000000
+            blobstore_cache_negative_ttl_ms=500
+            blobstore_compression_level=3
+            clonebundles_dir="/tmp/fbsource-bundles"
+            clonebundles_url="https://bundles.example.com/fbsource/"
+            bundle2_compression=false
+            lfs_threshold=1000
         "#;
         let www_content = r#"
             path="/tmp/www"
@@ -289,6 +353,17 @@ mod test {
                 generation_cache_size: 1024 * 1024,
                 repoid: 0,
                 scuba_table: Some("scuba_table".to_string()),
+                blobstore_cache: Some(BlobstoreCacheConfig {
+                    size: 1000000,
+                    negative_ttl: Duration::from_millis(500),
+                }),
+                blobstore_compression_level: Some(3),
+                clonebundles: Some(ClonebundlesConfig {
+                    dir: "/tmp/fbsource-bundles".into(),
+                    url: "https://bundles.example.com/fbsource".to_string(),
+                }),
+                bundle2_compression: false,
+                lfs_threshold: Some(1000),
             },
         );
         repos.insert(
@@ -298,6 +373,11 @@ mod test {
                 generation_cache_size: 10 * 1024 * 1024,
                 repoid: 1,
                 scuba_table: Some("scuba_table".to_string()),
+                blobstore_cache: None,
+                blobstore_compression_level: None,
+                clonebundles: None,
+                bundle2_compression: true,
+                lfs_threshold: None,
             },
         );
         assert_eq!(
@@ -45,6 +45,9 @@ pub use ancestors::{common_ancestors, greatest_common_ancestor, AncestorsNodeStr
 mod range;
 pub use range::RangeNodeStream;
 
+mod parentsfirst;
+pub use parentsfirst::ParentsFirstNodeStream;
+
 #[cfg(test)]
 extern crate ascii;
 #[cfg(test)]
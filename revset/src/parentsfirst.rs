@@ -0,0 +1,315 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem::replace;
+use std::sync::Arc;
+use std::vec;
+
+use futures::{Async, Poll};
+use futures::stream::Stream;
+
+use blobrepo::BlobRepo;
+use mercurial_types::NodeHash;
+use repoinfo::{Generation, RepoGenCache};
+
+use NodeStream;
+use errors::*;
+use setcommon::*;
+
+/// Outputs the nodes of its input parents first (by increasing generation number), which is the
+/// order Mercurial needs them in a changegroup. The other revsets output children first.
+///
+/// The oldest nodes come last in the input, so the input is walked once per window of
+/// generations: a walk keeps the lowest generations above the ones already output, as long as
+/// they have at most `window` nodes in total, and outputs them once it is done. The next walks
+/// stop as soon as they reach the generations already output. `make_input` creates the input of
+/// every walk, children first like the other revsets.
+pub struct ParentsFirstNodeStream {
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    make_input: Box<FnMut() -> Box<NodeStream> + Send>,
+    window: usize,
+    // The current walk, started when the nodes of the previous one are all output
+    input: Option<InputStream>,
+    // The lowest generations found by the current walk, only more than `window` nodes if a
+    // single generation has more
+    nodes: BTreeMap<Generation, BTreeSet<NodeHash>>,
+    nodes_count: usize,
+    // Generations from this one up were left for the next walks
+    dropped_from: Option<Generation>,
+    // Generations up to this one were output
+    output_up_to: Option<Generation>,
+    drain: vec::IntoIter<NodeHash>,
+    done: bool,
+}
+
+impl ParentsFirstNodeStream {
+    pub fn new<F>(
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        window: usize,
+        make_input: F,
+    ) -> ParentsFirstNodeStream
+    where
+        F: FnMut() -> Box<NodeStream> + Send + 'static,
+    {
+        ParentsFirstNodeStream {
+            repo: repo.clone(),
+            repo_generation,
+            make_input: Box::new(make_input),
+            window,
+            input: None,
+            nodes: BTreeMap::new(),
+            nodes_count: 0,
+            dropped_from: None,
+            output_up_to: None,
+            drain: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    pub fn boxed(self) -> Box<NodeStream> {
+        Box::new(self)
+    }
+
+    fn add(&mut self, hash: NodeHash, generation: Generation) {
+        if let Some(dropped_from) = self.dropped_from {
+            if generation >= dropped_from {
+                return;
+            }
+        }
+        if self.nodes
+            .entry(generation)
+            .or_insert_with(BTreeSet::new)
+            .insert(hash)
+        {
+            self.nodes_count += 1;
+        }
+
+        // The input is children first, so the highest generations can be dropped without
+        // missing any of the lower ones
+        while self.nodes_count > self.window && self.nodes.len() > 1 {
+            let highest = *self.nodes.keys().next_back().expect("nodes is not empty");
+            let dropped = self.nodes
+                .remove(&highest)
+                .expect("highest generation is missing");
+            self.nodes_count -= dropped.len();
+            self.dropped_from = Some(highest);
+        }
+    }
+
+    fn end_walk(&mut self) {
+        self.input = None;
+        let nodes = replace(&mut self.nodes, BTreeMap::new());
+        self.nodes_count = 0;
+        // Nothing was left for another walk
+        self.done = replace(&mut self.dropped_from, None).is_none();
+        self.output_up_to = nodes.keys().next_back().cloned().or(self.output_up_to);
+        self.drain = nodes
+            .into_iter()
+            .flat_map(|(_, hashes)| hashes)
+            .collect::<Vec<_>>()
+            .into_iter();
+    }
+}
+
+impl Stream for ParentsFirstNodeStream {
+    type Item = NodeHash;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(hash) = self.drain.next() {
+                return Ok(Async::Ready(Some(hash)));
+            }
+            if self.done {
+                return Ok(Async::Ready(None));
+            }
+
+            if self.input.is_none() {
+                let input = (self.make_input)();
+                self.input = Some(add_generations(
+                    input,
+                    self.repo_generation.clone(),
+                    self.repo.clone(),
+                ));
+            }
+            let next = match self.input.as_mut().expect("input was just set").poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(next) => next,
+            };
+            match next {
+                // The rest of the input was output after the previous walks
+                Some((_, generation)) if Some(generation) <= self.output_up_to => self.end_walk(),
+                Some((hash, generation)) => self.add(hash, generation),
+                None => self.end_walk(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::Future;
+
+    use SetDifferenceNodeStream;
+    use UnionNodeStream;
+    use ancestors::AncestorsNodeStream;
+    use branch_wide;
+    use linear;
+    use setcommon::NotReadyEmptyStream;
+    use tests::assert_node_sequence;
+    use tests::string_to_nodehash;
+
+    #[test]
+    fn linear_parents_first() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = ParentsFirstNodeStream::new(&repo, repo_generation.clone(), 100, {
+            let repo = repo.clone();
+            let repo_generation = repo_generation.clone();
+            move || {
+                AncestorsNodeStream::new(
+                    &repo,
+                    repo_generation.clone(),
+                    string_to_nodehash("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+                ).boxed()
+            }
+        }).boxed();
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536"),
+                string_to_nodehash("3e0e761030db6e479a7fb58b12881883f9f8c63f"),
+                string_to_nodehash("607314ef579bd2407752361ba1b0c1729d08b281"),
+                string_to_nodehash("d0a361e9022d226ae52f689667bd7d212a19cfe0"),
+                string_to_nodehash("cb15ca4a43a59acff5388cea9648c162afde8372"),
+                string_to_nodehash("eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b"),
+                string_to_nodehash("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+            ],
+            nodestream,
+        );
+    }
+
+    #[test]
+    fn branch_wide_difference_parents_first() {
+        let repo = Arc::new(branch_wide::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        // Everything but the root, which is an ancestor of all the heads
+        let make_input = {
+            let repo = repo.clone();
+            let repo_generation = repo_generation.clone();
+            move || {
+                let heads = vec![
+                    string_to_nodehash("49f53ab171171b3180e125b918bd1cf0af7e5449"),
+                    string_to_nodehash("4685e9e62e4885d477ead6964a7600c750e39b03"),
+                    string_to_nodehash("c27ef5b7f15e9930e5b93b1f32cc2108a2aabe12"),
+                    string_to_nodehash("9e8521affb7f9d10e9551a99c526e69909042b20"),
+                ];
+                let heads = heads.into_iter().map({
+                    let repo = repo.clone();
+                    let repo_generation = repo_generation.clone();
+                    move |head| {
+                        AncestorsNodeStream::new(&repo, repo_generation.clone(), head).boxed()
+                    }
+                });
+                SetDifferenceNodeStream::new(
+                    &repo,
+                    repo_generation.clone(),
+                    UnionNodeStream::new(&repo, repo_generation.clone(), heads).boxed(),
+                    AncestorsNodeStream::new(
+                        &repo,
+                        repo_generation.clone(),
+                        string_to_nodehash("ecba698fee57eeeef88ac3dcc3b623ede4af47bd"),
+                    ).boxed(),
+                ).boxed()
+            }
+        };
+        // One walk per generation
+        let nodestream =
+            ParentsFirstNodeStream::new(&repo, repo_generation.clone(), 1, make_input).boxed();
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("9e8521affb7f9d10e9551a99c526e69909042b20"),
+                string_to_nodehash("4685e9e62e4885d477ead6964a7600c750e39b03"),
+                string_to_nodehash("c27ef5b7f15e9930e5b93b1f32cc2108a2aabe12"),
+                string_to_nodehash("49f53ab171171b3180e125b918bd1cf0af7e5449"),
+            ],
+            nodestream,
+        );
+    }
+
+    #[test]
+    fn parents_first_empty() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = ParentsFirstNodeStream::new(&repo, repo_generation.clone(), 100, || {
+            Box::new(NotReadyEmptyStream { poll_count: 1 })
+        }).boxed();
+
+        assert_node_sequence(repo_generation, &repo, vec![], nodestream);
+    }
+
+    #[test]
+    fn parents_first_incremental() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+        let walks = Arc::new(AtomicUsize::new(0));
+
+        let mut nodestream = ParentsFirstNodeStream::new(&repo, repo_generation.clone(), 2, {
+            let repo = repo.clone();
+            let repo_generation = repo_generation.clone();
+            let walks = walks.clone();
+            move || {
+                walks.fetch_add(1, Ordering::SeqCst);
+                AncestorsNodeStream::new(
+                    &repo,
+                    repo_generation.clone(),
+                    string_to_nodehash("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+                ).boxed()
+            }
+        });
+
+        // The oldest nodes are output before the input is walked for the newer ones
+        let oldest = nodestream.by_ref().take(2).collect().wait().unwrap();
+        assert_eq!(
+            oldest,
+            vec![
+                string_to_nodehash("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536"),
+                string_to_nodehash("3e0e761030db6e479a7fb58b12881883f9f8c63f"),
+            ]
+        );
+        assert_eq!(walks.load(Ordering::SeqCst), 1);
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("607314ef579bd2407752361ba1b0c1729d08b281"),
+                string_to_nodehash("d0a361e9022d226ae52f689667bd7d212a19cfe0"),
+                string_to_nodehash("cb15ca4a43a59acff5388cea9648c162afde8372"),
+                string_to_nodehash("eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b"),
+                string_to_nodehash("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+            ],
+            nodestream.boxed(),
+        );
+        // Two generations per walk
+        assert_eq!(walks.load(Ordering::SeqCst), 4);
+    }
+}
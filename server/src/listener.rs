@@ -4,15 +4,19 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::ffi::CStr;
 use std::fs;
 use std::io;
+use std::mem;
 use std::path::Path;
+use std::ptr;
 
 use futures::Stream;
 use futures::sync::mpsc;
 use futures_ext::{BoxStream, FutureExt, StreamExt};
 
 use bytes::Bytes;
+use libc;
 use tokio_core::reactor::Handle;
 use tokio_io::{AsyncRead, AsyncWrite, IoStream};
 use tokio_io::codec::{FramedRead, FramedWrite};
@@ -57,6 +61,27 @@ where
     Ok(listener.incoming().map(|(socket, _)| socket).boxify())
 }
 
+/// The unix user on the other end of the socket, i.e. the user that the ssh session running
+/// hgcli belongs to. Falls back to the numeric uid if it has no name.
+pub fn peer_user(sock: &UnixStream) -> io::Result<String> {
+    let uid = sock.peer_cred()?.uid;
+    Ok(user_name(uid).unwrap_or_else(|| uid.to_string()))
+}
+
+fn user_name(uid: libc::uid_t) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let ret = unsafe {
+        libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result)
+    };
+    if ret != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
 pub struct Stdio {
     pub stdin: BoxStream<Bytes, io::Error>,
     pub stdout: mpsc::Sender<Bytes>,
@@ -33,11 +33,17 @@ extern crate lz4;
 #[macro_use]
 extern crate maplit;
 
+extern crate ascii;
 extern crate async_compression;
 extern crate blobrepo;
+extern crate blobstore;
+extern crate bookmarks;
 extern crate bundle2_resolver;
 extern crate bytes;
+extern crate cachingblob;
+extern crate changesets;
 extern crate hgproto;
+extern crate libc;
 #[cfg(test)]
 extern crate many_files_dirs;
 extern crate mercurial;
@@ -53,10 +59,12 @@ extern crate scuba;
 extern crate services;
 extern crate sshrelay;
 extern crate stats;
+extern crate tempdir;
 
 mod errors;
 mod repo;
 mod listener;
+mod streamclone;
 
 use std::io;
 use std::panic;
@@ -80,13 +88,12 @@ use slog_logview::LogViewDrain;
 use bytes::Bytes;
 use hgproto::{sshproto, HgProtoHandler};
 use mercurial::RevlogRepo;
-use mercurial_types::RepositoryId;
 use metaconfig::RepoConfigs;
-use metaconfig::repoconfig::RepoType;
+use metaconfig::repoconfig::RepoConfig;
 
 use errors::*;
 
-use listener::{ssh_server_mux, Stdio};
+use listener::{peer_user, ssh_server_mux, Stdio};
 
 struct SenderBytesWrite {
     chan: Wait<mpsc::Sender<Bytes>>,
@@ -227,7 +234,7 @@ fn get_config<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<RepoConfi
 
 fn start_repo_listeners<I>(repos: I, root_log: &Logger) -> Result<Vec<JoinHandle<!>>>
 where
-    I: IntoIterator<Item = (RepoType, usize, i32, Option<String>)>,
+    I: IntoIterator<Item = RepoConfig>,
 {
     // Given the list of paths to repos:
     // - create a thread for it
@@ -236,22 +243,14 @@ where
 
     let handles: Vec<_> = repos
         .into_iter()
-        .map(move |(repotype, cache_size, repoid, scuba_table)| {
+        .map(move |config| {
             // start a thread for each repo to own the reactor and start listening for
             // connections and detach it
             thread::Builder::new()
-                .name(format!("listener_{:?}", repotype))
+                .name(format!("listener_{:?}", config.repotype))
                 .spawn({
                     let root_log = root_log.clone();
-                    move || {
-                        repo_listen(
-                            repotype,
-                            cache_size,
-                            root_log.clone(),
-                            RepositoryId::new(repoid),
-                            scuba_table,
-                        )
-                    }
+                    move || repo_listen(config, root_log.clone())
                 })
                 .map_err(Error::from)
         })
@@ -270,22 +269,10 @@ where
 }
 
 // Listener thread for a specific repo
-fn repo_listen(
-    repotype: RepoType,
-    cache_size: usize,
-    root_log: Logger,
-    repoid: RepositoryId,
-    scuba_table: Option<String>,
-) -> ! {
+fn repo_listen(config: RepoConfig, root_log: Logger) -> ! {
     let mut core = tokio_core::reactor::Core::new().expect("failed to create tokio core");
-    let (sockname, repo) = repo::init_repo(
-        &root_log,
-        &repotype,
-        cache_size,
-        &core.remote(),
-        repoid,
-        scuba_table,
-    ).expect("failed to initialize repo");
+    let (sockname, repo) =
+        repo::init_repo(&root_log, &config, &core.remote()).expect("failed to initialize repo");
 
     let listen_log = root_log.new(o!("repo" => repo.path().clone()));
 
@@ -302,6 +289,13 @@ fn repo_listen(
                     error!(listen_log, "Failed to get peer addr"; SlogKVError(Error::from(err)))
                 }
             };
+            let user = match peer_user(&sock) {
+                Ok(user) => Some(user),
+                Err(err) => {
+                    error!(listen_log, "Failed to get peer user"; SlogKVError(Error::from(err)));
+                    None
+                }
+            };
 
             // Have a connection. Extract std{in,out,err} streams for socket
             let Stdio {
@@ -324,7 +318,7 @@ fn repo_listen(
             // Construct a hg protocol handler
             let proto_handler = HgProtoHandler::new(
                 stdin,
-                repo::RepoClient::new(repo.clone(), &conn_log),
+                repo::RepoClient::new(repo.clone(), &conn_log, user),
                 sshproto::HgSshCommandDecode,
                 sshproto::HgSshCommandEncode,
                 &conn_log,
@@ -375,13 +369,8 @@ fn main() {
         };
 
         let config = get_config(root_log, &matches)?;
-        let repo_listeners = start_repo_listeners(
-            config
-                .repos
-                .into_iter()
-                .map(|(_, c)| (c.repotype, c.generation_cache_size, c.repoid, c.scuba_table)),
-            root_log,
-        )?;
+        let repo_listeners =
+            start_repo_listeners(config.repos.into_iter().map(|(_, c)| c), root_log)?;
 
         for handle in vec![stats_aggregation]
             .into_iter()
@@ -6,7 +6,7 @@
 
 //! State for a single source control Repo
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug};
 use std::io::{Cursor, Write};
 use std::mem;
@@ -14,6 +14,8 @@ use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 
+use ascii::AsciiString;
+use async_compression::CompressorType;
 use bytes::{BufMut, Bytes, BytesMut};
 use failure::err_msg;
 use futures::{future, stream, Async, Future, IntoFuture, Poll, Stream};
@@ -27,59 +29,68 @@ use slog::{self, Drain, Logger};
 use slog_scuba::ScubaDrain;
 
 use blobrepo::BlobChangeset;
+use blobstore::Blobstore;
+use bookmarks::BookmarkUpdateReason;
 use bundle2_resolver;
+use cachingblob::CachingBlobstore;
+use changesets::{ErrorKind as ChangesetsErrorKind, HgChangesetIdPrefix};
 use mercurial;
-use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item};
+use mercurial::lfs::{LfsPointer, LFS_REVISION_FLAG};
+use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item, Capabilities};
 use mercurial_types::{percent_encode, BlobNode, Changeset, Entry, HgChangesetId, HgManifestId,
-                      MPath, NodeHash, Parents, RepoPath, RepositoryId, Type, NULL_HASH};
-use mercurial_types::manifest_utils::{changed_entry_stream, EntryStatus};
-use metaconfig::repoconfig::RepoType;
+                      MPath, Manifest, NodeHash, Parents, PathMatcher, RepoPath, RepositoryId,
+                      Type, NULL_HASH};
+use mercurial_types::manifest_utils::{changed_entry_stream_against_bases, ChangedEntry,
+                                      EntryStatus};
+use metaconfig::repoconfig::{ClonebundlesConfig, RepoConfig, RepoType};
 
 use hgproto::{self, GetbundleArgs, GettreepackArgs, HgCommandRes, HgCommands};
 
 use blobrepo::BlobRepo;
 
 use errors::*;
+use streamclone;
 
 use repoinfo::RepoGenCache;
-use revset::{AncestorsNodeStream, IntersectNodeStream, NodeStream, SetDifferenceNodeStream,
-             SingleNodeHash, UnionNodeStream};
+use revset::{AncestorsNodeStream, IntersectNodeStream, NodeStream, ParentsFirstNodeStream,
+             SetDifferenceNodeStream, SingleNodeHash, UnionNodeStream};
 
 const METAKEYFLAG: &str = "f";
 const METAKEYSIZE: &str = "s";
 
+/// Number of changesets fetched concurrently while a getbundle reply is generated.
+const GETBUNDLE_CHANGESET_FETCHES: usize = 100;
+/// Number of changeset hashes kept in memory while a getbundle reply is generated. The ancestors
+/// of the heads are walked once per this many changesets sent, see ParentsFirstNodeStream.
+const GETBUNDLE_WINDOW: usize = 100_000;
+
 mod ops {
     pub const HELLO: &str = "hello";
     pub const UNBUNDLE: &str = "unbundle";
     pub const HEADS: &str = "heads";
     pub const LOOKUP: &str = "lookup";
     pub const KNOWN: &str = "known";
+    pub const LISTKEYS: &str = "listkeys";
+    pub const PUSHKEY: &str = "pushkey";
     pub const BETWEEN: &str = "between";
     pub const GETBUNDLE: &str = "getbundle";
     pub const GETTREEPACK: &str = "gettreepack";
     pub const GETFILES: &str = "getfiles";
+    pub const STREAMOUT: &str = "stream_out";
+    pub const CLONEBUNDLES: &str = "clonebundles";
 }
 
 pub fn init_repo(
     parent_logger: &Logger,
-    repotype: &RepoType,
-    cache_size: usize,
+    config: &RepoConfig,
     remote: &Remote,
-    repoid: RepositoryId,
-    scuba_table: Option<String>,
 ) -> Result<(PathBuf, HgRepo)> {
-    let repopath = repotype.path();
+    let repopath = config.repotype.path();
 
     let mut sock = repopath.join(".hg");
 
-    let repo = HgRepo::new(
-        parent_logger,
-        repotype,
-        cache_size,
-        remote,
-        repoid,
-        scuba_table,
-    ).with_context(|_| format!("Failed to initialize repo {:?}", repopath))?;
+    let repo = HgRepo::new(parent_logger, config, remote)
+        .with_context(|_| format!("Failed to initialize repo {:?}", repopath))?;
 
     sock.push("mononoke.sock");
 
@@ -87,22 +98,41 @@ pub fn init_repo(
 }
 
 pub trait OpenableRepoType {
-    fn open(&self, logger: Logger, remote: &Remote, repoid: RepositoryId) -> Result<BlobRepo>;
+    fn open(
+        &self,
+        logger: Logger,
+        remote: &Remote,
+        repoid: RepositoryId,
+        compression_level: Option<i32>,
+    ) -> Result<BlobRepo>;
     fn path(&self) -> &Path;
 }
 
 impl OpenableRepoType for RepoType {
-    fn open(&self, logger: Logger, remote: &Remote, repoid: RepositoryId) -> Result<BlobRepo> {
+    fn open(
+        &self,
+        logger: Logger,
+        remote: &Remote,
+        repoid: RepositoryId,
+        compression_level: Option<i32>,
+    ) -> Result<BlobRepo> {
         use hgproto::ErrorKind;
         use metaconfig::repoconfig::RepoType::*;
 
         let ret = match *self {
             Revlog(_) => Err(ErrorKind::CantServeRevlogRepo)?,
-            BlobFiles(ref path) => BlobRepo::new_files(logger, &path, repoid)?,
-            BlobRocks(ref path) => BlobRepo::new_rocksdb(logger, &path, repoid)?,
-            TestBlobManifold(ref bucket, ref prefix, _) => {
-                BlobRepo::new_test_manifold(logger, bucket, &prefix, remote, repoid)?
+            BlobFiles(ref path) => BlobRepo::new_files(logger, &path, repoid, compression_level)?,
+            BlobRocks(ref path) => {
+                BlobRepo::new_rocksdb(logger, &path, repoid, compression_level)?
             }
+            TestBlobManifold(ref bucket, ref prefix, _) => BlobRepo::new_test_manifold(
+                logger,
+                bucket,
+                &prefix,
+                remote,
+                repoid,
+                compression_level,
+            )?,
         };
 
         Ok(ret)
@@ -138,24 +168,34 @@ pub struct HgRepo {
     hgrepo: Arc<BlobRepo>,
     repo_generation: RepoGenCache,
     scuba: Option<Arc<ScubaClient>>,
+    clonebundles: Option<ClonebundlesConfig>,
+    bundle2_compression: bool,
+    lfs_threshold: Option<u64>,
+    stream_cache: streamclone::StoreCache,
 }
 
 fn wireprotocaps() -> Vec<String> {
     vec![
         "lookup".to_string(),
         "known".to_string(),
+        "pushkey".to_string(),
         "getbundle".to_string(),
         "unbundle=HG10GZ,HG10BZ,HG10UN".to_string(),
         "gettreepack".to_string(),
         "remotefilelog".to_string(),
+        // Clients that support all the requirements of the generated store can do a streaming
+        // clone. This is the `stream` capability of repos that need more than `revlogv1`.
+        format!("streamreqs={}", streamclone::STREAM_REQUIREMENTS.join(",")),
     ]
 }
 
+// Clients ask for compressed replies in their own bundle2 caps, see
+// Capabilities::reply_compression
 fn bundle2caps() -> String {
     let caps = vec![
         ("HG20", vec![]),
         ("listkeys", vec![]),
-        ("changegroup", vec!["02"]),
+        ("changegroup", vec!["02", "03"]),
         ("b2x:infinitepush", vec![]),
         ("b2x:infinitepushscratchbookmarks", vec![]),
         ("pushkey", vec![]),
@@ -177,15 +217,9 @@ fn bundle2caps() -> String {
 }
 
 impl HgRepo {
-    pub fn new(
-        parent_logger: &Logger,
-        repo: &RepoType,
-        cache_size: usize,
-        remote: &Remote,
-        repoid: RepositoryId,
-        scuba_table: Option<String>,
-    ) -> Result<Self> {
-        let path = repo.path().to_owned();
+    pub fn new(parent_logger: &Logger, config: &RepoConfig, remote: &Remote) -> Result<Self> {
+        let path = config.repotype.path().to_owned();
+        let scuba_table = config.scuba_table.clone();
         let logger = {
             let kv = o!("repo" => format!("{}", path.display()));
             match scuba_table {
@@ -198,14 +232,33 @@ impl HgRepo {
             }
         };
 
+        let repoid = RepositoryId::new(config.repoid);
+        // Compression is applied when the repo is opened, underneath the cache, so that cached
+        // blobs are already decompressed
+        let compression_level = config.blobstore_compression_level;
+        let mut hgrepo = config.repotype.open(logger, remote, repoid, compression_level)?;
+        if let Some(ref cache) = config.blobstore_cache {
+            hgrepo = hgrepo.wrap_blobstore(|blobstore| {
+                Arc::new(CachingBlobstore::new(
+                    blobstore,
+                    cache.size,
+                    cache.negative_ttl,
+                )) as Arc<Blobstore>
+            });
+        }
+
         Ok(HgRepo {
             path: format!("{}", path.display()),
-            hgrepo: Arc::new(repo.open(logger, remote, repoid)?),
-            repo_generation: RepoGenCache::new(cache_size),
+            hgrepo: Arc::new(hgrepo),
+            repo_generation: RepoGenCache::new(config.generation_cache_size),
             scuba: match scuba_table {
                 Some(name) => Some(Arc::new(ScubaClient::new(name))),
                 None => None,
             },
+            clonebundles: config.clonebundles.clone(),
+            bundle2_compression: config.bundle2_compression,
+            lfs_threshold: config.lfs_threshold,
+            stream_cache: streamclone::StoreCache::new(),
         })
     }
 
@@ -229,13 +282,16 @@ impl Debug for HgRepo {
 pub struct RepoClient {
     repo: Arc<HgRepo>,
     logger: Logger,
+    // Who is on the other end of the connection, recorded when bookmarks are moved
+    user: Option<String>,
 }
 
 impl RepoClient {
-    pub fn new(repo: Arc<HgRepo>, parent_logger: &Logger) -> Self {
+    pub fn new(repo: Arc<HgRepo>, parent_logger: &Logger, user: Option<String>) -> Self {
         RepoClient {
             repo: repo,
             logger: parent_logger.new(o!()), // connection details?
+            user,
         }
     }
 
@@ -244,49 +300,88 @@ impl RepoClient {
         &self.logger
     }
 
+    /// Moves a bookmark if it still points to `old`. Empty `old` means that the bookmark must not
+    /// exist yet and empty `new` deletes it. Failing to move the bookmark is not an error: like
+    /// Mercurial, we just tell the client that nothing changed.
+    fn push_bookmark(&self, key: String, old: String, new: String) -> HgCommandRes<bool> {
+        fn parse_value(value: &str) -> Result<Option<HgChangesetId>> {
+            if value.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(HgChangesetId::from_str(value)?))
+            }
+        }
+
+        let logger = self.logger.clone();
+        let parsed = AsciiString::from_ascii(key.clone())
+            .map_err(|_| format_err!("bookmark name {:?} is not ASCII", key))
+            .and_then(|name| Ok((name, parse_value(&old)?, parse_value(&new)?)));
+        let (name, old, new) = match parsed {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                warn!(logger, "pushkey: invalid bookmark update: {}", err);
+                return future::ok(false).boxify();
+            }
+        };
+
+        self.repo
+            .hgrepo
+            .move_bookmark(&name, old, new, BookmarkUpdateReason::Pushkey, self.user.clone())
+            .then(move |res| match res {
+                Ok(()) => {
+                    info!(logger, "moved bookmark {} from {:?} to {:?}", name, old, new);
+                    Ok(true)
+                }
+                Err(err) => {
+                    warn!(logger, "pushkey: failed to move bookmark {}: {}", name, err);
+                    Ok(false)
+                }
+            })
+            .boxify()
+    }
+
     fn create_bundle(&self, args: GetbundleArgs) -> hgproto::Result<HgCommandRes<Bytes>> {
+        check_getbundle_patterns(&args.includepattern, &args.excludepattern)?;
+
         let writer = Cursor::new(Vec::new());
         let mut bundle = Bundle2EncodeBuilder::new(writer);
-        // Mercurial currently hangs while trying to read compressed bundles over the wire:
-        // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
-        // TODO: possibly enable compression support once this is fixed.
-        bundle.set_compressor_type(None);
+        // Mercurial versions that hang while reading compressed bundles over the wire
+        // (https://bz.mercurial-scm.org/show_bug.cgi?id=5646) don't ask for compression
+        bundle.set_compressor_type(self.reply_compression(&args.bundlecaps));
 
         let repo_generation = &self.repo.repo_generation;
         let hgrepo = &self.repo.hgrepo;
 
-        let ancestors_stream = |nodes: &Vec<NodeHash>| -> Box<NodeStream> {
-            let heads_ancestors = nodes.iter().map(|head| {
-                AncestorsNodeStream::new(&hgrepo, repo_generation.clone(), *head).boxed()
-            });
-            Box::new(UnionNodeStream::new(
-                &hgrepo,
-                repo_generation.clone(),
-                heads_ancestors,
-            ))
+        let make_nodestosend = {
+            let hgrepo = hgrepo.clone();
+            let repo_generation = repo_generation.clone();
+            let heads = args.heads.clone();
+            let common = args.common.clone();
+            move || {
+                SetDifferenceNodeStream::new(
+                    &hgrepo,
+                    repo_generation.clone(),
+                    ancestors_stream(&hgrepo, &repo_generation, &heads),
+                    ancestors_stream(&hgrepo, &repo_generation, &common),
+                ).boxed()
+            }
         };
 
-        let heads_ancestors = ancestors_stream(&args.heads);
-        let common_ancestors = ancestors_stream(&args.common);
-
-        let nodestosend = Box::new(SetDifferenceNodeStream::new(
+        // Only some of the hashes are kept in memory: changesets are fetched and serialized as
+        // the changegroup part is written
+        let nodestosend = ParentsFirstNodeStream::new(
             hgrepo,
             repo_generation.clone(),
-            heads_ancestors,
-            common_ancestors,
-        ));
-
-        // TODO(stash): avoid collecting all the changelogs in the vector - T25767311
-        let nodestosend = nodestosend
-            .collect()
-            .map(|nodes| stream::iter_ok(nodes.into_iter().rev()))
-            .flatten_stream();
+            GETBUNDLE_WINDOW,
+            make_nodestosend,
+        );
 
         let changelogentries = nodestosend
-            .and_then({
+            .map({
                 let hgrepo = hgrepo.clone();
                 move |node| hgrepo.get_changeset_by_changesetid(&HgChangesetId::new(node))
             })
+            .buffered(GETBUNDLE_CHANGESET_FETCHES)
             .and_then(|cs| {
                 let mut v = Vec::new();
                 mercurial::changeset::serialize_cs(&cs, &mut v)?;
@@ -300,27 +395,17 @@ impl RepoClient {
         // (note: just calling &b"bookmarks"[..] doesn't work because https://fburl.com/0p0sq6kp)
         if args.listkeys.contains(&b"bookmarks".to_vec()) {
             let hgrepo = self.repo.hgrepo.clone();
-            let bookmark_names = hgrepo.get_bookmark_keys();
-            let items = bookmark_names.and_then(move |name| {
-                // For each bookmark name, grab the corresponding value.
-                hgrepo.get_bookmark_value(&name).and_then(|result| {
-                    // If the name somehow wasn't found, it's possible a race happened. where the
-                    // bookmark was deleted from underneath. Skip it.
-                    // Boxing is necessary here to make the match arms return the same types.
-                    match result {
-                        Some((hash, _version)) => {
-                            // AsciiString doesn't currently implement AsRef<[u8]>, so switch to
-                            // Vec which does
-                            let hash: Vec<u8> = hash.to_hex().into();
-                            Ok((name, hash)).into_future().boxify()
-                        }
-                        None => future::empty().boxify(),
-                    }
-                })
-            });
+            let items = hgrepo
+                .get_bookmarks_by_prefix(&AsciiString::new())
+                .map(|(name, cs)| {
+                    // AsciiString doesn't currently implement AsRef<[u8]>, so switch to
+                    // Vec which does
+                    let name: Vec<u8> = name.into();
+                    let hash: Vec<u8> = cs.to_hex().into();
+                    (name, hash)
+                });
             bundle.add_part(parts::listkey_part("bookmarks", items)?);
         }
-        // TODO(stash): handle includepattern= and excludepattern=
 
         let encode_fut = bundle.build();
 
@@ -330,6 +415,20 @@ impl RepoClient {
             .boxify())
     }
 
+    /// Compression for a bundle2 sent in reply to a getbundle with these `bundlecaps`.
+    fn reply_compression(&self, bundlecaps: &[Vec<u8>]) -> Option<CompressorType> {
+        if !self.repo.bundle2_compression {
+            return None;
+        }
+        match Capabilities::from_bundlecaps(bundlecaps) {
+            Ok(caps) => caps.and_then(|caps| caps.reply_compression()),
+            Err(err) => {
+                warn!(self.logger, "invalid bundle2 caps in bundlecaps: {}", err);
+                None
+            }
+        }
+    }
+
     fn gettreepack_untimed(&self, params: GettreepackArgs) -> HgCommandRes<Bytes> {
         info!(self.logger, "gettreepack {:?}", params);
 
@@ -340,10 +439,6 @@ impl RepoClient {
                 .boxify();
         }
 
-        // TODO(stash): T25850889 only one basemfnodes is used. That means that trees that client
-        // already has can be sent to the client.
-        let basemfnode = params.basemfnodes.get(0).unwrap_or(&NULL_HASH);
-
         if params.rootdir.len() != 0 {
             // For now, only root repo
             return Err(err_msg("only empty rootdir is supported"))
@@ -358,20 +453,59 @@ impl RepoClient {
         // TODO: possibly enable compression support once this is fixed.
         bundle.set_compressor_type(None);
 
-        // TODO(stash): T25850889 same entries will be generated over and over again.
-        // Potentially it can be very inefficient.
-        let changed_entries = params.mfnodes.iter().fold(
-            stream::empty().boxify(),
-            |cur_stream, manifest_id| {
-                let new_stream =
-                    get_changed_entry_stream(self.repo.hgrepo.clone(), manifest_id, basemfnode);
-                cur_stream.select(new_stream).boxify()
-            },
+        let matcher = match PathMatcher::new(&params.includepattern, &params.excludepattern) {
+            Ok(matcher) => Arc::new(matcher),
+            Err(err) => return Err(err).into_future().boxify(),
+        };
+
+        let hgrepo = self.repo.hgrepo.clone();
+        let manifests = future::join_all(
+            params
+                .mfnodes
+                .iter()
+                .map(|mfid| {
+                    let mfid = *mfid;
+                    hgrepo
+                        .get_manifest_by_nodeid(&mfid)
+                        .map(move |manifest| (mfid, manifest))
+                })
+                .collect::<Vec<_>>(),
+        );
+        // The null manifest is empty, there is nothing to compare to
+        let basemanifests = future::join_all(
+            params
+                .basemfnodes
+                .iter()
+                .filter(|basemfid| **basemfid != NULL_HASH)
+                .map(|basemfid| hgrepo.get_manifest_by_nodeid(basemfid))
+                .collect::<Vec<_>>(),
         );
 
+        let changed_entries = manifests
+            .join(basemanifests)
+            .map(move |(manifests, basemanifests)| {
+                manifests.into_iter().fold(
+                    stream::empty().boxify(),
+                    |cur_stream, (mfid, manifest)| {
+                        let new_stream = get_changed_entry_stream(
+                            hgrepo.clone(),
+                            mfid,
+                            &manifest,
+                            &basemanifests,
+                            matcher.clone(),
+                        );
+                        cur_stream.select(new_stream).boxify()
+                    },
+                )
+            })
+            .flatten_stream();
+
+        // Trees that changed in several of the mfnodes would be sent several times
         let changed_entries = changed_entries.filter({
-            let mut used_hashes = HashSet::new();
-            move |entry| used_hashes.insert(*entry.0.get_hash())
+            let mut sent = HashSet::new();
+            move |&(ref entry, _, ref basepath)| {
+                sent.insert((basepath.join_element(entry.get_name()), *entry.get_hash()))
+            }
         });
 
         parts::treepack_part(changed_entries)
@@ -506,34 +640,33 @@ impl HgCommands for RepoClient {
 
     // @wireprotocommand('lookup', 'key')
     fn lookup(&self, key: String) -> HgCommandRes<Bytes> {
-        // TODO(stash): T25928839 lookup should support bookmarks and prefixes too
-        let repo = self.repo.hgrepo.clone();
+        fn generate_resp_buf(success: bool, message: &[u8]) -> Bytes {
+            let mut buf = BytesMut::with_capacity(message.len() + 3);
+            buf.put(if success { b'1' } else { b'0' });
+            buf.put(b' ');
+            buf.extend_from_slice(message);
+            buf.put(b'\n');
+            buf.freeze()
+        }
+
+        info!(self.logger, "lookup: {}", key);
         let scuba = self.repo.scuba.clone();
         let mut sample = self.repo.scuba_sample(ops::LOOKUP);
-        NodeHash::from_str(&key)
-            .into_future()
-            .and_then(move |node| {
-                let csid = HgChangesetId::new(node);
-                repo.changeset_exists(&csid)
-                    .map(move |exists| (node, exists))
-            })
-            .and_then(|(node, exists)| {
-                if exists {
-                    let mut buf = BytesMut::with_capacity(node.to_hex().len() + 3);
-                    buf.put(b'1');
-                    buf.put(b' ');
-                    buf.extend_from_slice(node.to_hex().as_bytes());
-                    buf.put(b'\n');
-                    Ok(buf.freeze())
-                } else {
-                    let err_msg = format!("{} not found", node);
-                    let mut buf = BytesMut::with_capacity(err_msg.len() + 3);
-                    buf.put(b'0');
-                    buf.put(b' ');
-                    buf.extend_from_slice(err_msg.as_bytes());
-                    buf.put(b'\n');
-                    Ok(buf.freeze())
+        resolve_lookup_key(self.repo.hgrepo.clone(), key.clone())
+            .then(move |res| match res {
+                Ok(Some(csid)) => Ok(generate_resp_buf(true, csid.to_hex().as_bytes())),
+                Ok(None) => {
+                    let err_msg = format!("{} not found", key);
+                    Ok(generate_resp_buf(false, err_msg.as_bytes()))
                 }
+                Err(err) => match err.downcast_ref::<ChangesetsErrorKind>() {
+                    // Tell the client, the same way Mercurial does
+                    Some(ambiguous @ &ChangesetsErrorKind::AmbiguousPrefix(..)) => {
+                        let err_msg = format!("{}", ambiguous);
+                        Ok(generate_resp_buf(false, err_msg.as_bytes()))
+                    }
+                    _ => Err(err),
+                },
             })
             .timed(move |stats, _| {
                 add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
@@ -613,6 +746,64 @@ impl HgCommands for RepoClient {
             .boxify()
     }
 
+    // @wireprotocommand('listkeys', 'namespace')
+    fn listkeys(&self, namespace: String) -> HgCommandRes<HashMap<Vec<u8>, Vec<u8>>> {
+        info!(self.logger, "listkeys: {}", namespace);
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.repo.scuba_sample(ops::LISTKEYS);
+
+        let keys = match namespace.as_str() {
+            "bookmarks" => self.repo
+                .hgrepo
+                .get_bookmarks_by_prefix(&AsciiString::new())
+                .map(|(name, cs)| {
+                    let name: Vec<u8> = name.into();
+                    let hash: Vec<u8> = cs.to_hex().into();
+                    (name, hash)
+                })
+                .collect()
+                .map(|bookmarks| bookmarks.into_iter().collect())
+                .boxify(),
+            // All changesets are public: there are no draft changesets on the server
+            "phases" => {
+                let mut keys = HashMap::new();
+                keys.insert(b"publishing".to_vec(), b"True".to_vec());
+                future::ok(keys).boxify()
+            }
+            // Mercurial returns nothing for namespaces it doesn't know about
+            _ => future::ok(HashMap::new()).boxify(),
+        };
+
+        keys.timed(move |stats, _| {
+            add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+        }).boxify()
+    }
+
+    // @wireprotocommand('pushkey', 'namespace key old new')
+    fn pushkey(
+        &self,
+        namespace: String,
+        key: String,
+        old: String,
+        new: String,
+    ) -> HgCommandRes<bool> {
+        info!(self.logger, "pushkey: {} {} {:?} -> {:?}", namespace, key, old, new);
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.repo.scuba_sample(ops::PUSHKEY);
+
+        let res = if namespace == "bookmarks" {
+            self.push_bookmark(key, old, new)
+        } else {
+            // Phases can't be changed either, since everything is public already
+            warn!(self.logger, "pushkey: unsupported namespace {}", namespace);
+            future::ok(false).boxify()
+        };
+
+        res.timed(move |stats, _| {
+            add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+        }).boxify()
+    }
+
     // @wireprotocommand('getbundle', '*')
     fn getbundle(&self, args: GetbundleArgs) -> HgCommandRes<Bytes> {
         info!(self.logger, "Getbundle: {:?}", args);
@@ -635,6 +826,9 @@ impl HgCommands for RepoClient {
 
         let mut res = HashMap::new();
         let mut caps = wireprotocaps();
+        if self.repo.clonebundles.is_some() {
+            caps.push("clonebundles".to_string());
+        }
         caps.push(format!("bundle2={}", bundle2caps()));
         res.insert("capabilities".to_string(), caps);
 
@@ -658,6 +852,9 @@ impl HgCommands for RepoClient {
             self.logger.new(o!("command" => "unbundle")),
             heads,
             stream,
+            self.repo.bundle2_compression,
+            self.user.clone(),
+            self.repo.lfs_threshold,
         );
 
         let scuba = self.repo.scuba.clone();
@@ -680,6 +877,45 @@ impl HgCommands for RepoClient {
             .boxify();
     }
 
+    // @wireprotocommand('stream_out')
+    fn stream_out(&self) -> BoxStream<Bytes, Error> {
+        info!(self.logger, "stream_out");
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.repo.scuba_sample(ops::STREAMOUT);
+
+        // The whole store is generated before anything is sent, and then read from disk as the
+        // response is sent
+        self.repo
+            .stream_cache
+            .get_store(self.repo.hgrepo.clone(), self.logger.clone())
+            .timed(move |stats, _| {
+                add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+            })
+            .map(|store| store.response())
+            .flatten_stream()
+            .boxify()
+    }
+
+    // @wireprotocommand('clonebundles')
+    fn clonebundles(&self) -> HgCommandRes<String> {
+        info!(self.logger, "clonebundles");
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.repo.scuba_sample(ops::CLONEBUNDLES);
+
+        // Without a config the capability isn't advertised, and an empty manifest makes the
+        // clients that ask anyway fall back to a regular clone
+        let manifest = match self.repo.clonebundles {
+            Some(ref config) => clonebundles_manifest(config),
+            None => Ok(String::new()),
+        };
+
+        future::result(manifest)
+            .timed(move |stats, _| {
+                add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+            })
+            .boxify()
+    }
+
     // @wireprotocommand('getfiles', 'files*')
     fn getfiles(&self, params: BoxStream<(NodeHash, MPath), Error>) -> BoxStream<Bytes, Error> {
         info!(self.logger, "getfiles");
@@ -687,27 +923,68 @@ impl HgCommands for RepoClient {
         params
             .and_then(move |(node, path)| {
                 let repo = repo.clone();
-                create_remotefilelog_blob(repo.hgrepo.clone(), node, path).timed(move |stats, _| {
-                    let mut sample = repo.scuba_sample(ops::GETFILES);
-                    add_common_stats_and_send_to_scuba(repo.scuba.clone(), &mut sample, &stats);
-                })
+                create_remotefilelog_blob(repo.hgrepo.clone(), node, path, repo.lfs_threshold)
+                    .timed(move |stats, _| {
+                        let mut sample = repo.scuba_sample(ops::GETFILES);
+                        add_common_stats_and_send_to_scuba(repo.scuba.clone(), &mut sample, &stats);
+                    })
             })
             .boxify()
     }
 }
 
+/// Resolves the key of a `lookup` command the way Mercurial does: a full changeset hash, then a
+/// bookmark name, then a unique prefix of a changeset hash.
+fn resolve_lookup_key(
+    repo: Arc<BlobRepo>,
+    key: String,
+) -> BoxFuture<Option<HgChangesetId>, Error> {
+    let by_hash = match HgChangesetId::from_str(&key) {
+        Ok(csid) => repo.changeset_exists(&csid)
+            .map(move |exists| if exists { Some(csid) } else { None })
+            .boxify(),
+        Err(_) => future::ok(None).boxify(),
+    };
+
+    by_hash
+        .and_then({
+            let repo = repo.clone();
+            let key = key.clone();
+            move |csid| match (csid, AsciiString::from_ascii(key)) {
+                (Some(csid), _) => future::ok(Some(csid)).boxify(),
+                (None, Ok(name)) => repo.get_bookmark(&name),
+                (None, Err(_)) => future::ok(None).boxify(),
+            }
+        })
+        .and_then(move |csid| match (csid, HgChangesetIdPrefix::from_hex(&key)) {
+            (Some(csid), _) => future::ok(Some(csid)).boxify(),
+            (None, Ok(prefix)) => repo.get_changeset_by_prefix(&prefix),
+            (None, Err(_)) => future::ok(None).boxify(),
+        })
+        .boxify()
+}
+
+/// The trees of the manifest `mfid` that are in none of `basemanifests`, with their linknodes.
+/// The directories that `matcher` doesn't visit are skipped, but the root manifest is always
+/// sent.
 fn get_changed_entry_stream(
     repo: Arc<BlobRepo>,
-    mfid: &NodeHash,
-    basemfid: &NodeHash,
+    mfid: NodeHash,
+    manifest: &Box<Manifest + Sync>,
+    basemanifests: &[Box<Manifest + Sync>],
+    matcher: Arc<PathMatcher>,
 ) -> BoxStream<(Box<Entry + Sync>, NodeHash, MPath), Error> {
-    let manifest = repo.get_manifest_by_nodeid(mfid);
-    let basemanifest = repo.get_manifest_by_nodeid(basemfid);
+    // Files are never sent, there is no need to go through them
+    let pruner = move |changed_entry: &ChangedEntry| match changed_entry.status {
+        EntryStatus::Added(ref entry) | EntryStatus::Modified(ref entry, _) => {
+            entry.get_type() == Type::Tree
+                && matcher.visit_dir(&changed_entry.path.join_element(entry.get_name()))
+        }
+        EntryStatus::Deleted(_) => false,
+    };
 
-    let changed_entries = manifest
-        .join(basemanifest)
-        .map(|(mf, basemf)| changed_entry_stream(&mf, &basemf, MPath::empty()))
-        .flatten_stream();
+    let changed_entries =
+        changed_entry_stream_against_bases(manifest, basemanifests, MPath::empty(), pruner);
 
     let changed_entries = changed_entries
         .filter_map(move |entry_status| match entry_status.status {
@@ -734,7 +1011,7 @@ fn get_changed_entry_stream(
         .map(|(entry, linknode, basepath)| (entry, linknode, basepath));
 
     // Append root manifest
-    let root_entry_stream = Ok(repo.get_root_entry(&HgManifestId::new(*mfid)))
+    let root_entry_stream = Ok(repo.get_root_entry(&HgManifestId::new(mfid)))
         .into_future()
         .and_then({
             let hgrepo = repo.clone();
@@ -769,52 +1046,77 @@ fn fetch_linknode(
         .boxify()
 }
 
-fn get_file_history(
-    repo: Arc<BlobRepo>,
-    startnode: NodeHash,
-    path: MPath,
-) -> BoxStream<(NodeHash, Parents, NodeHash, Option<(MPath, NodeHash)>), Error> {
-    if startnode == NULL_HASH {
-        return stream::empty().boxify();
-    }
-    let mut startstate = VecDeque::new();
-    startstate.push_back(startnode);
-    let seen_nodes: HashSet<_> = [startnode].iter().cloned().collect();
-
-    stream::unfold(
-        (startstate, seen_nodes),
-        move |cur_data: (VecDeque<NodeHash>, HashSet<NodeHash>)| {
-            let (mut nodes, mut seen_nodes) = cur_data;
-            let node = nodes.pop_front()?;
+/// Only the changelog is sent by getbundle: remotefilelog and treemanifest clients fetch the files
+/// and trees they want later on, with getfiles and gettreepack, and gettreepack applies the
+/// patterns itself. A client sending patterns to getbundle expects the files and manifests of
+/// the bundle to be narrowed down, which they can't be, so it gets an error rather than a bundle
+/// that doesn't match what it asked for.
+fn check_getbundle_patterns(includepattern: &[Vec<u8>], excludepattern: &[Vec<u8>]) -> Result<()> {
+    let matcher = PathMatcher::new(includepattern, excludepattern)?;
+    ensure_msg!(
+        matcher.is_always(),
+        "getbundle can't narrow the bundle down to {:?}, only gettreepack takes patterns",
+        matcher
+    );
+    Ok(())
+}
 
-            let parents = repo.get_parents(&node);
-            let copy = repo.get_file_copy(&node);
+/// The union of the ancestors of `nodes`.
+fn ancestors_stream(
+    repo: &Arc<BlobRepo>,
+    repo_generation: &RepoGenCache,
+    nodes: &[NodeHash],
+) -> Box<NodeStream> {
+    let ancestors = nodes
+        .iter()
+        .map(|node| AncestorsNodeStream::new(repo, repo_generation.clone(), *node).boxed());
+    Box::new(UnionNodeStream::new(repo, repo_generation.clone(), ancestors))
+}
 
-            let linknode = RepoPath::file(path.clone()).into_future().and_then({
-                let repo = repo.clone();
-                move |path| repo.get_linknode(path, &node)
-            });
+/// The clone bundles manifest: one line per bundle written by the `clonebundles` tool, newest
+/// first, with the URL the bundle is served under and its bundle spec.
+fn clonebundles_manifest(config: &ClonebundlesConfig) -> Result<String> {
+    let mut names = Vec::new();
+    let entries = config
+        .dir
+        .read_dir()
+        .with_context(|_| format!("failed to list clone bundles in {}", config.dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        // Bundles that are still being written have another suffix
+        if let Some(name) = entry.file_name().to_str() {
+            if name.ends_with(".hg") {
+                names.push(name.to_string());
+            }
+        }
+    }
+    // Bundle names start with the time they were generated, so the newest ones come first
+    names.sort_by(|a, b| b.cmp(a));
 
-            let joined = parents
-                .join(linknode)
-                .join(copy)
-                .map(|(pl, c)| (pl.0, pl.1, c));
-
-            Some(joined.map(move |(parents, linknode, copy)| {
-                nodes.extend(parents.into_iter().filter(|p| seen_nodes.insert(*p)));
-                ((node, parents, linknode, copy), (nodes, seen_nodes))
-            }))
-        },
-    ).boxify()
+    let mut manifest = String::new();
+    for name in names {
+        manifest.push_str(&format!("{}/{} BUNDLESPEC=none-v2\n", config.url, name));
+    }
+    Ok(manifest)
 }
 
 fn create_remotefilelog_blob(
     repo: Arc<BlobRepo>,
     node: NodeHash,
     path: MPath,
+    lfs_threshold: Option<u64>,
 ) -> BoxFuture<Bytes, Error> {
-    // raw_content includes copy information
-    let raw_content_bytes = repo.get_file_content(&node).and_then(move |raw_content| {
+    let content_and_flags = repo.get_file_content_and_copy(&node).and_then({
+        let repo = repo.clone();
+        move |(content, copy_from)| match lfs_threshold {
+            Some(threshold) if content.len() as u64 >= threshold => {
+                create_lfs_pointer(repo, content, copy_from)
+            }
+            _ => future::ok((content, 0)).boxify(),
+        }
+    });
+
+    let raw_content_bytes = content_and_flags.and_then(move |(raw_content, flags)| {
         // requires digit counting to know for sure, use reasonable approximation
         let approximate_header_size = 12;
         let mut writer = Cursor::new(Vec::with_capacity(
@@ -822,14 +1124,13 @@ fn create_remotefilelog_blob(
         ));
 
         // Write header
-        // TODO(stash): support LFS files using METAKEYFLAG
         let res = write!(
             writer,
             "v1\n{}{}\n{}{}\0",
             METAKEYSIZE,
             raw_content.len(),
             METAKEYFLAG,
-            0,
+            flags,
         );
 
         res.and_then(|_| writer.write_all(&raw_content))
@@ -837,7 +1138,7 @@ fn create_remotefilelog_blob(
             .map(|_| writer.into_inner())
     });
 
-    let file_history_bytes = get_file_history(repo, node, path)
+    let file_history_bytes = repo.get_file_history(path, node)
         .collect()
         .and_then(|history| {
             let approximate_history_entry_size = 81;
@@ -885,3 +1186,38 @@ fn create_remotefilelog_blob(
         .map(|bytes| Bytes::from(bytes))
         .boxify()
 }
+
+/// Makes the LFS pointer that replaces the content of a file revision, and returns it with the
+/// flags of the revision. The content is stored for the LFS protocol when it's pushed or
+/// imported: if it wasn't, it's sent as it is.
+fn create_lfs_pointer(
+    repo: Arc<BlobRepo>,
+    content: Bytes,
+    copy_from: Option<(MPath, NodeHash)>,
+) -> BoxFuture<(Bytes, u16), Error> {
+    let pointer = LfsPointer::from_content(&content, copy_from);
+    repo.lfs_content_exists(&pointer.oid)
+        .and_then(move |exists| {
+            if exists {
+                Ok((Bytes::from(pointer.serialize()?), LFS_REVISION_FLAG))
+            } else {
+                Ok((content, 0))
+            }
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_getbundle_patterns() {
+        assert!(check_getbundle_patterns(&[], &[]).is_ok());
+
+        assert!(check_getbundle_patterns(&[b"path:dir".to_vec()], &[]).is_err());
+        assert!(check_getbundle_patterns(&[], &[b"glob:**.bin".to_vec()]).is_err());
+        // Unknown kinds of patterns are refused too
+        assert!(check_getbundle_patterns(&[b"re:.*".to_vec()], &[]).is_err());
+    }
+}
@@ -0,0 +1,527 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Streaming clones.
+//!
+//! Instead of a changegroup, a client doing a streaming clone (`stream_out`) gets the files of a
+//! Mercurial store and writes them to disk as is. Mononoke doesn't keep a revlog store around, so
+//! one is generated from the blob repo: the changelog has the changesets in topological order
+//! (by generation number), and manifests and files get the revlogs of a `treemanifest` repo.
+//!
+//! The stream starts with the number of files and of bytes that follow, so the whole store is
+//! generated before anything is sent. The revlogs are written to a temporary spill file as they
+//! are generated and read back from it as they are sent, so only their indexes of nodes are held
+//! in memory. The last store is kept in a `StoreCache` until the heads of the repo change, so
+//! that the clones in between don't generate it again.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use slog::Logger;
+use tempdir::TempDir;
+
+use blobrepo::BlobRepo;
+use mercurial::revlog::{RevIdx, RevlogBuilder};
+use mercurial_types::{BlobNode, Changeset, Entry, HgChangesetId, HgManifestId, MPath, NodeHash,
+                      Type, NULL_HASH};
+use mercurial_types::manifest_utils::{changed_entry_stream, EntryStatus};
+
+use errors::*;
+
+/// Requirements a client must support to use the generated store.
+pub const STREAM_REQUIREMENTS: &[&str] = &["revlogv1", "treemanifest"];
+
+// How many changesets or nodes are fetched from the blob repo at the same time
+const FETCH_CONCURRENCY: usize = 100;
+// Largest read from the spill file, so that a large revision isn't read in memory at once
+const READ_SIZE: u64 = 1024 * 1024;
+const SPILL_FILE: &str = "store";
+
+/// The last generated store, along with the heads of the repo it was generated for. Only that
+/// one is kept: the spill file of a store goes away once it's replaced and the responses that
+/// were reading it are sent.
+#[derive(Clone)]
+pub struct StoreCache {
+    cached: Arc<Mutex<Option<(Vec<NodeHash>, Store)>>>,
+}
+
+impl StoreCache {
+    pub fn new() -> Self {
+        StoreCache {
+            cached: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The store for the current heads of the repo, generating it if they moved since the last
+    /// one.
+    pub fn get_store(&self, repo: Arc<BlobRepo>, logger: Logger) -> BoxFuture<Store, Error> {
+        let cached = self.cached.clone();
+
+        repo.get_heads()
+            .collect()
+            .and_then(move |mut heads| {
+                heads.sort();
+                if let Some((ref cached_heads, ref store)) =
+                    *cached.lock().expect("lock poisoned")
+                {
+                    if *cached_heads == heads {
+                        debug!(logger, "serving cached store");
+                        return future::ok(store.clone()).boxify();
+                    }
+                }
+
+                // Changesets pushed while the store is generated may end up in it: the heads
+                // then differ at the next clone, and a new store is generated
+                generate_store(repo, logger)
+                    .map(move |store| {
+                        *cached.lock().expect("lock poisoned") = Some((heads, store.clone()));
+                        store
+                    })
+                    .boxify()
+            })
+            .boxify()
+    }
+}
+
+/// A generated store, whose revlogs are in a spill file.
+#[derive(Clone)]
+pub struct Store {
+    dir: Arc<TempDir>,
+    // The `stream_out` response: the status line, the size of the store, and then every file of
+    // the store preceded by its name and size
+    parts: Arc<Vec<Part>>,
+}
+
+enum Part {
+    Bytes(Bytes),
+    // Offset and size of a part of the spill file
+    Spilled(u64, u64),
+}
+
+impl Store {
+    /// The `stream_out` response, read from the spill file as it is sent.
+    pub fn response(&self) -> BoxStream<Bytes, Error> {
+        let mut reader = match File::open(self.dir.path().join(SPILL_FILE)) {
+            Ok(file) => SpillReader {
+                file,
+                _dir: self.dir.clone(),
+            },
+            Err(err) => return stream::once(Err(err.into())).boxify(),
+        };
+        let parts = self.parts.clone();
+
+        stream::iter_ok(0..parts.len())
+            .and_then(move |idx| match parts[idx] {
+                Part::Bytes(ref bytes) => Ok(bytes.clone()),
+                Part::Spilled(offset, size) => reader.read(offset, size),
+            })
+            .boxify()
+    }
+}
+
+/// Reads the spill file of a store, which is kept as long as it's being read.
+struct SpillReader {
+    file: File,
+    _dir: Arc<TempDir>,
+}
+
+impl SpillReader {
+    fn read(&mut self, offset: u64, size: u64) -> Result<Bytes> {
+        let mut content = vec![0; size as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut content)?;
+        Ok(Bytes::from(content))
+    }
+}
+
+/// The file that the revlogs of a store are appended to as they are generated.
+struct Spill {
+    dir: TempDir,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl Spill {
+    fn new() -> Result<Self> {
+        let dir = TempDir::new("streamclone")?;
+        let file = File::create(dir.path().join(SPILL_FILE))?;
+        Ok(Spill {
+            dir,
+            file: BufWriter::new(file),
+            size: 0,
+        })
+    }
+
+    /// Appends `content`, and returns its offset in the file.
+    fn append(&mut self, content: &[u8]) -> Result<u64> {
+        self.file.write_all(content)?;
+        let offset = self.size;
+        self.size += content.len() as u64;
+        Ok(offset)
+    }
+
+    /// The directory of the complete file.
+    fn finish(mut self) -> Result<TempDir> {
+        self.file.flush()?;
+        Ok(self.dir)
+    }
+}
+
+/// A revlog that is appended to the spill file as it is generated.
+struct SpilledRevlog {
+    builder: RevlogBuilder,
+    // Offsets and sizes of its parts in the spill file, in order
+    parts: Vec<(u64, u64)>,
+}
+
+impl SpilledRevlog {
+    fn new() -> Self {
+        SpilledRevlog {
+            builder: RevlogBuilder::new(),
+            parts: Vec::new(),
+        }
+    }
+
+    fn add_node(
+        &mut self,
+        spill: &mut Spill,
+        node: NodeHash,
+        raw: &BlobNode,
+        linkrev: RevIdx,
+    ) -> Result<()> {
+        add_node(&mut self.builder, node, raw, linkrev)?;
+        let content = self.builder.take_bytes();
+        let offset = spill.append(&content)?;
+        let size = content.len() as u64;
+
+        // The revisions of a revlog are often written one after the other
+        if let Some(last) = self.parts.last_mut() {
+            if last.0 + last.1 == offset {
+                last.1 += size;
+                return Ok(());
+            }
+        }
+        self.parts.push((offset, size));
+        Ok(())
+    }
+}
+
+fn generate_store(repo: Arc<BlobRepo>, logger: Logger) -> BoxFuture<Store, Error> {
+    sorted_changesets(repo.clone())
+        .and_then(move |changesets| {
+            info!(logger, "generating store for {} changesets", changesets.len());
+            StoreBuilder::new(repo.clone())
+                .into_future()
+                .and_then(move |store| {
+                    stream::iter_ok(changesets.into_iter().enumerate()).fold(
+                        store,
+                        |store, (rev, csid)| store.add_changeset(RevIdx::from(rev), csid),
+                    )
+                })
+        })
+        .and_then(|store| store.into_store())
+        .boxify()
+}
+
+/// All the changesets of the repo, parents first.
+fn sorted_changesets(repo: Arc<BlobRepo>) -> BoxFuture<Vec<HgChangesetId>, Error> {
+    repo.get_changesets()
+        .map({
+            let repo = repo.clone();
+            move |node| {
+                let csid = HgChangesetId::new(node);
+                repo.get_generation_number(&csid)
+                    .and_then(move |gen| match gen {
+                        Some(gen) => Ok((gen, csid)),
+                        None => Err(format_err!("changeset {} has no generation number", csid)),
+                    })
+            }
+        })
+        .buffered(FETCH_CONCURRENCY)
+        .collect()
+        .map(|mut changesets| {
+            // A changeset's generation number is greater than its parents'
+            changesets.sort();
+            changesets.into_iter().map(|(_, csid)| csid).collect()
+        })
+        .boxify()
+}
+
+struct StoreBuilder {
+    repo: Arc<BlobRepo>,
+    spill: Spill,
+    changelog: SpilledRevlog,
+    manifestlog: SpilledRevlog,
+    // Revlogs of directories and files, by store path
+    revlogs: BTreeMap<Vec<u8>, SpilledRevlog>,
+    // Root manifests of the changesets added so far, to diff against their children
+    manifests: HashMap<HgChangesetId, HgManifestId>,
+}
+
+impl StoreBuilder {
+    fn new(repo: Arc<BlobRepo>) -> Result<Self> {
+        Ok(StoreBuilder {
+            repo,
+            spill: Spill::new()?,
+            changelog: SpilledRevlog::new(),
+            manifestlog: SpilledRevlog::new(),
+            revlogs: BTreeMap::new(),
+            manifests: HashMap::new(),
+        })
+    }
+
+    /// Adds the changeset and all the manifests and files it introduces, linked to `linkrev`.
+    fn add_changeset(mut self, linkrev: RevIdx, csid: HgChangesetId) -> BoxFuture<Self, Error> {
+        let repo = self.repo.clone();
+        let raw_changeset = repo.get_raw_changeset(&csid)
+            .and_then(move |raw| raw.ok_or(format_err!("changeset {} is missing", csid)));
+        let changeset = repo.get_changeset_by_changesetid(&csid);
+
+        raw_changeset
+            .join(changeset)
+            .and_then(move |(raw, cs)| {
+                self.changelog
+                    .add_node(&mut self.spill, *csid.as_nodehash(), &raw, linkrev)?;
+
+                let mfid = *cs.manifestid();
+                let p1_mfid = match cs.parents().get_nodes().0 {
+                    Some(p1) => self.manifests
+                        .get(&HgChangesetId::new(*p1))
+                        .cloned()
+                        .ok_or(format_err!("parent of {} was not added first", csid))?,
+                    None => HgManifestId::new(NULL_HASH),
+                };
+                self.manifests.insert(csid, mfid);
+                Ok((self, mfid, p1_mfid))
+            })
+            .and_then(move |(store, mfid, p1_mfid)| {
+                let nodes = new_nodes(repo.clone(), mfid, p1_mfid)
+                    .map(move |(path, node)| {
+                        repo.get_raw_node(&node).and_then(move |raw| match raw {
+                            Some(raw) => Ok((path, node, raw)),
+                            None => Err(format_err!("node {} is missing", node)),
+                        })
+                    })
+                    .buffered(FETCH_CONCURRENCY);
+
+                nodes.fold(store, move |mut store, (path, node, raw)| {
+                    store.add_node(path, node, &raw, linkrev)?;
+                    Ok::<_, Error>(store)
+                })
+            })
+            .boxify()
+    }
+
+    fn add_node(
+        &mut self,
+        path: Option<Vec<u8>>,
+        node: NodeHash,
+        raw: &BlobNode,
+        linkrev: RevIdx,
+    ) -> Result<()> {
+        let revlog = match path {
+            None => &mut self.manifestlog,
+            Some(path) => self.revlogs.entry(path).or_insert_with(SpilledRevlog::new),
+        };
+        // Nodes that a merge took from its second parent are "changed" compared to the first
+        // one, but they have been added already
+        if revlog.builder.contains(&node) {
+            Ok(())
+        } else {
+            revlog.add_node(&mut self.spill, node, raw, linkrev)
+        }
+    }
+
+    /// The store with everything that is sent to the client. Like Mercurial, the changelog is
+    /// sent last.
+    fn into_store(self) -> Result<Store> {
+        let StoreBuilder {
+            spill,
+            changelog,
+            manifestlog,
+            revlogs,
+            ..
+        } = self;
+
+        let mut files: Vec<(Vec<u8>, SpilledRevlog)> = revlogs.into_iter().collect();
+        files.push((b"00manifest.i".to_vec(), manifestlog));
+        files.push((b"00changelog.i".to_vec(), changelog));
+        // An empty repo has no revlogs at all
+        files.retain(|&(_, ref revlog)| !revlog.builder.is_empty());
+
+        let total_size: u64 = files.iter().map(|&(_, ref revlog)| revlog.builder.size()).sum();
+        let mut parts = vec![
+            Part::Bytes(Bytes::from(&b"0\n"[..])),
+            Part::Bytes(Bytes::from(format!("{} {}\n", files.len(), total_size))),
+        ];
+        for (path, revlog) in files {
+            let size = format!("{}", revlog.builder.size());
+            let mut header = BytesMut::with_capacity(path.len() + size.len() + 2);
+            header.put_slice(&path);
+            header.put_u8(b'\0');
+            header.put_slice(size.as_bytes());
+            header.put_u8(b'\n');
+            parts.push(Part::Bytes(header.freeze()));
+            for (offset, size) in revlog.parts {
+                let mut read = 0;
+                while read < size {
+                    let len = (size - read).min(READ_SIZE);
+                    parts.push(Part::Spilled(offset + read, len));
+                    read += len;
+                }
+            }
+        }
+
+        Ok(Store {
+            dir: Arc::new(spill.finish()?),
+            parts: Arc::new(parts),
+        })
+    }
+}
+
+fn add_node(
+    revlog: &mut RevlogBuilder,
+    node: NodeHash,
+    raw: &BlobNode,
+    linkrev: RevIdx,
+) -> Result<()> {
+    let text = raw.as_blob()
+        .as_slice()
+        .ok_or(format_err!("content of node {} is missing", node))?;
+    let (p1, p2) = raw.parents().get_nodes();
+    revlog.add_revision(node, p1.cloned(), p2.cloned(), linkrev, text)?;
+    Ok(())
+}
+
+/// The root manifest and every directory and file node of `mfid` that is not in `p1_mfid`, with
+/// the store path of its revlog (`None` for the root manifest).
+fn new_nodes(
+    repo: Arc<BlobRepo>,
+    mfid: HgManifestId,
+    p1_mfid: HgManifestId,
+) -> BoxStream<(Option<Vec<u8>>, NodeHash), Error> {
+    let root = stream::once(Ok((None, *mfid.as_nodehash())));
+
+    let manifest = repo.get_manifest_by_nodeid(mfid.as_nodehash());
+    let p1_manifest = repo.get_manifest_by_nodeid(p1_mfid.as_nodehash());
+    let changed = manifest
+        .join(p1_manifest)
+        .map(|(mf, p1_mf)| changed_entry_stream(&mf, &p1_mf, MPath::empty()))
+        .flatten_stream()
+        .filter_map(|changed| match changed.status {
+            EntryStatus::Added(entry) | EntryStatus::Modified(entry, _) => {
+                let path = changed.path.join_element(entry.get_name());
+                Some((Some(store_path(&path, &*entry)), entry.get_hash().into_nodehash()))
+            }
+            EntryStatus::Deleted(_) => None,
+        });
+
+    root.chain(changed).boxify()
+}
+
+/// Where Mercurial keeps the revlog of an entry, relative to the store.
+fn store_path(path: &MPath, entry: &Entry) -> Vec<u8> {
+    let path = path.to_vec();
+    let mut store_path = Vec::with_capacity(path.len() + 16);
+    if entry.get_type() == Type::Tree {
+        store_path.extend_from_slice(b"meta/");
+        store_path.extend_from_slice(&path);
+        store_path.extend_from_slice(b"/00manifest.i");
+    } else {
+        store_path.extend_from_slice(b"data/");
+        store_path.extend_from_slice(&path);
+        store_path.extend_from_slice(b".i");
+    }
+    encode_dir(store_path)
+}
+
+/// Mercurial's `encodedir`: directories that look like revlogs or `.hg` get a `.hg` suffix, so
+/// that they can't clash with the files of the store. Clients decode this before writing.
+fn encode_dir(path: Vec<u8>) -> Vec<u8> {
+    let mut path = path;
+    for &(from, to) in &[
+        (&b".hg/"[..], &b".hg.hg/"[..]),
+        (&b".i/"[..], &b".i.hg/"[..]),
+        (&b".d/"[..], &b".d.hg/"[..]),
+    ] {
+        path = replace_all(mem::replace(&mut path, Vec::new()), from, to);
+    }
+    path
+}
+
+fn replace_all(haystack: Vec<u8>, from: &[u8], to: &[u8]) -> Vec<u8> {
+    if !haystack.windows(from.len()).any(|window| window == from) {
+        return haystack;
+    }
+
+    let mut out = Vec::with_capacity(haystack.len() + to.len());
+    let mut idx = 0;
+    while idx < haystack.len() {
+        if haystack[idx..].starts_with(from) {
+            out.extend_from_slice(to);
+            idx += from.len();
+        } else {
+            out.push(haystack[idx]);
+            idx += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use many_files_dirs;
+    use slog::{Discard, Drain};
+
+    #[test]
+    fn test_store_cache() {
+        let repo = Arc::new(many_files_dirs::getrepo(None));
+        let logger = Logger::root(Discard {}.ignore_res(), o!());
+        let cache = StoreCache::new();
+
+        let first = cache.get_store(repo.clone(), logger.clone()).wait().unwrap();
+        // The heads didn't move, so the same store is served again
+        let second = cache.get_store(repo, logger).wait().unwrap();
+        assert!(Arc::ptr_eq(&first.dir, &second.dir));
+
+        let parts = first.response().collect().wait().unwrap();
+        let response: Vec<u8> = parts.iter().flat_map(|part| part.iter().cloned()).collect();
+        let mut lines = response.splitn(3, |byte| *byte == b'\n');
+        assert_eq!(lines.next(), Some(&b"0"[..]));
+        let sizes = String::from_utf8(lines.next().unwrap().to_vec()).unwrap();
+        let total_size: usize = sizes.split(' ').nth(1).unwrap().parse().unwrap();
+        // Each file is preceded by its name and size
+        let files = lines.next().unwrap();
+        assert!(files.len() > total_size);
+        assert!(files.starts_with(b"data/"));
+        assert!(
+            files
+                .windows(b"00changelog.i\0".len())
+                .any(|window| window == b"00changelog.i\0")
+        );
+    }
+
+    #[test]
+    fn test_encode_dir() {
+        assert_eq!(encode_dir(b"data/foo.i".to_vec()), b"data/foo.i".to_vec());
+        assert_eq!(
+            encode_dir(b"data/.hg/hgrc.i".to_vec()),
+            b"data/.hg.hg/hgrc.i".to_vec()
+        );
+        assert_eq!(
+            encode_dir(b"meta/a.i/b.d/00manifest.i".to_vec()),
+            b"meta/a.i.hg/b.d.hg/00manifest.i".to_vec()
+        );
+    }
+}
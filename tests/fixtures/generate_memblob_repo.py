@@ -40,8 +40,8 @@ if __name__ == '__main__':
 // GNU General Public License version 2 or any later version.
 
 extern crate changesets;
+extern crate dbbookmarks;
 extern crate memblob;
-extern crate membookmarks;
 extern crate mercurial_types;
 extern crate memheads;
 extern crate memlinknodes;
@@ -57,8 +57,8 @@ use std::str::FromStr;
 
 use bytes::Bytes;
 use changesets::{Changesets, ChangesetInsert, SqliteChangesets};
+use dbbookmarks::SqliteDbBookmarks;
 use memblob::EagerMemblob;
-use membookmarks::MemBookmarks;
 use mercurial_types::{HgChangesetId, NodeHash, RepositoryId};
 use memheads::MemHeads;
 use memlinknodes::MemLinknodes;
@@ -70,7 +70,8 @@ use futures::future::Future;
 use slog::Logger;
 
 pub fn getrepo(logger: Option<Logger>) -> BlobRepo {
-    let bookmarks: MemBookmarks = MemBookmarks::new();
+    let bookmarks = SqliteDbBookmarks::in_memory()
+        .expect("cannot create in-memory bookmarks table");
     let heads: MemHeads = MemHeads::new();
     let blobs = EagerMemblob::new();
     let linknodes = MemLinknodes::new();
@@ -59,7 +59,6 @@ function blobimport {
   $MONONOKE_BLOBIMPORT --blobstore rocksdb --linknodes "$@" >> "$TESTTMP/blobimport.out" 2>&1
   reponame=$_
   mkdir -p "$reponame"/.hg
-  mkdir -p "$reponame"/books
 }
 
 function edenserver {
@@ -0,0 +1,58 @@
+  $ . $TESTDIR/library.sh
+
+setup configuration
+
+  $ setup_common_config
+
+  $ cd $TESTTMP
+
+setup repo
+
+  $ hginit_treemanifest repo-hg
+  $ cd repo-hg
+  $ echo "a file content" > a
+  $ hg add a
+  $ hg ci -ma
+
+  $ cd $TESTTMP
+  $ blobimport repo-hg repo
+
+setup two repos: one will be used to push from, another will be used
+to pull these pushed commits
+
+  $ hgclone_treemanifest ssh://user@dummy/repo-hg repo-push
+  $ hgclone_treemanifest ssh://user@dummy/repo-hg repo-pull
+
+start mononoke
+
+  $ mononoke -P $TESTTMP/mononoke-config -B test-config
+  $ wait_for_mononoke $TESTTMP/repo
+
+The hg client doesn't ask for compressed bundle2 replies by default. This
+extension adds the compressions it can read to the bundle2 caps it sends, and
+prints the compression of every bundle2 it reads
+
+  $ cat > $TESTTMP/compression.py <<'EOF'
+  > from mercurial import bundle2
+  > bundle2.capabilities['compression'] = ('GZ', 'UN')
+  > origcompression = bundle2.b2streamparamsmap['compression']
+  > def processcompression(unbundler, param, value):
+  >     unbundler.ui.write('bundle2 compression: %s\n' % value)
+  >     return origcompression(unbundler, param, value)
+  > bundle2.b2streamparamsmap['compression'] = processcompression
+  > EOF
+
+The reply to a push is compressed
+
+  $ cd repo-push
+  $ echo b > b && hg add b && hg ci -m b
+  $ hgmn push -q --force --config extensions.compression=$TESTTMP/compression.py | grep -v UN
+  bundle2 compression: GZ
+
+And so is the bundle of a pull
+
+  $ cd ../repo-pull
+  $ hgmn pull -q --config extensions.compression=$TESTTMP/compression.py | grep -v UN
+  bundle2 compression: GZ
+  $ hg log -r tip -T '{desc}\n'
+  b
@@ -44,7 +44,7 @@ Do infinitepush (aka commit cloud) push
   sending hello command
   sending between command
   remote: * (glob)
-  remote: capabilities: lookup known getbundle unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog bundle2=* (glob)
+  remote: capabilities: lookup known pushkey getbundle unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog streamreqs=revlogv1,treemanifest bundle2=* (glob)
   remote: 1
   query 1; heads
   sending batch command
@@ -87,7 +87,7 @@ Pushbackup also works
   sending hello command
   sending between command
   remote: * (glob)
-  remote: capabilities: lookup known getbundle unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog bundle2=* (glob)
+  remote: capabilities: lookup known pushkey getbundle unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog streamreqs=revlogv1,treemanifest bundle2=* (glob)
   remote: 1
   query 1; heads
   sending batch command
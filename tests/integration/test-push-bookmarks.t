@@ -37,7 +37,7 @@ Push with bookmark
   sending hello command
   sending between command
   remote: 204
-  remote: capabilities: lookup known getbundle unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog bundle2=* (glob)
+  remote: capabilities: lookup known pushkey getbundle unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog streamreqs=revlogv1,treemanifest bundle2=* (glob)
   remote: 1
   pushing rev 11f53bbd855a to destination ssh://user@dummy/repo bookmark withbook
   query 1; heads
@@ -56,6 +56,31 @@ Push with bookmark
   bundle2-output-part: "b2x:treegroup2" (params: 3 mandatory) streamed payload
   bundle2-input-bundle: 1 params no-transaction
   bundle2-input-part: "reply:changegroup" (params: 2 mandatory) supported
-  bundle2-input-bundle: 0 parts total
-  server ignored bookmark withbook update
+  bundle2-input-part: "reply:pushkey" (params: 2 mandatory) supported
+  bundle2-input-bundle: 1 parts total
+  exporting bookmark withbook
   sending branchmap command
+
+Pull the pushed commit by a prefix of its hash and by the bookmark name
+  $ cd ../repo-pull
+  $ hgmn pull -r 11f53bbd -q
+  $ hg log -r 11f53bbd855a -T '{node|short} {desc}\n'
+  11f53bbd855a withbook
+  $ hgmn pull -r withbook -q
+
+The bookmark was moved on the server
+  $ hgmn debugpushkey ssh://user@dummy/repo bookmarks
+  withbook\t11f53bbd855ac06521a8895bd57e6ce5f46a9980 (esc)
+
+Move the bookmark forward with another push
+  $ cd ../repo-push
+  $ echo moved > withbook && hg ci -m moved
+  $ hgmn push --config extensions.remotenames= --to withbook -q
+  $ hg log -r . -T '{node}\n' | xargs printf 'withbook\t%s\n' > $TESTTMP/expected
+  $ hgmn debugpushkey ssh://user@dummy/repo bookmarks | diff - $TESTTMP/expected
+
+Moving the bookmark to a changeset the server doesn't have is refused
+  $ hgmn debugpushkey ssh://user@dummy/repo bookmarks withbook "$(hg log -r . -T '{node}')" 1111111111111111111111111111111111111111
+  False
+  [1]
+  $ hgmn debugpushkey ssh://user@dummy/repo bookmarks | diff - $TESTTMP/expected